@@ -1,6 +1,264 @@
+extern crate toml;
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
 use std::process::Command;
+use toml::Value;
+
+/// Isolation policy of the kernel, turned into src/policy.rs's generated part
+const POLICY_FILE: &str = "isolation.toml";
+
+/// Compartments the kernel refers to by name, with the section of their statics (see macros.rs)
+const REQUIRED_COMPARTMENTS: [(&str, Option<&str>); 8] = [
+	("kernel", Some(".safe_data")),
+	("unsafe", Some(".unsafe_data")),
+	("shared", None),
+	("sealed", None),
+	("secret", None),
+	("jit", None),
+	("shadow", None),
+	("fnptr", None),
+];
+
+/// Domains, which may appear in the rights and calls of the policy
+const DOMAINS: [(&str, &str); 3] = [("user", "User"), ("kernel", "Kernel"), ("isolated", "Isolated")];
+
+/// Windows of the data sections are mapped with 2 MiB pages.
+const LARGE_PAGE_SIZE: i64 = 0x200000;
+
+struct Compartment {
+	name: String,
+	key: i64,
+	section: Option<(String, i64, i64)>,
+}
+
+fn policy_error(message: String) -> ! {
+	panic!("{}: {}", POLICY_FILE, message);
+}
+
+fn get_str<'a>(table: &'a Value, field: &str, context: &str) -> &'a str {
+	table
+		.get(field)
+		.and_then(Value::as_str)
+		.unwrap_or_else(|| policy_error(format!("{} needs the string `{}`", context, field)))
+}
+
+fn get_int(table: &Value, field: &str, context: &str) -> i64 {
+	table
+		.get(field)
+		.and_then(Value::as_integer)
+		.unwrap_or_else(|| policy_error(format!("{} needs the integer `{}`", context, field)))
+}
+
+fn parse_compartments(policy: &Value) -> Vec<Compartment> {
+	let entries = policy
+		.get("compartment")
+		.and_then(Value::as_array)
+		.unwrap_or_else(|| policy_error("no [[compartment]] found".to_string()));
+
+	let mut compartments: Vec<Compartment> = Vec::new();
+	for entry in entries {
+		let name = get_str(entry, "name", "[[compartment]]").to_string();
+		let context = format!("compartment `{}`", name);
+		let key = get_int(entry, "key", &context);
+
+		if key < 1 || key > 15 {
+			policy_error(format!("{} has key {}, only 1 to 15 are available", context, key));
+		}
+		if let Some(other) = compartments.iter().find(|other| other.name == name || other.key == key) {
+			policy_error(format!("{} clashes with compartment `{}` (key {})", context, other.name, other.key));
+		}
+
+		let section = entry.get("section").map(|_| {
+			(
+				get_str(entry, "section", &context).to_string(),
+				get_int(entry, "start", &context),
+				get_int(entry, "size", &context),
+			)
+		});
+		compartments.push(Compartment { name, key, section });
+	}
+
+	for (name, section) in REQUIRED_COMPARTMENTS.iter() {
+		let compartment = compartments
+			.iter()
+			.find(|compartment| compartment.name == *name)
+			.unwrap_or_else(|| policy_error(format!("compartment `{}` is missing", name)));
+
+		// The section names are part of safe_global_var! and unsafe_global_var!.
+		match (section, &compartment.section) {
+			(Some(expected), Some((section, start, size))) => {
+				if section.as_str() != *expected {
+					policy_error(format!("compartment `{}` must use the section {}", name, expected));
+				}
+				if start % LARGE_PAGE_SIZE != 0 || size % LARGE_PAGE_SIZE != 0 || *size == 0 {
+					policy_error(format!("the window of {} must consist of 2 MiB pages", section));
+				}
+			}
+			(Some(expected), None) => policy_error(format!("compartment `{}` needs the section {}", name, expected)),
+			(None, Some(_)) => policy_error(format!("compartment `{}` cannot have a section", name)),
+			(None, None) => {}
+		}
+	}
+
+	compartments
+}
+
+/// Returns the window of the section of `name`.
+fn section_window(compartments: &[Compartment], name: &str) -> (i64, i64) {
+	let compartment = compartments.iter().find(|compartment| compartment.name == name).unwrap();
+	let (_, start, size) = compartment.section.as_ref().unwrap();
+	(*start, *size)
+}
+
+/// Translates the rights of `domain` into PKRU format.
+fn parse_rights(policy: &Value, domain: &str, compartments: &[Compartment]) -> u32 {
+	let rights = match policy.get("domain").and_then(|domains| domains.get(domain)) {
+		Some(rights) => rights
+			.as_table()
+			.unwrap_or_else(|| policy_error(format!("[domain.{}] must be a table", domain))),
+		None => return 0,
+	};
+
+	let mut permission = 0;
+	for (name, access) in rights {
+		let compartment = compartments
+			.iter()
+			.find(|compartment| compartment.name == *name)
+			.unwrap_or_else(|| policy_error(format!("[domain.{}] names the unknown compartment `{}`", domain, name)));
+
+		let bits = match access.as_str() {
+			Some("none") => 0b11,
+			Some("read") => 0b10,
+			Some("write") => 0b00,
+			_ => policy_error(format!("[domain.{}] gives `{}` neither \"none\", \"read\" nor \"write\"", domain, name)),
+		};
+		permission |= bits << (2 * compartment.key);
+	}
+
+	permission
+}
+
+fn domain_variant(name: &str) -> &'static str {
+	DOMAINS
+		.iter()
+		.find(|domain| domain.0 == name)
+		.map(|domain| domain.1)
+		.unwrap_or_else(|| policy_error(format!("unknown domain `{}`", name)))
+}
+
+fn parse_calls(policy: &Value) -> Vec<(&'static str, &'static str)> {
+	let entries = match policy.get("call").and_then(Value::as_array) {
+		Some(entries) => entries,
+		None => return Vec::new(),
+	};
+
+	entries
+		.iter()
+		.map(|entry| {
+			let from = domain_variant(get_str(entry, "from", "[[call]]"));
+			let to = domain_variant(get_str(entry, "to", "[[call]]"));
+			(from, to)
+		})
+		.collect()
+}
+
+/// Warns if the linker script of the test application places a data section elsewhere.
+fn check_linker_script(compartments: &[Compartment]) {
+	let script = match fs::read_to_string("tests/src/linker.ld") {
+		Ok(script) => script,
+		Err(_) => return,
+	};
+
+	for compartment in compartments {
+		if let Some((section, start, _)) = &compartment.section {
+			let placement = format!("{} {:#x}:", section, start);
+			if !script.contains(&placement) {
+				println!("cargo:warning=tests/src/linker.ld does not place {} at {:#x}", section, start);
+			}
+		}
+	}
+
+	// mm::fnptr and mm::seal_boot_data find their pages through these symbols.
+	for symbol in ["__fnptr_start", "__fnptr_end", "__sealed_start", "__sealed_end"].iter() {
+		if !script.contains(symbol) {
+			println!("cargo:warning=tests/src/linker.ld does not define {}", symbol);
+		}
+	}
+}
+
+/// Reads the isolation policy and generates the constants and tables of src/policy.rs.
+fn generate_policy() {
+	let text = fs::read_to_string(POLICY_FILE).unwrap_or_else(|err| policy_error(err.to_string()));
+	let policy: Value = text.parse().unwrap_or_else(|err: toml::de::Error| policy_error(err.to_string()));
+
+	let compartments = parse_compartments(&policy);
+	let (kernel_start, kernel_size) = section_window(&compartments, "kernel");
+	let (unsafe_start, unsafe_size) = section_window(&compartments, "unsafe");
+	// The kernel image treats both windows as one range (see paging::protect_kernel_image).
+	if unsafe_start != kernel_start + kernel_size || unsafe_size != kernel_size {
+		policy_error("the window of .unsafe_data must follow the one of .safe_data with the same size".to_string());
+	}
+	check_linker_script(&compartments);
+
+	let user_permission = parse_rights(&policy, "user", &compartments);
+	let isolated_permission = parse_rights(&policy, "isolated", &compartments);
+	let calls = parse_calls(&policy);
+	// The isolated calls and kernel callbacks are not checked at runtime, so they must be allowed.
+	for required in [("Kernel", "Isolated"), ("Isolated", "Kernel")].iter() {
+		if !calls.contains(required) {
+			policy_error(format!(
+				"calls from `{}` to `{}` must be allowed",
+				required.0.to_lowercase(),
+				required.1.to_lowercase()
+			));
+		}
+	}
+
+	let mut out = String::new();
+	writeln!(out, "// Generated by build.rs from {}, do not edit.", POLICY_FILE).unwrap();
+	writeln!(out).unwrap();
+	for compartment in &compartments {
+		writeln!(out, "pub const {}_KEY: u8 = {};", compartment.name.to_uppercase(), compartment.key).unwrap();
+	}
+	let first_free_key = compartments.iter().map(|compartment| compartment.key).max().unwrap() + 1;
+	writeln!(out, "pub const FIRST_FREE_KEY: u8 = {};", first_free_key).unwrap();
+	writeln!(out, "pub const SAFE_DATA_START: usize = {:#x};", kernel_start).unwrap();
+	writeln!(out, "pub const UNSAFE_DATA_START: usize = {:#x};", unsafe_start).unwrap();
+	writeln!(out, "pub const DATA_SECTION_SIZE: usize = {:#x};", kernel_size).unwrap();
+	writeln!(out, "pub const USER_PERMISSION: u32 = {:#x};", user_permission).unwrap();
+	writeln!(out, "pub const ISOLATED_PERMISSION: u32 = {:#x};", isolated_permission).unwrap();
+
+	writeln!(out, "pub static COMPARTMENTS: [Compartment; {}] = [", compartments.len()).unwrap();
+	for compartment in &compartments {
+		let section = match &compartment.section {
+			Some((section, _, _)) => format!("Some({:?})", section),
+			None => "None".to_string(),
+		};
+		writeln!(
+			out,
+			"\tCompartment {{ name: {:?}, key: {}, section: {} }},",
+			compartment.name, compartment.key, section
+		)
+		.unwrap();
+	}
+	writeln!(out, "];").unwrap();
+
+	writeln!(out, "pub static CALLS: [Call; {}] = [", calls.len()).unwrap();
+	for (from, to) in &calls {
+		writeln!(out, "\tCall {{ from: Domain::{}, to: Domain::{} }},", from, to).unwrap();
+	}
+	writeln!(out, "];").unwrap();
+
+	let path = Path::new(&env::var("OUT_DIR").unwrap()).join("policy.rs");
+	fs::write(path, out).unwrap();
+}
 
 fn main() {
+	generate_policy();
+
 	// create boot code for application processors
 	/*let _ = Command::new("nasm").args(&["-f", "bin", "-o", "src/arch/x86_64/kernel/boot.bin", "src/arch/x86_64/kernel/boot.asm"]).output().unwrap();
 	let _ = Command::new("sh").args(&["-c", "echo -n \"pub static SMP_BOOT_CODE: [u8; \" > src/arch/x86_64/kernel/smp_boot_code.rs"]).output().unwrap();
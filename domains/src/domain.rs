@@ -0,0 +1,126 @@
+//! Compartments backed by protection keys.
+
+use crate::pkru::{self, Access, PkruGuard};
+use crate::sys::*;
+use crate::{check, Result, ENOMEM};
+use std::alloc::{self, Layout};
+use std::marker::PhantomData;
+use std::ptr::{self, NonNull};
+
+const PAGE_SIZE: usize = 4096;
+
+/// A compartment of the application with its own protection key.
+///
+/// The domain is closed everywhere except inside `call`. Other threads start without rights
+/// for application keys, so they cannot reach the domain, and it stays with its thread.
+pub struct Domain {
+	pkey: i32,
+	_thread: PhantomData<*const ()>,
+}
+
+impl Domain {
+	/// Allocates a protection key for a new, closed domain.
+	/// Fails with `ENOSPC` if no key is left or the CPU lacks PKU.
+	pub fn new() -> Result<Domain> {
+		let pkey = check(unsafe { sys_pkey_alloc(0, PKEY_DISABLE_ACCESS) })?;
+
+		Ok(Domain {
+			pkey,
+			_thread: PhantomData,
+		})
+	}
+
+	/// Returns the protection key of the domain.
+	pub fn pkey(&self) -> i32 {
+		self.pkey
+	}
+
+	/// Returns whether the current thread may access the domain right now.
+	pub fn is_open(&self) -> bool {
+		pkru::access(self.pkey) != Access::None
+	}
+
+	/// Runs `f` with read and write access to the domain.
+	pub fn call<R, F: FnOnce() -> R>(&self, f: F) -> R {
+		let _guard = PkruGuard::new(self, Access::ReadWrite);
+		f()
+	}
+
+	/// Runs `f` with read access to the domain.
+	pub fn call_read_only<R, F: FnOnce() -> R>(&self, f: F) -> R {
+		let _guard = PkruGuard::new(self, Access::ReadOnly);
+		f()
+	}
+
+	/// Moves `value` into memory of the domain.
+	pub fn boxed<T>(&self, value: T) -> Result<DomainBox<T>> {
+		let size = (std::mem::size_of::<T>().max(1) + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+		let layout = Layout::from_size_align(size, PAGE_SIZE).map_err(|_| ENOMEM)?;
+
+		let memory = NonNull::new(unsafe { alloc::alloc(layout) } as *mut T).ok_or(ENOMEM)?;
+		let ret = unsafe { sys_pkey_mprotect(memory.as_ptr() as usize, size, PROT_READ | PROT_WRITE, self.pkey) };
+		if let Err(error) = check(ret) {
+			unsafe { alloc::dealloc(memory.as_ptr() as *mut u8, layout) };
+			return Err(error);
+		}
+
+		self.call(|| unsafe { ptr::write(memory.as_ptr(), value) });
+
+		Ok(DomainBox {
+			memory,
+			layout,
+			domain: self,
+		})
+	}
+}
+
+impl Drop for Domain {
+	fn drop(&mut self) {
+		// Boxes borrow the domain, so no memory of it is left.
+		let _ = check(unsafe { sys_pkey_free(self.pkey) });
+	}
+}
+
+/// A value in the memory of a domain, which is only accessible through the box.
+pub struct DomainBox<'d, T> {
+	memory: NonNull<T>,
+	layout: Layout,
+	domain: &'d Domain,
+}
+
+impl<'d, T> DomainBox<'d, T> {
+	/// Returns the domain of the value.
+	pub fn domain(&self) -> &'d Domain {
+		self.domain
+	}
+
+	/// Runs `f` with the value, while the domain is readable.
+	pub fn with<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+		let value = self.memory.as_ptr();
+		self.domain.call_read_only(|| f(unsafe { &*value }))
+	}
+
+	/// Runs `f` with the value, while the domain is writable.
+	pub fn with_mut<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+		let value = self.memory.as_ptr();
+		self.domain.call(|| f(unsafe { &mut *value }))
+	}
+
+	/// Returns the address of the value. It is only accessible inside `Domain::call`.
+	pub fn as_ptr(&self) -> *const T {
+		self.memory.as_ptr()
+	}
+}
+
+impl<'d, T> Drop for DomainBox<'d, T> {
+	fn drop(&mut self) {
+		let memory = self.memory.as_ptr();
+		self.domain.call(|| unsafe { ptr::drop_in_place(memory) });
+
+		// The heap only gets the memory back with the default key, otherwise it is leaked.
+		let ret = unsafe { sys_pkey_mprotect(memory as usize, self.layout.size(), PROT_READ | PROT_WRITE, 0) };
+		if check(ret).is_ok() {
+			unsafe { alloc::dealloc(memory as *mut u8, self.layout) };
+		}
+	}
+}
@@ -0,0 +1,50 @@
+//! Safe compartments for applications on top of the kernel.
+//!
+//! A [`Domain`] owns a protection key of the application. Memory placed in a
+//! [`DomainBox`] carries this key and is only accessible inside [`Domain::call`]
+//! (or through the box itself), because the domain is closed everywhere else.
+//! [`PkruGuard`] switches the rights of a single key for a scope.
+//!
+//! Protection keys require PKU. Without it, [`Domain::new`] fails with `ENOSPC`.
+
+#![feature(asm)]
+
+mod domain;
+mod pkru;
+#[cfg(not(feature = "ring3"))]
+mod secret;
+mod sys;
+
+pub use domain::{Domain, DomainBox};
+pub use pkru::{rdpkru, wrpkru, Access, PkruGuard, UNSAFE_PKEY};
+#[cfg(not(feature = "ring3"))]
+pub use secret::Secret;
+
+use std::fmt;
+
+/// Error number returned by the kernel (see errno.h).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Error(pub i32);
+
+pub const EINVAL: Error = Error(22);
+pub const ENOMEM: Error = Error(12);
+pub const ENOSPC: Error = Error(28);
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "errno {}", self.0)
+	}
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Turns the return value of a system call into a result.
+fn check(ret: i32) -> Result<i32> {
+	if ret < 0 {
+		Err(Error(-ret))
+	} else {
+		Ok(ret)
+	}
+}
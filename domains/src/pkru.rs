@@ -0,0 +1,107 @@
+//! Access rights of the protection keys in the PKRU register of the current thread.
+
+use crate::domain::Domain;
+use std::marker::PhantomData;
+
+/// Rights of a protection key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+	None,
+	ReadOnly,
+	ReadWrite,
+}
+
+impl Access {
+	/// Access-disable and write-disable bit of a key in PKRU format
+	fn bits(self) -> u32 {
+		match self {
+			Access::None => 0b11,
+			Access::ReadOnly => 0b10,
+			Access::ReadWrite => 0b00,
+		}
+	}
+
+	fn from_bits(bits: u32) -> Access {
+		if bits & 0b01 != 0 {
+			Access::None
+		} else if bits & 0b10 != 0 {
+			Access::ReadOnly
+		} else {
+			Access::ReadWrite
+		}
+	}
+}
+
+/// Protection key of the unsafe compartment of the kernel (see isolation.toml of the kernel)
+pub const UNSAFE_PKEY: i32 = 2;
+
+/// Returns the PKRU register of the current thread.
+#[inline(always)]
+pub fn rdpkru() -> u32 {
+	let val: u32;
+	unsafe {
+		asm!("xor %ecx, %ecx;
+		      rdpkru"
+			: "={eax}"(val)
+			:
+			: "ecx", "edx"
+			: "volatile");
+	}
+	val
+}
+
+/// Writes the PKRU register of the current thread.
+/// Unsafe, because it changes the rights for every key, including the ones of the kernel.
+#[inline(always)]
+pub unsafe fn wrpkru(val: u32) {
+	asm!("xor %ecx, %ecx;
+	      xor %edx, %edx;
+	      wrpkru;
+	      lfence"
+		:
+		: "{eax}"(val)
+		: "ecx", "edx", "memory"
+		: "volatile");
+}
+
+/// Returns the rights of the current thread for `pkey`.
+pub(crate) fn access(pkey: i32) -> Access {
+	Access::from_bits((rdpkru() >> (2 * pkey)) & 0b11)
+}
+
+/// Sets the rights of the current thread for `pkey`.
+pub(crate) fn set_access(pkey: i32, access: Access) {
+	let shift = 2 * pkey as u32;
+	unsafe {
+		wrpkru((rdpkru() & !(0b11 << shift)) | (access.bits() << shift));
+	}
+}
+
+/// Grants rights for the key of a domain in the current thread until it is dropped.
+/// Afterwards, the previous rights of this key are restored.
+pub struct PkruGuard<'d> {
+	pkey: i32,
+	previous: Access,
+	// PKRU belongs to the thread.
+	_domain: PhantomData<(&'d Domain, *const ())>,
+}
+
+impl<'d> PkruGuard<'d> {
+	pub fn new(domain: &'d Domain, access: Access) -> PkruGuard<'d> {
+		let pkey = domain.pkey();
+		let previous = self::access(pkey);
+		set_access(pkey, access);
+
+		PkruGuard {
+			pkey,
+			previous,
+			_domain: PhantomData,
+		}
+	}
+}
+
+impl<'d> Drop for PkruGuard<'d> {
+	fn drop(&mut self) {
+		set_access(self.pkey, self.previous);
+	}
+}
@@ -0,0 +1,49 @@
+//! Secrets of the kernel, which are only accessible inside `Secret::with`.
+
+use crate::check;
+use crate::sys::*;
+use crate::Result;
+use std::slice;
+
+/// Bytes in secret memory of the kernel. Not even the kernel may access them outside of `with`.
+pub struct Secret {
+	id: i32,
+}
+
+type Accessor<'a> = &'a mut dyn FnMut(&mut [u8]);
+
+extern "C" fn trampoline(address: *mut u8, size: usize, arg: usize) -> i32 {
+	let accessor = unsafe { &mut *(arg as *mut Accessor) };
+	accessor(unsafe { slice::from_raw_parts_mut(address, size) });
+	0
+}
+
+impl Secret {
+	/// Creates a zeroed secret of `size` bytes.
+	pub fn new(size: usize) -> Result<Secret> {
+		let id = check(unsafe { sys_secret_create(size, Some(trampoline)) })?;
+		Ok(Secret { id })
+	}
+
+	/// Runs `f` with the bytes of the secret.
+	/// `f` runs with interrupts disabled, so it should be short and must not block.
+	pub fn with<R, F: FnOnce(&mut [u8]) -> R>(&self, f: F) -> Result<R> {
+		let mut f = Some(f);
+		let mut result = None;
+		let mut call = |bytes: &mut [u8]| {
+			if let Some(f) = f.take() {
+				result = Some(f(bytes));
+			}
+		};
+		let mut accessor: Accessor = &mut call;
+
+		check(unsafe { sys_secret_call(self.id, &mut accessor as *mut Accessor as usize) })?;
+		Ok(result.expect("secret accessor did not run"))
+	}
+}
+
+impl Drop for Secret {
+	fn drop(&mut self) {
+		let _ = check(unsafe { sys_secret_destroy(self.id) });
+	}
+}
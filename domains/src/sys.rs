@@ -0,0 +1,48 @@
+//! Raw bindings of the system calls.
+
+pub const PKEY_DISABLE_ACCESS: u32 = 0x1;
+pub const PKEY_DISABLE_WRITE: u32 = 0x2;
+
+pub const PROT_READ: i32 = 0x1;
+pub const PROT_WRITE: i32 = 0x2;
+
+#[cfg(not(feature = "ring3"))]
+pub type SecretAccessor = extern "C" fn(*mut u8, usize, usize) -> i32;
+
+#[cfg(not(feature = "ring3"))]
+extern "C" {
+	pub fn sys_pkey_alloc(flags: u32, access_rights: u32) -> i32;
+	pub fn sys_pkey_free(pkey: i32) -> i32;
+	pub fn sys_pkey_mprotect(addr: usize, len: usize, prot: i32, pkey: i32) -> i32;
+	pub fn sys_secret_create(size: usize, accessor: Option<SecretAccessor>) -> i32;
+	pub fn sys_secret_call(id: i32, arg: usize) -> i32;
+	pub fn sys_secret_destroy(id: i32) -> i32;
+}
+
+/// System call numbers of the ring-3 mode (see syscalls/dispatch.rs of the kernel)
+#[cfg(feature = "ring3")]
+mod number {
+	pub const SYS_PKEY_ALLOC: usize = 19;
+	pub const SYS_PKEY_FREE: usize = 20;
+	pub const SYS_PKEY_MPROTECT: usize = 21;
+}
+
+#[cfg(feature = "ring3")]
+extern "C" {
+	fn sys_syscall(number: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> usize;
+}
+
+#[cfg(feature = "ring3")]
+pub unsafe fn sys_pkey_alloc(flags: u32, access_rights: u32) -> i32 {
+	sys_syscall(number::SYS_PKEY_ALLOC, flags as usize, access_rights as usize, 0, 0, 0) as i32
+}
+
+#[cfg(feature = "ring3")]
+pub unsafe fn sys_pkey_free(pkey: i32) -> i32 {
+	sys_syscall(number::SYS_PKEY_FREE, pkey as usize, 0, 0, 0, 0) as i32
+}
+
+#[cfg(feature = "ring3")]
+pub unsafe fn sys_pkey_mprotect(addr: usize, len: usize, prot: i32, pkey: i32) -> i32 {
+	sys_syscall(number::SYS_PKEY_MPROTECT, addr, len, prot as usize, pkey as usize, 0) as i32
+}
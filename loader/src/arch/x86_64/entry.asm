@@ -150,9 +150,10 @@ L1:
     mov cr4, eax
 
     ; switch to the compatibility mode (which is part of long mode)
+    ; and enable EXECUTE_DISABLE-protected pages through EFER_NXE
     mov ecx, 0xC0000080
     rdmsr
-    or eax, 1 << 8
+    or eax, (1 << 8) | (1 << 11)
     wrmsr
 
     ; Set CR4
@@ -15,6 +15,7 @@ use arch::x86_64::serial::SerialPort;
 pub use self::bootinfo::*;
 use core::{mem, slice};
 use elf::*;
+use kernel_segments;
 use multiboot::Multiboot;
 use physicalmem;
 
@@ -117,15 +118,16 @@ pub unsafe fn move_kernel(
 	mem_size: usize,
 	file_size: usize,
 ) -> usize {
-	// We want to move the application to realize a identify mapping
-	let page_count = align_up!(mem_size, LargePageSize::SIZE) / LargePageSize::SIZE;
-	loaderlog!("Use {} large pages for the application.", page_count);
+	// We want to move the application to realize a identify mapping.
+	// Base pages allow mapping its segments with different rights afterwards.
+	let page_count = align_up!(mem_size, BasePageSize::SIZE) / BasePageSize::SIZE;
+	loaderlog!("Use {} pages for the application.", page_count);
 
-	paging::map::<LargePageSize>(
+	paging::map::<BasePageSize>(
 		virtual_address,
 		virtual_address,
 		page_count,
-		PageTableEntryFlags::WRITABLE,
+		PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE,
 	);
 
 	for i in (0..align_up!(file_size, BasePageSize::SIZE) / BasePageSize::SIZE).rev() {
@@ -151,9 +153,41 @@ pub unsafe fn move_kernel(
 		*((virtual_address + i) as *mut u8) = 0;
 	}
 
+	protect_kernel(virtual_address, page_count);
+
 	virtual_address
 }
 
+/// Maps every page of the kernel with the rights of the segments it belongs to (W^X):
+/// code is read-only and executable, everything else is not executable.
+/// Pages outside of all segments become read-only.
+unsafe fn protect_kernel(virtual_address: usize, page_count: usize) {
+	for i in 0..page_count {
+		let page_address = virtual_address + i * BasePageSize::SIZE;
+		let mut writable = false;
+		let mut executable = false;
+
+		for segment in kernel_segments() {
+			if segment.start < page_address + BasePageSize::SIZE && segment.start + segment.size > page_address {
+				writable |= segment.flags & ELF_PF_W != 0;
+				executable |= segment.flags & ELF_PF_X != 0;
+			}
+		}
+
+		let mut flags = PageTableEntryFlags::empty();
+		if writable && executable {
+			loaderlog!("Page {:#X} is shared by code and data, keep it writable and executable", page_address);
+			flags.insert(PageTableEntryFlags::WRITABLE);
+		} else if writable {
+			flags.insert(PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE);
+		} else if !executable {
+			flags.insert(PageTableEntryFlags::EXECUTE_DISABLE);
+		}
+
+		paging::map::<BasePageSize>(page_address, page_address, 1, flags);
+	}
+}
+
 pub unsafe fn boot_kernel(
 	new_physical_address: usize,
 	virtual_address: usize,
@@ -171,7 +205,7 @@ pub unsafe fn boot_kernel(
 		virtual_address - KERNEL_STACK_SIZE,
 		virtual_address - KERNEL_STACK_SIZE,
 		KERNEL_STACK_SIZE / BasePageSize::SIZE,
-		PageTableEntryFlags::WRITABLE,
+		PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE,
 	);
 
 	loaderlog!("BootInfo located at 0x{:x}", &BOOT_INFO as *const _ as u64);
@@ -57,6 +57,11 @@ pub const ELF_PT_LOAD: u32 = 1;
 /// TLS	 segment
 pub const ELF_PT_TLS: u32 = 7;
 
+/// Executable segment
+pub const ELF_PF_X: u32 = 1 << 0;
+/// Writable segment
+pub const ELF_PF_W: u32 = 1 << 1;
+
 #[repr(C, packed)]
 pub struct ElfProgramHeader {
 	pub ty: u32,
@@ -46,6 +46,30 @@ extern "C" {
 	static mut bss_start: u8;
 }
 
+/// Maximum number of loadable segments of the kernel
+const MAX_KERNEL_SEGMENTS: usize = 8;
+
+/// Loadable segment of the kernel, whose rights are applied after moving the kernel
+#[derive(Clone, Copy)]
+pub struct KernelSegment {
+	pub start: usize,
+	pub size: usize,
+	/// ELF segment flags (ELF_PF_*)
+	pub flags: u32,
+}
+
+static mut KERNEL_SEGMENTS: [KernelSegment; MAX_KERNEL_SEGMENTS] = [KernelSegment {
+	start: 0,
+	size: 0,
+	flags: 0,
+}; MAX_KERNEL_SEGMENTS];
+static mut KERNEL_SEGMENT_COUNT: usize = 0;
+
+/// Returns the loadable segments found by check_kernel_elf_file.
+pub fn kernel_segments() -> &'static [KernelSegment] {
+	unsafe { &KERNEL_SEGMENTS[..KERNEL_SEGMENT_COUNT] }
+}
+
 // FUNCTIONS
 pub unsafe fn sections_init() {
 	// Initialize .bss section
@@ -88,6 +112,15 @@ pub unsafe fn check_kernel_elf_file(start_address: usize) -> (usize, usize, usiz
 
 			file_size = program_header.virt_addr + program_header.file_size - virtual_address;
 			mem_size = program_header.virt_addr + program_header.mem_size - virtual_address;
+
+			// The program headers may be overwritten when moving the kernel, so keep the rights.
+			assert!(KERNEL_SEGMENT_COUNT < MAX_KERNEL_SEGMENTS, "Too many loadable segments");
+			KERNEL_SEGMENTS[KERNEL_SEGMENT_COUNT] = KernelSegment {
+				start: program_header.virt_addr,
+				size: program_header.mem_size,
+				flags: program_header.flags,
+			};
+			KERNEL_SEGMENT_COUNT += 1;
 		} else if program_header.ty == ELF_PT_TLS {
 			BOOT_INFO.tls_start = program_header.virt_addr as u64;
 			BOOT_INFO.tls_filesz = program_header.file_size as u64;
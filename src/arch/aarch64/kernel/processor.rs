@@ -29,6 +29,16 @@ impl FPUState {
 	}
 }
 
+pub struct PkruState {
+	// TODO
+}
+
+impl PkruState {
+	pub fn new() -> Self {
+		Self {}
+	}
+}
+
 pub fn generate_random_number() -> Option<u32> {
 	None
 }
@@ -5,6 +5,8 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use arch::aarch64::kernel::processor::PkruState;
+
 pub fn set_oneshot_timer(wakeup_time: Option<u64>) {
 	// TODO
 	debug!("set_oneshot_timer stub");
@@ -49,4 +51,4 @@ pub extern "C" fn makecontext() {}
 pub extern "C" fn setcontext() {}
 
 #[no_mangle]
-pub extern "C" fn switch(_old_stack: *mut usize, _new_stack: usize) {}
+pub extern "C" fn switch(_old_stack: *mut usize, _new_stack: usize, _old_pkru: *mut PkruState, _new_pkru: *const PkruState) {}
@@ -45,13 +45,13 @@ const SLP_EN: u16 = 1 << 13;
 
 #[allow(unused)]
 /// The "Multiple APIC Description Table" (MADT) preserved for get_apic_table().
-safe_global_var!(static mut MADT: Option<AcpiTable<'_>> = None);
+sealed_global_var!(static mut MADT: Option<AcpiTable<'_>> = None);
 #[allow(unused)]
 /// The PM1A Control I/O Port for powering off the computer through ACPI.
-safe_global_var!(static mut PM1A_CNT_BLK: Option<u16> = None);
+sealed_global_var!(static mut PM1A_CNT_BLK: Option<u16> = None);
 #[allow(unused)]
 /// The Sleeping State Type code for powering off the computer through ACPI.
-safe_global_var!(static mut SLP_TYPA: Option<u8> = None);
+sealed_global_var!(static mut SLP_TYPA: Option<u8> = None);
 
 /// The "Root System Description Pointer" structure providing pointers to all other ACPI tables.
 #[repr(C, packed)]
@@ -476,6 +476,22 @@ pub fn get_madt() -> Option<&'static AcpiTable<'static>> {
 	unsafe { MADT.as_ref() }
 }
 
+/// Seals the mapping of the MADT, so that the APIC table cannot be changed after boot.
+/// The parsed values themselves are sealed with the boot data (see mm::seal_boot_data).
+pub fn seal() {
+	if cfg!(feature = "no-mpk") {
+		return;
+	}
+
+	if let Some(madt) = get_madt() {
+		assert!(
+			mm::seal(madt.allocated_virtual_address, madt.allocated_length),
+			"Unable to seal the MADT at {:#X}",
+			madt.allocated_virtual_address
+		);
+	}
+}
+
 pub fn poweroff() {
 	if let (Some(pm1a_cnt_blk), Some(slp_typa)) = unsafe {(PM1A_CNT_BLK, SLP_TYPA)} {
 	        let bits = (u16::from(slp_typa) << 10) | SLP_EN;
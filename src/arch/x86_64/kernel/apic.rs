@@ -80,7 +80,7 @@ safe_global_var!(static mut IOAPIC_ADDRESS: usize = 0);
 ///
 /// As Rust currently implements no way of zero-initializing a global Vec in a no_std environment,
 /// we have to encapsulate it in an Option...
-safe_global_var!(static mut CPU_LOCAL_APIC_IDS:[u8;100] = [255;100]);
+safe_global_var!(static mut CPU_LOCAL_APIC_IDS: [u8; MAX_CORES] = [255; MAX_CORES]);
 
 #[allow(unused)]
 /// After calibration, initialize the APIC Timer with this counter value to let it fire an interrupt
@@ -168,7 +168,7 @@ extern "x86-interrupt" fn wakeup_handler(_stack_frame: &mut irq::ExceptionStackF
 pub fn add_local_apic_id(id: u8) {
 	safe_global_var!(static mut IDX: usize = 0);
 	unsafe {
-		if IDX >= 100 {
+		if IDX >= MAX_CORES {
 			error!("LIST is full!!");
 			error!(" ");
 			return;
@@ -275,7 +275,7 @@ pub extern "C" fn eoi() {
 pub fn init() {
 	// Initialize an empty vector for the Local APIC IDs of all CPUs.
 	//unsafe {
-	//	CPU_LOCAL_APIC_IDS = mm::allocate(100, true);
+	//	CPU_LOCAL_APIC_IDS = mm::allocate(100);
 	//}
 
 	// Detect CPUs and APICs.
@@ -484,24 +484,16 @@ pub fn init_x2apic() {
 
 /// Initialize the required entry.asm variables for the next CPU to be booted.
 pub fn init_next_processor_variables(core_id: usize) {
-	// Allocate stack and PerCoreVariables structure for the CPU and pass the addresses.
-	// Keep the stack executable to possibly support dynamically generated code on the stack (see https://security.stackexchange.com/a/47825).
-	let stack = mm::allocate(KERNEL_STACK_SIZE, false);
-	let mut boxed_percore = PerCoreVariables::new(core_id);
-	let percore_ptr = &mut boxed_percore as *mut _;
-	let alloc_percore = mm::allocate(mem::size_of::<PerCoreVariables>(), true) as *mut PerCoreVariables;
-	list_add(alloc_percore as usize);
-	list_add(percore_ptr as usize);
-	copy_from_safe(percore_ptr, 1);
-	copy_to_safe(alloc_percore, 1);
-	clear_unsafe_storage();
+	// Allocate a stack and prepare the per-core data for the CPU and pass the addresses.
+	let stack = mm::allocate(KERNEL_STACK_SIZE);
+	let percore_address = prepare_core(core_id);
 
 	let unsafe_storage = get_unsafe_storage();
 	unsafe {
 		copy_from_safe(BOOT_INFO, 1);
 		isolation_start!();
 		intrinsics::volatile_store(&mut (*(unsafe_storage as *mut BootInfo)).current_stack_address, stack as u64);
-		intrinsics::volatile_store(&mut (*(unsafe_storage as *mut BootInfo)).current_percore_address, alloc_percore as u64,);
+		intrinsics::volatile_store(&mut (*(unsafe_storage as *mut BootInfo)).current_percore_address, percore_address as u64);
 		isolation_end!();
 		copy_to_safe(BOOT_INFO, 1);
 		clear_unsafe_storage();
@@ -527,7 +519,7 @@ pub fn boot_application_processors() {
 		SMP_BOOT_CODE_ADDRESS
 	);
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().pkey(mm::SAFE_MEM_REGION);
+	flags.normal().writable().execute_disable().pkey(mm::SAFE_MEM_REGION);
 	paging::map::<BasePageSize>(SMP_BOOT_CODE_ADDRESS, SMP_BOOT_CODE_ADDRESS, 1, flags);
 	unsafe {
         isolate_function_strong!(copy_nonoverlapping(
@@ -551,6 +543,9 @@ pub fn boot_application_processors() {
         isolation_end!();
 	}
 
+	// The boot code is complete, so it may become executable (W^X).
+	paging::set_page_rights(SMP_BOOT_CODE_ADDRESS, BasePageSize::SIZE, false, true);
+
 	// Now wake up each application processor.
 	let apic_ids = unsafe { CPU_LOCAL_APIC_IDS };
 	let core_id = core_id();
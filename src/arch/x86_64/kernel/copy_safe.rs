@@ -1,70 +1,28 @@
 #![allow(dead_code)]
 use core::ptr::{write_bytes, copy_nonoverlapping};
 use core::mem::size_of;
-use x86::msr::*;
 use mm;
-use arch::x86_64::kernel::processor;
+use arch::x86_64::kernel::percore;
 
-safe_global_var!(static mut LIST: [usize;100] = [0;100]);
 safe_global_var!(static SIZE: usize = 0x1000);
 
+/// Allocates the buffer of the current core, which passes data to the unsafe domain.
+/// Its address is kept in the per-core data of the kernel.
 pub fn unsafe_storage_init() {
-        let unsafe_storage = mm::unsafe_allocate(SIZE, true);
-        unsafe {
-                info!("Init unsafe_storage: {:#X}", unsafe_storage);
-                wrmsr(IA32_KERNEL_GSBASE, unsafe_storage as u64);
-                list_add(processor::readgs());
-        }
-}
-
-#[inline]
-pub fn is_unsafe_storage_init() -> bool {
-        unsafe { (rdmsr(IA32_KERNEL_GSBASE) != 0) }
+        let unsafe_storage = mm::unsafe_allocate(SIZE);
+        info!("Init unsafe_storage: {:#X}", unsafe_storage);
+        percore::set_unsafe_storage(unsafe_storage);
 }
 
 #[inline]
 pub fn get_unsafe_storage() -> usize {
-        unsafe {
-                let address: usize;
-
-                if rdmsr(IA32_KERNEL_GSBASE) == 0 {
-                    return 0;
-                }
-
-	        asm!("swapgs; rdgsbase $0; swapgs" : "=r"(address) ::: "volatile");
-                address
-        }
-}
-
-pub fn list_add(addr: usize) {
-        safe_global_var!(static mut IDX: usize = 0);
-        unsafe {
-                if LIST.iter().any(|v| v == &addr) {
-                        return;
-                }
-                if IDX >= 100 {
-                        error!("LIST is full!!");
-                        error!(" ");
-                        return;
-                }
-                LIST[IDX] = addr;
-                IDX+=1;
-        };
+        percore::unsafe_storage()
 }
 
+/// Checks that `count` objects of type T fit into the unsafe storage.
 #[inline]
-fn is_valid(addr: usize) -> bool {
-        if addr == 0 {
-                return false;
-        }
-        else if unsafe{LIST.iter().any(|v| v == &addr)} {
-                //info!("addr {:#X} is valid", addr);
-                return true;
-        }
-        else {
-                //info!("addr {:#X} is invalid", addr);
-                return false;
-        }
+fn fits<T>(count: usize) -> bool {
+        count.checked_mul(size_of::<T>()).map_or(false, |size| size <= SIZE)
 }
 
 pub fn copy_from_safe<T>(src: *const T, count: usize) {
@@ -74,20 +32,15 @@ pub fn copy_from_safe<T>(src: *const T, count: usize) {
                 return;
         }
 
-        if count > SIZE {
+        if !fits::<T>(count) {
                 error!("copy_from_safe error, too large size");
                 error!(" ");
                 return;
         }
 
-        if is_valid(src as usize) {
-                unsafe {
-                        copy_nonoverlapping(src, get_unsafe_storage() as *mut T, count);
-                }
-                return;
+        unsafe {
+                copy_nonoverlapping(src, get_unsafe_storage() as *mut T, count);
         }
-        error!("copy_from_safe error");
-        error!(" ");
 }
 
 pub fn copy_to_safe<T>(dst: *mut T, count: usize) {
@@ -97,20 +50,15 @@ pub fn copy_to_safe<T>(dst: *mut T, count: usize) {
                 return;
         }
 
-        if count > SIZE {
+        if !fits::<T>(count) {
                 error!("copy_to_safe error, too large size");
                 error!(" ");
                 return;
         }
 
-        if is_valid(dst as usize) {
-                unsafe {
-                        copy_nonoverlapping(get_unsafe_storage() as *const T, dst, count);
-                }
-                return;
+        unsafe {
+                copy_nonoverlapping(get_unsafe_storage() as *const T, dst, count);
         }
-        error!("copy_to_safe error");
-        error!(" ");
 }
 
 pub fn clear_unsafe_storage()
@@ -10,6 +10,7 @@
 use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::{BOOT_INFO, BootInfo};
 use arch::x86_64::kernel::copy_safe::*;
+use arch::x86_64::kernel::syscall;
 use config::*;
 use core::{intrinsics, mem};
 use scheduler::task::TaskStatus;
@@ -19,22 +20,25 @@ use x86::dtables::{DescriptorTablePointer, lgdt};
 use x86::segmentation::*;
 use x86::task::*;
 use x86::Ring;
-use mm;
 
 pub const GDT_NULL: u16 = 0;
 pub const GDT_KERNEL_CODE: u16 = 1;
 pub const GDT_KERNEL_DATA: u16 = 2;
-pub const GDT_FIRST_TSS: u16 = 3;
+/// sysret loads the user segments relative to this (unused) entry, see syscall.rs.
+pub const GDT_SYSRET_BASE: u16 = 3;
+pub const GDT_USER_DATA: u16 = 4;
+pub const GDT_USER_CODE: u16 = 5;
+pub const GDT_FIRST_TSS: u16 = 6;
 
 /// We dynamically allocate a GDT large enough to hold the maximum number of entries.
 const GDT_ENTRIES: usize = 8192;
 
 /// We use IST1 through IST4.
-/// Each critical exception (NMI, Double Fault, Machine Check) gets a dedicated one while IST1 is shared for all other
-/// interrupts. See also irq.rs.
+/// Each critical exception (NMI, Double Fault, Machine Check) gets a dedicated one while IST1 is reserved for the
+/// Page Fault Exception. See also irq.rs.
 const IST_ENTRIES: usize = 4;
 
-unsafe_global_var!(static mut GDT: *mut Gdt = 0 as *mut Gdt);
+safe_global_var!(static mut GDT: *mut Gdt = 0 as *mut Gdt);
 safe_global_var!(static mut GDTR: DescriptorTablePointer<Descriptor> = DescriptorTablePointer {
 	base: 0 as *const Descriptor,
 	limit: 0,
@@ -48,12 +52,9 @@ pub fn init() {
     let gdt_ref;
 	unsafe {
 		// Dynamically allocate memory for the GDT.
-		GDT = ::mm::allocate(mem::size_of::<Gdt>(), true) as *mut Gdt;
-
-        // Get gdt reference
-        isolation_start!();
+		// It comes from the kernel heap and therefore carries the safe key.
+		GDT = ::mm::allocate(mem::size_of::<Gdt>()) as *mut Gdt;
 		gdt_ref = &mut *GDT;
-        isolation_end!();
     }
 	    // The NULL descriptor is always the first entry.
         (*gdt_ref).entries[GDT_NULL as usize] = Descriptor::NULL;
@@ -75,6 +76,20 @@ pub fn init() {
 				.dpl(Ring::Ring0)
 				.finish();
 
+		// The user segments (Ring 3) are only used if the application runs in ring 3 (-ring3).
+		(*gdt_ref).entries[GDT_SYSRET_BASE as usize] = Descriptor::NULL;
+		(*gdt_ref).entries[GDT_USER_DATA as usize] =
+			DescriptorBuilder::data_descriptor(0, 0, DataSegmentType::ReadWrite)
+				.present()
+				.dpl(Ring::Ring3)
+				.finish();
+		(*gdt_ref).entries[GDT_USER_CODE as usize] =
+			DescriptorBuilder::code_descriptor(0, 0, CodeSegmentType::ExecuteRead)
+				.present()
+				.dpl(Ring::Ring3)
+				.l()
+				.finish();
+
 		// Let GDTR point to our newly crafted GDT.
     let temp_gdtr = DescriptorTablePointer::new_from_slice(&((*gdt_ref).entries[0..GDT_ENTRIES]));
     unsafe {
@@ -86,7 +101,6 @@ pub fn add_current_core() {
 	unsafe {
 		// Load the GDT for the current core.
 		/*
-		list_add(&GDTR as *const DescriptorTablePointer<Descriptor> as usize);
 		let unsafe_storage = get_unsafe_storage();
 		copy_from_safe(&GDTR, 1);
 		isolation_start!();
@@ -104,8 +118,12 @@ pub fn add_current_core() {
 	}
 
 	// Dynamically allocate memory for a Task-State Segment (TSS) for this core.
-	let mut boxed_tss = TaskStateSegment::new();
-	//let mut boxed_tss = TaskStateSegment::new(); /* FIXME maybe some memory leak? */
+	// The TSS holds the stack pointers used on interrupts, so it is taken from the kernel heap,
+	// which carries the safe key.
+	let tss = ::mm::allocate(mem::size_of::<TaskStateSegment>()) as *mut TaskStateSegment;
+	unsafe {
+		*tss = TaskStateSegment::new();
+	}
 
 	// Every task later gets its own stack, so this boot stack is only used by the Idle task on each core.
 	// When switching to another task on this core, this entry is replaced.
@@ -116,21 +134,21 @@ pub fn add_current_core() {
 		isolation_start!();
 		let temp_rsp = intrinsics::volatile_load(&(*(unsafe_storage as *const BootInfo)).current_stack_address) + KERNEL_STACK_SIZE as u64 - 0x10;
 		isolation_end!();
-		boxed_tss.rsp[0] = temp_rsp;
+		(*tss).rsp[0] = temp_rsp;
 		clear_unsafe_storage();
 	}
 
 	// Allocate all ISTs for this core.
 	// Every task later gets its own IST1, so the IST1 allocated here is only used by the Idle task.
 	for i in 0..IST_ENTRIES {
-		let ist = ::mm::user_allocate(KERNEL_STACK_SIZE, true);
-		boxed_tss.ist[i] = (ist + KERNEL_STACK_SIZE - 0x10) as u64;
+		let ist = ::mm::user_allocate(KERNEL_STACK_SIZE);
+		unsafe {
+			(*tss).ist[i] = (ist + KERNEL_STACK_SIZE - 0x10) as u64;
+		}
 	}
 
 	// Add this TSS to the GDT.
 	let idx = GDT_FIRST_TSS as usize + (core_id() as usize) * 2;
-	//let tss = Box::into_raw(boxed_tss);
-	let tss = &mut boxed_tss as *mut _;
 	{
 		let base = tss as u64;
 		let tss_descriptor: Descriptor64 =
@@ -143,22 +161,9 @@ pub fn add_current_core() {
 			.dpl(Ring::Ring0)
 			.finish();
 
-		list_add(&tss_descriptor as *const _ as usize);
-		let unsafe_storage = get_unsafe_storage();
-		copy_from_safe(&tss_descriptor, 1);
-
-		let gdt_ref;
-		unsafe {
-			isolation_start!();
-			gdt_ref = &mut (*GDT);
-			isolation_end!();
-		}
-		let entry = &mut (*gdt_ref).entries[idx..idx + 2];
-
 		unsafe {
-			let tss_desc = &mem::transmute::<Descriptor64, [Descriptor; 2]>(*(unsafe_storage as *const Descriptor64),);
-			(*entry).copy_from_slice(tss_desc);
-			clear_unsafe_storage();
+			let entry = &mut (*GDT).entries[idx..idx + 2];
+			entry.copy_from_slice(&mem::transmute::<Descriptor64, [Descriptor; 2]>(tss_descriptor));
 		}
 	}
 
@@ -167,17 +172,21 @@ pub fn add_current_core() {
 	unsafe {
 		load_tr(sel);
 
-		let alloc_tss = mm::user_allocate(mem::size_of::<TaskStateSegment>(), true) as *mut TaskStateSegment;
-		list_add(alloc_tss as usize);
-		list_add(tss as usize);
-		copy_from_safe(tss, 1);
-		copy_to_safe(alloc_tss, 1);
-		clear_unsafe_storage();
 		// Store it in the PerCoreVariables structure for further manipulation.
-		PERCORE.tss.safe_set(alloc_tss);
+		set_core_tss(tss);
 	}
 }
 
+/// Returns the address and size of the GDT.
+pub fn table_range() -> (usize, usize) {
+	unsafe { (GDT as usize, mem::size_of::<Gdt>()) }
+}
+
+/// Returns the address and size of the TSS of the current core.
+pub fn tss_range() -> (usize, usize) {
+	(core_tss() as usize, mem::size_of::<TaskStateSegment>())
+}
+
 #[no_mangle]
 pub extern "C" fn set_current_kernel_stack() {
 	let current_task_borrowed = core_scheduler().current_task.borrow();
@@ -187,8 +196,13 @@ pub extern "C" fn set_current_kernel_stack() {
 		DEFAULT_STACK_SIZE
 	};
 
-	let tss = unsafe { &mut (*PERCORE.tss.safe_get()) };
+	let tss = unsafe { &mut (*core_tss()) };
 
 	tss.rsp[0] = (current_task_borrowed.stacks.stack + stack_size - 0x10) as u64;
 	tss.ist[0] = (current_task_borrowed.stacks.ist0 + KERNEL_STACK_SIZE - 0x10) as u64;
+
+	// System calls of the application enter on the kernel stack of the task (see syscall.rs).
+	if syscall::is_enabled() {
+		set_syscall_stack(current_task_borrowed.kernel_stack_pointer);
+	}
 }
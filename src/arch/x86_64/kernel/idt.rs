@@ -9,7 +9,9 @@
 #![allow(dead_code)]
 
 use arch::x86_64::kernel::gdt;
+use core::mem;
 use core::sync::atomic::{AtomicBool, Ordering};
+use mm::fnptr;
 use x86::bits64::paging::VAddr;
 use x86::dtables::{DescriptorTablePointer, lidt};
 use x86::segmentation::{SegmentSelector, SystemDescriptorTypes64};
@@ -97,8 +99,9 @@ impl IdtEntry {
 /// an "Unhandled Interrupt" exception.
 pub const IDT_ENTRIES: usize = 256;
 
-safe_global_var!(static mut IDT: [IdtEntry; IDT_ENTRIES] = [IdtEntry::MISSING; IDT_ENTRIES]);
-safe_global_var!(static mut IDTP: DescriptorTablePointer<IdtEntry> = DescriptorTablePointer {
+/// The IDT and its pointer are write-protected after boot, set_gate unseals them (see mm::fnptr).
+fnptr_global_var!(static mut IDT: [IdtEntry; IDT_ENTRIES] = [IdtEntry::MISSING; IDT_ENTRIES]);
+fnptr_global_var!(static mut IDTP: DescriptorTablePointer<IdtEntry> = DescriptorTablePointer {
 	base: 0 as *const IdtEntry,
 	limit: 0,
 });
@@ -112,13 +115,18 @@ pub fn install() {
 		if !is_init {
 			// TODO: As soon as https://github.com/rust-lang/rust/issues/44580 is implemented, it should be possible to
 			// implement "new" as "const fn" and do this call already in the initialization of IDTP.
-			IDTP = DescriptorTablePointer::new_from_slice(&IDT);
+			fnptr::unseal(|| IDTP = DescriptorTablePointer::new_from_slice(&IDT));
 		};
 
 		lidt(&IDTP);
 	}
 }
 
+/// Returns the address and size of the IDT.
+pub fn table_range() -> (usize, usize) {
+	unsafe { (IDT.as_ptr() as usize, mem::size_of_val(&IDT)) }
+}
+
 /// Set an entry in the IDT.
 ///
 /// # Arguments
@@ -137,7 +145,7 @@ pub fn set_gate(index: u8, handler: usize, ist_index: u8) {
 		ist_index,
 	);
 
-	unsafe {
+	fnptr::unseal(|| unsafe {
 		IDT[index as usize] = entry;
-	}
+	});
 }
@@ -10,8 +10,10 @@ use arch::x86_64::kernel::apic;
 use arch::x86_64::kernel::idt;
 use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::processor;
+use arch::x86_64::mm::isolation;
 use arch::x86_64::mm::paging;
 use core::fmt;
+use mm;
 use scheduler;
 use x86::bits64::rflags;
 
@@ -108,16 +110,16 @@ pub fn nested_enable(was_enabled: bool) {
 
 pub fn install() {
 	// Set gates to the Interrupt Service Routines (ISRs) for all 32 CPU exceptions.
-	// All of them use a dedicated stack per task (IST1) to prevent clobbering the current task stack.
+	// The Page Fault Exception uses a dedicated stack per task (IST1) to prevent clobbering the current task stack.
 	// Some critical exceptions also get their own stacks to always execute on a known good stack:
-	//   - Non-Maskable Interrupt Exception (IST2)
-	//   - Double Fault Exception (IST3)
-	//   - Machine Check Exception (IST4)
+	//   - Double Fault Exception (IST2)
+	//   - Machine Check Exception (IST3)
+	//   - Non-Maskable Interrupt Exception (IST4), which may interrupt the Page Fault Exception
 	//
 	// Refer to Intel Vol. 3A, 6.14.5 Interrupt Stack Table.
 	idt::set_gate(0, divide_error_exception as usize, 0);
 	idt::set_gate(1, debug_exception as usize, 0);
-	idt::set_gate(2, nmi_exception as usize, 1);
+	idt::set_gate(2, nmi_exception as usize, 4);
 	idt::set_gate(3, breakpoint_exception as usize, 0);
 	idt::set_gate(4, overflow_exception as usize, 0);
 	idt::set_gate(5, bound_range_exceeded_exception as usize, 0);
@@ -152,7 +154,10 @@ pub fn install() {
             print_page_table_entry::<BasePageSize>(remapped_page_fault_handler);
         }
 */
-	idt::set_gate(14, paging::page_fault_handler as usize, 0);
+	// The page fault handler runs on the per-task IST1, because a stack overflow into a guard page
+	// would otherwise escalate to a double fault while pushing the exception frame.
+	// No other gate uses IST1, so a nested NMI cannot overwrite the frame of the handler.
+	idt::set_gate(14, paging::page_fault_handler as usize, 1);
         idt::set_gate(15, reserved_exception as usize, 0);
 	idt::set_gate(16, floating_point_exception as usize, 0);
 	idt::set_gate(17, alignment_check_exception as usize, 0);
@@ -209,8 +214,26 @@ pub fn install() {
 	}
 }
 
+/// Number of device interrupts, which use the vectors 32 to 63
+const IRQ_COUNT: u32 = 32;
+
+/// Installs `handler` for the device interrupt `irq_number`.
+/// The symbol is reachable from every domain, so it only accepts callers with the rights of the
+/// kernel and the vectors of device interrupts. Neither exceptions nor the vectors of the APIC
+/// can be taken over. Only the gate is write-protected (see mm::fnptr). The state used by the
+/// handler stays in the kernel domain, and trait objects it calls through belong in
+/// fnptr_global_var! like the one of the network driver (see drivers::net).
 #[no_mangle]
 pub extern "C" fn irq_install_handler(irq_number: u32, handler: usize) {
+	// Without the rights of the kernel, not even an error can be logged.
+	if isolation::read_permissions() & (0b11 << (2 * mm::SAFE_MEM_REGION)) != 0 {
+		return;
+	}
+	if irq_number >= IRQ_COUNT {
+		error!("Refusing to install a handler for interrupt {}", irq_number);
+		return;
+	}
+
 	debug!("Install handler for interrupt {}", irq_number);
 	idt::set_gate((32 + irq_number) as u8, handler, 0);
 }
@@ -25,6 +25,7 @@ mod smp_boot_code;
 #[cfg(not(test))]
 mod start;
 pub mod switch;
+pub mod syscall;
 pub mod systemtime;
 #[cfg(feature = "vga")]
 mod vga;
@@ -32,6 +33,8 @@ mod vga;
 use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::serial::SerialPort;
 use arch::x86_64::kernel::copy_safe::*;
+use arch::x86_64::mm::isolation;
+use arch::x86_64::mm::paging;
 
 use core::{intrinsics, ptr};
 use mm;
@@ -242,6 +245,26 @@ pub fn get_mbinfo() -> usize {
 	}
 }
 
+/// Returns the boot stack of the boot processor, which the loader identity-maps below the kernel.
+/// Only valid until the application processors are started (see apic::boot_application_processors).
+pub fn get_boot_stack() -> usize {
+	let unsafe_storage = get_unsafe_storage();
+	unsafe {
+		if unsafe_storage == 0 {
+			intrinsics::volatile_load(&(*BOOT_INFO).current_stack_address) as usize
+		}
+		else {
+			copy_from_safe(BOOT_INFO, 1);
+			isolation_start!();
+			let stack = intrinsics::volatile_load(&(*(unsafe_storage as *const BootInfo)).current_stack_address) as usize;
+			isolation_end!();
+			clear_unsafe_storage();
+
+			return stack;
+		}
+	}
+}
+
 pub fn get_processor_count() -> usize {
 	let unsafe_storage = get_unsafe_storage();
 	unsafe {
@@ -399,11 +422,9 @@ pub fn output_message_byte(byte: u8) {
 /// Real Boot Processor initialization as soon as we have put the first Welcome message on the screen.
 #[cfg(not(test))]
 pub fn boot_processor_init() {
-	/* Add BOOT_INFO to white list */
-	unsafe {list_add(BOOT_INFO as usize)};
-
 	processor::detect_features();
 	processor::configure();
+	isolation::init();
 
 	if cfg!(feature = "vga") && environment::is_single_kernel() && !environment::is_uhyve() {
 		#[cfg(feature = "vga")]
@@ -411,11 +432,15 @@ pub fn boot_processor_init() {
 	}
 
 	::mm::init();
+	::mm::shadow_stack::init();
 	::mm::print_information();
+	::policy::print_information();
 	environment::init();
 	copy_safe::unsafe_storage_init();
 	gdt::init();
 	gdt::add_current_core();
+	syscall::init();
+	syscall::add_current_core();
 	idt::install();
 	if !environment::is_uhyve() {
 		pic::init();
@@ -434,6 +459,7 @@ pub fn boot_processor_init() {
 
 	apic::init();
 	scheduler::install_timer_handler();
+	check_isolation_invariants();
 	finish_processor_init();
 }
 
@@ -441,6 +467,16 @@ pub fn boot_processor_init() {
 /// Called after the Boot Processor has been fully initialized along with its scheduler.
 #[cfg(not(test))]
 pub fn boot_application_processors() {
+	// Without MPK, no page is tagged and the backend never restricts anything.
+	let backend = isolation::backend();
+	if !cfg!(feature = "no-mpk") && !backend.supports_smp() {
+		warn!(
+			"The {} isolation backend supports only a single core, so no Application Processors are booted",
+			backend.name()
+		);
+		return;
+	}
+
 	apic::boot_application_processors();
 	apic::print_information();
 }
@@ -452,13 +488,46 @@ pub fn application_processor_init() {
 	processor::configure();
 	copy_safe::unsafe_storage_init();
 	gdt::add_current_core();
+	syscall::add_current_core();
 	idt::install();
 	apic::init_x2apic();
 	apic::init_local_apic();
 	irq::enable();
+	check_isolation_invariants();
 	finish_processor_init();
 }
 
+/// Panics if a structure that controls the isolation is accessible outside of the kernel domain.
+fn check_isolation_invariants() {
+	if cfg!(feature = "no-mpk") {
+		return;
+	}
+
+	let unprotected = paging::unprotected_page_tables();
+	assert!(unprotected == 0, "{} page tables do not carry the safe key", unprotected);
+
+	// The IDT is a function-pointer table, which is write-protected after boot (see mm::fnptr).
+	let idt_key = if mm::fnptr::is_sealed() {
+		mm::FNPTR_MEM_REGION
+	} else {
+		mm::SAFE_MEM_REGION
+	};
+	let structures = [
+		("IDT", idt::table_range(), idt_key),
+		("GDT", gdt::table_range(), mm::SAFE_MEM_REGION),
+		("TSS", gdt::tss_range(), mm::SAFE_MEM_REGION),
+	];
+	for &(name, (address, size), key) in structures.iter() {
+		assert!(
+			paging::has_pkey(address, size, key),
+			"The {} at {:#X} does not carry the key {}",
+			name,
+			address,
+			key
+		);
+	}
+}
+
 fn finish_processor_init() {
 	debug!("Initialized Processor");
 
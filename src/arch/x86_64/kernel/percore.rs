@@ -5,24 +5,47 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use arch::x86_64::kernel::{BOOT_INFO, BootInfo};
-use arch::x86_64::kernel::copy_safe::*;
-use arch::x86_64::kernel::processor;
-use core::{intrinsics, ptr};
+use arch::x86_64::kernel::BOOT_INFO;
+use arch::x86_64::mm::isolation;
+use arch::x86_64::mm::mpk;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use core::{intrinsics, mem, ptr};
 use scheduler::PerCoreScheduler;
 use x86::bits64::task::TaskStateSegment;
 use x86::msr::*;
 use mm;
 
-pub static mut PERCORE: PerCoreVariables = PerCoreVariables::new(0); /* CHECK THIS OUT */
+/// Maximum number of cores, for which per-core data is reserved.
+pub const MAX_CORES: usize = 100;
 
+/// Offsets of user_stack and syscall_stack to GS, used by the system call entry (see syscall.rs)
+pub const USER_STACK_OFFSET: usize = 32;
+pub const SYSCALL_STACK_OFFSET: usize = 40;
+
+/// Per-core data of the kernel, one entry per core.
+/// GS points to the entry of the current core.
+safe_global_var!(static mut PERCORE: [PerCoreVariables; MAX_CORES] = [PerCoreVariables::new(0); MAX_CORES]);
+
+/// Per-core data, which the unsafe domain may read.
+/// Its entries have the same size as the ones of PERCORE, so the distance between the entries of
+/// a core is the same for every core and the unsafe domain reaches its entry through GS as well.
+unsafe_global_var!(static mut UNSAFE_PERCORE: [UnsafePerCoreVariables; MAX_CORES] = [UnsafePerCoreVariables::new(0); MAX_CORES]);
+
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
 pub struct PerCoreVariables {
 	/// Sequential ID of this CPU Core.
 	core_id: PerCoreVariable<usize>,
 	/// Scheduler for this CPU Core.
 	scheduler: PerCoreVariable<*mut PerCoreScheduler>,
 	/// Task State Segment (TSS) allocated for this CPU Core.
-	pub tss: PerCoreVariable<*mut TaskStateSegment>,
+	tss: PerCoreVariable<*mut TaskStateSegment>,
+	/// Buffer used by copy_safe to pass data to the unsafe domain.
+	unsafe_storage: PerCoreVariable<usize>,
+	/// Stack pointer of the application while it is in a system call (see USER_STACK_OFFSET).
+	user_stack: PerCoreVariable<usize>,
+	/// Kernel stack pointer loaded on a system call (see SYSCALL_STACK_OFFSET).
+	syscall_stack: PerCoreVariable<usize>,
 }
 
 impl PerCoreVariables {
@@ -31,21 +54,52 @@ impl PerCoreVariables {
 			core_id: PerCoreVariable::new(core_id),
 			scheduler: PerCoreVariable::new(ptr::null_mut() as *mut PerCoreScheduler),
 			tss: PerCoreVariable::new(ptr::null_mut() as *mut TaskStateSegment),
+			unsafe_storage: PerCoreVariable::new(0),
+			user_stack: PerCoreVariable::new(0),
+			syscall_stack: PerCoreVariable::new(0),
+		}
+	}
+}
+
+/// Copies of the per-core variables used by the unsafe domain.
+/// They are updated together with the originals, so an access never has to copy anything.
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct UnsafePerCoreVariables {
+	core_id: PerCoreVariable<usize>,
+	scheduler: PerCoreVariable<*mut PerCoreScheduler>,
+}
+
+impl UnsafePerCoreVariables {
+	const fn new(core_id: usize) -> Self {
+		Self {
+			core_id: PerCoreVariable::new(core_id),
+			scheduler: PerCoreVariable::new(ptr::null_mut() as *mut PerCoreScheduler),
 		}
 	}
 }
 
+/// Per-core data of the shadow stacks (see mm::shadow_stack), which every domain may read,
+/// but only the shadow-stack gates may write. Allocated by init_shadow_stacks, its entries
+/// have the same size as the ones of PERCORE, so they are reached through GS as well.
+#[repr(C, align(64))]
+struct ShadowPerCoreVariables {
+	/// Shadow stack of the current task
+	shadow_stack: usize,
+}
+
+/// Distance between PERCORE and the per-core data of the shadow stacks, 0 until init_shadow_stacks
+sealed_global_var!(static mut SHADOW_PERCORE_OFFSET: usize = 0);
+
 #[repr(C)]
+#[derive(Clone, Copy)]
 pub struct PerCoreVariable<T> {
 	data: T,
 }
 
 pub trait PerCoreVariableMethods<T> {
 	unsafe fn get(&self) -> T;
-	fn safe_get(&self) -> T;
 	unsafe fn set(&self, value: T);
-	fn safe_set(&self, value: T);
-	fn bad_set(&self, value: T);
 }
 
 impl<T> PerCoreVariable<T> {
@@ -53,11 +107,14 @@ impl<T> PerCoreVariable<T> {
 		Self { data: value }
 	}
 
+	/// Offset of this variable to GS. The variable has to be part of the first entry
+	/// of PERCORE or UNSAFE_PERCORE.
+	/// Only the addresses are used, so this works in every domain.
 	#[inline]
 	unsafe fn offset(&self) -> usize {
-		let base = &PERCORE as *const _ as usize;
+		let base = &PERCORE[0] as *const _ as usize;
 		let field = self as *const _ as usize;
-		field - base
+		field.wrapping_sub(base)
 	}
 }
 
@@ -72,54 +129,10 @@ impl<T> PerCoreVariableMethods<T> for PerCoreVariable<T> {
 		value
 	}
 
-	#[inline]
-	default fn safe_get(&self) -> T {
-		let value: T;
-		let gs = processor::readgs();
-		copy_from_safe(gs as *const PerCoreVariables, 1);			
-		unsafe {
-			let offset = self.offset();
-			isolation_start!();
-			asm!("swapgs; movq %gs:($1), $0; swapgs" : "=r"(value) : "r"(offset) :: "volatile");
-			isolation_end!();
-		}
-		//clear_unsafe_storage2(gs as *const PerCoreVariables);
-		value
-	}
-
 	#[inline]
 	default unsafe fn set(&self, value: T) {
 		asm!("movq $0, %gs:($1)" :: "r"(value), "r"(self.offset()) :: "volatile");
 	}
-
-	#[inline]
-	default fn safe_set(&self, value: T) {
-		let gs = processor::readgs();
-		copy_from_safe(gs as *const PerCoreVariables, 1);			
-		unsafe {
-			let offset = self.offset();
-			isolation_start!();
-			asm!("swapgs; movq $0, %gs:($1); swapgs" :: "r"(value), "r"(offset) :: "volatile");
-			isolation_end!();
-		}
-		copy_to_safe(gs as *mut PerCoreVariables, 1);			
-		clear_unsafe_storage2(gs as *mut PerCoreVariables);
-	}
-
-
-	#[inline]
-	default fn bad_set(&self, value: T) {
-		let gs = processor::readgs();
-		copy_from_safe(gs as *const PerCoreVariables, 1);			
-		unsafe {
-			let offset = 0x400000 - 0x1000000;
-			isolation_start!();
-			asm!("swapgs; movq $0, %gs:($1); swapgs" :: "r"(value), "r"(offset) :: "volatile");
-			isolation_end!();
-		}
-		copy_to_safe(gs as *mut PerCoreVariables, 1);			
-		clear_unsafe_storage2(gs as *mut PerCoreVariables);
-	}
 }
 
 // Define and implement a trait to mark all 32-bit variables used inside PerCoreVariables.
@@ -136,56 +149,28 @@ impl<T: Is32BitVariable> PerCoreVariableMethods<T> for PerCoreVariable<T> {
 		value
 	}
 
-	#[inline]
-	fn safe_get(&self) -> T {
-		let value: T;
-		let gs = processor::readgs();
-		copy_from_safe(gs as *const PerCoreVariables, 1);			
-		unsafe {
-			let offset = self.offset();
-			isolation_start!();
-			asm!("swapgs; movq %gs:($1), $0; swapgs" : "=r"(value) : "r"(offset) :: "volatile");
-			isolation_end!();
-		}
-		clear_unsafe_storage2(gs as *const PerCoreVariables);
-		value
-	}
-
 	#[inline]
 	unsafe fn set(&self, value: T) {
 		asm!("movl $0, %gs:($1)" :: "r"(value), "r"(self.offset()) :: "volatile");
 	}
-
-	#[inline]
-	fn safe_set(&self, value: T) {
-		let gs = processor::readgs();
-		copy_from_safe(gs as *const PerCoreVariables, 1);			
-		unsafe {
-			let offset = self.offset();
-			isolation_start!();
-			asm!("swapgs; movq $0, %gs:($1); swapgs" :: "r"(value), "r"(offset) :: "volatile");
-			isolation_end!();
-		}
-		copy_to_safe(gs as *mut PerCoreVariables, 1);			
-		//clear_unsafe_storage2(gs as *const PerCoreVariables);
-	}
 }
 
-#[cfg(not(test))]
+/// Returns true if the current domain is allowed to access the per-core data of the kernel.
 #[inline]
-pub fn core_id() -> usize {
-	if is_unsafe_storage_init() {
-		unsafe { PERCORE.core_id.safe_get() }
-	}
-	else {
-		unsafe { PERCORE.core_id.get() }
-	}
+fn is_kernel_domain() -> bool {
+	isolation::read_permissions() & (1 << (2 * mm::SAFE_MEM_REGION)) == 0
 }
 
 #[cfg(not(test))]
 #[inline]
-pub fn safe_core_id() -> usize {
-	unsafe { PERCORE.core_id.safe_get() }
+pub fn core_id() -> usize {
+	unsafe {
+		if is_kernel_domain() {
+			PERCORE[0].core_id.get()
+		} else {
+			UNSAFE_PERCORE[0].core_id.get()
+		}
+	}
 }
 
 #[cfg(test)]
@@ -196,69 +181,141 @@ pub fn core_id() -> usize {
 #[no_mangle]
 #[inline]
 pub fn core_scheduler() -> &'static mut PerCoreScheduler {
-//unsafe { &mut *PERCORE.scheduler.get() }
-/* FIXME, for performance?*/
-	if is_unsafe_storage_init() {
-		unsafe { &mut *PERCORE.scheduler.safe_get() }
+	unsafe {
+		if is_kernel_domain() {
+			&mut *PERCORE[0].scheduler.get()
+		} else {
+			&mut *UNSAFE_PERCORE[0].scheduler.get()
+		}
 	}
-	else {
-		unsafe { &mut *PERCORE.scheduler.get() }
+}
+
+#[inline]
+pub fn set_core_scheduler(scheduler: *mut PerCoreScheduler) {
+	unsafe {
+		PERCORE[0].scheduler.set(scheduler);
+		UNSAFE_PERCORE[0].scheduler.set(scheduler);
 	}
 }
 
-#[no_mangle]
+/// Tries to overwrite the scheduler of the kernel from the unsafe domain.
+/// With isolation enabled, this has to raise a page fault.
 #[inline]
-pub fn safe_core_scheduler() -> &'static mut PerCoreScheduler {
-	unsafe { &mut *PERCORE.scheduler.safe_get() }
+pub fn bad_set_core_scheduler(scheduler: *mut PerCoreScheduler) {
+	unsafe {
+		isolation_start!();
+		PERCORE[0].scheduler.set(scheduler);
+		isolation_end!();
+	}
 }
 
 #[inline]
-pub fn set_core_scheduler(scheduler: *mut PerCoreScheduler) {
-	if is_unsafe_storage_init() {
-		unsafe {
-			PERCORE.scheduler.safe_set(scheduler);
-		}
+pub fn core_tss() -> *mut TaskStateSegment {
+	unsafe { PERCORE[0].tss.get() }
+}
+
+#[inline]
+pub fn set_core_tss(tss: *mut TaskStateSegment) {
+	unsafe {
+		PERCORE[0].tss.set(tss);
 	}
-	else {
+}
+
+#[inline]
+pub fn unsafe_storage() -> usize {
+	unsafe { PERCORE[0].unsafe_storage.get() }
+}
+
+#[inline]
+pub fn set_unsafe_storage(address: usize) {
+	unsafe {
+		PERCORE[0].unsafe_storage.set(address);
+	}
+}
+
+#[inline]
+pub fn set_syscall_stack(stack_pointer: usize) {
+	unsafe {
+		PERCORE[0].syscall_stack.set(stack_pointer);
+	}
+}
+
+/// Returns the shadow stack of the current task, 0 without shadow stacks.
+/// Works in every domain.
+#[inline(always)]
+pub fn shadow_stack() -> usize {
+	let offset = unsafe { SHADOW_PERCORE_OFFSET };
+	if offset == 0 {
+		return 0;
+	}
+
+	let stack: usize;
+	unsafe {
+		asm!("movq %gs:($1), $0" : "=r"(stack) : "r"(offset) :: "volatile");
+	}
+	stack
+}
+
+/// Sets the shadow stack of the current task. Must be called inside
+/// isolation::with_shadow_stack_access.
+#[inline(always)]
+pub fn set_shadow_stack(stack: usize) {
+	let offset = unsafe { SHADOW_PERCORE_OFFSET };
+	if offset != 0 {
 		unsafe {
-			PERCORE.scheduler.set(scheduler);
+			asm!("movq $0, %gs:($1)" :: "r"(stack), "r"(offset) :: "volatile");
 		}
 	}
 }
 
-#[inline]
-pub fn safe_set_core_scheduler(scheduler: *mut PerCoreScheduler) {
+/// Allocates the per-core data of the shadow stacks. Must be called once, before the
+/// first task switch.
+pub fn init_shadow_stacks() {
+	assert_eq!(
+		mem::size_of::<PerCoreVariables>(),
+		mem::size_of::<ShadowPerCoreVariables>(),
+		"Per-core entries of the shadow stacks must have the same size"
+	);
+
+	let size = mem::size_of::<ShadowPerCoreVariables>() * MAX_CORES;
+	let address = mm::allocate(size);
 	unsafe {
-		PERCORE.scheduler.safe_set(scheduler);
+		ptr::write_bytes(address as *mut u8, 0, size);
+		SHADOW_PERCORE_OFFSET = address.wrapping_sub(&PERCORE[0] as *const _ as usize);
 	}
+	mpk::mpk_mem_set_key::<BasePageSize>(address, align_up!(size, BasePageSize::SIZE), mm::SHADOW_MEM_REGION);
 }
 
-#[inline]
-pub fn bad_set_core_scheduler(scheduler: *mut PerCoreScheduler) {
+/// Initializes the per-core data of the core `core_id` and returns the address,
+/// which the core has to load into GS.
+pub fn prepare_core(core_id: usize) -> usize {
+	assert!(core_id < MAX_CORES, "Core {} exceeds the maximum number of cores", core_id);
+
 	unsafe {
-		PERCORE.scheduler.bad_set(scheduler);
+		PERCORE[core_id] = PerCoreVariables::new(core_id);
+		UNSAFE_PERCORE[core_id] = UnsafePerCoreVariables::new(core_id);
+		&PERCORE[core_id] as *const _ as usize
 	}
 }
 
 pub fn init() {
+	assert_eq!(
+		mem::size_of::<PerCoreVariables>(),
+		mem::size_of::<UnsafePerCoreVariables>(),
+		"Per-core entries of both domains must have the same size"
+	);
 	unsafe {
-		let address;
-		if is_unsafe_storage_init() {
-			let unsafe_storage = get_unsafe_storage();
-			copy_from_safe(BOOT_INFO, 1);
-			isolation_start!();
-			address = intrinsics::volatile_load(&(*(unsafe_storage as *const BootInfo)).current_percore_address);
-			isolation_end!();
-			clear_unsafe_storage();
-		}
-		else {
-			// Store the address to the PerCoreVariables structure allocated for this core in GS.
-			address = intrinsics::volatile_load(&(*BOOT_INFO).current_percore_address);
-		}
+		assert_eq!(PERCORE[0].user_stack.offset(), USER_STACK_OFFSET, "Offset of user_stack changed");
+		assert_eq!(PERCORE[0].syscall_stack.offset(), SYSCALL_STACK_OFFSET, "Offset of syscall_stack changed");
+	}
+
+	unsafe {
+		// Store the address to the PerCoreVariables structure of this core in GS.
+		let address = intrinsics::volatile_load(&(*BOOT_INFO).current_percore_address);
 		if address == 0 {
-			wrmsr(IA32_GS_BASE, &PERCORE as *const _ as u64);
+			wrmsr(IA32_GS_BASE, &PERCORE[0] as *const _ as u64);
 		} else {
-			wrmsr(IA32_GS_BASE, address as u64);
+			wrmsr(IA32_GS_BASE, address);
 		}
 	}
 }
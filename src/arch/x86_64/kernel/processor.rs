@@ -13,10 +13,11 @@ use arch::x86_64::kernel::idt;
 use arch::x86_64::kernel::irq;
 use arch::x86_64::kernel::pic;
 use arch::x86_64::kernel::pit;
+use arch::x86_64::kernel::syscall;
 use arch::x86_64::kernel::{BOOT_INFO, BootInfo};
 use arch::x86_64::kernel::copy_safe::*;
 use core::sync::atomic::spin_loop_hint;
-use core::{fmt, intrinsics, u32};
+use core::{fmt, intrinsics};
 use environment;
 use x86::controlregs::*;
 use x86::cpuid::*;
@@ -38,23 +39,26 @@ const EFER_LMSLE: u64 = (1 << 13);
 const EFER_FFXSR: u64 = (1 << 14);
 const EFER_TCE: u64 = (1 << 15);
 
-safe_global_var!(static mut CPU_FREQUENCY: CpuFrequency = CpuFrequency::new());
-safe_global_var!(static mut CPU_SPEEDSTEP: CpuSpeedStep = CpuSpeedStep::new());
-safe_global_var!(static mut PHYSICAL_ADDRESS_BITS: u8 = 0);
-safe_global_var!(static mut LINEAR_ADDRESS_BITS: u8 = 0);
+sealed_global_var!(static mut CPU_FREQUENCY: CpuFrequency = CpuFrequency::new());
+sealed_global_var!(static mut CPU_SPEEDSTEP: CpuSpeedStep = CpuSpeedStep::new());
+sealed_global_var!(static mut PHYSICAL_ADDRESS_BITS: u8 = 0);
+sealed_global_var!(static mut LINEAR_ADDRESS_BITS: u8 = 0);
 safe_global_var!(static mut MEASUREMENT_TIMER_TICKS: u64 = 0);
-safe_global_var!(static mut SUPPORTS_1GIB_PAGES: bool = false);
-safe_global_var!(static mut SUPPORTS_AVX: bool = false);
-safe_global_var!(static mut SUPPORTS_RDRAND: bool = false);
-safe_global_var!(static mut SUPPORTS_TSC_DEADLINE: bool = false);
-safe_global_var!(static mut SUPPORTS_X2APIC: bool = false);
-safe_global_var!(static mut SUPPORTS_XSAVE: bool = false);
-
-safe_global_var!(static mut SUPPORTS_PKU: bool = false);
-safe_global_var!(static mut SUPPORTS_OSPKE: bool = false);
-
-safe_global_var!(static mut SUPPORTS_FSGS: bool = false);
-safe_global_var!(static mut TIMESTAMP_FUNCTION: unsafe fn() -> u64 = get_timestamp_rdtsc);
+sealed_global_var!(static mut SUPPORTS_1GIB_PAGES: bool = false);
+sealed_global_var!(static mut SUPPORTS_AVX: bool = false);
+sealed_global_var!(static mut SUPPORTS_RDRAND: bool = false);
+sealed_global_var!(static mut SUPPORTS_TSC_DEADLINE: bool = false);
+sealed_global_var!(static mut SUPPORTS_X2APIC: bool = false);
+sealed_global_var!(static mut SUPPORTS_XSAVE: bool = false);
+sealed_global_var!(static mut SUPPORTS_XSAVEC: bool = false);
+sealed_global_var!(static mut SUPPORTS_XSAVES: bool = false);
+sealed_global_var!(static mut SUPPORTS_XSAVE_PKRU: bool = false);
+
+sealed_global_var!(static mut SUPPORTS_PKU: bool = false);
+sealed_global_var!(static mut SUPPORTS_OSPKE: bool = false);
+
+sealed_global_var!(static mut SUPPORTS_FSGS: bool = false);
+sealed_global_var!(static mut TIMESTAMP_FUNCTION: unsafe fn() -> u64 = get_timestamp_rdtsc);
 
 #[repr(C, align(16))]
 pub struct XSaveLegacyRegion {
@@ -110,6 +114,13 @@ pub struct XSaveBndcsr {
 	pub bndstatus_register: u64,
 }
 
+/// XSAVE state component of PKRU
+const XSAVE_PKRU_STATE: u64 = 1 << 9;
+/// XCOMP_BV bit of an area in the compacted format
+const XSAVE_COMPACTED_FORMAT: u64 = 1 << 63;
+/// State components switched lazily by FPUState. PKRU belongs to the task switch (see PkruState).
+const FPU_STATE_MASK: u64 = !XSAVE_PKRU_STATE;
+
 #[repr(C, align(64))]
 pub struct FPUState {
 	pub legacy_region: XSaveLegacyRegion,
@@ -171,10 +182,10 @@ impl FPUState {
 
 	pub fn restore(&self) {
 		if supports_xsave() {
-			let bitmask = u32::MAX;
+			let (low, high) = (FPU_STATE_MASK as u32, (FPU_STATE_MASK >> 32) as u32);
 			unsafe {
 				//isolation_start!();
-				asm!("xrstorq $0" :: "*m"(self as *const Self), "{eax}"(bitmask), "{edx}"(bitmask) :: "volatile");
+				asm!("xrstorq $0" :: "*m"(self as *const Self), "{eax}"(low), "{edx}"(high) :: "volatile");
 				//isolation_end!();
 			}
 		} else {
@@ -188,10 +199,10 @@ impl FPUState {
 
 	pub fn save(&mut self) {
 		if supports_xsave() {
-			let bitmask: u32 = u32::MAX;
+			let (low, high) = (FPU_STATE_MASK as u32, (FPU_STATE_MASK >> 32) as u32);
 			unsafe {
 				//isolation_start!();
-				asm!("xsaveq $0" : "=*m"(self as *mut Self) : "{eax}"(bitmask), "{edx}"(bitmask) : "memory" : "volatile");
+				asm!("xsaveq $0" : "=*m"(self as *mut Self) : "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
 				//isolation_end!();
 			}
 		} else {
@@ -212,6 +223,64 @@ impl FPUState {
 	}
 }
 
+/// Access rights of a task, which the task switch saves and restores (see switch::switch).
+///
+/// If supports_xsave_pkru(), PKRU is the only component of an XSAVE area in the compacted format,
+/// where it directly follows the header. It is saved with XSAVES (or XSAVEC) and restored with
+/// XRSTORS (or XRSTOR), so that the task switch needs no WRPKRU. Unlike FPUState, it is never
+/// switched lazily, because the new task must run with its own rights right away.
+/// Otherwise, the isolation backend reads and writes `pkru` directly.
+#[repr(C, align(64))]
+pub struct PkruState {
+	/// Never accessed, but the header has to start at offset 512
+	legacy_region: [u8; 512],
+	header: XSaveHeader,
+	pub pkru: u32,
+	padding: u32,
+}
+
+impl PkruState {
+	/// A new task starts in the kernel domain without access to the keys of the application.
+	pub const fn new() -> Self {
+		Self {
+			legacy_region: [0; 512],
+			header: XSaveHeader {
+				xstate_bv: XSAVE_PKRU_STATE,
+				xcomp_bv: XSAVE_COMPACTED_FORMAT | XSAVE_PKRU_STATE,
+				reserved: [0; 6],
+			},
+			pkru: mm::KERNEL_PERMISSION | mm::APPLICATION_KEY_DISABLED,
+			padding: 0,
+		}
+	}
+
+	/// Saves PKRU. Requires supports_xsave_pkru().
+	#[inline(always)]
+	pub fn save(&mut self) {
+		let (low, high) = (XSAVE_PKRU_STATE as u32, (XSAVE_PKRU_STATE >> 32) as u32);
+		unsafe {
+			if supports_xsaves() {
+				asm!("xsaves64 $0" : "=*m"(self as *mut Self) : "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
+			} else {
+				asm!("xsavec64 $0" : "=*m"(self as *mut Self) : "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
+			}
+		}
+	}
+
+	/// Restores PKRU. Requires supports_xsave_pkru().
+	#[inline(always)]
+	pub fn restore(&self) {
+		let (low, high) = (XSAVE_PKRU_STATE as u32, (XSAVE_PKRU_STATE >> 32) as u32);
+		unsafe {
+			if supports_xsaves() {
+				asm!("xrstors64 $0" :: "*m"(self as *const Self), "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
+			} else {
+				asm!("xrstor64 $0" :: "*m"(self as *const Self), "{eax}"(low), "{edx}"(high) : "memory" : "volatile");
+			}
+		}
+	}
+}
+
 enum CpuFrequencySources {
 	Invalid,
 	CommandLine,
@@ -734,6 +803,10 @@ pub fn detect_features() {
 		SUPPORTS_TSC_DEADLINE = feature_info.has_tsc_deadline();
 		SUPPORTS_X2APIC = feature_info.has_x2apic();
 		SUPPORTS_XSAVE = feature_info.has_xsave();
+		if let Some(extended_state_info) = cpuid.get_extended_state_info() {
+			SUPPORTS_XSAVEC = extended_state_info.has_xsavec();
+			SUPPORTS_XSAVES = extended_state_info.has_xsaves_xrstors();
+		}
 
         SUPPORTS_PKU = extended_feature_info.has_pku();
 
@@ -802,9 +875,12 @@ pub fn configure() {
 		cr4.insert(Cr4::CR4_ENABLE_OS_XSAVE);
 	}
 
-    if supports_pku() {
+    if supports_pku() && !cfg!(feature = "no-mpk") {
 		cr4.insert(Cr4::CR4_ENABLE_PROTECTION_KEY);
-        unsafe { SUPPORTS_OSPKE = true; }
+		// The application processors only read the flag, it may already be sealed (see sealed_global_var!).
+		if !supports_ospke() {
+			unsafe { SUPPORTS_OSPKE = true; }
+		}
     }
 
     if supports_fsgs() {
@@ -834,8 +910,20 @@ pub fn configure() {
 			xcr0.insert(Xcr0::XCR0_AVX_STATE);
 		}
 
-		// Disable xrstor to modify PKRU
-		xcr0.remove(Xcr0::XCR0_PKRU_STATE);
+		// Let the task switch save PKRU as XSAVE state component (see PkruState).
+		// Then, any XRSTOR or XRSTORS requesting this component loads PKRU from memory,
+		// whatever the format, so mm::wrpkru_scan rejects them like WRPKRU.
+		// PkruState needs the compacted format, which only XSAVEC and XSAVES write.
+		if supports_ospke() && (supports_xsavec() || supports_xsaves()) {
+			xcr0.insert(Xcr0::XCR0_PKRU_STATE);
+			if !supports_xsave_pkru() {
+				unsafe {
+					SUPPORTS_XSAVE_PKRU = true;
+				}
+			}
+		} else {
+			xcr0.remove(Xcr0::XCR0_PKRU_STATE);
+		}
 		unsafe {
 			xcr0_write(xcr0);
 		}
@@ -850,8 +938,6 @@ pub fn configure() {
 	unsafe {
 		CPU_SPEEDSTEP.configure();
 	}
-	/* Add CPU_FREQUENCY to white list */
-	unsafe {list_add(&CPU_FREQUENCY as *const CpuFrequency as usize)};
 }
 
 pub fn detect_frequency() {
@@ -953,6 +1039,22 @@ pub fn supports_xsave() -> bool {
 	unsafe { SUPPORTS_XSAVE }
 }
 
+#[inline]
+pub fn supports_xsavec() -> bool {
+	unsafe { SUPPORTS_XSAVEC }
+}
+
+#[inline]
+pub fn supports_xsaves() -> bool {
+	unsafe { SUPPORTS_XSAVES }
+}
+
+/// Returns `true` if PKRU is an XSAVE state component, which PkruState saves and restores.
+#[inline]
+pub fn supports_xsave_pkru() -> bool {
+	unsafe { SUPPORTS_XSAVE_PKRU }
+}
+
 #[inline]
 pub fn supports_pku() -> bool {
 	unsafe { SUPPORTS_PKU }
@@ -1021,12 +1123,36 @@ pub fn get_frequency() -> u16 {
 	unsafe { CPU_FREQUENCY.get()}
 }
 
+/// Reads the FS base, with `rdfsbase` or through its MSR in ring-3 mode.
+/// Also called by the context switch (see switch.rs).
+#[no_mangle]
+extern "C" fn read_fs_base() -> u64 {
+	if syscall::is_enabled() {
+		unsafe { rdmsr(IA32_FS_BASE) }
+	} else {
+		let val: u64;
+		unsafe { asm!("rdfsbase $0" : "=r"(val) ::: "volatile"); }
+		val
+	}
+}
+
+/// Writes the FS base, with `wrfsbase` or through its MSR in ring-3 mode.
+/// Also called by the context switch (see switch.rs).
+#[no_mangle]
+extern "C" fn write_fs_base(fs: u64) {
+	if syscall::is_enabled() {
+		unsafe { wrmsr(IA32_FS_BASE, fs); }
+	} else {
+		unsafe { asm!("wrfsbase $0" :: "r"(fs) :: "volatile"); }
+	}
+}
+
 #[inline]
 pub fn readfs() -> usize {
 	let val: u64;
 	unsafe {
 		isolation_start!();
-		asm!("rdfsbase $0" : "=r"(val) ::: "volatile");
+		val = read_fs_base();
 		isolation_end!();
 	}
 	val as usize
@@ -1037,7 +1163,11 @@ pub fn readgs() -> usize {
 	let val: u64;
 	unsafe {
 		isolation_start!();
-		asm!("rdgsbase $0" : "=r"(val) ::: "volatile");
+		if syscall::is_enabled() {
+			val = rdmsr(IA32_GS_BASE);
+		} else {
+			asm!("rdgsbase $0" : "=r"(val) ::: "volatile");
+		}
 		isolation_end!();
 	}
 	val as usize
@@ -1047,7 +1177,7 @@ pub fn readgs() -> usize {
 pub fn writefs(fs: usize) {
 	unsafe {
 		isolation_start!();
-		asm!("wrfsbase $0" :: "r"(fs as u64) :: "volatile");
+		write_fs_base(fs as u64);
 		isolation_end!();
 	}
 }
@@ -1056,11 +1186,24 @@ pub fn writefs(fs: usize) {
 pub fn writegs(gs: usize) {
 	unsafe {
 		isolation_start!();
-		asm!("wrgsbase $0" :: "r"(gs as u64) :: "volatile");
+		if syscall::is_enabled() {
+			wrmsr(IA32_GS_BASE, gs as u64);
+		} else {
+			asm!("wrgsbase $0" :: "r"(gs as u64) :: "volatile");
+		}
 		isolation_end!();
 	}
 }
 
+/// Takes `rdfsbase`, `wrfsbase`, `rdgsbase` and `wrgsbase` from the current core.
+/// In ring-3 mode, the application must not move GS away from the per-core data,
+/// which interrupts and exceptions from ring 3 rely on (see syscall::add_current_core).
+pub fn disable_fsgsbase() {
+	unsafe {
+		cr4_write(cr4() & !Cr4::CR4_ENABLE_FSGSBASE);
+	}
+}
+
 #[inline]
 pub fn get_timestamp() -> u64 {
 	unsafe { TIMESTAMP_FUNCTION() }
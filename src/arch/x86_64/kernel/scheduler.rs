@@ -15,19 +15,20 @@ use arch::x86_64::kernel::idt;
 use arch::x86_64::kernel::irq;
 use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::processor;
+use arch::x86_64::kernel::syscall;
 use arch::x86_64::kernel::copy_safe::*;
+use arch::x86_64::mm::mpk;
 use config::*;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::mem;
 use core::ptr::write_bytes;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicUsize, Ordering};
 use environment;
 use mm;
 use scheduler::task::{Task, TaskFrame, TaskTLS};
 
 #[repr(C, packed)]
 struct State {
-    /// PKRU
-    pkru: usize,
 	/// FS register for TLS support
 	fs: usize,
 	/// R15 register
@@ -74,10 +75,16 @@ pub struct TaskStacks {
 	pub stack: usize,
 	/// Stack to handle asynchronous interrupts
 	pub ist0: usize,
-	/// Isolated stack of the task
-	pub isolated_stack: usize,
+	/// Isolated stack of the task, taken from the per-core pool on the first isolated call (0 until then)
+	pub isolated_stack: Cell<usize>,
+	/// Compartment generation, in which the isolated stack was last used (see reset_compartment)
+	isolated_stack_generation: Cell<usize>,
 	/// User stack
 	pub user_stack: usize,
+	/// Saved contexts of nested isolated calls and kernel callbacks (see IsolationContexts)
+	pub isolation_contexts: usize,
+	/// Shadow stack of the task, 0 if shadow stacks are disabled (see mm::shadow_stack)
+	pub shadow_stack: usize,
 
 	//pub current_kernel_stack: usize,
 	//pub current_user_stack: usize,
@@ -85,32 +92,34 @@ pub struct TaskStacks {
 
 impl TaskStacks {
 	pub fn new() -> Self {
-		// Allocate an executable stack to possibly support dynamically generated code on the stack (see https://security.stackexchange.com/a/47825).
-		let stack = ::mm::allocate(DEFAULT_STACK_SIZE, true);
+		// Every stack gets an unmapped guard page below it (see mm::guarded_allocate),
+		// so that an overflow raises a #PF instead of corrupting the neighbouring allocation.
+		// Like all allocations, the stacks are not executable (W^X).
+		let stack = ::mm::guarded_allocate(DEFAULT_STACK_SIZE, mm::SAFE_MEM_REGION);
 		//info!("Allocating stack {:#X} ~ {:#X}", stack, stack + DEFAULT_STACK_SIZE);
 
-		let ist0 = ::mm::user_allocate(KERNEL_STACK_SIZE, true);
+		let ist0 = ::mm::guarded_allocate(KERNEL_STACK_SIZE, 0);
 		//info!("Allocating stack {:#X} ~ {:#X}", stack, stack + KERNEL_STACK_SIZE);
 
-		let isolated_stack = ::mm::unsafe_allocate(DEFAULT_STACK_SIZE, true);
-		//info!("Allocating isolated_stack {:#X} ~ {:#X}", isolated_stack, isolated_stack + DEFAULT_STACK_SIZE);
-
-		let user_stack = ::mm::user_allocate(DEFAULT_STACK_SIZE, true);
+		let user_stack = ::mm::guarded_allocate(DEFAULT_STACK_SIZE, 0);
 		//info!("Allocating user_stack {:#X} ~ {:#X}", user_stack, user_stack + DEFAULT_STACK_SIZE);
 
 		Self {
 			is_boot_stack: false,
 			stack: stack,
 			ist0: ist0,
-			isolated_stack: isolated_stack,
+			isolated_stack: Cell::new(0),
+			isolated_stack_generation: Cell::new(0),
 			user_stack: user_stack,
+			isolation_contexts: IsolationContexts::allocate(),
+			shadow_stack: ::mm::shadow_stack::allocate(),
 			//current_kernel_stack: 0xaaaabeefusize,
 			//current_user_stack: user_stack + DEFAULT_STACK_SIZE,
 		}
 	}
 
 	pub fn from_boot_stacks() -> Self {
-		let tss = unsafe { &(*PERCORE.tss.get()) };
+		let tss = unsafe { &(*core_tss()) };
 		let stack = tss.rsp[0] as usize + 0x10 - KERNEL_STACK_SIZE;
 		debug!("Using boot stack {:#X}", stack);
 		let ist0 = tss.ist[0] as usize + 0x10 - KERNEL_STACK_SIZE;
@@ -120,12 +129,39 @@ impl TaskStacks {
 			is_boot_stack: true,
 			stack: stack,
 			ist0: ist0,
-			isolated_stack: 0usize,
+			isolated_stack: Cell::new(0),
+			isolated_stack_generation: Cell::new(0),
 			user_stack: 0usize,
+			isolation_contexts: IsolationContexts::allocate(),
+			shadow_stack: ::mm::shadow_stack::allocate(),
 			//current_kernel_stack: 0xeeeebeefusize,
 			//current_user_stack: 0xffffbeefusize,
 		}
 	}
+
+	/// Returns the name of the stack whose guard page contains `virtual_address`.
+	/// Boot stacks are not guarded, so this always returns None for them.
+	pub fn guard_page_hit(&self, virtual_address: usize) -> Option<&'static str> {
+		if self.is_boot_stack {
+			return None;
+		}
+
+		let stacks = [
+			(self.stack, "kernel stack"),
+			(self.ist0, "IST stack"),
+			(self.isolated_stack.get(), "isolated stack"),
+			(self.user_stack, "user stack"),
+			(self.shadow_stack, "shadow stack"),
+		];
+
+		for &(start, name) in stacks.iter() {
+			if start != 0 && virtual_address < start && virtual_address >= start - mm::GUARD_PAGE_SIZE {
+				return Some(name);
+			}
+		}
+
+		None
+	}
 }
 
 impl Drop for TaskStacks {
@@ -133,16 +169,307 @@ impl Drop for TaskStacks {
 		if !self.is_boot_stack {
 			debug!("Deallocating stack {:#X} and ist0 {:#X}", self.stack, self.ist0);
 
-			::mm::deallocate(self.stack, DEFAULT_STACK_SIZE);
-			::mm::deallocate(self.ist0, KERNEL_STACK_SIZE);
+			::mm::guarded_deallocate(self.stack, DEFAULT_STACK_SIZE);
+			::mm::guarded_deallocate(self.ist0, KERNEL_STACK_SIZE);
+
+			let isolated_stack = self.isolated_stack.replace(0);
+			if isolated_stack != 0 {
+				debug!("Returning isolated_stack {:#X} to the pool", isolated_stack);
+				put_isolated_stack(isolated_stack);
+			}
+
+			::mm::guarded_deallocate(self.user_stack, DEFAULT_STACK_SIZE);
+
+			// The task has been aborted inside an isolated call, e.g. after a protection fault.
+			let contexts = unsafe { &*(self.isolation_contexts as *const IsolationContexts) };
+			if contexts.depth > 0 {
+				ISOLATED_CALLS.fetch_sub(1, Ordering::SeqCst);
+			}
+			::mm::deallocate(self.isolation_contexts, mem::size_of::<IsolationContexts>());
+			::mm::shadow_stack::deallocate(self.shadow_stack);
+		}
+	}
+}
+
+/// Determines when the contents of an isolated stack are wiped (-isolated-stack-scrub=call|reuse).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IsolatedStackScrub {
+	/// Zero the isolated stack after every isolated call, so that nothing
+	/// leaks from one call into the unsafe domain to the next one.
+	AfterCall,
+	/// Zero the isolated stack only when its task has finished, before it is handed to another task.
+	BeforeReuse,
+}
+
+safe_global_var!(static mut ISOLATED_STACK_SCRUB: IsolatedStackScrub = IsolatedStackScrub::BeforeReuse);
+
+pub fn set_isolated_stack_scrub(policy: IsolatedStackScrub) {
+	unsafe {
+		ISOLATED_STACK_SCRUB = policy;
+	}
+}
+
+pub fn get_isolated_stack_scrub() -> IsolatedStackScrub {
+	unsafe { ISOLATED_STACK_SCRUB }
+}
+
+#[inline]
+fn scrub_isolated_stack(stack: usize) {
+	// The kernel domain has full access to UNSAFE_MEM_REGION, so we can wipe the stack directly.
+	unsafe {
+		write_bytes(stack as *mut u8, 0, DEFAULT_STACK_SIZE);
+	}
+}
+
+/// Takes an isolated stack from the pool of the current core or allocates a new one.
+/// The returned stack is always zeroed.
+fn get_isolated_stack() -> usize {
+	let pooled = core_scheduler().isolated_stack_pool.lock().pop();
+	let stack = match pooled {
+		// Stacks are wiped before they are put into the pool.
+		Some(stack) => stack,
+		None => {
+			let stack = ::mm::guarded_allocate(DEFAULT_STACK_SIZE, mm::UNSAFE_MEM_REGION);
+			scrub_isolated_stack(stack);
+			stack
+		}
+	};
+	debug!("Using isolated_stack {:#X} ~ {:#X}", stack, stack + DEFAULT_STACK_SIZE);
+
+	stack
+}
+
+/// Hands an isolated stack back to the pool of the current core.
+/// If the pool is already full, the stack is released.
+fn put_isolated_stack(stack: usize) {
+	let mut pool = core_scheduler().isolated_stack_pool.lock();
+	if pool.len() < ISOLATED_STACK_POOL_SIZE {
+		if get_isolated_stack_scrub() != IsolatedStackScrub::AfterCall {
+			scrub_isolated_stack(stack);
+		}
+		pool.push(stack);
+	} else {
+		drop(pool);
+		::mm::guarded_deallocate(stack, DEFAULT_STACK_SIZE);
+	}
+}
+
+/// Number of tasks inside an isolated call
+safe_global_var!(static ISOLATED_CALLS: AtomicUsize = AtomicUsize::new(0));
+/// Set while the unsafe compartment is reset, no isolated call may start meanwhile
+safe_global_var!(static COMPARTMENT_RESET: AtomicBool = AtomicBool::new(false));
+/// Incremented by every reset of the unsafe compartment
+safe_global_var!(static COMPARTMENT_GENERATION: AtomicUsize = AtomicUsize::new(0));
+
+/// Counts the outermost isolated call of a task. Waits while the unsafe compartment is reset.
+fn begin_isolated_call() {
+	loop {
+		ISOLATED_CALLS.fetch_add(1, Ordering::SeqCst);
+		if !COMPARTMENT_RESET.load(Ordering::SeqCst) {
+			return;
+		}
+
+		ISOLATED_CALLS.fetch_sub(1, Ordering::SeqCst);
+		while COMPARTMENT_RESET.load(Ordering::SeqCst) {
+			spin_loop_hint();
+		}
+	}
+}
+
+/// Keeps isolated calls from starting until end_compartment_reset.
+/// Returns false if an isolated call runs, which may still use the unsafe compartment.
+pub fn begin_compartment_reset() -> bool {
+	if COMPARTMENT_RESET.compare_and_swap(false, true, Ordering::SeqCst) {
+		return false;
+	}
+
+	if ISOLATED_CALLS.load(Ordering::SeqCst) != 0 {
+		COMPARTMENT_RESET.store(false, Ordering::SeqCst);
+		return false;
+	}
+
+	true
+}
+
+/// Lets isolated calls start again. Isolated stacks of the previous generation are wiped before their next use.
+pub fn end_compartment_reset() {
+	COMPARTMENT_GENERATION.fetch_add(1, Ordering::SeqCst);
+	COMPARTMENT_RESET.store(false, Ordering::SeqCst);
+}
+
+/// Returns the top of the isolated stack of the current task.
+/// The isolated stack is taken from the per-core pool on the first isolated call of a task.
+fn isolated_stack_top() -> usize {
+	let current_task_borrowed = core_scheduler().current_task.borrow();
+	let stacks = &current_task_borrowed.stacks;
+	let generation = COMPARTMENT_GENERATION.load(Ordering::SeqCst);
+	let mut stack = stacks.isolated_stack.get();
+
+	if stack == 0 {
+		stack = get_isolated_stack();
+		stacks.isolated_stack.set(stack);
+	} else if stacks.isolated_stack_generation.get() != generation {
+		// The stack may hold data of the unsafe compartment from before its reset.
+		scrub_isolated_stack(stack);
+	}
+	stacks.isolated_stack_generation.set(generation);
+
+	stack + DEFAULT_STACK_SIZE
+}
+
+/// Called when the outermost isolated call has returned.
+fn isolated_call_finished() {
+	if get_isolated_stack_scrub() == IsolatedStackScrub::AfterCall {
+		let stack = core_scheduler().current_task.borrow().stacks.isolated_stack.get();
+		if stack != 0 {
+			scrub_isolated_stack(stack);
+		}
+	}
+}
+
+/// State of a domain that has been suspended by an isolated call or a kernel callback.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct IsolationContext {
+	/// Stack pointer of the suspended domain
+	rsp: usize,
+	/// PKRU value of the suspended domain
+	pkru: u32,
+	/// Suspended domain (SAFE_MEM_REGION for the kernel, UNSAFE_MEM_REGION for isolated code)
+	domain: u8,
+}
 
-			debug!("Deallocating isolated_stack {:#X}", self.stack);
+/// Per-task stack of suspended domains, kept in SAFE_MEM_REGION.
+#[repr(C)]
+pub struct IsolationContexts {
+	depth: usize,
+	contexts: [IsolationContext; MAX_ISOLATION_DEPTH],
+}
 
-			::mm::deallocate(self.isolated_stack, DEFAULT_STACK_SIZE);
+impl IsolationContexts {
+	/// Allocates an empty context stack in SAFE_MEM_REGION.
+	fn allocate() -> usize {
+		let size = mem::size_of::<IsolationContexts>();
+		let contexts = ::mm::allocate(size);
+		unsafe {
+			write_bytes(contexts as *mut u8, 0, size);
+		}
 
-			::mm::deallocate(self.user_stack, DEFAULT_STACK_SIZE);
+		contexts
+	}
+
+	/// Suspends the frame of `domain` at `rsp`, which returns to the access rights `pkru`.
+	fn push(&mut self, rsp: usize, domain: u8, pkru: u32) {
+		if self.depth >= MAX_ISOLATION_DEPTH {
+			panic!("Maximum isolation depth of {} exceeded", MAX_ISOLATION_DEPTH);
 		}
+
+		self.contexts[self.depth] = IsolationContext {
+			rsp: rsp,
+			pkru: pkru,
+			domain: domain,
+		};
+		self.depth += 1;
 	}
+
+	fn pop(&mut self, rsp: usize, domain: u8) -> IsolationContext {
+		assert!(self.depth > 0, "Isolation context stack underflow");
+
+		self.depth -= 1;
+		let context = self.contexts[self.depth];
+		assert!(
+			context.domain == domain && context.rsp == rsp,
+			"Unbalanced isolation exit (domain {}, rsp {:#X}), expected domain {}, rsp {:#X}",
+			domain,
+			rsp,
+			context.domain,
+			context.rsp
+		);
+
+		context
+	}
+
+	/// Returns the stack pointer of the most recently suspended frame of `domain`.
+	fn suspended_rsp(&self, domain: u8) -> Option<usize> {
+		self.contexts[..self.depth]
+			.iter()
+			.rev()
+			.find(|context| context.domain == domain)
+			.map(|context| context.rsp)
+	}
+}
+
+/// Runs `f` on the isolation contexts of the current task.
+/// The reference must not outlive `f`, because nested isolated calls and callbacks use the same contexts.
+fn with_isolation_contexts<F, R>(f: F) -> R
+where
+	F: FnOnce(&mut IsolationContexts) -> R,
+{
+	let contexts = core_scheduler().current_task.borrow().stacks.isolation_contexts;
+	f(unsafe { &mut *(contexts as *mut IsolationContexts) })
+}
+
+/// Returns a stack pointer below the suspended frame at `rsp`, skipping its red zone.
+#[inline]
+fn below_suspended_frame(rsp: usize) -> usize {
+	align_down!(rsp - 128, 16)
+}
+
+/// Called by the isolate_function_* macros before switching to the isolated domain.
+/// Saves the context of the kernel and returns the stack pointer for the isolated call.
+pub fn isolation_enter(rsp: usize) -> usize {
+	let isolated_rsp = with_isolation_contexts(|contexts| {
+		if contexts.depth == 0 {
+			begin_isolated_call();
+		}
+		contexts.push(rsp, mm::SAFE_MEM_REGION, mpk::mpk_get_pkru());
+		contexts.suspended_rsp(mm::UNSAFE_MEM_REGION)
+	});
+
+	match isolated_rsp {
+		// A kernel callback starts a new isolated call, so continue below the suspended isolated frame.
+		Some(isolated_rsp) => below_suspended_frame(isolated_rsp),
+		None => isolated_stack_top(),
+	}
+}
+
+/// Called by the isolate_function_* macros when an isolated call has returned.
+pub fn isolation_exit(rsp: usize) {
+	let (context, depth, isolated_suspended) = with_isolation_contexts(|contexts| {
+		let context = contexts.pop(rsp, mm::SAFE_MEM_REGION);
+		(context, contexts.depth, contexts.suspended_rsp(mm::UNSAFE_MEM_REGION).is_some())
+	});
+	mpk::mpk_set_pkru(context.pkru);
+	if depth == 0 {
+		ISOLATED_CALLS.fetch_sub(1, Ordering::SeqCst);
+	}
+
+	// The isolated stack may only be wiped if no isolated frame is suspended below us.
+	if !isolated_suspended {
+		isolated_call_finished();
+	}
+}
+
+/// Called by kernel_callback! after the isolated code has regained access to the kernel.
+/// Saves the context of the isolated caller with its access rights `pkru`, which kernel_callback!
+/// read before regaining access, and returns the stack pointer for the callback.
+pub fn kernel_callback_enter(rsp: usize, pkru: u32) -> usize {
+	let kernel_rsp = with_isolation_contexts(|contexts| {
+		let kernel_rsp = contexts
+			.suspended_rsp(mm::SAFE_MEM_REGION)
+			.expect("Kernel callback outside of an isolated call");
+		contexts.push(rsp, mm::UNSAFE_MEM_REGION, pkru);
+		kernel_rsp
+	});
+
+	below_suspended_frame(kernel_rsp)
+}
+
+/// Called by kernel_callback! when the callback has returned.
+/// Restores the PKRU value of the isolated caller.
+pub fn kernel_callback_exit(rsp: usize) {
+	let context = with_isolation_contexts(|contexts| contexts.pop(rsp, mm::UNSAFE_MEM_REGION));
+	mpk::mpk_set_pkru(context.pkru);
 }
 
 extern "C" fn leave_task() -> ! {
@@ -187,8 +514,6 @@ extern "C" fn task_entry(func: extern "C" fn(usize), arg: usize) {
 		/* Copy TLS variables with their initial values on the tls's unsafe_storage.
 			Then copy back the TLS variables with their initial values on tls.address()
 		*/
-		list_add(environment::get_tls_start());
-		list_add(tls.address());
 		copy_from_safe(environment::get_tls_start() as *const u8, tdata_size);
 		copy_to_safe(tls.address() as *mut u8, tls_size);
 		clear_unsafe_storage();
@@ -204,7 +529,11 @@ extern "C" fn task_entry(func: extern "C" fn(usize), arg: usize) {
 	}
 
 	//info!("Task_entry of {}", core_scheduler().current_task.borrow().id.into());
-	if core_scheduler().current_task.borrow().id.into() >= 2 {
+	let id = core_scheduler().current_task.borrow().id.into();
+	if id >= 2 && syscall::is_enabled() {
+		// Tasks of the application run in ring 3.
+		syscall::enter_user(func as usize, arg, 0, 0);
+	} else if id >= 2 {
 		user_start!(false);
 		func(arg);
 		user_end!();
@@ -5,11 +5,16 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use arch::x86_64::kernel::processor::PkruState;
+
+#[cfg(not(feature = "no-mpk"))]
 #[inline(never)]
 #[naked]
-pub extern "C" fn switch(_old_stack: *mut usize, _new_stack: usize) {
+pub extern "C" fn switch(_old_stack: *mut usize, _new_stack: usize, _old_pkru: *mut PkruState, _new_pkru: *const PkruState) {
 	// rdi = old_stack => the address to store the old rsp
 	// rsi = new_stack => stack pointer of the new task
+	// rdx = old_pkru => the access rights of the old task are saved here
+	// rcx = new_pkru => the access rights of the new task
 
 	unsafe {
 		asm!(
@@ -30,12 +35,19 @@ pub extern "C" fn switch(_old_stack: *mut usize, _new_stack: usize) {
 			push %r13\n\t\
 			push %r14\n\t\
 			push %r15\n\t\
-			rdfsbaseq %rax\n\t\
-			push %rax\n\t\
-			xor %rax, %rax\n\t\
-			xor %ecx, %ecx\n\t\
-			rdpkru\n\t\
-			push %rax\n\t\
+			// keep new_pkru in r12 and old_pkru in r13, which are restored below anyway,\n\t\
+			// and store the FS base (see processor::read_fs_base)\n\t\
+			mov %rcx, %r12\n\t\
+			mov %rdx, %r13\n\t\
+			sub $$8, %rsp\n\t\
+			call read_fs_base\n\t\
+			mov %rax, (%rsp)\n\t\
+			// save the access rights of the old task (see processor::PkruState)\n\t\
+			// and reload rdi and rsi, which are clobbered by the calls\n\t\
+			mov %r13, %rdi\n\t\
+			call isolation_save_permissions\n\t\
+			mov 72(%rsp), %rdi\n\t\
+			mov 80(%rsp), %rsi\n\t\
 			// store the old stack pointer in the dereferenced first parameter\n\t\
 			// and load the new stack pointer in the second parameter.\n\t\
 			mov %rsp, (%rdi)\n\t\
@@ -47,12 +59,79 @@ pub extern "C" fn switch(_old_stack: *mut usize, _new_stack: usize) {
 			// set stack pointer in TSS \n\t\
 			call set_current_kernel_stack \n\t\
 			// restore context \n\t\
+			mov %r12, %rdi\n\t\
+			call isolation_restore_permissions\n\t\
+			mov (%rsp), %rdi\n\t\
+			call write_fs_base\n\t\
+			add $$8, %rsp\n\t\
+			pop %r15\n\t\
+			pop %r14\n\t\
+			pop %r13\n\t\
+			pop %r12\n\t\
+			pop %r11\n\t\
+			pop %r10\n\t\
+			pop %r9\n\t\
+			pop %r8\n\t\
+			pop %rdi\n\t\
+			pop %rsi\n\t\
+			pop %rbp\n\t\
+			pop %rbx\n\t\
+			pop %rdx\n\t\
+			pop %rcx\n\t\
 			pop %rax\n\t\
-			xor %ecx, %ecx\n\t\
-			xor %edx, %edx\n\t\
-			wrpkru\n\t\
-			pop %rax\n\t\
-			wrfsbaseq %rax\n\t\
+			popfq" :::: "volatile"
+		);
+	}
+}
+
+/// Without MPK, the access rights are never switched.
+#[cfg(feature = "no-mpk")]
+#[inline(never)]
+#[naked]
+pub extern "C" fn switch(_old_stack: *mut usize, _new_stack: usize, _old_pkru: *mut PkruState, _new_pkru: *const PkruState) {
+	// rdi = old_stack => the address to store the old rsp
+	// rsi = new_stack => stack pointer of the new task
+
+	unsafe {
+		asm!(
+			// store context
+			"pushfq\n\t\
+			push %rax\n\t\
+			push %rcx\n\t\
+			push %rdx\n\t\
+			push %rbx\n\t\
+			push %rbp\n\t\
+			push %rsi\n\t\
+			push %rdi\n\t\
+			push %r8\n\t\
+			push %r9\n\t\
+			push %r10\n\t\
+			push %r11\n\t\
+			push %r12\n\t\
+			push %r13\n\t\
+			push %r14\n\t\
+			push %r15\n\t\
+			// store the FS base (see processor::read_fs_base)\n\t\
+			// and reload rdi and rsi, which are clobbered by the call\n\t\
+			sub $$8, %rsp\n\t\
+			call read_fs_base\n\t\
+			mov %rax, (%rsp)\n\t\
+			mov 72(%rsp), %rdi\n\t\
+			mov 80(%rsp), %rsi\n\t\
+			// store the old stack pointer in the dereferenced first parameter\n\t\
+			// and load the new stack pointer in the second parameter.\n\t\
+			mov %rsp, (%rdi)\n\t\
+			mov %rsi, %rsp\n\t\
+			// Set task switched flag \n\t\
+			mov %cr0, %rax\n\t\
+			or $$8, %rax\n\t\
+			mov %rax, %cr0\n\t\
+			// set stack pointer in TSS \n\t\
+			call set_current_kernel_stack \n\t\
+			// restore context \n\t\
+			mov (%rsp), %rdi\n\t\
+			call write_fs_base\n\t\
+			add $$8, %rsp\n\t\
 			pop %r15\n\t\
 			pop %r14\n\t\
 			pop %r13\n\t\
@@ -0,0 +1,187 @@
+//! Optional ring-3 mode of the application (-ring3).
+//!
+//! The application runs in ring 3 and enters the kernel with `syscall` through
+//! `sys_syscall`. The entry stub switches to the kernel stack of the task and
+//! dispatches the call by number (see syscalls::dispatch).
+//! CR4.FSGSBASE is cleared in this mode, so ring 3 cannot move GS away from the per-core
+//! data. The stub as well as interrupts and exceptions from ring 3 rely on it, and the kernel
+//! accesses the FS and GS bases through their MSRs (see processor::disable_fsgsbase).
+//!
+//! The pages of the kernel domains and the page tables become supervisor pages
+//! (see paging::restrict_user_access). The kernel image stays a user page, because the
+//! application is linked into it. Hence, state that decides about the isolation must not
+//! live in plain statics of the image, but in sealed or safe memory.
+//! Protection keys only apply to user pages, so they no longer separate the kernel domains
+//! from each other in this mode.
+//! Calling `sys_*` functions directly from ring 3 faults.
+
+use arch::x86_64::kernel::gdt;
+use arch::x86_64::kernel::irq;
+use arch::x86_64::kernel::percore::*;
+use arch::x86_64::kernel::processor;
+use arch::x86_64::mm::isolation;
+use arch::x86_64::mm::paging;
+use environment;
+use mm;
+use syscalls;
+use x86::msr::*;
+
+/// Flags cleared on a system call: TF, IF, DF and AC
+const SYSCALL_FLAG_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+
+/// Whether the application runs in ring 3.
+/// The gates consult it before the kernel domain is accessible, so it is sealed instead of safe.
+sealed_global_var!(static mut RING3: bool = false);
+
+#[inline(always)]
+pub fn is_enabled() -> bool {
+	unsafe { RING3 }
+}
+
+/// Chooses the mode of the application and takes the user bit from all kernel pages.
+/// Must be called after environment::init.
+pub fn init() {
+	if !environment::isolation_config().ring3 {
+		return;
+	}
+
+	// The page-table backend hides the kernel domain from the entry stub.
+	if !cfg!(feature = "no-mpk") && !processor::supports_ospke() {
+		warn!("-ring3 requires PKU, the application keeps running in ring 0");
+		return;
+	}
+
+	unsafe {
+		RING3 = true;
+	}
+	paging::restrict_user_access();
+
+	info!("The application runs in ring 3");
+}
+
+/// Enables the system call entry on the current core.
+/// EFER.SCE is already set by processor::configure.
+pub fn add_current_core() {
+	if !is_enabled() {
+		return;
+	}
+
+	let kernel_code = u64::from(gdt::GDT_KERNEL_CODE) << 3;
+	let sysret_base = (u64::from(gdt::GDT_SYSRET_BASE) << 3) | 3;
+
+	unsafe {
+		wrmsr(IA32_STAR, (sysret_base << 48) | (kernel_code << 32));
+		wrmsr(IA32_LSTAR, syscall_entry as u64);
+		wrmsr(IA32_FMASK, SYSCALL_FLAG_MASK);
+	}
+	processor::disable_fsgsbase();
+}
+
+/// Entry point of `syscall`.
+/// rax holds the number of the system call, rdi, rsi, rdx, r10 and r8 its arguments.
+/// The fourth argument moves to rcx and the number becomes the sixth argument of syscall_handler.
+#[cfg(not(test))]
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+	asm!(
+		// switch to the kernel stack of the task (user_stack and syscall_stack of PerCoreVariables)
+		"mov %rsp, %gs:${0:c}\n\t\
+		mov %gs:${1:c}, %rsp\n\t\
+		pushq %gs:${0:c}\n\t\
+		push %rcx\n\t\
+		push %r11\n\t\
+		sub $$8, %rsp\n\t\
+		mov %r10, %rcx\n\t\
+		mov %rax, %r9\n\t\
+		call syscall_handler\n\t\
+		add $$8, %rsp\n\t\
+		pop %r11\n\t\
+		pop %rcx\n\t\
+		pop %rsp\n\t\
+		sysretq"
+		:
+		: "i"(USER_STACK_OFFSET), "i"(SYSCALL_STACK_OFFSET)
+		:
+		: "volatile"
+	);
+}
+
+#[cfg(test)]
+unsafe extern "C" fn syscall_entry() {}
+
+#[no_mangle]
+extern "C" fn syscall_handler(arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize, number: usize) -> usize {
+	isolation::write_permissions(mm::kernel_permission());
+	irq::enable();
+
+	let ret = syscalls::dispatch(number, [arg0, arg1, arg2, arg3, arg4]);
+
+	// The stub needs the per-core data until sysret.
+	irq::disable();
+	isolation::write_permissions(mm::user_permission());
+	ret
+}
+
+/// Issues the system call `number` from ring 3.
+#[no_mangle]
+pub extern "C" fn sys_syscall(number: usize, arg0: usize, arg1: usize, arg2: usize, arg3: usize, arg4: usize) -> usize {
+	let ret: usize;
+	unsafe {
+		asm!("syscall"
+			: "={rax}"(ret)
+			: "{rax}"(number), "{rdi}"(arg0), "{rsi}"(arg1), "{rdx}"(arg2), "{r10}"(arg3), "{r8}"(arg4)
+			: "rcx", "r9", "r11", "memory"
+			: "volatile");
+	}
+	ret
+}
+
+/// Terminates a task of the application, whose entry point has returned.
+extern "C" fn user_thread_exit() -> ! {
+	sys_syscall(syscalls::SYS_THREAD_EXIT, 0, 0, 0, 0, 0);
+	unreachable!();
+}
+
+/// Leaves the kernel and continues at `entry` in ring 3 on the user stack of the current task,
+/// passing `arg0` to `arg2` in rdi, rsi and rdx. If `entry` returns, the task exits.
+pub fn enter_user(entry: usize, arg0: usize, arg1: usize, arg2: usize) -> ! {
+	let kernel_stack_pointer: usize;
+	unsafe {
+		asm!("mov %rsp, $0" : "=r"(kernel_stack_pointer) ::: "volatile");
+	}
+	// System calls use the kernel stack below this frame, which is never left.
+	let kernel_stack_pointer = align_down!(kernel_stack_pointer, 16);
+
+	let user_stack_pointer = {
+		let mut task = core_scheduler().current_task.borrow_mut();
+		task.kernel_stack_pointer = kernel_stack_pointer;
+		task.user_stack_pointer
+	};
+	set_syscall_stack(kernel_stack_pointer);
+
+	// Enter like a call: the return address is on the stack, which is 16-byte aligned before.
+	let user_stack_pointer = align_down!(user_stack_pointer, 16) - 8;
+	unsafe {
+		*(user_stack_pointer as *mut usize) = user_thread_exit as usize;
+	}
+
+	let user_code = (u64::from(gdt::GDT_USER_CODE) << 3) | 3;
+	let user_data = (u64::from(gdt::GDT_USER_DATA) << 3) | 3;
+
+	irq::disable();
+	isolation::write_permissions(mm::user_permission());
+	unsafe {
+		asm!("push $0\n\t\
+			push $1\n\t\
+			pushq $$0x202\n\t\
+			push $2\n\t\
+			push $3\n\t\
+			iretq"
+			:
+			: "r"(user_data), "r"(user_stack_pointer), "r"(user_code), "r"(entry),
+			  "{rdi}"(arg0), "{rsi}"(arg1), "{rdx}"(arg2)
+			:
+			: "volatile");
+	}
+	unreachable!();
+}
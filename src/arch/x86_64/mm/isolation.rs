@@ -0,0 +1,312 @@
+//! Backends that switch the access rights of the protection domains.
+//!
+//! Access rights are always expressed in the PKRU format: bit 2k disables all
+//! accesses to the pages with key k and bit 2k+1 disables writes to them.
+
+use arch::x86_64::kernel::irq;
+use arch::x86_64::kernel::processor::{self, PkruState};
+use arch::x86_64::mm::paging;
+use core::mem;
+use mm;
+
+/// Primitives every isolation backend has to provide.
+pub trait IsolationBackend {
+	/// Name of the backend for boot messages.
+	fn name(&self) -> &'static str;
+
+	/// Returns `true` if the access rights are kept per core.
+	fn supports_smp(&self) -> bool;
+
+	/// Returns the access rights of the current domain.
+	fn read_permissions(&self) -> u32;
+
+	/// Switches to the access rights `permissions`.
+	fn write_permissions(&self, permissions: u32);
+}
+
+/// Uses Memory Protection Keys (PKU) and switches the PKRU register.
+pub struct PkuBackend;
+
+impl IsolationBackend for PkuBackend {
+	fn name(&self) -> &'static str {
+		"PKU"
+	}
+
+	fn supports_smp(&self) -> bool {
+		true
+	}
+
+	#[inline(always)]
+	fn read_permissions(&self) -> u32 {
+		let val: u32;
+		unsafe {
+			asm!("xor %ecx, %ecx;
+			      rdpkru"
+				: "={eax}"(val)
+				:
+				: "ecx", "edx"
+				: "volatile");
+		}
+		val
+	}
+
+	#[inline(always)]
+	fn write_permissions(&self, permissions: u32) {
+		unsafe {
+			asm!("xor %ecx, %ecx;
+			      xor %edx, %edx;
+			      wrpkru;
+			      lfence"
+				:
+				: "{eax}"(permissions)
+				: "ecx", "edx"
+				: "volatile");
+		}
+	}
+}
+
+/// Fallback for CPUs without PKU.
+/// Pages of a disabled key are made non-present (or read-only) in the page tables,
+/// which are shared by all cores. Therefore, this backend only supports a single core.
+pub struct PageTableBackend;
+
+/// Access rights currently applied to the page tables.
+/// The gates read and write it before the kernel domain is accessible, so it lives in key 0.
+/// It is only a record for read_permissions: write_permissions applies the rights of all keys
+/// every time, so that a forged value cannot keep pages of a disabled key accessible.
+static mut PAGE_TABLE_PERMISSIONS: u32 = 0;
+
+impl IsolationBackend for PageTableBackend {
+	fn name(&self) -> &'static str {
+		"page table"
+	}
+
+	fn supports_smp(&self) -> bool {
+		false
+	}
+
+	#[inline(always)]
+	fn read_permissions(&self) -> u32 {
+		unsafe { PAGE_TABLE_PERMISSIONS }
+	}
+
+	#[inline(never)]
+	fn write_permissions(&self, permissions: u32) {
+		// The caller still needs its stack to return, which the PKU gates never touch.
+		let stack_pointer: usize;
+		unsafe {
+			asm!("mov %rsp, $0" : "=r"(stack_pointer) ::: "volatile");
+		}
+
+		paging::set_pkey_permissions(permissions, stack_pointer);
+
+		unsafe {
+			PAGE_TABLE_PERMISSIONS = permissions;
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Backend {
+	Pku,
+	PageTable,
+}
+
+/// Page holding the backend, which `seal` makes read-only in every domain
+#[repr(C, align(4096))]
+struct BackendPage(Backend);
+
+/// Backend chosen at boot.
+/// Until `init` has run, only the kernel domain exists. The page-table backend
+/// does not touch any hardware register for it, so it is the safe default.
+/// The gates consult it before the kernel domain is accessible, so it lives in key 0
+/// until `seal` moves its page to SEALED_MEM_REGION.
+static mut BACKEND: BackendPage = BackendPage(Backend::PageTable);
+
+#[inline(always)]
+fn current_backend() -> Backend {
+	unsafe { BACKEND.0 }
+}
+
+/// Chooses the isolation backend. Must be called after `processor::configure`.
+pub fn init() {
+	unsafe {
+		BACKEND.0 = if processor::supports_ospke() {
+			Backend::Pku
+		} else {
+			Backend::PageTable
+		};
+	}
+
+	info!("Isolation backend: {}", backend().name());
+	if current_backend() == Backend::Pku {
+		let instruction = if !processor::supports_xsave_pkru() {
+			"RDPKRU/WRPKRU"
+		} else if processor::supports_xsaves() {
+			"XSAVES/XRSTORS"
+		} else {
+			"XSAVEC/XRSTOR"
+		};
+		info!("Task switch saves PKRU with {}", instruction);
+	}
+}
+
+/// Seals the choice of the backend, so that it cannot be switched to turn off the isolation.
+/// Must be called once, after the kernel has been initialized.
+pub fn seal() {
+	let address = unsafe { &BACKEND as *const BackendPage as usize };
+	assert!(
+		mm::seal(address, mem::size_of::<BackendPage>()),
+		"Could not seal the isolation backend at {:#X}",
+		address
+	);
+}
+
+/// Returns whether the PKU backend is in use. The page-table backend cannot restrict key 0,
+/// which also holds the kernel code (see paging::set_pkey_permissions).
+pub fn uses_pku() -> bool {
+	current_backend() == Backend::Pku
+}
+
+/// Returns the isolation backend chosen at boot.
+pub fn backend() -> &'static dyn IsolationBackend {
+	match current_backend() {
+		Backend::Pku => &PkuBackend,
+		Backend::PageTable => &PageTableBackend,
+	}
+}
+
+/// Returns the access rights of the current domain.
+#[inline(always)]
+pub fn read_permissions() -> u32 {
+	match current_backend() {
+		Backend::Pku => PkuBackend.read_permissions(),
+		Backend::PageTable => PageTableBackend.read_permissions(),
+	}
+}
+
+/// Switches to the access rights `permissions`.
+/// Sealed memory, JIT regions, shadow stacks and function-pointer tables stay write-protected and
+/// secret memory inaccessible, whatever `permissions` says.
+#[inline(always)]
+pub fn write_permissions(permissions: u32) {
+	switch_permissions(permissions | mm::ENFORCED_PERMISSION)
+}
+
+#[inline(always)]
+fn switch_permissions(permissions: u32) {
+	match current_backend() {
+		Backend::Pku => PkuBackend.write_permissions(permissions),
+		Backend::PageTable => PageTableBackend.write_permissions(permissions),
+	}
+}
+
+/// Runs `f` with the rights in `mask` lifted in addition to the current rights.
+/// Interrupts stay disabled meanwhile, so that neither interrupt handlers nor other tasks
+/// run with this access.
+#[inline(always)]
+fn with_lifted<R, F: FnOnce() -> R>(mask: u32, f: F) -> R {
+	let irq = irq::nested_disable();
+	let permissions = read_permissions();
+
+	switch_permissions(permissions & !mask);
+	let result = f();
+	switch_permissions(permissions);
+
+	irq::nested_enable(irq);
+	result
+}
+
+/// Runs `f` with access to secret memory (see mm::secret).
+pub fn with_secret_access<R, F: FnOnce() -> R>(f: F) -> R {
+	with_lifted(mm::SECRET_PERMISSION, f)
+}
+
+/// Runs `f` with write access to JIT regions (see mm::jit).
+pub fn with_jit_access<R, F: FnOnce() -> R>(f: F) -> R {
+	with_lifted(mm::JIT_PERMISSION, f)
+}
+
+/// Runs `f` with write access to the function-pointer tables (see mm::fnptr).
+pub fn with_fnptr_access<R, F: FnOnce() -> R>(f: F) -> R {
+	with_lifted(mm::FNPTR_PERMISSION, f)
+}
+
+/// Runs `f` with write access to the shadow stacks (see mm::shadow_stack).
+#[inline(always)]
+pub fn with_shadow_stack_access<R, F: FnOnce() -> R>(f: F) -> R {
+	with_lifted(mm::SHADOW_PERMISSION, f)
+}
+
+/// Additionally disables the rights set in `mask` (e.g. mm::UNSAFE_PERMISSION_IN).
+#[inline(always)]
+pub fn restrict_permissions(mask: u32) {
+	write_permissions(read_permissions() | mask)
+}
+
+/// Keeps only the restrictions set in `mask` (e.g. mm::UNSAFE_PERMISSION_OUT).
+#[inline(always)]
+pub fn relax_permissions(mask: u32) {
+	write_permissions(read_permissions() & mask)
+}
+
+/// Called by the context switch to save the access rights of the old task.
+#[no_mangle]
+pub extern "C" fn isolation_save_permissions(state: &mut PkruState) {
+	if current_backend() == Backend::Pku && processor::supports_xsave_pkru() {
+		state.save();
+	} else {
+		state.pkru = read_permissions();
+	}
+}
+
+/// Called by the context switch to restore the access rights of the new task.
+/// Like write_permissions, it never lifts mm::ENFORCED_PERMISSION, even if XRSTOR(S) loads saved
+/// rights without it.
+#[no_mangle]
+pub extern "C" fn isolation_restore_permissions(state: &PkruState) {
+	if current_backend() == Backend::Pku && processor::supports_xsave_pkru() {
+		state.restore();
+		let permissions = read_permissions();
+		if permissions & mm::ENFORCED_PERMISSION != mm::ENFORCED_PERMISSION {
+			write_permissions(permissions);
+		}
+	} else {
+		write_permissions(state.pkru);
+	}
+}
+
+/// Upper bound of the iterations of bench_pkru_switch, which keeps interrupts disabled
+const MAX_BENCH_ITERATIONS: u64 = 1_000_000;
+
+/// Measures a save and a restore of the access rights `iterations` times (at most
+/// MAX_BENCH_ITERATIONS), once as XSAVE state component (see PkruState) and once with
+/// RDPKRU/WRPKRU, which the task switch used before.
+/// Returns the average ticks of both or None if the PKU backend or the XSAVE component is missing.
+pub fn bench_pkru_switch(iterations: u64) -> Option<(u64, u64)> {
+	if current_backend() != Backend::Pku || !processor::supports_xsave_pkru() || iterations == 0 {
+		return None;
+	}
+	let iterations = iterations.min(MAX_BENCH_ITERATIONS);
+
+	let irq = irq::nested_disable();
+	let mut state = PkruState::new();
+	state.save();
+
+	let start = processor::get_timestamp();
+	for _ in 0..iterations {
+		state.save();
+		state.restore();
+	}
+	let xsave_ticks = processor::get_timestamp() - start;
+
+	let start = processor::get_timestamp();
+	for _ in 0..iterations {
+		let permissions = PkuBackend.read_permissions();
+		PkuBackend.write_permissions(permissions);
+	}
+	let register_ticks = processor::get_timestamp() - start;
+
+	irq::nested_enable(irq);
+	Some((xsave_ticks / iterations, register_ticks / iterations))
+}
@@ -2,7 +2,7 @@
 
 use arch::x86_64::mm::paging;
 use arch::x86_64::mm::paging::PageSize;
-use arch::x86_64::kernel::processor;
+use arch::x86_64::mm::isolation;
 
 const EINVAL: i32 = 22;
 const ENOSYS: i32 = 38;
@@ -13,44 +13,19 @@ pub enum MpkPerm {
     MpkNone
 }
 
+/* The PKRU format is used by every isolation backend, not only by PKU. */
 #[inline]
 fn rdpkru() -> u32 {
-
-    let val: u32;
-    unsafe {
-        asm!("xor %ecx, %ecx;
-              rdpkru;
-              movl %eax, $0"
-             : "=r"(val)
-             :
-             : "eax", "edx", "ecx"
-             : "volatile");
-    }
-    val
+    isolation::read_permissions()
 }
 
 #[inline]
 fn wrpkru(val: u32) {
-
-    unsafe {
-        asm!("mov $0, %eax;
-              xor %ecx, %ecx;
-              xor %edx, %edx;
-              wrpkru;
-              lfence"
-             :
-             : "r"(val)
-             : "eax", "ecx", "edx"
-             : "volatile");
-    }
+    isolation::write_permissions(val);
 }
 
 pub fn mpk_swap_pkru(new_pkru: u32) -> u32 {
 
-    if processor::supports_ospke() == true {
-        return 0;
-    }
-
     let old_pkru: u32;
     old_pkru = rdpkru();
     wrpkru(new_pkru);
@@ -98,10 +73,6 @@ fn pkru_set_no_access(key: u8, val: &mut u32) -> i32 {
 
 pub fn mpk_mem_set_key<S: PageSize>(mut addr: usize, mut size: usize, key: u8) -> i32 {
 
-    if processor::supports_ospke() == false {
-        return -ENOSYS;
-    }
-
     if key > 15
     {
         return -EINVAL;
@@ -126,10 +97,6 @@ pub fn mpk_mem_set_key<S: PageSize>(mut addr: usize, mut size: usize, key: u8) -
 
 pub fn mpk_set_perm(key: u8, perm: MpkPerm) -> i32 {
 
-    if processor::supports_ospke() == false {
-        return -ENOSYS;
-    }
-
     let mut pkru: u32;
     pkru = rdpkru();
 
@@ -153,27 +120,17 @@ pub fn mpk_set_perm(key: u8, perm: MpkPerm) -> i32 {
 
 pub fn mpk_clear_pkru() {
 
-    if processor::supports_ospke() == false {
-        return;
-    }
-
     wrpkru(0x0);
 }
 
 /* Return the PKRU value */
 pub fn mpk_get_pkru() -> u32 {
 
-    if processor::supports_ospke() == false {
-        return 0;
-    }
-
     return rdpkru();
 }
 
 /* Set the pkru value to 'val' */
 pub fn mpk_set_pkru(val: u32) {
 
-    if processor::supports_ospke() == true {
-        wrpkru(val);
-    }
+    wrpkru(val);
 }
\ No newline at end of file
@@ -8,27 +8,40 @@
 #![allow(dead_code)]
 
 use arch::x86_64::kernel::apic;
-use arch::x86_64::kernel::get_mbinfo;
+use arch::x86_64::kernel::{get_boot_stack, get_mbinfo};
 use arch::x86_64::kernel::irq;
+use arch::x86_64::kernel::percore::core_scheduler;
 //use arch::x86_64::kernel::is_uhyve;
 use arch::x86_64::kernel::processor;
+use arch::x86_64::kernel::syscall;
+use arch::x86_64::mm::isolation;
 use arch::x86_64::mm::paddr_to_slice;
 use arch::x86_64::mm::physicalmem;
+use arch::x86_64::mm::virtualmem;
+use config::KERNEL_STACK_SIZE;
 use core::marker::PhantomData;
 use core::mem;
 use core::ptr::write_bytes;
 use environment;
+use environment::PkeyFault;
 use mm;
 use multiboot::Multiboot;
 use scheduler;
+use selftest;
 use x86::controlregs;
 use x86::irq::PageFaultError;
 
 /// Uhyve's address of the initial GDT
 const BOOT_GDT: usize = 0x1000;
 
-/// Pointer to the root page table (PML4)
-const PML4_ADDRESS: *mut PageTable<PML4> = 0xFFFF_FFFF_FFFF_F000 as *mut PageTable<PML4>;
+/// Address of the root page table (PML4) in the recursive mapping
+pub const ROOT_PAGE_TABLE_ADDRESS: usize = 0xFFFF_FFFF_FFFF_F000;
+const PML4_ADDRESS: *mut PageTable<PML4> = ROOT_PAGE_TABLE_ADDRESS as *mut PageTable<PML4>;
+
+/// Base addresses of the PDPTs, PDs and PTs in the recursive mapping
+const PDPT_ADDRESS: usize = 0xFFFF_FFFF_FFE0_0000;
+const PD_ADDRESS: usize = 0xFFFF_FFFF_C000_0000;
+const PT_ADDRESS: usize = 0xFFFF_FF80_0000_0000;
 
 /// Number of Offset bits of a virtual address for a 4 KiB page, which are shifted away to get its Page Frame Number (PFN).
 const PAGE_BITS: usize = 12;
@@ -39,6 +52,16 @@ const PAGE_MAP_BITS: usize = 9;
 /// A mask where PAGE_MAP_BITS are set to calculate a table index.
 const PAGE_MAP_MASK: usize = 0x1FF;
 
+/// Bits of an entry that hold the physical address (up to 52 bits).
+const PHYSICAL_ADDRESS_MASK: usize = 0x000F_FFFF_FFFF_F000;
+
+/// Number of ranges identity_map can record for the page-table audit.
+const MAX_IDENTITY_MAPPINGS: usize = 64;
+
+/// Ranges (start, end) that have been identity-mapped on purpose through identity_map.
+safe_global_var!(static mut IDENTITY_MAPPINGS: [(usize, usize); MAX_IDENTITY_MAPPINGS] = [(0, 0); MAX_IDENTITY_MAPPINGS]);
+safe_global_var!(static mut IDENTITY_MAPPING_COUNT: usize = 0);
+
 bitflags! {
 	/// Possible flags for an entry in either table (PML4, PDPT, PD, PT)
 	///
@@ -73,6 +96,15 @@ bitflags! {
 		/// be flushed from the TLB when CR3 is reset.
 		const GLOBAL = 1 << 8;
 
+		/// Available to software: Set if the page-table isolation backend has cleared PRESENT of this page.
+		const DOMAIN_HIDDEN = 1 << 9;
+
+		/// Available to software: Set if the page-table isolation backend has cleared WRITABLE of this page.
+		const DOMAIN_READ_ONLY = 1 << 10;
+
+		/// Available to software: Set if PRESENT of this page has been cleared by set_page_inaccessible.
+		const INACCESSIBLE = 1 << 11;
+
 		/// Set if code execution shall be disabled for memory referenced by this entry.
 		const EXECUTE_DISABLE = 1 << 63;
 	}
@@ -109,6 +141,11 @@ impl PageTableEntryFlags {
 	}
 
 	pub fn pkey(&mut self, key: u8) -> &mut Self {
+		// Without MPK, all pages keep the default key 0.
+		if cfg!(feature = "no-mpk") {
+			return self;
+		}
+
 		let pkey: usize = (key as usize)& 15;
 		let pkey_flag: PageTableEntryFlags = PageTableEntryFlags { bits: (pkey << 59) };
 		self.insert(pkey_flag);
@@ -165,7 +202,8 @@ impl PageTableEntry {
 	///
 	/// * `physical_address` - The physical memory address this entry shall translate to
 	/// * `flags` - Flags from PageTableEntryFlags (note that the PRESENT and ACCESSED flags are set automatically)
-	fn set(&mut self, physical_address: usize, flags: PageTableEntryFlags) {
+	/// * `physical_address_bits` - The CPU's physical address width
+	fn set(&mut self, physical_address: usize, flags: PageTableEntryFlags, physical_address_bits: u8) {
         if flags.contains(PageTableEntryFlags::HUGE_PAGE) {
 			// HUGE_PAGE may indicate a 2 MiB or 1 GiB page.
 			// We don't know this here, so we can only verify that at least the offset bits for a 2 MiB page are zero.
@@ -185,7 +223,7 @@ impl PageTableEntry {
 
 		// Verify that the physical address does not exceed the CPU's physical address width.
 		assert!(
-			physical_address >> processor::get_physical_address_bits() == 0,
+			physical_address >> physical_address_bits == 0,
 			"Physical address exceeds CPU's physical address width (physical_address = {:#X})",
 			physical_address
 		);
@@ -193,8 +231,7 @@ impl PageTableEntry {
 		let mut flags_to_set = flags;
 		flags_to_set.insert(PageTableEntryFlags::PRESENT);
 		flags_to_set.insert(PageTableEntryFlags::ACCESSED);
-		flags_to_set.insert(PageTableEntryFlags::USER_ACCESSIBLE);
-		self.physical_address_and_flags = physical_address | flags_to_set.bits();
+		self.physical_address_and_flags = physical_address | flags_to_set.bits() | user_bit(entry_pkey(flags.bits()));
 	}
 }
 
@@ -395,21 +432,102 @@ struct PageTable<L> {
 	level: PhantomData<L>,
 }
 
+/// Memory that holds the page tables walked by PageTableMethods.
+///
+/// The kernel reaches its page tables through the recursive mapping in the last PML4 entry.
+/// The host tests provide fake physical memory instead.
+trait PageTableMemory {
+	/// Returns the address of the root page table (PML4).
+	fn root_table_address(&self) -> usize;
+
+	/// Returns the address of the subtable referenced by entry `index` of the table at `table_address`.
+	fn subtable_address(&self, table_address: usize, index: usize) -> usize;
+
+	/// Allocates a 4 KiB frame for a new page table and returns its physical address.
+	fn allocate_table(&self) -> usize;
+
+	/// Allocates a page table that already holds `entries` and returns its physical address.
+	/// Such a table can be linked into the hierarchy without ever being incomplete.
+	fn allocate_filled_table(&self, entries: &[usize; 1 << PAGE_MAP_BITS]) -> usize;
+
+	/// Flushes the page containing `virtual_address` from the TLB of this CPU.
+	fn flush_from_tlb(&self, virtual_address: usize);
+
+	/// Asks the other CPUs to flush their TLBs.
+	fn flush_remote_tlbs(&self);
+
+	/// Returns the physical address width of the CPU.
+	fn physical_address_bits(&self) -> u8;
+
+	/// Returns the root page table (PML4).
+	fn root_table(&self) -> &mut PageTable<PML4> {
+		unsafe { &mut *(self.root_table_address() as *mut PageTable<PML4>) }
+	}
+}
+
+/// The page tables of the running kernel, accessed through the recursive mapping.
+struct RecursiveMapping;
+
+impl PageTableMemory for RecursiveMapping {
+	fn root_table_address(&self) -> usize {
+		PML4_ADDRESS as usize
+	}
+
+	fn subtable_address(&self, table_address: usize, index: usize) -> usize {
+		(table_address << PAGE_MAP_BITS) | (index << PAGE_BITS)
+	}
+
+	fn allocate_table(&self) -> usize {
+		physicalmem::allocate(BasePageSize::SIZE).unwrap()
+	}
+
+	fn allocate_filled_table(&self, entries: &[usize; 1 << PAGE_MAP_BITS]) -> usize {
+		// The recursive mapping only reaches linked tables, so fill the new one through a temporary mapping.
+		let physical_address = self.allocate_table();
+		let virtual_address = virtualmem::allocate(BasePageSize::SIZE).unwrap();
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable().pkey(mm::SAFE_MEM_REGION);
+		map_in::<BasePageSize, _>(self, virtual_address, physical_address, 1, flags);
+
+		unsafe {
+			*(virtual_address as *mut [usize; 1 << PAGE_MAP_BITS]) = *entries;
+		}
+
+		unmap::<BasePageSize>(virtual_address, 1);
+		virtualmem::deallocate(virtual_address, BasePageSize::SIZE);
+		physical_address
+	}
+
+	fn flush_from_tlb(&self, virtual_address: usize) {
+		Page::<BasePageSize>::including_address(virtual_address).flush_from_tlb();
+	}
+
+	fn flush_remote_tlbs(&self) {
+		apic::ipi_tlb_flush();
+	}
+
+	fn physical_address_bits(&self) -> u8 {
+		processor::get_physical_address_bits()
+	}
+}
+
 /// A trait defining methods every page table has to implement.
 /// This additional trait is necessary to make use of Rust's specialization feature and provide a default
 /// implementation of some methods.
 trait PageTableMethods {
-	fn get_page_table_entry<S: PageSize>(&self, page: Page<S>) -> Option<PageTableEntry>;
-	fn set_page_table_entry<S: PageSize>(&mut self, page: Page<S>, entry: usize);
-	fn set_pkey_on_page_table_entry<S: PageSize>(&mut self, page: Page<S>, pkey: u8);
-	fn map_page_in_this_table<S: PageSize>(
+	fn get_page_table_entry<S: PageSize, M: PageTableMemory>(&self, mem: &M, page: Page<S>) -> Option<PageTableEntry>;
+	fn set_page_table_entry<S: PageSize, M: PageTableMemory>(&mut self, mem: &M, page: Page<S>, entry: usize);
+	fn set_pkey_on_page_table_entry<S: PageSize, M: PageTableMemory>(&mut self, mem: &M, page: Page<S>, pkey: u8);
+	fn map_page_in_this_table<S: PageSize, M: PageTableMemory>(
 		&mut self,
+		mem: &M,
 		page: Page<S>,
 		physical_address: usize,
 		flags: PageTableEntryFlags,
 	) -> bool;
-	fn map_page<S: PageSize>(
+	fn map_page<S: PageSize, M: PageTableMemory>(
 		&mut self,
+		mem: &M,
 		page: Page<S>,
 		physical_address: usize,
 		flags: PageTableEntryFlags,
@@ -421,8 +539,9 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 	/// Returns whether an existing entry was updated. You can use this return value to flush TLBs.
 	///
 	/// Must only be called if a page of this size is mapped at this page table level!
-	fn map_page_in_this_table<S: PageSize>(
+	fn map_page_in_this_table<S: PageSize, M: PageTableMemory>(
 		&mut self,
+		mem: &M,
 		page: Page<S>,
 		physical_address: usize,
 		flags: PageTableEntryFlags,
@@ -434,10 +553,11 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 		self.entries[index].set(
 			physical_address,
 			PageTableEntryFlags::DIRTY | S::MAP_EXTRA_FLAG | flags,
+			mem.physical_address_bits(),
 		);
 
 		if flush {
-			page.flush_from_tlb();
+			mem.flush_from_tlb(page.address());
 		}
 
 		flush
@@ -447,7 +567,7 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 	///
 	/// This is the default implementation called only for PT.
 	/// It is overridden by a specialized implementation for all tables with sub tables (all except PT).
-	default fn get_page_table_entry<S: PageSize>(&self, page: Page<S>) -> Option<PageTableEntry> {
+	default fn get_page_table_entry<S: PageSize, M: PageTableMemory>(&self, _mem: &M, page: Page<S>) -> Option<PageTableEntry> {
 		assert!(L::LEVEL == S::MAP_LEVEL);
 		let index = page.table_index::<L>();
     /*
@@ -463,7 +583,7 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 		}
 	}
 
-	default fn set_page_table_entry<S: PageSize>(&mut self, page: Page<S>, entry: usize) {
+	default fn set_page_table_entry<S: PageSize, M: PageTableMemory>(&mut self, mem: &M, page: Page<S>, entry: usize) {
 		assert!(L::LEVEL == S::MAP_LEVEL);
 		let index = page.table_index::<L>();
     /*
@@ -474,13 +594,13 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 	*/
 		if self.entries[index].is_present() {
 			self.entries[index].physical_address_and_flags = entry;
-			page.flush_from_tlb();
+			mem.flush_from_tlb(page.address());
 		} else {
 			panic!("Level {} entry is not present!!", L::LEVEL);
 		}
 	}
 
-	default fn set_pkey_on_page_table_entry<S: PageSize>(&mut self, page: Page<S>, pkey: u8) {
+	default fn set_pkey_on_page_table_entry<S: PageSize, M: PageTableMemory>(&mut self, mem: &M, page: Page<S>, pkey: u8) {
 		assert!(L::LEVEL == S::MAP_LEVEL);
 		let index = page.table_index::<L>();
     /*
@@ -491,8 +611,8 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 	*/
 		if self.entries[index].is_present() {
 			self.entries[index].physical_address_and_flags = 
-					self.entries[index].physical_address_and_flags & !(0xF << 59) | (pkey as usize)<< 59;
-			page.flush_from_tlb();
+					self.entries[index].physical_address_and_flags & !(0xF << 59 | PageTableEntryFlags::USER_ACCESSIBLE.bits()) | (pkey as usize)<< 59 | user_bit(pkey);
+			mem.flush_from_tlb(page.address());
 		} else {
 			panic!("Level {} entry is not present!!", L::LEVEL);
 		}
@@ -503,13 +623,14 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 	///
 	/// This is the default implementation that just calls the map_page_in_this_table method.
 	/// It is overridden by a specialized implementation for all tables with sub tables (all except PT).
-	default fn map_page<S: PageSize>(
+	default fn map_page<S: PageSize, M: PageTableMemory>(
 		&mut self,
+		mem: &M,
 		page: Page<S>,
 		physical_address: usize,
 		flags: PageTableEntryFlags,
 	) -> bool {
-		self.map_page_in_this_table::<S>(page, physical_address, flags)
+		self.map_page_in_this_table::<S, M>(mem, page, physical_address, flags)
 	}
 }
 
@@ -521,15 +642,15 @@ where
 	///
 	/// This is the implementation for all tables with subtables (PML4, PDPT, PDT).
 	/// It overrides the default implementation above.
-	fn get_page_table_entry<S: PageSize>(&self, page: Page<S>) -> Option<PageTableEntry> {
+	fn get_page_table_entry<S: PageSize, M: PageTableMemory>(&self, mem: &M, page: Page<S>) -> Option<PageTableEntry> {
 		assert!(L::LEVEL >= S::MAP_LEVEL);
 		let index = page.table_index::<L>();
 		//error!("MAP_LEVEL: {}, table LEVEL: {}, index: {:#X}, entry: {:#X}, addr: {:#X}, is_user: {}, is_present: {}", S::MAP_LEVEL, L::LEVEL, index, self.entries[index].physical_address_and_flags, self.entries[index].address(), self.entries[index].is_user(), self.entries[index].is_present());
 
 		if self.entries[index].is_present() {
 			if L::LEVEL > S::MAP_LEVEL {
-				let subtable = self.subtable::<S>(page);
-				subtable.get_page_table_entry::<S>(page)
+				let subtable = self.subtable::<S, M>(mem, page);
+				subtable.get_page_table_entry::<S, M>(mem, page)
 			} else {
 				Some(self.entries[index])
 			}
@@ -538,37 +659,37 @@ where
 		}
 	}
 
-	fn set_page_table_entry<S: PageSize>(&mut self, page: Page<S>, entry: usize) {
+	fn set_page_table_entry<S: PageSize, M: PageTableMemory>(&mut self, mem: &M, page: Page<S>, entry: usize) {
 		assert!(L::LEVEL >= S::MAP_LEVEL);
 		let index = page.table_index::<L>();
 		//error!("MAP_LEVEL: {}, table LEVEL: {}, index: {:#X}, entry: {:#X}, addr: {:#X}, is_user: {}, is_present: {}", S::MAP_LEVEL, L::LEVEL, index, self.entries[index].physical_address_and_flags, self.entries[index].address(), self.entries[index].is_user(), self.entries[index].is_present());
 
 		if self.entries[index].is_present() {
 			if L::LEVEL > S::MAP_LEVEL {
-				let subtable = self.subtable::<S>(page);
-				subtable.set_page_table_entry::<S>(page, entry);
+				let subtable = self.subtable::<S, M>(mem, page);
+				subtable.set_page_table_entry::<S, M>(mem, page, entry);
 			} else {
 				self.entries[index].physical_address_and_flags = entry;
-				page.flush_from_tlb();
+				mem.flush_from_tlb(page.address());
 			}
 		} else {
 			panic!("Level {} entry is not present!!", L::LEVEL);
 		}
 	}
 
-	fn set_pkey_on_page_table_entry<S: PageSize>(&mut self, page: Page<S>, pkey: u8) {
+	fn set_pkey_on_page_table_entry<S: PageSize, M: PageTableMemory>(&mut self, mem: &M, page: Page<S>, pkey: u8) {
 		assert!(L::LEVEL >= S::MAP_LEVEL);
 		let index = page.table_index::<L>();
 		//error!("MAP_LEVEL: {}, table LEVEL: {}, index: {:#X}, entry: {:#X}, addr: {:#X}, is_user: {}, is_present: {}", S::MAP_LEVEL, L::LEVEL, index, self.entries[index].physical_address_and_flags, self.entries[index].address(), self.entries[index].is_user(), self.entries[index].is_present());
 
 		if self.entries[index].is_present() {
 			if L::LEVEL > S::MAP_LEVEL {
-				let subtable = self.subtable::<S>(page);
-				subtable.set_pkey_on_page_table_entry::<S>(page, pkey);
+				let subtable = self.subtable::<S, M>(mem, page);
+				subtable.set_pkey_on_page_table_entry::<S, M>(mem, page, pkey);
 			} else {
 				self.entries[index].physical_address_and_flags = 
-						self.entries[index].physical_address_and_flags & !(0xF << 59) | (pkey as usize)<< 59;
-				page.flush_from_tlb();
+						self.entries[index].physical_address_and_flags & !(0xF << 59 | PageTableEntryFlags::USER_ACCESSIBLE.bits()) | (pkey as usize)<< 59 | user_bit(pkey);
+				mem.flush_from_tlb(page.address());
 			}
 		} else {
 			panic!("Level {} entry is not present!!", L::LEVEL);
@@ -580,8 +701,9 @@ where
 	///
 	/// This is the implementation for all tables with subtables (PML4, PDPT, PDT).
 	/// It overrides the default implementation above.
-	fn map_page<S: PageSize>(
+	fn map_page<S: PageSize, M: PageTableMemory>(
 		&mut self,
+		mem: &M,
 		page: Page<S>,
 		physical_address: usize,
 		flags: PageTableEntryFlags,
@@ -594,22 +716,26 @@ where
 			// Does the table exist yet?
 			if !self.entries[index].is_present() {
 				// Allocate a single 4 KiB page for the new entry and mark it as a valid, writable subtable.
-				let physical_address = physicalmem::allocate(BasePageSize::SIZE).unwrap();
-			    self.entries[index].set(physical_address, PageTableEntryFlags::WRITABLE);
+				// The key protects the subtable in the recursive mapping (see protect_page_tables_in).
+				let physical_address = mem.allocate_table();
+				// Subtables are user-accessible, only the leaf entries decide about user pages.
+				let mut table_flags = PageTableEntryFlags::WRITABLE | PageTableEntryFlags::USER_ACCESSIBLE;
+				table_flags.pkey(mm::SAFE_MEM_REGION);
+			    self.entries[index].set(physical_address, table_flags, mem.physical_address_bits());
 
 				// Mark all entries as unused in the newly created table.
-				let subtable = self.subtable::<S>(page);
+				let subtable = self.subtable::<S, M>(mem, page);
 				for entry in subtable.entries.iter_mut() {
 					entry.physical_address_and_flags = 0;
 				}
 			}
 
-			let subtable = self.subtable::<S>(page);
-			subtable.map_page::<S>(page, physical_address, flags)
+			let subtable = self.subtable::<S, M>(mem, page);
+			subtable.map_page::<S, M>(mem, page, physical_address, flags)
 		} else {
 			// Calling the default implementation from a specialized one is not supported (yet),
 			// so we have to resort to an extra function.
-			self.map_page_in_this_table::<S>(page, physical_address, flags)
+			self.map_page_in_this_table::<S, M>(mem, page, physical_address, flags)
 		}
 	}
 }
@@ -621,13 +747,13 @@ where
 	/// Returns the next subtable for the given page in the page table hierarchy.
 	///
 	/// Must only be called if a page of this size is mapped in a subtable!
-	fn subtable<S: PageSize>(&self, page: Page<S>) -> &mut PageTable<L::SubtableLevel> {
+	fn subtable<S: PageSize, M: PageTableMemory>(&self, mem: &M, page: Page<S>) -> &mut PageTable<L::SubtableLevel> {
 		assert!(L::LEVEL > S::MAP_LEVEL);
 
 		// Calculate the address of the subtable.
 		let index = page.table_index::<L>();
 		let table_address = self as *const PageTable<L> as usize;
-		let subtable_address = (table_address << PAGE_MAP_BITS) | (index << PAGE_BITS);
+		let subtable_address = mem.subtable_address(table_address, index);
 		unsafe { &mut *(subtable_address as *mut PageTable<L::SubtableLevel>) }
 	}
 
@@ -635,12 +761,14 @@ where
 	///
 	/// # Arguments
 	///
+	/// * `mem` - The memory holding the page tables
 	/// * `range` - The range of pages of size S
 	/// * `physical_address` - First physical address to map these pages to
 	/// * `flags` - Flags from PageTableEntryFlags to set for the page table entry (e.g. WRITABLE or EXECUTE_DISABLE).
 	///             The PRESENT, ACCESSED, and DIRTY flags are already set automatically.
-	fn map_pages<S: PageSize>(
+	fn map_pages<S: PageSize, M: PageTableMemory>(
 		&mut self,
+		mem: &M,
 		range: PageIter<S>,
 		physical_address: usize,
 		flags: PageTableEntryFlags,
@@ -649,12 +777,12 @@ where
 		let mut send_ipi = false;
 
 		for page in range {
-			send_ipi |= self.map_page::<S>(page, current_physical_address, flags);
+			send_ipi |= self.map_page::<S, M>(mem, page, current_physical_address, flags);
 			current_physical_address += S::SIZE;
 		}
 
 		if send_ipi {
-			apic::ipi_tlb_flush();
+			mem.flush_remote_tlbs();
 		}
 	}
 }
@@ -670,8 +798,8 @@ pub fn set_pkey<S: PageSize>(virtual_address: usize, count :usize, key: u8) -> i
     let pkey_flag: PageTableEntryFlags = PageTableEntryFlags { bits: (pkey << 59) | get_existing_flags::<S>(virtual_address) };
     /* Set pkey bits */
     flags.insert(pkey_flag);
-    /* Set user bit */
-    flags.insert(PageTableEntryFlags::USER_ACCESSIBLE);
+    /* The user bit follows the new key (see user_bit) */
+    flags.remove(PageTableEntryFlags::USER_ACCESSIBLE);
     /* Set flags on the PTE */
     map::<S>(virtual_address, get_physical_address::<S>(virtual_address), count, flags);
 
@@ -704,19 +832,35 @@ pub extern "x86-interrupt" fn page_fault_handler(
 	stack_frame: &mut irq::ExceptionStackFrame,
 	error_code: u64,
 ) {
-	unsafe {
-        asm!("xor %eax, %eax;
-              xor %ecx, %ecx;
-              xor %edx, %edx;
-              wrpkru;
-              lfence"
-             :
-             :
-             : "eax", "ecx", "edx"
-             : "volatile");
-        }
-
 	let virtual_address = unsafe { controlregs::cr2() };
+	// Must be checked before the access rights of the kernel are restored.
+	let hidden_by_domain = is_hidden_by_domain(virtual_address);
+
+	#[cfg(not(feature = "no-mpk"))]
+	isolation::write_permissions(0);
+
+	// Did the current task run into the guard page of one of its stacks?
+	if let Ok(task) = core_scheduler().current_task.try_borrow() {
+		if let Some(name) = task.stacks.guard_page_hit(virtual_address) {
+			error!("Page Fault (#PF) Exception: {:#?}", stack_frame);
+			error!(
+				"stack overflow in task {} ({}), virtual_address = {:#X}",
+				task.id, name, virtual_address
+			);
+			drop(task);
+
+			unsafe {controlregs::cr2_write(0);}
+			scheduler::abort();
+			return;
+		}
+	}
+
+	// Faults provoked by the isolation self-test are expected.
+	if selftest::record_fault(virtual_address) {
+		unsafe {controlregs::cr2_write(0);}
+		scheduler::abort();
+		return;
+	}
 
 	// Anything else is an error!
 	let pferror = PageFaultError::from_bits_truncate(error_code as u32);
@@ -724,6 +868,9 @@ pub extern "x86-interrupt" fn page_fault_handler(
     if pferror.bits() & 0b100000 != 0 {
         error!("virtual_address = {:#X}, page fault error = There was a protection key violation.", virtual_address);
         error!("{}", pferror);
+    } else if hidden_by_domain {
+        error!("virtual_address = {:#X}, page fault error = The page belongs to a disabled protection domain.", virtual_address);
+        error!("{}", pferror);
     } else {
         error!(
 		    "virtual_address = {:#X}, page fault error = {}",
@@ -739,6 +886,12 @@ pub extern "x86-interrupt" fn page_fault_handler(
 	// clear cr2 to signalize that the pagefault is solved by the pagefault handler
 	unsafe {controlregs::cr2_write(0);}
 
+	let domain_violation = pferror.bits() & 0b100000 != 0 || hidden_by_domain;
+	if domain_violation && environment::isolation_config().pkey_fault == PkeyFault::Abort {
+		error!("Protection domain violation, shutting down (-pkfault=abort)");
+		processor::shutdown();
+	}
+
 	scheduler::abort();
 }
 
@@ -753,34 +906,552 @@ pub fn get_page_table_entry<S: PageSize>(virtual_address: usize) -> Option<PageT
 	trace!("Looking up Page Table Entry for {:#X}", virtual_address);
 
 	let page = Page::<S>::including_address(virtual_address);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-	root_pagetable.get_page_table_entry(page)
+	let mem = RecursiveMapping;
+	mem.root_table().get_page_table_entry(&mem, page)
 }
 
 pub fn set_page_table_entry<S: PageSize>(virtual_address: usize, entry: usize) {
 	trace!("Looking up Page Table Entry for {:#X}", virtual_address);
 
 	let page = Page::<S>::including_address(virtual_address);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-	root_pagetable.set_page_table_entry(page, entry);
+	let mem = RecursiveMapping;
+	mem.root_table().set_page_table_entry(&mem, page, entry);
 }
 
 pub fn set_pkey_on_page_table_entry<S: PageSize>(virtual_address: usize, count: usize, pkey: u8) {
+	set_pkey_on_page_table_entry_in::<S, _>(&RecursiveMapping, virtual_address, count, pkey);
+}
+
+fn set_pkey_on_page_table_entry_in<S: PageSize, M: PageTableMemory>(mem: &M, virtual_address: usize, count: usize, pkey: u8) {
+	if cfg!(feature = "no-mpk") {
+		return;
+	}
+
+	// Only seal_pages hands out the sealed key and nobody takes it away again.
+	assert!(pkey != mm::SEALED_MEM_REGION, "Use mm::seal to seal memory");
+	// The same holds for protect_fnptr_pages and the key of the function-pointer tables.
+	assert!(pkey != mm::FNPTR_MEM_REGION, "Use fnptr_global_var! to protect function pointers");
+
 	trace!("Looking up Page Table Entry for {:#X}", virtual_address);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+	let root_pagetable = mem.root_table();
 	for i in 0..count {
 		let page = Page::<S>::including_address(virtual_address + S::SIZE*i);
-		root_pagetable.set_pkey_on_page_table_entry(page, pkey);
+		if is_sealed_in(mem, page.address()) {
+			error!("Refusing to change the key of the sealed page at {:#X}", page.address());
+			continue;
+		}
+		if get_raw_leaf_entry(mem, page.address()).map(|(entry, _)| entry_pkey(entry)) == Some(mm::FNPTR_MEM_REGION) {
+			error!("Refusing to change the key of the function-pointer table at {:#X}", page.address());
+			continue;
+		}
+		root_pagetable.set_pkey_on_page_table_entry(mem, page, pkey);
+	}
+}
+
+/// Applies the access rights `permissions` (in PKRU format) to the page tables in a single walk
+/// (used by the page-table isolation backend). Pages are hidden by clearing PRESENT and
+/// write-protected by clearing WRITABLE. Both are recorded in software-available bits, so that
+/// they can be restored afterwards. The page containing `keep_address` is left untouched.
+pub fn set_pkey_permissions(permissions: u32, keep_address: usize) {
+	let tables = |address: usize| unsafe { &mut *(address as *mut [usize; 1 << PAGE_MAP_BITS]) };
+	let present = PageTableEntryFlags::PRESENT.bits();
+	let huge = PageTableEntryFlags::HUGE_PAGE.bits();
+
+	let pml4 = tables(PML4_ADDRESS as usize);
+	// The last entry is the recursive mapping, which must always stay accessible.
+	for i in 0..(1 << PAGE_MAP_BITS) - 1 {
+		if pml4[i] & present == 0 {
+			continue;
+		}
+
+		let pdpt = tables(PDPT_ADDRESS + (i << PAGE_BITS));
+		for j in 0..1 << PAGE_MAP_BITS {
+			let address = canonical_address((i << 39) | (j << 30));
+			if pdpt[j] & huge != 0 {
+				apply_pkey_permissions::<HugePageSize>(&mut pdpt[j], address, permissions, keep_address);
+				continue;
+			} else if pdpt[j] & present == 0 {
+				continue;
+			}
+
+			let pd = tables(PD_ADDRESS + (i << 21) + (j << PAGE_BITS));
+			for k in 0..1 << PAGE_MAP_BITS {
+				let address = address | (k << 21);
+				if pd[k] & huge != 0 {
+					apply_pkey_permissions::<LargePageSize>(&mut pd[k], address, permissions, keep_address);
+					continue;
+				} else if pd[k] & present == 0 {
+					continue;
+				}
+
+				let pt = tables(PT_ADDRESS + (i << 30) + (j << 21) + (k << PAGE_BITS));
+				for l in 0..1 << PAGE_MAP_BITS {
+					if pt[l] != 0 {
+						apply_pkey_permissions::<BasePageSize>(&mut pt[l], address | (l << PAGE_BITS), permissions, keep_address);
+					}
+				}
+			}
+		}
+	}
+}
+
+fn apply_pkey_permissions<S: PageSize>(entry: &mut usize, virtual_address: usize, permissions: u32, keep_address: usize) {
+	let hidden = PageTableEntryFlags::DOMAIN_HIDDEN.bits();
+	let read_only = PageTableEntryFlags::DOMAIN_READ_ONLY.bits();
+	let present = PageTableEntryFlags::PRESENT.bits();
+	let writable = PageTableEntryFlags::WRITABLE.bits();
+
+	// Key 0 also holds the kernel code. PKU never restricts instruction fetches,
+	// so we cannot hide these pages without breaking the equivalence.
+	let pkey = entry_pkey(*entry);
+	if pkey == 0 {
+		return;
+	}
+	if keep_address >= virtual_address && keep_address < virtual_address + S::SIZE {
+		return;
+	}
+	let rights = (permissions >> (2 * pkey as u32)) & 0b11;
+	let access_disable = rights & 0b01 != 0;
+	let write_disable = rights & 0b10 != 0;
+
+	let mut new_entry = *entry;
+	if new_entry & hidden != 0 {
+		new_entry = (new_entry | present) & !hidden;
+	}
+	if new_entry & read_only != 0 {
+		new_entry = (new_entry | writable) & !read_only;
 	}
+
+	if access_disable {
+		if new_entry & present != 0 {
+			new_entry = (new_entry & !present) | hidden;
+		}
+	} else if write_disable {
+		if new_entry & writable != 0 {
+			new_entry = (new_entry & !writable) | read_only;
+		}
+	}
+
+	if new_entry != *entry {
+		*entry = new_entry;
+		Page::<S>::including_address(virtual_address).flush_from_tlb();
+	}
+}
+
+/// Returns the number of page tables that do not carry the safe key and tags them with it if `retag` is set.
+///
+/// In the recursive mapping, the leaf entry of a page table is the entry that references it
+/// in its parent table (the PML4 is referenced by its own last entry). The CPU ignores the key
+/// of these entries when they are used to translate other addresses, so tagging them only
+/// restricts accesses to the page tables themselves.
+fn protect_page_tables_in<M: PageTableMemory>(mem: &M, retag: bool) -> usize {
+	fn protect_table<M: PageTableMemory>(mem: &M, table_address: usize, level: usize, retag: bool) -> usize {
+		let table = unsafe { &mut *(table_address as *mut [usize; 1 << PAGE_MAP_BITS]) };
+		let safe_key = mm::SAFE_MEM_REGION as usize;
+		let mut count = 0;
+
+		for index in 0..1 << PAGE_MAP_BITS {
+			let entry = table[index];
+			if entry & PageTableEntryFlags::PRESENT.bits() == 0 || entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0 {
+				continue;
+			}
+
+			if (entry >> 59) & 0xF != safe_key {
+				count += 1;
+				if retag {
+					table[index] = entry & !(0xF << 59) | safe_key << 59;
+				}
+			}
+
+			// The subtables of a PD are PTs, which only reference pages.
+			// The recursive entry references the table itself.
+			let subtable_address = mem.subtable_address(table_address, index);
+			if level > 1 && subtable_address != table_address {
+				count += protect_table(mem, subtable_address, level - 1, retag);
+			}
+		}
+
+		count
+	}
+
+	if cfg!(feature = "no-mpk") {
+		return 0;
+	}
+
+	protect_table(mem, mem.root_table_address(), PML4::LEVEL, retag)
+}
+
+/// Returns whether all pages in the range of `size` bytes at `virtual_address` carry the key `pkey`.
+pub fn has_pkey(virtual_address: usize, size: usize, pkey: u8) -> bool {
+	let first_page = align_down!(virtual_address, BasePageSize::SIZE);
+	let last_page = align_down!(virtual_address + size - 1, BasePageSize::SIZE);
+
+	(first_page..=last_page)
+		.step_by(BasePageSize::SIZE)
+		.all(|address| get_pkey(address) == Some(pkey))
+}
+
+/// Returns the number of page tables that are accessible outside of the kernel domain.
+pub fn unprotected_page_tables() -> usize {
+	protect_page_tables_in(&RecursiveMapping, false)
+}
+
+/// Sign-extends bit 47 of `address` as required for a valid x86-64 address.
+fn canonical_address(address: usize) -> usize {
+	if address & (1 << 47) != 0 {
+		address | 0xFFFF_0000_0000_0000
+	} else {
+		address
+	}
+}
+
+/// A range of pages of the same size that are mapped contiguously with the same attributes.
+#[derive(Clone, Copy)]
+struct MappedRange {
+	virtual_address: usize,
+	physical_address: usize,
+	page_size: usize,
+	count: usize,
+	/// Raw leaf entry of the first page
+	entry: usize,
+}
+
+impl MappedRange {
+	/// Flags and key, without the physical address and the bits maintained by the CPU
+	fn attributes(entry: usize) -> usize {
+		entry & !PHYSICAL_ADDRESS_MASK & !(PageTableEntryFlags::ACCESSED | PageTableEntryFlags::DIRTY).bits()
+	}
+
+	fn size(&self) -> usize {
+		self.page_size * self.count
+	}
+
+	fn pkey(&self) -> u8 {
+		((self.entry >> 59) & 0xF) as u8
+	}
+
+	fn has_flag(&self, flag: PageTableEntryFlags) -> bool {
+		self.entry & flag.bits() != 0
+	}
+
+	/// Returns whether the next page continues this range.
+	fn is_continued_by(&self, virtual_address: usize, page_size: usize, entry: usize) -> bool {
+		self.page_size == page_size
+			&& self.virtual_address + self.size() == virtual_address
+			&& self.physical_address + self.size() == entry & PHYSICAL_ADDRESS_MASK & !(page_size - 1)
+			&& Self::attributes(self.entry) == Self::attributes(entry)
+	}
+}
+
+/// Calls `f` for all mapped ranges in ascending order, except for the recursive mapping of the page tables.
+/// Pages hidden by the page-table isolation backend are included.
+fn walk_mapped_ranges<M: PageTableMemory, F: FnMut(&MappedRange)>(mem: &M, mut f: F) {
+	fn walk_table<M: PageTableMemory, F: FnMut(usize, usize, usize)>(mem: &M, table_address: usize, level: usize, base: usize, f: &mut F) {
+		let table = unsafe { &*(table_address as *const [usize; 1 << PAGE_MAP_BITS]) };
+		let page_bits = PAGE_BITS + level * PAGE_MAP_BITS;
+
+		for index in 0..1 << PAGE_MAP_BITS {
+			let entry = table[index];
+			let virtual_address = canonical_address(base | (index << page_bits));
+
+			if level == 0 || (level < 3 && entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0) {
+				if entry & (PageTableEntryFlags::PRESENT | PageTableEntryFlags::DOMAIN_HIDDEN).bits() != 0 {
+					f(virtual_address, 1 << page_bits, entry);
+				}
+			} else if entry & PageTableEntryFlags::PRESENT.bits() != 0 {
+				let subtable_address = mem.subtable_address(table_address, index);
+				if subtable_address != table_address {
+					walk_table(mem, subtable_address, level - 1, virtual_address, f);
+				}
+			}
+		}
+	}
+
+	let mut current: Option<MappedRange> = None;
+	walk_table(mem, mem.root_table_address(), PML4::LEVEL, 0, &mut |virtual_address, page_size, entry| {
+		if let Some(ref mut range) = current {
+			if range.is_continued_by(virtual_address, page_size, entry) {
+				range.count += 1;
+				return;
+			}
+
+			f(range);
+		}
+
+		current = Some(MappedRange {
+			virtual_address: virtual_address,
+			physical_address: entry & PHYSICAL_ADDRESS_MASK & !(page_size - 1),
+			page_size: page_size,
+			count: 1,
+			entry: entry,
+		});
+	});
+
+	if let Some(ref range) = current {
+		f(range);
+	}
+}
+
+/// Returns whether the unsafe domain may read pages with the key `pkey`.
+fn is_reachable_from_unsafe_domain(pkey: u8) -> bool {
+	((mm::UNSAFE_PERMISSION_IN | mm::ENFORCED_PERMISSION) >> (2 * pkey as u32)) & 1 == 0
+}
+
+/// Returns whether the identity mapping of `start` to `end` is intended.
+fn is_expected_identity_mapping(start: usize, end: usize) -> bool {
+	let contains = |range: (usize, usize)| start >= range.0 && end <= range.1;
+	let recorded = unsafe { &IDENTITY_MAPPINGS[..IDENTITY_MAPPING_COUNT] };
+
+	contains((mm::kernel_start_address(), mm::kernel_end_address()))
+		|| contains((mm::SAFE_DATA_START, mm::SAFE_DATA_START + mm::DATA_SECTION_SIZE))
+		|| contains((mm::UNSAFE_DATA_START, mm::UNSAFE_DATA_START + mm::DATA_SECTION_SIZE))
+		|| recorded.iter().any(|range| contains(*range))
+}
+
+/// Checks a mapped range against the isolation invariants and returns the number of violations.
+fn audit_range(range: &MappedRange) -> usize {
+	let start = range.virtual_address;
+	let end = start + range.size();
+	let mut violations = 0;
+
+	// JIT regions are write-protected by their key instead (see mm::jit).
+	if range.has_flag(PageTableEntryFlags::WRITABLE)
+		&& !range.has_flag(PageTableEntryFlags::EXECUTE_DISABLE)
+		&& range.pkey() != mm::JIT_MEM_REGION
+	{
+		warn!("{:#X} - {:#X} is writable and executable", start, end);
+		violations += 1;
+	}
+
+	let safe_data_end = mm::SAFE_DATA_START + mm::DATA_SECTION_SIZE;
+	if !cfg!(feature = "no-mpk")
+		&& start < safe_data_end
+		&& end > mm::SAFE_DATA_START
+		&& is_reachable_from_unsafe_domain(range.pkey())
+		// The sealed boot data is meant to be readable everywhere (see sealed_global_var!).
+		&& !(range.pkey() == mm::SEALED_MEM_REGION && !range.has_flag(PageTableEntryFlags::WRITABLE))
+	{
+		warn!(
+			"Safe data at {:#X} - {:#X} carries key {}, which the unsafe domain can access",
+			start, end, range.pkey()
+		);
+		violations += 1;
+	}
+
+	if start == range.physical_address && !is_expected_identity_mapping(start, end) {
+		warn!("{:#X} - {:#X} is identity-mapped unexpectedly", start, end);
+		violations += 1;
+	}
+
+	violations
+}
+
+/// Prints every mapping of the address space and checks the isolation invariants:
+/// no writable and executable mappings apart from JIT regions, page tables and safe data
+/// out of reach of the unsafe domain, and no identity mappings apart from the intended ones.
+/// Returns the number of violations.
+pub fn audit_address_space() -> usize {
+	let mem = RecursiveMapping;
+	let mut ranges = 0;
+	let mut violations = 0;
+
+	info!("Address space (virtual range -> physical address, pages, rights, pkey):");
+	walk_mapped_ranges(&mem, |range| {
+		ranges += 1;
+		violations += audit_range(range);
+
+		// Secrets are checked, but left out of the dump.
+		if range.pkey() == mm::SECRET_MEM_REGION {
+			return;
+		}
+
+		info!(
+			"{:#018X} - {:#018X} -> {:#018X} {:>5} x {:<4} {}{}{}{} {:>4}{}",
+			range.virtual_address,
+			range.virtual_address + range.size() - 1,
+			range.physical_address,
+			range.count,
+			if range.page_size == BasePageSize::SIZE {
+				"4K"
+			} else if range.page_size == LargePageSize::SIZE {
+				"2M"
+			} else {
+				"1G"
+			},
+			"R",
+			if range.has_flag(PageTableEntryFlags::WRITABLE) { "W" } else { "-" },
+			if range.has_flag(PageTableEntryFlags::EXECUTE_DISABLE) { "-" } else { "X" },
+			if range.has_flag(PageTableEntryFlags::USER_ACCESSIBLE) { "U" } else { "S" },
+			range.pkey(),
+			if range.has_flag(PageTableEntryFlags::DOMAIN_HIDDEN) { " (hidden)" } else { "" }
+		);
+	});
+
+	let unprotected = protect_page_tables_in(&mem, false);
+	if unprotected > 0 {
+		warn!("{} page tables do not carry the safe key", unprotected);
+		violations += unprotected;
+	}
+
+	info!("Page-table audit: {} ranges, {} violations", ranges, violations);
+	violations
+}
+
+/// Returns the raw leaf entry mapping `virtual_address` together with the number of offset bits
+/// of its page, or None if there is none.
+/// get_page_table_entry only returns present entries, but pages hidden by the
+/// page-table isolation backend are not present, so we have to look at the raw entries.
+fn get_raw_leaf_entry<M: PageTableMemory>(mem: &M, virtual_address: usize) -> Option<(usize, usize)> {
+	find_raw_leaf_entry(mem, virtual_address).map(|(entry, page_bits)| (unsafe { *entry }, page_bits))
+}
+
+/// Like get_raw_leaf_entry, but returns a pointer to the entry, so that it can be modified.
+fn find_raw_leaf_entry<M: PageTableMemory>(mem: &M, virtual_address: usize) -> Option<(*mut usize, usize)> {
+	let present = PageTableEntryFlags::PRESENT.bits();
+	let huge = PageTableEntryFlags::HUGE_PAGE.bits();
+	let mut table_address = mem.root_table_address();
+
+	for level in (0..4).rev() {
+		let page_bits = PAGE_BITS + level * PAGE_MAP_BITS;
+		let index = (virtual_address >> page_bits) & PAGE_MAP_MASK;
+		let entry = unsafe { (table_address as *mut usize).add(index) };
+		let value = unsafe { *entry };
+
+		if level == 0 {
+			return if value == 0 { None } else { Some((entry, page_bits)) };
+		} else if level < 3 && value & huge != 0 {
+			return Some((entry, page_bits));
+		} else if value & present == 0 {
+			return None;
+		}
+
+		table_address = mem.subtable_address(table_address, index);
+	}
+
+	None
+}
+
+/// Returns whether the page-table isolation backend has hidden the page containing `virtual_address`.
+pub fn is_hidden_by_domain(virtual_address: usize) -> bool {
+	match get_raw_leaf_entry(&RecursiveMapping, virtual_address) {
+		Some((entry, _)) => entry & PageTableEntryFlags::DOMAIN_HIDDEN.bits() != 0,
+		None => false,
+	}
+}
+
+/// Returns the protection key of the page containing `virtual_address` or None if it is not mapped.
+pub fn get_pkey(virtual_address: usize) -> Option<u8> {
+	get_raw_leaf_entry(&RecursiveMapping, virtual_address).map(|(entry, _)| entry_pkey(entry))
+}
+
+/// Returns whether all pages of `size` bytes at `virtual_address` are user pages in the lower half
+/// of the address space, which `permissions` (in PKRU format) allow to read, and to write if `write` is set.
+/// Rights taken by the page-table isolation backend do not count.
+pub fn is_user_range(virtual_address: usize, size: usize, write: bool, permissions: u32) -> bool {
+	let end = match virtual_address.checked_add(size) {
+		Some(end) if end <= 1 << 47 => end,
+		_ => return false,
+	};
+
+	let mut address = virtual_address;
+	while address < end {
+		let (entry, page_bits) = match get_raw_leaf_entry(&RecursiveMapping, address) {
+			Some(leaf) => leaf,
+			None => return false,
+		};
+
+		let present = PageTableEntryFlags::PRESENT.bits() | PageTableEntryFlags::DOMAIN_HIDDEN.bits();
+		let writable = PageTableEntryFlags::WRITABLE.bits() | PageTableEntryFlags::DOMAIN_READ_ONLY.bits();
+		if entry & present == 0 || entry & PageTableEntryFlags::USER_ACCESSIBLE.bits() == 0 {
+			return false;
+		}
+		if write && entry & writable == 0 {
+			return false;
+		}
+
+		let rights = (permissions >> (2 * entry_pkey(entry) as u32)) & 0b11;
+		if rights & 0b01 != 0 || (write && rights & 0b10 != 0) {
+			return false;
+		}
+
+		address = align_down!(address, 1 << page_bits) + (1 << page_bits);
+	}
+
+	true
+}
+
+/// Returns the protection key of a raw page table entry.
+fn entry_pkey(entry: usize) -> u8 {
+	((entry >> 59) & 0xF) as u8
+}
+
+/// Returns the user bit of a page with the key `pkey`.
+/// In ring 3, the kernel domains, the function-pointer tables and the shadow stacks are supervisor
+/// pages (see syscall.rs). Secrets and JIT regions stay user pages, because the application
+/// accesses them inside their gates.
+fn user_bit(pkey: u8) -> usize {
+	let supervisor = match pkey {
+		mm::SAFE_MEM_REGION | mm::UNSAFE_MEM_REGION | mm::FNPTR_MEM_REGION | mm::SHADOW_MEM_REGION => true,
+		_ => false,
+	};
+
+	if syscall::is_enabled() && supervisor {
+		0
+	} else {
+		PageTableEntryFlags::USER_ACCESSIBLE.bits()
+	}
+}
+
+/// Takes the user bit from all pages of the kernel domains and from the page tables.
+/// Called once the application is known to run in ring 3 (see syscall::init).
+pub fn restrict_user_access() {
+	fn restrict_table(table_address: usize, level: usize) -> usize {
+		let table = unsafe { &mut *(table_address as *mut [usize; 1 << PAGE_MAP_BITS]) };
+		let mut count = 0;
+
+		for index in 0..1 << PAGE_MAP_BITS {
+			let entry = table[index];
+			if entry & PageTableEntryFlags::PRESENT.bits() == 0 {
+				continue;
+			}
+
+			if level == 0 || entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0 {
+				if user_bit(entry_pkey(entry)) == 0 && entry & PageTableEntryFlags::USER_ACCESSIBLE.bits() != 0 {
+					table[index] = entry & !PageTableEntryFlags::USER_ACCESSIBLE.bits();
+					count += 1;
+				}
+			} else {
+				let subtable_address = RecursiveMapping.subtable_address(table_address, index);
+				if subtable_address != table_address {
+					count += restrict_table(subtable_address, level - 1);
+				}
+			}
+		}
+
+		count
+	}
+
+	let root_address = RecursiveMapping.root_table_address();
+	let count = restrict_table(root_address, PML4::LEVEL);
+
+	// Without the recursive entry, ring 3 cannot reach any page table.
+	let root = unsafe { &mut *(root_address as *mut [usize; 1 << PAGE_MAP_BITS]) };
+	root[(1 << PAGE_MAP_BITS) - 1] &= !PageTableEntryFlags::USER_ACCESSIBLE.bits();
+
+	RecursiveMapping.flush_remote_tlbs();
+	unsafe {
+		controlregs::cr3_write(controlregs::cr3());
+	}
+	info!("Turned {} pages of the kernel domains into supervisor pages", count);
 }
 
 pub fn get_physical_address<S: PageSize>(virtual_address: usize) -> usize {
 	trace!("Getting physical address forlet new_entry =  {:#X}", virtual_address);
 
 	let page = Page::<S>::including_address(virtual_address);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-    let address = root_pagetable
-		.get_page_table_entry(page)
+	let mem = RecursiveMapping;
+    let address = mem.root_table()
+		.get_page_table_entry(&mem, page)
 		.expect("Entry not present")
 		.address();
 	let offset = virtual_address & (S::SIZE - 1);
@@ -789,36 +1460,19 @@ pub fn get_physical_address<S: PageSize>(virtual_address: usize) -> usize {
 
 /// Translate a virtual memory address to a physical one.
 pub fn virtual_to_physical(virtual_address: usize) -> usize {
-	let mut page_bits: usize = 39;
-
-	// A self-reference enables direct access to all page tables
-	safe_global_var!(static SELF: [usize; 4] = {
-		[
-			0xFFFFFF8000000000usize,
-			0xFFFFFFFFC0000000usize,
-			0xFFFFFFFFFFE00000usize,
-			0xFFFFFFFFFFFFF000usize,
-		]
-	});
-
-	for i in (0..3).rev() {
-		page_bits = page_bits - PAGE_MAP_BITS;
-
-		let vpn = (virtual_address >> page_bits) as isize;
-		let ptr = SELF[i] as *const usize;
-		let entry = unsafe { *ptr.offset(vpn) };
+	translate(&RecursiveMapping, virtual_address)
+}
 
-		if entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0 || i == 0 {
-			let off = virtual_address
-				& !(((!0usize) << page_bits) & !PageTableEntryFlags::EXECUTE_DISABLE.bits());
-			let phys =
-				entry & (((!0usize) << page_bits) & !PageTableEntryFlags::EXECUTE_DISABLE.bits() & /* exclude pkey */ !(0xF << 59));
+fn translate<M: PageTableMemory>(mem: &M, virtual_address: usize) -> usize {
+	let (entry, page_bits) = get_raw_leaf_entry(mem, virtual_address)
+		.expect("virtual_to_physical called for an unmapped address");
 
-			return off | phys;
-		}
-	}
+	let off = virtual_address
+		& !(((!0usize) << page_bits) & !PageTableEntryFlags::EXECUTE_DISABLE.bits());
+	let phys =
+		entry & (((!0usize) << page_bits) & !PageTableEntryFlags::EXECUTE_DISABLE.bits() & /* exclude pkey */ !(0xF << 59));
 
-	panic!("virtual_to_physical should never reach this point");
+	off | phys
 }
 
 #[no_mangle]
@@ -839,9 +1493,336 @@ pub fn map<S: PageSize>(
 		count
 	);
 
+	map_in::<S, _>(&RecursiveMapping, virtual_address, physical_address, count, flags);
+}
+
+fn map_in<S: PageSize, M: PageTableMemory>(
+	mem: &M,
+	virtual_address: usize,
+	physical_address: usize,
+	count: usize,
+	flags: PageTableEntryFlags,
+) {
 	let range = get_page_range::<S>(virtual_address, count);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-	root_pagetable.map_pages(range, physical_address, flags);
+	mem.root_table().map_pages(mem, range, physical_address, flags);
+}
+
+/// Removes the translation for `count` pages starting at `virtual_address`.
+/// Pages that are not mapped are silently skipped, so this can be used to make sure
+/// that a range (e.g. a stack guard page) is really unmapped before handing it out.
+pub fn unmap<S: PageSize>(virtual_address: usize, count: usize) {
+	trace!(
+		"Unmapping virtual address {:#X} ({} pages)",
+		virtual_address,
+		count
+	);
+
+	let range = get_page_range::<S>(virtual_address, count);
+	let mem = RecursiveMapping;
+	let root_pagetable = mem.root_table();
+	let mut send_ipi = false;
+
+	for page in range {
+		if root_pagetable.get_page_table_entry(&mem, page).is_some() {
+			root_pagetable.set_page_table_entry(&mem, page, 0);
+			send_ipi = true;
+		}
+	}
+
+	if send_ipi {
+		mem.flush_remote_tlbs();
+	}
+}
+
+/// Replaces the 2 MiB page containing `virtual_address` by a page table with the same translations,
+/// rights and keys. The new table is complete before it is linked in, because the large page
+/// may hold the code or stack in use. Returns false if the address is not mapped by a 2 MiB page.
+fn split_large_page_in<M: PageTableMemory>(mem: &M, virtual_address: usize) -> bool {
+	let entry = match find_raw_leaf_entry(mem, virtual_address) {
+		Some((entry, page_bits)) if page_bits == PAGE_BITS + PAGE_MAP_BITS => entry,
+		_ => return false,
+	};
+	let large_entry = unsafe { *entry };
+	if large_entry & PageTableEntryFlags::PRESENT.bits() == 0 {
+		return false;
+	}
+
+	// Bit 12 of a large page entry is PAT, so only take the address bits of a 2 MiB page.
+	let physical_address = large_entry & PHYSICAL_ADDRESS_MASK & !(LargePageSize::SIZE - 1);
+	let attributes = large_entry & !PHYSICAL_ADDRESS_MASK & !PageTableEntryFlags::HUGE_PAGE.bits();
+	let mut entries = [0usize; 1 << PAGE_MAP_BITS];
+	for (i, base_entry) in entries.iter_mut().enumerate() {
+		*base_entry = (physical_address + i * BasePageSize::SIZE) | attributes;
+	}
+
+	let mut table_flags = PageTableEntryFlags::WRITABLE | PageTableEntryFlags::USER_ACCESSIBLE;
+	table_flags.pkey(mm::SAFE_MEM_REGION);
+	let mut table_entry = PageTableEntry { physical_address_and_flags: 0 };
+	table_entry.set(mem.allocate_filled_table(&entries), table_flags, mem.physical_address_bits());
+	unsafe {
+		*entry = table_entry.physical_address_and_flags;
+	}
+
+	// Flush the large page and the address of the new table in the recursive mapping,
+	// which translated to the first frame of the large page before.
+	let table_address = entry as usize & !(BasePageSize::SIZE - 1);
+	let index = (entry as usize & (BasePageSize::SIZE - 1)) / mem::size_of::<usize>();
+	mem.flush_from_tlb(virtual_address);
+	mem.flush_from_tlb(mem.subtable_address(table_address, index));
+
+	true
+}
+
+/// Replaces WRITABLE and EXECUTE_DISABLE of the page containing `virtual_address` and keeps its
+/// translation and key. A 2 MiB page is split first and an inaccessible page becomes present again.
+/// Returns false if the page is not mapped.
+fn set_page_rights_in<M: PageTableMemory>(mem: &M, virtual_address: usize, writable: bool, executable: bool) -> bool {
+	split_large_page_in(mem, virtual_address);
+
+	let entry = match find_raw_leaf_entry(mem, virtual_address) {
+		Some((entry, page_bits)) if page_bits == PAGE_BITS => entry,
+		_ => return false,
+	};
+	let mut value = unsafe { *entry };
+	if value & PageTableEntryFlags::INACCESSIBLE.bits() != 0 {
+		value = (value | PageTableEntryFlags::PRESENT.bits()) & !PageTableEntryFlags::INACCESSIBLE.bits();
+	}
+	if value & PageTableEntryFlags::PRESENT.bits() == 0 {
+		return false;
+	}
+	if entry_pkey(value) == mm::SEALED_MEM_REGION {
+		error!("Refusing to change the rights of the sealed page at {:#X}", virtual_address);
+		return false;
+	}
+
+	value &= !(PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE).bits();
+	if writable {
+		value |= PageTableEntryFlags::WRITABLE.bits();
+	}
+	if !executable {
+		value |= PageTableEntryFlags::EXECUTE_DISABLE.bits();
+	}
+
+	unsafe {
+		*entry = value;
+	}
+	mem.flush_from_tlb(virtual_address);
+
+	true
+}
+
+/// Sets the rights of all pages in the range of `size` bytes at `virtual_address` and keeps their
+/// translations and keys. Pages are never writable and executable at the same time (W^X).
+/// Returns false if a page in the range is not mapped.
+pub fn set_page_rights(virtual_address: usize, size: usize, writable: bool, executable: bool) -> bool {
+	assert!(
+		!(writable && executable),
+		"Pages at {:#X} must not be writable and executable",
+		virtual_address
+	);
+
+	let mem = RecursiveMapping;
+	let first_page = align_down!(virtual_address, BasePageSize::SIZE);
+	let last_page = align_down!(virtual_address + size - 1, BasePageSize::SIZE);
+	let mut mapped = true;
+
+	for address in (first_page..=last_page).step_by(BasePageSize::SIZE) {
+		mapped &= set_page_rights_in(&mem, address, writable, executable);
+	}
+	mem.flush_remote_tlbs();
+
+	mapped
+}
+
+/// Makes all pages in the range of `size` bytes at `virtual_address` inaccessible (PROT_NONE).
+/// They keep their translations and keys, so that set_page_rights makes them accessible again.
+/// Returns false if a page in the range is not mapped.
+pub fn set_page_inaccessible(virtual_address: usize, size: usize) -> bool {
+	if !set_page_rights(virtual_address, size, false, false) {
+		return false;
+	}
+
+	let mem = RecursiveMapping;
+	let first_page = align_down!(virtual_address, BasePageSize::SIZE);
+	let last_page = align_down!(virtual_address + size - 1, BasePageSize::SIZE);
+
+	for address in (first_page..=last_page).step_by(BasePageSize::SIZE) {
+		if let Some((entry, _)) = find_raw_leaf_entry(&mem, address) {
+			unsafe {
+				*entry = (*entry & !PageTableEntryFlags::PRESENT.bits()) | PageTableEntryFlags::INACCESSIBLE.bits();
+			}
+			mem.flush_from_tlb(address);
+		}
+	}
+	mem.flush_remote_tlbs();
+
+	true
+}
+
+/// Makes the JIT region of `size` bytes at `virtual_address` writable, and executable if `executable` is set.
+/// Only pages with JIT_MEM_REGION qualify, whose key is write-disabled outside of mm::jit::jit_write.
+/// Returns false if a page in the range is not mapped.
+pub fn set_jit_page_rights(virtual_address: usize, size: usize, executable: bool) -> bool {
+	let mem = RecursiveMapping;
+	let first_page = align_down!(virtual_address, BasePageSize::SIZE);
+	let last_page = align_down!(virtual_address + size - 1, BasePageSize::SIZE);
+	let mut mapped = true;
+
+	for address in (first_page..=last_page).step_by(BasePageSize::SIZE) {
+		assert_eq!(
+			get_pkey(address),
+			Some(mm::JIT_MEM_REGION),
+			"Only JIT regions may be writable and executable"
+		);
+		mapped &= set_page_rights_in(&mem, address, true, executable);
+	}
+	mem.flush_remote_tlbs();
+
+	mapped
+}
+
+/// Gives the page at `virtual_address` the sealed key and makes it read-only and not executable.
+/// Returns false if the page is not mapped.
+fn seal_page_in<M: PageTableMemory>(mem: &M, virtual_address: usize) -> bool {
+	split_large_page_in(mem, virtual_address);
+
+	let entry = match find_raw_leaf_entry(mem, virtual_address) {
+		Some((entry, page_bits)) if page_bits == PAGE_BITS => entry,
+		_ => return false,
+	};
+	let mut value = unsafe { *entry };
+	if value & PageTableEntryFlags::PRESENT.bits() == 0 {
+		return false;
+	}
+
+	value &= !(PageTableEntryFlags::WRITABLE.bits() | (0xF << 59));
+	value |= PageTableEntryFlags::EXECUTE_DISABLE.bits() | (mm::SEALED_MEM_REGION as usize) << 59;
+
+	unsafe {
+		*entry = value;
+	}
+	mem.flush_from_tlb(virtual_address);
+
+	true
+}
+
+/// Seals all pages in the range of `size` bytes at `virtual_address` (see mm::seal).
+/// Returns false if a page in the range is not mapped.
+pub fn seal_pages(virtual_address: usize, size: usize) -> bool {
+	let mem = RecursiveMapping;
+	let first_page = align_down!(virtual_address, BasePageSize::SIZE);
+	let last_page = align_down!(virtual_address + size - 1, BasePageSize::SIZE);
+	let mut mapped = true;
+
+	for address in (first_page..=last_page).step_by(BasePageSize::SIZE) {
+		mapped &= seal_page_in(&mem, address);
+	}
+	mem.flush_remote_tlbs();
+
+	mapped
+}
+
+fn is_sealed_in<M: PageTableMemory>(mem: &M, virtual_address: usize) -> bool {
+	match get_raw_leaf_entry(mem, virtual_address) {
+		Some((entry, _)) => entry_pkey(entry) == mm::SEALED_MEM_REGION,
+		None => false,
+	}
+}
+
+/// Returns whether the page containing `virtual_address` is sealed.
+pub fn is_sealed(virtual_address: usize) -> bool {
+	is_sealed_in(&RecursiveMapping, virtual_address)
+}
+
+/// Gives the page at `virtual_address` the key of the function-pointer tables and makes it not
+/// executable. The page stays writable, the key write-protects it (see mm::fnptr).
+/// Returns false if the page is not mapped.
+fn protect_fnptr_page_in<M: PageTableMemory>(mem: &M, virtual_address: usize) -> bool {
+	split_large_page_in(mem, virtual_address);
+
+	let entry = match find_raw_leaf_entry(mem, virtual_address) {
+		Some((entry, page_bits)) if page_bits == PAGE_BITS => entry,
+		_ => return false,
+	};
+	let mut value = unsafe { *entry };
+	if value & PageTableEntryFlags::PRESENT.bits() == 0 {
+		return false;
+	}
+
+	value &= !(PageTableEntryFlags::USER_ACCESSIBLE.bits() | (0xF << 59));
+	value |= PageTableEntryFlags::EXECUTE_DISABLE.bits()
+		| (mm::FNPTR_MEM_REGION as usize) << 59
+		| user_bit(mm::FNPTR_MEM_REGION);
+
+	unsafe {
+		*entry = value;
+	}
+	mem.flush_from_tlb(virtual_address);
+
+	true
+}
+
+/// Write-protects all pages in the range of `size` bytes at `virtual_address` with the key of
+/// the function-pointer tables (see mm::fnptr). Returns false if a page in the range is not mapped.
+pub fn protect_fnptr_pages(virtual_address: usize, size: usize) -> bool {
+	let mem = RecursiveMapping;
+	let first_page = align_down!(virtual_address, BasePageSize::SIZE);
+	let last_page = align_down!(virtual_address + size - 1, BasePageSize::SIZE);
+	let mut mapped = true;
+
+	for address in (first_page..=last_page).step_by(BasePageSize::SIZE) {
+		mapped &= protect_fnptr_page_in(&mem, address);
+	}
+	mem.flush_remote_tlbs();
+
+	mapped
+}
+
+#[cfg(not(test))]
+extern "C" {
+	static __rodata_start: u8;
+	static __data_start: u8;
+}
+
+/// Maps the kernel image with the rights of its sections (see the linker script):
+/// .text read-only and executable, .rodata read-only and everything behind it writable.
+/// Apart from .text, nothing is executable.
+/// The windows of .safe_data and .unsafe_data are mapped by mm::init and stay untouched.
+/// Has to be called before the application processors are started.
+#[cfg(not(test))]
+pub fn protect_kernel_image() {
+	let text_start = environment::get_base_address();
+	let rodata_start = unsafe { &__rodata_start as *const u8 as usize };
+	let data_start = unsafe { &__data_start as *const u8 as usize };
+	let mem = RecursiveMapping;
+
+	debug!(
+		"Protecting the kernel image: .text {:#X} - {:#X}, .rodata {:#X} - {:#X}",
+		text_start,
+		rodata_start,
+		rodata_start,
+		data_start
+	);
+
+	for address in (mm::kernel_start_address()..mm::kernel_end_address()).step_by(BasePageSize::SIZE) {
+		if address >= mm::SAFE_DATA_START && address < mm::UNSAFE_DATA_START + mm::DATA_SECTION_SIZE {
+			continue;
+		}
+
+		if address >= text_start && address < rodata_start {
+			set_page_rights_in(&mem, address, false, true);
+		} else if address < data_start {
+			set_page_rights_in(&mem, address, false, false);
+		} else {
+			set_page_rights_in(&mem, address, true, false);
+		}
+	}
+
+	// The loader may have mapped the data windows with base pages. Their translations are not
+	// removed by flushing the large pages, so flush the whole TLB.
+	unsafe {
+		controlregs::cr3_write(controlregs::cr3());
+	}
 }
 
 pub fn identity_map(start_address: usize, end_address: usize) {
@@ -853,12 +1834,36 @@ pub fn identity_map(start_address: usize, end_address: usize) {
 		last_page.address()
 	);
 
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+	let mem = RecursiveMapping;
 	let range = Page::<BasePageSize>::range(first_page, last_page);
 	let mut flags = PageTableEntryFlags::empty();
 	// Protect pages with the protection key
 	flags.normal().read_only().execute_disable().pkey(::mm::SAFE_MEM_REGION);
-	root_pagetable.map_pages(range, first_page.address(), flags);
+	mem.root_table().map_pages(&mem, range, first_page.address(), flags);
+
+	record_identity_mapping(first_page.address(), last_page.address() + BasePageSize::SIZE);
+}
+
+/// Records the range from `start` to `end` as identity-mapped on purpose,
+/// for mappings that are not created through identity_map.
+pub fn record_identity_mapping(start: usize, end: usize) {
+	unsafe {
+		// ACPI maps its tables page by page, so extend a neighbouring range if possible.
+		for range in IDENTITY_MAPPINGS[..IDENTITY_MAPPING_COUNT].iter_mut() {
+			if start <= range.1 && end >= range.0 {
+				range.0 = range.0.min(start);
+				range.1 = range.1.max(end);
+				return;
+			}
+		}
+
+		if IDENTITY_MAPPING_COUNT < MAX_IDENTITY_MAPPINGS {
+			IDENTITY_MAPPINGS[IDENTITY_MAPPING_COUNT] = (start, end);
+			IDENTITY_MAPPING_COUNT += 1;
+		} else {
+			warn!("Too many identity mappings, the audit will report {:#X} - {:#X}", start, end);
+		}
+	}
 }
 
 #[inline]
@@ -874,14 +1879,6 @@ pub fn init_page_tables() {
 	let pml4 = unsafe {controlregs::cr3()};
 	let pde = pml4 + 2 * BasePageSize::SIZE as u64;
 
-	/* 
-	 * Set pkey on the page that contains PML4
-	 * As RustyHermitCore has a single page for all levels of page tables using Recursive Page Tables
-	 * It recursively walks through the lower level of the page tables through "subtable".
-	 * So we only need to set the pkey on the physical frame mapped with PML4_ADDRESS
-	 */
-	set_pkey_on_page_table_entry::<BasePageSize>(PML4_ADDRESS as usize, 1, ::mm::SAFE_MEM_REGION);
-
 	debug!("Found PML4 at 0x{:x}", pml4);
 
 	// make sure that only the required areas are mapped
@@ -890,8 +1887,17 @@ pub fn init_page_tables() {
 			as u64;
 	let size = (512 - (mm::kernel_end_address() >> (PAGE_MAP_BITS + PAGE_BITS)))
 		* mem::size_of::<u64>();
+	// There is neither a scheduler nor an isolation context yet, and the page tables belong to
+	// the kernel domain anyway, so clear them directly.
+	unsafe {
+		write_bytes(start as *mut u8, 0, size);
+	}
+
+	// Tables created from now on are tagged by map_page, so only the tables set up by the loader are left.
+	let retagged = protect_page_tables_in(&RecursiveMapping, true);
+	debug!("Tagged {} page tables with the safe key", retagged);
 	unsafe {
-		isolate_function_strong!(write_bytes(start as *mut u8, 0, size));
+		// Also flushes the old keys of the recursive mapping from the TLB.
 		controlregs::cr3_write(pml4);
 	}
 
@@ -918,4 +1924,330 @@ pub fn init_page_tables() {
 		info!("Found cmdline at 0x{:x} (size {})", cmdline, cmdsize);
 		identity_map(cmdline, cmdline + cmdsize - 1);
 	}
+
+	// The loader has already identity-mapped the boot stack.
+	let boot_stack = get_boot_stack();
+	record_identity_mapping(
+		align_down!(boot_stack, BasePageSize::SIZE),
+		align_up!(boot_stack + KERNEL_STACK_SIZE, BasePageSize::SIZE),
+	);
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::cell::{Cell, RefCell};
+	use std::prelude::v1::*;
+
+	/// Physical address of the first fake frame
+	const FAKE_PHYSICAL_BASE: usize = 0x10_0000;
+
+	/// Fake physical memory that holds the page tables on the host.
+	struct FakeMemory {
+		frames: RefCell<Vec<Box<[usize; 1 << PAGE_MAP_BITS]>>>,
+		flushed_pages: Cell<usize>,
+	}
+
+	impl FakeMemory {
+		fn new() -> Self {
+			let mem = FakeMemory {
+				frames: RefCell::new(Vec::new()),
+				flushed_pages: Cell::new(0),
+			};
+
+			// The first frame holds the PML4.
+			mem.allocate_table();
+			mem
+		}
+
+		fn frame_address(&self, physical_address: usize) -> usize {
+			let frames = self.frames.borrow();
+			let frame = &frames[(physical_address - FAKE_PHYSICAL_BASE) / BasePageSize::SIZE];
+			&**frame as *const [usize; 1 << PAGE_MAP_BITS] as usize
+		}
+
+		fn table_count(&self) -> usize {
+			self.frames.borrow().len()
+		}
+
+		fn raw_entry(&self, virtual_address: usize) -> usize {
+			get_raw_leaf_entry(self, virtual_address)
+				.expect("Entry not present")
+				.0
+		}
+	}
+
+	impl PageTableMemory for FakeMemory {
+		fn root_table_address(&self) -> usize {
+			self.frame_address(FAKE_PHYSICAL_BASE)
+		}
+
+		fn subtable_address(&self, table_address: usize, index: usize) -> usize {
+			let entry = unsafe { (*(table_address as *const [PageTableEntry; 1 << PAGE_MAP_BITS]))[index] };
+			self.frame_address(entry.address())
+		}
+
+		fn allocate_table(&self) -> usize {
+			let mut frames = self.frames.borrow_mut();
+			frames.push(Box::new([0; 1 << PAGE_MAP_BITS]));
+			FAKE_PHYSICAL_BASE + (frames.len() - 1) * BasePageSize::SIZE
+		}
+
+		fn allocate_filled_table(&self, entries: &[usize; 1 << PAGE_MAP_BITS]) -> usize {
+			let mut frames = self.frames.borrow_mut();
+			frames.push(Box::new(*entries));
+			FAKE_PHYSICAL_BASE + (frames.len() - 1) * BasePageSize::SIZE
+		}
+
+		fn flush_from_tlb(&self, _virtual_address: usize) {
+			self.flushed_pages.set(self.flushed_pages.get() + 1);
+		}
+
+		fn flush_remote_tlbs(&self) {}
+
+		fn physical_address_bits(&self) -> u8 {
+			46
+		}
+	}
+
+	fn data_flags() -> PageTableEntryFlags {
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		flags
+	}
+
+	#[test]
+	fn map_base_pages() {
+		let mem = FakeMemory::new();
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x20_0000, 3, data_flags());
+
+		// PML4, PDPT, PD and PT
+		assert_eq!(mem.table_count(), 4);
+		for i in 0..3 {
+			let page = Page::<BasePageSize>::including_address(0x4000_0000 + i * BasePageSize::SIZE);
+			let entry = mem.root_table().get_page_table_entry(&mem, page).unwrap();
+			assert_eq!(entry.address(), 0x20_0000 + i * BasePageSize::SIZE);
+		}
+		assert_eq!(translate(&mem, 0x4000_1234), 0x20_1234);
+
+		let page = Page::<BasePageSize>::including_address(0x4000_3000);
+		assert!(mem.root_table().get_page_table_entry(&mem, page).is_none());
+		assert!(get_raw_leaf_entry(&mem, 0x4000_3000).is_none());
+		assert!(get_raw_leaf_entry(&mem, 0x8000_0000).is_none());
+	}
+
+	#[test]
+	fn map_large_page() {
+		let mem = FakeMemory::new();
+		map_in::<LargePageSize, _>(&mem, 0x8000_0000, 0x4000_0000, 1, data_flags());
+
+		// PML4, PDPT and PD
+		assert_eq!(mem.table_count(), 3);
+		let entry = mem.raw_entry(0x8000_0000);
+		assert!(entry & PageTableEntryFlags::HUGE_PAGE.bits() != 0);
+		assert_eq!(translate(&mem, 0x8012_3456), 0x4012_3456);
+	}
+
+	#[test]
+	fn map_mixed_page_sizes() {
+		let mem = FakeMemory::new();
+		map_in::<LargePageSize, _>(&mem, 0x4000_0000, 0x60_0000, 1, data_flags());
+		map_in::<BasePageSize, _>(&mem, 0x4020_0000, 0x20_0000, 2, data_flags());
+
+		// Both ranges share the PD, only the base pages need a PT.
+		assert_eq!(mem.table_count(), 4);
+		assert_eq!(translate(&mem, 0x401F_FFF8), 0x7F_FFF8);
+		assert_eq!(translate(&mem, 0x4020_1008), 0x20_1008);
+		assert!(mem.raw_entry(0x4000_0000) & PageTableEntryFlags::HUGE_PAGE.bits() != 0);
+		assert!(mem.raw_entry(0x4020_0000) & PageTableEntryFlags::HUGE_PAGE.bits() == 0);
+	}
+
+	#[test]
+	fn remap_flushes_tlb() {
+		let mem = FakeMemory::new();
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x20_0000, 1, data_flags());
+		assert_eq!(mem.flushed_pages.get(), 0);
+
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x30_0000, 1, data_flags());
+		assert_eq!(mem.flushed_pages.get(), 1);
+		assert_eq!(translate(&mem, 0x4000_0008), 0x30_0008);
+	}
+
+	#[cfg(not(feature = "no-mpk"))]
+	#[test]
+	fn pkey_is_not_part_of_the_address() {
+		let mem = FakeMemory::new();
+		let mut flags = data_flags();
+		flags.pkey(15);
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x20_0000, 1, flags);
+
+		let page = Page::<BasePageSize>::including_address(0x4000_0000);
+		let entry = mem.root_table().get_page_table_entry(&mem, page).unwrap();
+		assert_eq!(entry.address(), 0x20_0000);
+		assert_eq!(mem.raw_entry(0x4000_0000) >> 59 & 0xF, 15);
+		assert_eq!(translate(&mem, 0x4000_0010), 0x20_0010);
+	}
+
+	#[cfg(not(feature = "no-mpk"))]
+	#[test]
+	fn retag_pages() {
+		let mem = FakeMemory::new();
+		let mut flags = data_flags();
+		flags.pkey(mm::SAFE_MEM_REGION);
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x20_0000, 3, flags);
+		let old_entry = mem.raw_entry(0x4000_0000);
+
+		set_pkey_on_page_table_entry_in::<BasePageSize, _>(&mem, 0x4000_0000, 2, mm::UNSAFE_MEM_REGION);
+
+		let new_entry = mem.raw_entry(0x4000_0000);
+		assert_eq!(new_entry >> 59 & 0xF, mm::UNSAFE_MEM_REGION as usize);
+		assert_eq!(new_entry & !(0xF << 59), old_entry & !(0xF << 59));
+		assert_eq!(mem.raw_entry(0x4000_1000) >> 59 & 0xF, mm::UNSAFE_MEM_REGION as usize);
+		// Only the requested pages are retagged.
+		assert_eq!(mem.raw_entry(0x4000_2000) >> 59 & 0xF, mm::SAFE_MEM_REGION as usize);
+		assert_eq!(mem.flushed_pages.get(), 2);
+		assert_eq!(translate(&mem, 0x4000_1008), 0x20_1008);
+	}
+
+	#[cfg(not(feature = "no-mpk"))]
+	#[test]
+	fn page_tables_carry_the_safe_key() {
+		let mem = FakeMemory::new();
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x20_0000, 1, data_flags());
+		map_in::<LargePageSize, _>(&mem, 0x8000_0000, 0x60_0000, 1, data_flags());
+		assert_eq!(protect_page_tables_in(&mem, false), 0);
+
+		// Simulate a PT set up without the key.
+		let pd_address = mem.subtable_address(mem.subtable_address(mem.root_table_address(), 0), 1);
+		let pd = unsafe { &mut *(pd_address as *mut [usize; 1 << PAGE_MAP_BITS]) };
+		pd[0] &= !(0xF << 59);
+
+		assert_eq!(protect_page_tables_in(&mem, true), 1);
+		assert_eq!(protect_page_tables_in(&mem, false), 0);
+		assert_eq!(pd[0] >> 59 & 0xF, mm::SAFE_MEM_REGION as usize);
+		// The key of a PT entry is the key of a page and stays untouched.
+		assert_eq!(mem.raw_entry(0x4000_0000) >> 59 & 0xF, 0);
+		assert_eq!(translate(&mem, 0x4000_0008), 0x20_0008);
+	}
+
+	#[test]
+	fn walk_coalesces_ranges() {
+		let mem = FakeMemory::new();
+		let mut read_only = PageTableEntryFlags::empty();
+		read_only.normal().execute_disable();
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x20_0000, 3, data_flags());
+		// Not contiguous in physical memory
+		map_in::<BasePageSize, _>(&mem, 0x4000_3000, 0x30_0000, 1, data_flags());
+		// Other attributes
+		map_in::<BasePageSize, _>(&mem, 0x4000_4000, 0x30_1000, 1, read_only);
+		map_in::<LargePageSize, _>(&mem, 0x8000_0000, 0x60_0000, 2, data_flags());
+
+		let mut ranges = Vec::new();
+		walk_mapped_ranges(&mem, |range| ranges.push((range.virtual_address, range.physical_address, range.page_size, range.count)));
+
+		assert_eq!(
+			ranges,
+			vec![
+				(0x4000_0000, 0x20_0000, BasePageSize::SIZE, 3),
+				(0x4000_3000, 0x30_0000, BasePageSize::SIZE, 1),
+				(0x4000_4000, 0x30_1000, BasePageSize::SIZE, 1),
+				(0x8000_0000, 0x60_0000, LargePageSize::SIZE, 2),
+			]
+		);
+	}
+
+	#[test]
+	fn split_large_page_for_rights() {
+		let mem = FakeMemory::new();
+		let mut flags = data_flags();
+		flags.pkey(mm::SAFE_MEM_REGION);
+		map_in::<LargePageSize, _>(&mem, 0x4000_0000, 0x60_0000, 1, flags);
+		let large_entry = mem.raw_entry(0x4000_0000);
+
+		assert!(set_page_rights_in(&mem, 0x4000_1000, false, true));
+
+		// PML4, PDPT, PD and the new PT
+		assert_eq!(mem.table_count(), 4);
+		for offset in (0..LargePageSize::SIZE).step_by(BasePageSize::SIZE) {
+			assert_eq!(translate(&mem, 0x4000_0008 + offset), 0x60_0008 + offset);
+		}
+
+		let entry = mem.raw_entry(0x4000_1000);
+		assert!(entry & PageTableEntryFlags::HUGE_PAGE.bits() == 0);
+		assert!(entry & PageTableEntryFlags::WRITABLE.bits() == 0);
+		assert!(entry & PageTableEntryFlags::EXECUTE_DISABLE.bits() == 0);
+		assert_eq!(entry >> 59 & 0xF, large_entry >> 59 & 0xF);
+		// The other pages keep the rights of the large page.
+		assert_eq!(
+			mem.raw_entry(0x4000_2000) & !PHYSICAL_ADDRESS_MASK,
+			large_entry & !PHYSICAL_ADDRESS_MASK & !PageTableEntryFlags::HUGE_PAGE.bits()
+		);
+		// The new table is protected like all others.
+		assert_eq!(protect_page_tables_in(&mem, false), 0);
+
+		assert!(!set_page_rights_in(&mem, 0x8000_0000, true, false));
+	}
+
+	#[cfg(not(feature = "no-mpk"))]
+	#[test]
+	fn sealed_page_stays_sealed() {
+		let mem = FakeMemory::new();
+		let mut flags = data_flags();
+		flags.pkey(mm::SAFE_MEM_REGION);
+		map_in::<BasePageSize, _>(&mem, 0x4000_0000, 0x60_0000, 2, flags);
+
+		assert!(seal_page_in(&mem, 0x4000_0000));
+		let sealed_entry = mem.raw_entry(0x4000_0000);
+		assert!(sealed_entry & PageTableEntryFlags::WRITABLE.bits() == 0);
+		assert!(sealed_entry & PageTableEntryFlags::EXECUTE_DISABLE.bits() != 0);
+		assert_eq!(entry_pkey(sealed_entry), mm::SEALED_MEM_REGION);
+		assert!(is_sealed_in(&mem, 0x4000_0000));
+		assert!(!is_sealed_in(&mem, 0x4000_1000));
+
+		// Neither the rights nor the key of a sealed page may change.
+		assert!(!set_page_rights_in(&mem, 0x4000_0000, true, false));
+		set_pkey_on_page_table_entry_in::<BasePageSize, _>(&mem, 0x4000_0000, 2, mm::SAFE_MEM_REGION);
+		assert_eq!(mem.raw_entry(0x4000_0000), sealed_entry);
+		assert_eq!(entry_pkey(mem.raw_entry(0x4000_1000)), mm::SAFE_MEM_REGION);
+	}
+
+	#[cfg(not(feature = "no-mpk"))]
+	#[test]
+	fn fnptr_page_in_large_page() {
+		let mem = FakeMemory::new();
+		let mut flags = data_flags();
+		flags.pkey(mm::SAFE_MEM_REGION);
+		map_in::<LargePageSize, _>(&mem, 0x4000_0000, 0x60_0000, 1, flags);
+
+		assert!(protect_fnptr_page_in(&mem, 0x4000_1000));
+		let entry = mem.raw_entry(0x4000_1000);
+		assert!(entry & PageTableEntryFlags::HUGE_PAGE.bits() == 0);
+		assert!(entry & PageTableEntryFlags::WRITABLE.bits() != 0);
+		assert!(entry & PageTableEntryFlags::EXECUTE_DISABLE.bits() != 0);
+		assert_eq!(entry_pkey(entry), mm::FNPTR_MEM_REGION);
+		assert_eq!(translate(&mem, 0x4000_1008), 0x60_1008);
+		assert_eq!(entry_pkey(mem.raw_entry(0x4000_2000)), mm::SAFE_MEM_REGION);
+
+		// The key of a function-pointer table may not change.
+		set_pkey_on_page_table_entry_in::<BasePageSize, _>(&mem, 0x4000_1000, 2, mm::SHARED_MEM_REGION);
+		assert_eq!(mem.raw_entry(0x4000_1000), entry);
+		assert_eq!(entry_pkey(mem.raw_entry(0x4000_2000)), mm::SHARED_MEM_REGION);
+	}
+
+	#[cfg(not(feature = "no-mpk"))]
+	#[test]
+	fn retag_large_page() {
+		let mem = FakeMemory::new();
+		let mut flags = data_flags();
+		flags.pkey(mm::SAFE_MEM_REGION);
+		map_in::<LargePageSize, _>(&mem, 0x4000_0000, 0x60_0000, 1, flags);
+		let old_entry = mem.raw_entry(0x4000_0000);
+
+		set_pkey_on_page_table_entry_in::<LargePageSize, _>(&mem, 0x4000_0000, 1, mm::SHARED_MEM_REGION);
+
+		let new_entry = mem.raw_entry(0x4000_0000);
+		assert_eq!(new_entry >> 59 & 0xF, mm::SHARED_MEM_REGION as usize);
+		assert_eq!(new_entry & !(0xF << 59), old_entry & !(0xF << 59));
+		assert!(new_entry & PageTableEntryFlags::EXECUTE_DISABLE.bits() != 0);
+	}
 }
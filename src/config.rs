@@ -2,4 +2,10 @@
 pub const KERNEL_STACK_SIZE: usize = 32_768;
 
 #[allow(dead_code)]
-pub const DEFAULT_STACK_SIZE: usize = 262_144;
\ No newline at end of file
+pub const DEFAULT_STACK_SIZE: usize = 262_144;
+/// Maximum number of isolated stacks each core keeps for reuse
+#[allow(dead_code)]
+pub const ISOLATED_STACK_POOL_SIZE: usize = 8;
+/// Maximum nesting depth of isolated calls and kernel callbacks per task
+#[allow(dead_code)]
+pub const MAX_ISOLATION_DEPTH: usize = 16;
@@ -10,13 +10,18 @@ pub mod uhyve;
 
 use alloc::boxed::Box;
 use core::ffi::c_void;
+use mm::fnptr;
 use synch::spinlock::SpinlockIrqSave;
 
-static NIC: SpinlockIrqSave<Option<Box<dyn NetworkInterface>>> = SpinlockIrqSave::new(None);
+/// Serializes the accesses to the driver
+safe_global_var!(static NIC_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(()));
+/// The driver is set once by `init`, afterwards the trait object is write-protected (see mm::fnptr).
+fnptr_global_var!(static mut NIC: Option<Box<dyn NetworkInterface>> = None);
 
 pub fn init() -> Result<(), ()> {
 	let nic = uhyve::init()?;
-	*NIC.lock() = Some(nic);
+	let _lock = NIC_LOCK.lock();
+	fnptr::unseal(|| unsafe { NIC = Some(nic) });
 
 	info!("Network initialized!");
 
@@ -42,47 +47,78 @@ pub trait NetworkInterface {
 	fn write(&self, buf: usize, len: usize) -> usize;
 }
 
-#[no_mangle]
-pub extern "C" fn sys_network_init(
+fn __sys_network_init(
 	sem: *const c_void,
 	ip: &mut [u8; 4],
 	gateway: &mut [u8; 4],
 	mac: &mut [u8; 18],
 ) -> i32 {
-	match &mut *NIC.lock() {
+	let _lock = NIC_LOCK.lock();
+	match unsafe { &mut NIC } {
 		Some(nic) => nic.init(sem, ip, gateway, mac),
 		None => -1,
 	}
 }
 
 #[no_mangle]
-pub extern "C" fn sys_is_polling() -> bool {
-	match &*NIC.lock() {
+pub extern "C" fn sys_network_init(
+	sem: *const c_void,
+	ip: &mut [u8; 4],
+	gateway: &mut [u8; 4],
+	mac: &mut [u8; 18],
+) -> i32 {
+	kernel_function!(__sys_network_init(sem, ip, gateway, mac))
+}
+
+fn __sys_is_polling() -> bool {
+	let _lock = NIC_LOCK.lock();
+	match unsafe { &NIC } {
 		Some(nic) => nic.is_polling(),
 		None => false,
 	}
 }
 
 #[no_mangle]
-pub extern "C" fn sys_set_polling(mode: bool) {
-	match &mut *NIC.lock() {
+pub extern "C" fn sys_is_polling() -> bool {
+	kernel_function!(__sys_is_polling())
+}
+
+/// Also called by the interrupt handler of the driver, which already runs in the kernel domain.
+pub fn __sys_set_polling(mode: bool) {
+	let _lock = NIC_LOCK.lock();
+	match unsafe { &mut NIC } {
 		Some(nic) => nic.set_polling(mode),
 		None => {}
 	}
 }
 
 #[no_mangle]
-pub extern "C" fn sys_netread(buf: usize, len: usize) -> usize {
-	match &mut *NIC.lock() {
+pub extern "C" fn sys_set_polling(mode: bool) {
+	kernel_function!(__sys_set_polling(mode))
+}
+
+fn __sys_netread(buf: usize, len: usize) -> usize {
+	let _lock = NIC_LOCK.lock();
+	match unsafe { &mut NIC } {
 		Some(nic) => nic.read(buf, len),
 		None => 0,
 	}
 }
 
 #[no_mangle]
-pub extern "C" fn sys_netwrite(buf: usize, len: usize) -> usize {
-	match &*NIC.lock() {
+pub extern "C" fn sys_netread(buf: usize, len: usize) -> usize {
+	kernel_function!(__sys_netread(buf, len))
+}
+
+fn __sys_netwrite(buf: usize, len: usize) -> usize {
+	let _lock = NIC_LOCK.lock();
+	match unsafe { &NIC } {
 		Some(nic) => nic.write(buf, len),
 		None => 0,
 	}
 }
+
+#[no_mangle]
+pub extern "C" fn sys_netwrite(buf: usize, len: usize) -> usize {
+	kernel_function!(__sys_netwrite(buf, len))
+}
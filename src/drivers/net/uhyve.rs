@@ -232,7 +232,7 @@ pub fn init() -> Result<Box<dyn NetworkInterface>, ()> {
 #[cfg(target_arch = "x86_64")]
 extern "x86-interrupt" fn uhyve_irqhandler(_stack_frame: &mut ExceptionStackFrame) {
 	debug!("Receive network interrupt from uhyve");
-	crate::drivers::net::sys_set_polling(true);
+	crate::drivers::net::__sys_set_polling(true);
 	apic::eoi();
 	core_scheduler().scheduler();
 }
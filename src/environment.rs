@@ -20,12 +20,145 @@ pub use arch::aarch64::kernel::{
 	get_base_address, get_cmdline, get_cmdsize, get_image_size, is_single_kernel, is_uhyve,
 };
 
+#[cfg(target_arch = "x86_64")]
+use arch::x86_64::kernel::scheduler::{set_isolated_stack_scrub, IsolatedStackScrub};
 use core::slice::from_raw_parts;
 use core::str::from_utf8_unchecked;
 use mm;
 
-safe_global_var!(static mut COMMAND_LINE_CPU_FREQUENCY: u16 = 0);
-safe_global_var!(static mut IS_PROXY: bool = false);
+sealed_global_var!(static mut COMMAND_LINE_CPU_FREQUENCY: u16 = 0);
+sealed_global_var!(static mut IS_PROXY: bool = false);
+sealed_global_var!(static mut ISOLATION_CONFIG: IsolationConfig = IsolationConfig {
+	mode: IsolationMode::Strong,
+	user_permission: mm::USER_PERMISSION,
+	wrpkru_scan: WrpkruScan::Warn,
+	pkey_fault: PkeyFault::Recover,
+	selftest: cfg!(feature = "isolation-selftest"),
+	audit_page_tables: cfg!(feature = "audit-page-tables"),
+	ring3: cfg!(feature = "ring3"),
+});
+
+/// How isolate_function_* run their functions (-isolation=off|weak|strong)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IsolationMode {
+	/// Isolated functions are called directly in the kernel domain.
+	Off,
+	/// Isolated functions may access the stack frame of their caller,
+	/// i.e. isolate_function_strong! behaves like isolate_function_weak!.
+	Weak,
+	/// Isolated functions run as requested by the caller.
+	Strong,
+}
+
+/// What to do if code that is about to become executable contains WRPKRU (-wrpkru-scan=warn|enforce)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WrpkruScan {
+	/// Log the finding and continue.
+	Warn,
+	/// Refuse to make the code executable.
+	Enforce,
+}
+
+/// How a protection key violation is handled (-pkfault=abort|recover)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PkeyFault {
+	/// Shut down the kernel.
+	Abort,
+	/// Terminate only the faulting task.
+	Recover,
+}
+
+/// Isolation policy of this deployment, parsed from the command line
+#[derive(Clone, Copy, Debug)]
+pub struct IsolationConfig {
+	pub mode: IsolationMode,
+	/// PKRU value of the application (-pkru-user=<hex>)
+	pub user_permission: u32,
+	pub wrpkru_scan: WrpkruScan,
+	pub pkey_fault: PkeyFault,
+	/// Run the isolation self-test instead of the application (-isolation-selftest)
+	pub selftest: bool,
+	/// Print and check the address space at boot (-audit-page-tables)
+	pub audit_page_tables: bool,
+	/// Run the application in ring 3 and enter the kernel through syscall (-ring3)
+	pub ring3: bool,
+}
+
+/// Returns the value of the command-line option `-name=value`.
+fn find_option<'a>(cmdline_str: &'a str, name: &str) -> Option<&'a str> {
+	cmdline_str
+		.split(' ')
+		.find(|arg| arg.starts_with(name) && arg[name.len()..].starts_with('='))
+		.map(|arg| &arg[name.len() + 1..])
+}
+
+fn parse_isolation_options(cmdline_str: &str) {
+	let mut config = isolation_config();
+
+	if let Some(mode) = find_option(cmdline_str, "-isolation") {
+		config.mode = match mode {
+			"off" => IsolationMode::Off,
+			"weak" => IsolationMode::Weak,
+			"strong" => IsolationMode::Strong,
+			_ => panic!("Invalid -isolation command line"),
+		};
+	}
+
+	if let Some(pkru_str) = find_option(cmdline_str, "-pkru-user") {
+		let pkru_str = pkru_str.trim_start_matches("0x").trim_start_matches("0X");
+		let pkru = u32::from_str_radix(pkru_str, 16)
+			.expect("Could not parse -pkru-user command line as hexadecimal number");
+
+		// The application lives in key 0, so it must never lose access to it.
+		if pkru & 0b11 != 0 {
+			warn!("-pkru-user {:#X} disables key 0, ignoring these bits", pkru);
+		}
+		config.user_permission = pkru & !0b11;
+	}
+
+	if let Some(scan) = find_option(cmdline_str, "-wrpkru-scan") {
+		config.wrpkru_scan = match scan {
+			"warn" => WrpkruScan::Warn,
+			"enforce" => WrpkruScan::Enforce,
+			_ => panic!("Invalid -wrpkru-scan command line"),
+		};
+	}
+
+	if let Some(fault) = find_option(cmdline_str, "-pkfault") {
+		config.pkey_fault = match fault {
+			"abort" => PkeyFault::Abort,
+			"recover" => PkeyFault::Recover,
+			_ => panic!("Invalid -pkfault command line"),
+		};
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	{
+		if let Some(scrub) = find_option(cmdline_str, "-isolated-stack-scrub") {
+			set_isolated_stack_scrub(match scrub {
+				"call" => IsolatedStackScrub::AfterCall,
+				"reuse" => IsolatedStackScrub::BeforeReuse,
+				_ => panic!("Invalid -isolated-stack-scrub command line"),
+			});
+		}
+	}
+
+	if cmdline_str.split(' ').any(|arg| arg == "-isolation-selftest") {
+		config.selftest = true;
+	}
+
+	if cmdline_str.split(' ').any(|arg| arg == "-audit-page-tables") {
+		config.audit_page_tables = true;
+	}
+
+	if cmdline_str.split(' ').any(|arg| arg == "-ring3") {
+		config.ring3 = true;
+	}
+
+	unsafe {
+		ISOLATION_CONFIG = config;
+	}
+}
 
 fn parse_command_line() {
 	let cmdsize = get_cmdsize();
@@ -58,6 +191,8 @@ fn parse_command_line() {
 
 	// Check for the -proxy option.
 	unsafe { IS_PROXY = cmdline_str.find("-proxy").is_some(); }
+
+	parse_isolation_options(cmdline_str);
 }
 
 pub fn init() {
@@ -70,6 +205,8 @@ pub fn init() {
 		// We are running side-by-side to Linux, which implies communication with "proxy".
 		unsafe { IS_PROXY = true; }
 	}
+
+	info!("Isolation policy: {:?}", isolation_config());
 }
 
 /// CPU Frequency in MHz if given through the -freq command-line parameter, otherwise zero.
@@ -82,3 +219,15 @@ pub fn get_command_line_cpu_frequency() -> u16 {
 pub fn is_proxy() -> bool {
 	unsafe { IS_PROXY }
 }
+
+/// Isolation policy given on the command line (or the defaults).
+/// Only valid after calling init()!
+pub fn isolation_config() -> IsolationConfig {
+	unsafe { ISOLATION_CONFIG }
+}
+
+/// Shortcut for isolation_config().mode, which is consulted on every isolated call.
+#[inline]
+pub fn isolation_mode() -> IsolationMode {
+	unsafe { ISOLATION_CONFIG.mode }
+}
@@ -32,11 +32,15 @@
 #![feature(specialization)]
 #![feature(naked_functions)]
 #![feature(core_intrinsics)]
+#![feature(global_asm)]
 #![feature(type_ascription)]
 #![feature(alloc_error_handler)]
 #![allow(unused_macros)]
 #![no_std]
 
+#[cfg(all(feature = "shadow-stack", feature = "no-mpk"))]
+compile_error!("The shadow stacks are protected by MPK and cannot be used with feature no-mpk");
+
 #[cfg(test)]
 #[macro_use]
 extern crate std;
@@ -74,9 +78,12 @@ mod environment;
 mod errno;
 mod kernel_message_buffer;
 mod mm;
+mod module;
+mod policy;
 #[cfg(not(test))]
 mod runtime_glue;
 mod scheduler;
+mod selftest;
 mod synch;
 mod syscalls;
 
@@ -89,10 +96,18 @@ use arch::percore::*;
 use core::alloc::GlobalAlloc;
 use mm::allocator::LockedHeap;
 
+/// Kernel heap, which serves all kernel-internal allocations.
+/// The heap and its metadata are mapped with SAFE_MEM_REGION.
 #[cfg(not(test))]
 #[global_allocator]
+#[link_section = ".safe_data"]
 static mut ALLOCATOR: LockedHeap = LockedHeap::empty();
 
+/// Application heap, which serves sys_malloc, sys_realloc and sys_free.
+/// The application calls these functions directly, so the allocator has to stay accessible to it.
+#[cfg(not(test))]
+static mut USER_ALLOCATOR: LockedHeap = LockedHeap::empty();
+
 /// Interface to allocate memory from system heap
 #[cfg(not(test))]
 #[no_mangle]
@@ -104,7 +119,7 @@ pub extern "C" fn sys_malloc(size: usize, align: usize) -> *mut u8 {
 		//isolation_start!();
 		//ptr = ALLOCATOR.alloc(layout);
 		//isolation_end!();
-		ptr = ALLOCATOR.alloc(layout);
+		ptr = USER_ALLOCATOR.alloc(layout);
 	}
 
 	trace!(
@@ -124,7 +139,7 @@ pub extern "C" fn sys_realloc(ptr: *mut u8, size: usize, align: usize, new_size:
 	let new_ptr;
 
 	unsafe {
-		new_ptr = ALLOCATOR.realloc(ptr, layout, new_size);
+		new_ptr = USER_ALLOCATOR.realloc(ptr, layout, new_size);
 	}
 
 	trace!(
@@ -148,7 +163,7 @@ pub extern "C" fn sys_free(ptr: *mut u8, size: usize, align: usize) {
 	);
 
 	unsafe {
-		ALLOCATOR.dealloc(ptr, layout);
+		USER_ALLOCATOR.dealloc(ptr, layout);
 	}
 }
 
@@ -203,11 +218,30 @@ extern "C" fn initd(_arg: usize) {
 	// give the IP thread time to initialize the network interface
 	core_scheduler().reschedule();
 
+	// From now on, the function-pointer tables are only writable inside mm::fnptr::unseal.
+	mm::fnptr::seal();
+	// The choice of the isolation backend cannot change anymore either.
+	arch::mm::isolation::seal();
+	// Neither can the command line, the ACPI tables and the CPU features.
+	arch::x86_64::kernel::acpi::seal();
+	mm::seal_boot_data();
+
+	if environment::isolation_config().selftest {
+		selftest::run();
+	}
+
 	#[cfg(not(feature = "newlib"))]
 	{
-		// Switch global allocator to user heap
+		// Set up the application heap
 		mm::init_user_allocator();
 	}
+
+	// From now on, the unsafe compartment can be reset to this state.
+	mm::compartment::snapshot();
+
+	if environment::isolation_config().audit_page_tables {
+		arch::mm::paging::audit_address_space();
+	}
 	// Get the application arguments and environment variables.
 	let (argc, argv, environ) = syscalls::get_application_parameters();
 
@@ -215,8 +249,8 @@ extern "C" fn initd(_arg: usize) {
         //let scheduler = core_scheduler();
         let mut start = arch::processor::get_timer_ticks();
         for _ in 0..100000000 {
-            //safe_set_core_scheduler(scheduler);
-            let _ = safe_core_id();
+            //set_core_scheduler(scheduler);
+            let _ = core_id();
         }
         start = arch::processor::get_timer_ticks() - start;
         info!("set time:{}", start);
@@ -225,6 +259,12 @@ extern "C" fn initd(_arg: usize) {
         //performance_evaluation();
         //performance_evaluation2();
 
+	if arch::x86_64::kernel::syscall::is_enabled() {
+		arch::processor::fpu_init();
+		info!("Call runtime_entry in ring 3");
+		arch::x86_64::kernel::syscall::enter_user(runtime_entry as usize, argc as usize, argv as usize, environ as usize);
+	}
+
         user_start!(false);
         arch::processor::fpu_init();
         info!("Call runtime_entry");
@@ -246,7 +286,7 @@ unsafe_global_var!(static mut buffer: usize = 0);
 unsafe_global_var!(static mut _size: usize = 0);
 fn performance_evaluation() {
 	use core::ptr::write_bytes;
-        unsafe {buffer = mm::unsafe_allocate(4096, true);}
+        unsafe {buffer = mm::unsafe_allocate(4096);}
         //unsafe  { PTR = buffer as u64; }
         for size in [1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024, 2048, 4096].iter() {
 		//let scheduler = core_scheduler();
@@ -110,6 +110,33 @@ macro_rules! unsafe_global_var {
         };
 }
 
+/// Places a static among the data, which is sealed after boot (see mm::seal_boot_data).
+/// Afterwards, every domain may read it, but none may write it.
+macro_rules! sealed_global_var {
+	(static mut $name:ident: $var_type:ty = $val:expr) => {
+		#[link_section = ".safe_data.sealed"]
+		static mut $name: $var_type = $val;
+	};
+	(pub static mut $name:ident: $var_type:ty = $val:expr) => {
+		#[link_section = ".safe_data.sealed"]
+		pub static mut $name: $var_type = $val;
+	};
+}
+
+/// Places a static among the function-pointer tables, which are write-protected after boot
+/// (see mm::fnptr). Afterwards, it may only be changed inside mm::fnptr::unseal.
+macro_rules! fnptr_global_var {
+	(static mut $name:ident: $var_type:ty = $val:expr) => {
+		#[link_section = ".safe_data.fnptr"]
+		static mut $name: $var_type = $val;
+	};
+	(pub static mut $name:ident: $var_type:ty = $val:expr) => {
+		#[link_section = ".safe_data.fnptr"]
+		pub static mut $name: $var_type = $val;
+	};
+}
+
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! user_start {
 	($e:expr) => {
 		let user_stack_pointer = core_scheduler().current_task.borrow().user_stack_pointer;
@@ -131,34 +158,19 @@ macro_rules! user_start {
 				: "volatile");
 
 			if $e {
-				asm!("mov $$0xfc, %eax;
-				      xor %ecx, %ecx;
-			              xor %edx, %edx;
-				      wrpkru;
-				      lfence"
-					:
-					:
-					: "eax", "ecx", "edx"
-					: "volatile");
+				::arch::x86_64::mm::isolation::write_permissions(::mm::user_permission());
 			}
 		}
 	};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! user_end {
 	() => {
 		// And finally start the application.
 		#[allow(unused)]
 		unsafe {
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				:
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+			::arch::x86_64::mm::isolation::write_permissions(::mm::kernel_permission());
 
 			let kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
 
@@ -188,262 +200,207 @@ macro_rules! print_kernel_stack_pointer {
 	}
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! kernel_enter {
 	($e:expr) => {
-		//unsafe{::SYSCALL_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-		let kernel_stack_pointer: usize; 
-		let user_stack_pointer: usize;
-
-		#[allow(unused)]
-		unsafe {
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				:
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
-
-			asm!("mov %rsp, $0"
-				: "=r"(user_stack_pointer)
-				: 
-				:
-				: "volatile");
+		// In ring 3, syscall_entry has already switched the stack and the permissions.
+		if !::arch::x86_64::kernel::syscall::is_enabled() {
+			//unsafe{::SYSCALL_COUNTER += 1; }
+			use x86_64::kernel::percore::core_scheduler;
+			let kernel_stack_pointer: usize; 
+			let user_stack_pointer: usize;
+
+			#[allow(unused)]
+			unsafe {
+				::arch::x86_64::mm::isolation::write_permissions(::mm::kernel_permission());
+
+				asm!("mov %rsp, $0"
+					: "=r"(user_stack_pointer)
+					: 
+					:
+					: "volatile");
 
-			kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
-			// Switch to kernel stack
-			asm!("mov $0, %rsp"
-				: 
-				: "r"(kernel_stack_pointer)
-				:
-				: "volatile");
+				kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
+				// Switch to kernel stack
+				asm!("mov $0, %rsp"
+					: 
+					: "r"(kernel_stack_pointer)
+					:
+					: "volatile");
 			
-			core_scheduler().current_task.borrow_mut().user_stack_pointer = user_stack_pointer;
-			//println!("=========enter : {}\\", $e);
+				core_scheduler().current_task.borrow_mut().user_stack_pointer = user_stack_pointer;
+				//println!("=========enter : {}\\", $e);
+			}
 		}
 	};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! kernel_exit {
 	($e:expr) => {
-		let user_stack_pointer = core_scheduler().current_task.borrow().user_stack_pointer;
-		let kernel_stack_pointer: usize;
+		// In ring 3, syscall_entry has already switched the stack and the permissions.
+		if !::arch::x86_64::kernel::syscall::is_enabled() {
+			use x86_64::kernel::percore::core_scheduler;
+			let user_stack_pointer = core_scheduler().current_task.borrow().user_stack_pointer;
+			let kernel_stack_pointer: usize;
+
+			#[allow(unused)]
+			unsafe {
+				asm!("mov %rsp, $0"
+					: "=r"(kernel_stack_pointer)
+					:
+					:
+					: "volatile");
+				core_scheduler().current_task.borrow_mut().kernel_stack_pointer = kernel_stack_pointer;
 
-		#[allow(unused)]
-		unsafe {
-			asm!("mov %rsp, $0"
-				: "=r"(kernel_stack_pointer)
-				:
-				:
-				: "volatile");
-			core_scheduler().current_task.borrow_mut().kernel_stack_pointer = kernel_stack_pointer;
+				// Switch to user stack
+				asm!("mov $0, %rsp"
+				  : 
+				  : "r"(user_stack_pointer)
+				  :
+				  : "volatile");
 
-			// Switch to user stack
-			asm!("mov $0, %rsp"
-			  : 
-			  : "r"(user_stack_pointer)
-			  :
-			  : "volatile");
-
-			//println!("=========exit : {}/", $e);
-
-			asm!("mov $$0xfc, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				:
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+				//println!("=========exit : {}/", $e);
+
+				::arch::x86_64::mm::isolation::write_permissions(::mm::user_permission());
+			}
 		}
 	};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! kernel_function {
 	($f:ident($($x:tt)*)) => {{
-		//unsafe{::SYSCALL_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-		let mut kernel_stack_pointer: usize;
-		let mut user_stack_pointer: usize;
-		#[allow(unused)]
-		unsafe {
-			// switch permission
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				: 
-				: "eax", "ecx", "edx"
-				: "volatile");
+		// In ring 3, syscall_entry has already switched the stack and the permissions.
+		if ::arch::x86_64::kernel::syscall::is_enabled() {
+			#[allow(unused_unsafe)]
+			unsafe { $f($($x)*) }
+		} else {
+			//unsafe{::SYSCALL_COUNTER += 1; }
+			use x86_64::kernel::percore::core_scheduler;
+			let mut kernel_stack_pointer: usize;
+			let mut user_stack_pointer: usize;
+			#[allow(unused)]
+			unsafe {
+				// switch permission
+				::arch::x86_64::mm::isolation::write_permissions(::mm::kernel_permission());
 	
-			// Save user stack pointer and 
-			// switch stack to the kernel stack
-			asm!("mov %rsp, $0"
-				: "=r"(user_stack_pointer)
-				:
-				:
-				: "volatile");
+				// Save user stack pointer and 
+				// switch stack to the kernel stack
+				asm!("mov %rsp, $0"
+					: "=r"(user_stack_pointer)
+					:
+					:
+					: "volatile");
 
-			kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
-			asm!("mov $0, %rsp"
-				: 
-				: "r"(kernel_stack_pointer)
-				:
-				: "volatile");
+				kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
+				asm!("mov $0, %rsp"
+					: 
+					: "r"(kernel_stack_pointer)
+					:
+					: "volatile");
 
-			let temp_ret = $f($($x)*);
+				let temp_ret = $f($($x)*);
 
-			// Save kernel stack pinter and
-			// swiatch back to the user stack
-			/*
-			asm!("mov %rsp, $0"
-				: "=r"(kernel_stack_pointer)
-				:
-				:
-				: "volatile");
-			core_scheduler().current_task.borrow_mut().kernel_stack_pointer = kernel_stack_pointer;
-			*/
-			asm!("mov $0, %rsp"
-				: 
-				: "r"(user_stack_pointer)
-				:
-				: "volatile");
+				// Save kernel stack pinter and
+				// swiatch back to the user stack
+				/*
+				asm!("mov %rsp, $0"
+					: "=r"(kernel_stack_pointer)
+					:
+					:
+					: "volatile");
+				core_scheduler().current_task.borrow_mut().kernel_stack_pointer = kernel_stack_pointer;
+				*/
+				asm!("mov $0, %rsp"
+					: 
+					: "r"(user_stack_pointer)
+					:
+					: "volatile");
 
-			asm!("mov $$0xfc, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+				::arch::x86_64::mm::isolation::write_permissions(::mm::user_permission());
 
-			temp_ret
+				temp_ret
+			}
 		}
 	}};
 
 	($p:tt.$f:ident($($x:tt)*)) => {{
-		//unsafe{::SYSCALL_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-		#[allow(unused)]
-		let mut kernel_stack_pointer: usize;
-		#[allow(unused)]
-		let mut user_stack_pointer: usize;
-		#[allow(unused)]
-		unsafe {
-			// switch permission
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				: 
-				: "eax", "ecx", "edx"
-				: "volatile");
+		// In ring 3, syscall_entry has already switched the stack and the permissions.
+		if ::arch::x86_64::kernel::syscall::is_enabled() {
+			#[allow(unused_unsafe)]
+			unsafe { $p.$f($($x)*) }
+		} else {
+			//unsafe{::SYSCALL_COUNTER += 1; }
+			use x86_64::kernel::percore::core_scheduler;
+			#[allow(unused)]
+			let mut kernel_stack_pointer: usize;
+			#[allow(unused)]
+			let mut user_stack_pointer: usize;
+			#[allow(unused)]
+			unsafe {
+				// switch permission
+				::arch::x86_64::mm::isolation::write_permissions(::mm::kernel_permission());
 	
-			// Save user stack pointer and 
-			// switch stack to the kernel stack
-			asm!("mov %rsp, $0"
-				: "=r"(user_stack_pointer)
-				:
-				:
-				: "volatile");
+				// Save user stack pointer and 
+				// switch stack to the kernel stack
+				asm!("mov %rsp, $0"
+					: "=r"(user_stack_pointer)
+					:
+					:
+					: "volatile");
 
-			kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
-			asm!("mov $0, %rsp"
-				: 
-				: "r"(kernel_stack_pointer)
-				:
-				: "volatile");
+				kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
+				asm!("mov $0, %rsp"
+					: 
+					: "r"(kernel_stack_pointer)
+					:
+					: "volatile");
 
-			let temp_ret = $p.$f($($x)*);
+				let temp_ret = $p.$f($($x)*);
 
-			asm!("mov $0, %rsp"
-				: 
-				: "r"(user_stack_pointer)
-				:
-				: "volatile");
+				asm!("mov $0, %rsp"
+					: 
+					: "r"(user_stack_pointer)
+					:
+					: "volatile");
 
-			asm!("mov $$0xfc, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+				::arch::x86_64::mm::isolation::write_permissions(::mm::user_permission());
 
-			temp_ret
+				temp_ret
+			}
 		}
 	}};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! isolation_start {
 	() => {
 		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      or $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_IN)
-			: "eax", "ecx", "edx"
-			: "volatile");
+		if ::environment::isolation_mode() != ::environment::IsolationMode::Off {
+			::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
+		}
 	};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! isolation_end {
 	() => {
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT)
-			: "eax", "ecx", "edx"
-			: "volatile"); 
+		::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
 	};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! isolation_wrapper {
 	($f:ident($($x:tt)*)) => {{
 		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      or $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_IN)
-			: "eax", "ecx", "edx"
-			: "volatile");
+		if ::environment::isolation_mode() != ::environment::IsolationMode::Off {
+			::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
+		}
 
 		let temp_ret = $f($($x)*);
 
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT)
-			: "eax", "ecx", "edx"
-			: "volatile"); 
+		::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
 
 		temp_ret
 	}};
@@ -452,7 +409,7 @@ macro_rules! isolation_wrapper {
 macro_rules! print_this_page {
     ($addr: expr) => {
 		use x86_64::mm::paging::{BasePageSize, LargePageSize, print_page_table_entry};
-		if ($addr as usize) <= mm::kernel_end_address() {
+		if ($addr as usize) <= ::mm::kernel_end_address() {
 			print_page_table_entry::<LargePageSize>($addr as usize);
 		}
 		else {
@@ -464,22 +421,22 @@ macro_rules! print_this_page {
 macro_rules! share {
     ($addr: expr) => {
 		use x86_64::mm::paging::{BasePageSize, LargePageSize, set_pkey_on_page_table_entry};
-		if ($addr as usize) <= mm::kernel_end_address() {
-			set_pkey_on_page_table_entry::<LargePageSize>($addr as usize, 1, mm::SHARED_MEM_REGION);
+		if ($addr as usize) <= ::mm::kernel_end_address() {
+			set_pkey_on_page_table_entry::<LargePageSize>($addr as usize, 1, ::mm::SHARED_MEM_REGION);
 		}
 		else {
-			set_pkey_on_page_table_entry::<BasePageSize>($addr as usize, 1, mm::SHARED_MEM_REGION);
+			set_pkey_on_page_table_entry::<BasePageSize>($addr as usize, 1, ::mm::SHARED_MEM_REGION);
 		}
 	};
 }
 
 macro_rules! unshare {
     ($addr: expr) => {
-		if ($addr as usize) <= mm::kernel_end_address() {
-			set_pkey_on_page_table_entry::<LargePageSize>($addr as usize, 1, mm::SAFE_MEM_REGION);
+		if ($addr as usize) <= ::mm::kernel_end_address() {
+			set_pkey_on_page_table_entry::<LargePageSize>($addr as usize, 1, ::mm::SAFE_MEM_REGION);
 		}
 		else {
-			set_pkey_on_page_table_entry::<BasePageSize>($addr as usize, 1, mm::SAFE_MEM_REGION);
+			set_pkey_on_page_table_entry::<BasePageSize>($addr as usize, 1, ::mm::SAFE_MEM_REGION);
 		}
 	};
 }
@@ -487,34 +444,34 @@ macro_rules! unshare {
 macro_rules! share_local_var {
 	($name:ident: $var_type:ty) => {
 		use x86_64::mm::paging::{BasePageSize, LargePageSize, set_pkey_on_page_table_entry};
-		if (&$name as *const $var_type as usize) <= mm::kernel_end_address() {
-			set_pkey_on_page_table_entry::<LargePageSize>(&$name as *const $var_type as usize, 1, mm::SHARED_MEM_REGION);
+		if (&$name as *const $var_type as usize) <= ::mm::kernel_end_address() {
+			set_pkey_on_page_table_entry::<LargePageSize>(&$name as *const $var_type as usize, 1, ::mm::SHARED_MEM_REGION);
 		}
 		else {
-			set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, mm::SHARED_MEM_REGION);
+			set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, ::mm::SHARED_MEM_REGION);
 		}
 	};
 
 	($p:ident.$name:ident: $var_type:ty) => {
 		use x86_64::mm::paging::{BasePageSize, LargePageSize, set_pkey_on_page_table_entry};
-		if (&$p.$name as *const $var_type as usize) <= mm::kernel_end_address() {
-			set_pkey_on_page_table_entry::<LargePageSize>(&$p.$name as *const $var_type as usize, 1, mm::SHARED_MEM_REGION);
+		if (&$p.$name as *const $var_type as usize) <= ::mm::kernel_end_address() {
+			set_pkey_on_page_table_entry::<LargePageSize>(&$p.$name as *const $var_type as usize, 1, ::mm::SHARED_MEM_REGION);
 		}
 		else {
-			set_pkey_on_page_table_entry::<BasePageSize>(&$p.$name as *const $var_type as usize, 1, mm::SHARED_MEM_REGION);
+			set_pkey_on_page_table_entry::<BasePageSize>(&$p.$name as *const $var_type as usize, 1, ::mm::SHARED_MEM_REGION);
 		}
 	};
 
 	(let $name:ident: $var_type:ty = $expr:expr) => {
 		use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
 		let $name: $var_type = $expr;
-		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, mm::SHARED_MEM_REGION);
+		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, ::mm::SHARED_MEM_REGION);
 	};
 
 	(let mut $name:ident: $var_type:ty = $expr:expr) => {
 		use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
 		let mut $name: $var_type = $expr;
-		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, mm::SHARED_MEM_REGION);
+		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, ::mm::SHARED_MEM_REGION);
 	};
 }
 
@@ -522,235 +479,395 @@ macro_rules! unshare_local_var {
 	(let $name:ident: $var_type:ty = $expr:expr) => {
 		use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
 		let $name: $var_type = $expr;
-		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, mm::SAFE_MEM_REGION);
+		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, ::mm::SAFE_MEM_REGION);
 	};
 
 	(let mut $name:ident: $var_type:ty = $expr:expr) => {
 		use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
 		let mut $name: $var_type = $expr;
-		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, mm::SAFE_MEM_REGION);
+		set_pkey_on_page_table_entry::<BasePageSize>(&$name as *const $var_type as usize, 1, ::mm::SAFE_MEM_REGION);
 	};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! isolate_function_weak {
 	($f:ident($($x:tt)*)) => {{
-		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-		use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
-		use mm::{SAFE_MEM_REGION, SHARED_MEM_REGION};
-                use config::DEFAULT_STACK_SIZE;
+		if ::environment::isolation_mode() == ::environment::IsolationMode::Off {
+			$f($($x)*)
+		} else {
+			//unsafe{ ::UNSAFE_COUNTER += 1; }
+			use x86_64::kernel::scheduler::{isolation_enter, isolation_exit};
+			use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
+			use mm::{SAFE_MEM_REGION, SHARED_MEM_REGION};
+
+			let mut __current_rbp: usize = 0;
+			let mut __current_rsp: usize = 0;
+			let mut __count:usize = 0;
+
+			/* We get the address of current stack frame and calculate size of the stack frame. */
+			asm!("mov %rbp, $0;
+			      mov %rsp, $1"
+				: "=r"(__current_rbp), "=r"(__current_rsp)
+				:
+				:
+				: "volatile");
 
-		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
-		let mut __current_rbp: usize = 0;
-		let mut __current_rsp: usize = 0;
-		let mut __count:usize = 0;
-
-		/* We get the address of current stack frame and calculate size of the stack frame. */
-		asm!("mov %rbp, $0;
-		      mov %rsp, $1"
-			: "=r"(__current_rbp), "=r"(__current_rsp)
-			:
-			:
-			: "volatile");
-
-		/* Calculate the number of pages of the current stack frame */
-		__count = (align_up!(__current_rbp, 4096) - align_down!(__current_rsp, 4096))/4096;
-		/* Set the current stack frame as SHARED_MEM_REGION in order that the isolated unsafe function can access it. */
-		set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SHARED_MEM_REGION);
-
-		/* or $1, %eax -> Add mm::UNSAFE_PERMISSION to current value of PKRU */
-		asm!("mov $0, %rsp;
-		      xor %ecx, %ecx;
-		      rdpkru;
-		      or $1, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			: 
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
-			: "eax", "ecx", "edx"
-			: "volatile");
+			/* Calculate the number of pages of the current stack frame */
+			__count = (align_up!(__current_rbp, 4096) - align_down!(__current_rsp, 4096))/4096;
+			/* Set the current stack frame as SHARED_MEM_REGION in order that the isolated unsafe function can access it. */
+			set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SHARED_MEM_REGION);
+			let __isolated_stack = isolation_enter(__current_rsp);
 
-		let temp_ret = $f($($x)*);
+			/* Add mm::UNSAFE_PERMISSION_IN to the current access rights */
+			asm!("mov $0, %rsp" :: "r"(__isolated_stack) :: "volatile");
+			::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
 
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;		
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence;
-		      mov $1, %rsp"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
-			: "eax", "ecx", "edx"
-			: "volatile");
-
-		set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SAFE_MEM_REGION);
-		temp_ret
+			let temp_ret = $f($($x)*);
+
+			::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
+			asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+
+			set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SAFE_MEM_REGION);
+			isolation_exit(__current_rsp);
+			temp_ret
+		}
 	}};
 
 	($p:tt.$f:ident($($x:tt)*)) => {{
-		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-		use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
-		use mm::{SAFE_MEM_REGION, SHARED_MEM_REGION};
-                use config::DEFAULT_STACK_SIZE;
+		if ::environment::isolation_mode() == ::environment::IsolationMode::Off {
+			$p.$f($($x)*)
+		} else {
+			//unsafe{ ::UNSAFE_COUNTER += 1; }
+			use x86_64::kernel::scheduler::{isolation_enter, isolation_exit};
+			use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
+			use mm::{SAFE_MEM_REGION, SHARED_MEM_REGION};
+
+			let mut __current_rbp: usize = 0;
+			let mut __current_rsp: usize = 0;
+			let mut __count:usize = 0;
+
+			asm!("mov %rbp, $0;
+			      mov %rsp, $1"
+				: "=r"(__current_rbp), "=r"(__current_rsp)
+				:
+				:
+				: "volatile");
 
-		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
-		let mut __current_rbp: usize = 0;
-		let mut __current_rsp: usize = 0;
-		let mut __count:usize = 0;
-
-		asm!("mov %rbp, $0;
-		      mov %rsp, $1"
-			: "=r"(__current_rbp), "=r"(__current_rsp)
-			:
-			:
-			: "volatile");
-
-		__count = (align_up!(__current_rbp, 4096) - align_down!(__current_rsp, 4096))/4096;
-		set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SHARED_MEM_REGION);
-
-		asm!("mov $0, %rsp;
-		      xor %ecx, %ecx;
-		      rdpkru;
-		      or $1, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			: 
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
-			: "eax", "ecx", "edx"
-			: "volatile");
-
-		let temp_ret = $p.$f($($x)*);
-
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;		
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence;
-		      mov $1, %rsp"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
-			: "eax", "ecx", "edx"
-			: "volatile");
-
-		set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SAFE_MEM_REGION);
-		temp_ret
+			__count = (align_up!(__current_rbp, 4096) - align_down!(__current_rsp, 4096))/4096;
+			set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SHARED_MEM_REGION);
+			let __isolated_stack = isolation_enter(__current_rsp);
+
+			asm!("mov $0, %rsp" :: "r"(__isolated_stack) :: "volatile");
+			::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
+
+			let temp_ret = $p.$f($($x)*);
+
+			::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
+			asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+
+			set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SAFE_MEM_REGION);
+			isolation_exit(__current_rsp);
+			temp_ret
+		}
+	}};
+
+	($p:tt::$f:ident($($x:tt)*)) => {{
+		if ::environment::isolation_mode() == ::environment::IsolationMode::Off {
+			$p::$f($($x)*)
+		} else {
+			//unsafe{ ::UNSAFE_COUNTER += 1; }
+			use x86_64::kernel::scheduler::{isolation_enter, isolation_exit};
+			use x86_64::mm::paging::{BasePageSize, set_pkey_on_page_table_entry};
+			use mm::{SAFE_MEM_REGION, SHARED_MEM_REGION};
+
+			let mut __current_rbp: usize = 0;
+			let mut __current_rsp: usize = 0;
+			let mut __count:usize = 0;
+
+			asm!("mov %rbp, $0;
+			      mov %rsp, $1"
+				: "=r"(__current_rbp), "=r"(__current_rsp)
+				:
+				:
+				: "volatile");
+
+			__count = (align_up!(__current_rbp, 4096) - align_down!(__current_rsp, 4096))/4096;
+			set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SHARED_MEM_REGION);
+			let __isolated_stack = isolation_enter(__current_rsp);
+
+			asm!("mov $0, %rsp" :: "r"(__isolated_stack) :: "volatile");
+			::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
+
+			let temp_ret = $p::$f($($x)*);
+
+			::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
+			asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+
+			set_pkey_on_page_table_entry::<BasePageSize>(align_down!(__current_rsp, 4096), __count, SAFE_MEM_REGION);
+			isolation_exit(__current_rsp);
+			temp_ret
+		}
 	}};
 }
 
+#[cfg(not(feature = "no-mpk"))]
 macro_rules! isolate_function_strong {
 	($f:ident($($x:tt)*)) => {{
-		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-                use config::DEFAULT_STACK_SIZE;
-		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
-		let mut __current_rsp: usize = 0;
+		match ::environment::isolation_mode() {
+			::environment::IsolationMode::Off => $f($($x)*),
+			::environment::IsolationMode::Weak => isolate_function_weak!($f($($x)*)),
+			::environment::IsolationMode::Strong => {
+				//unsafe{ ::UNSAFE_COUNTER += 1; }
+				use x86_64::kernel::scheduler::{isolation_enter, isolation_exit};
+				let mut __current_rsp: usize = 0;
 
-		asm!("mov %rsp, $0;
-		      mov $1, %rsp;
-                      push %rax; push %rcx; push %rdx;
-		      xor %ecx, %ecx;
-		      rdpkru;
-		      or $2, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence;
-                      pop %rdx; pop %rcx; pop %rax"
-			: "=r"(__current_rsp)
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
-			:: "volatile");
+				asm!("mov %rsp, $0" : "=r"(__current_rsp) ::: "volatile");
+				/* Saves the caller context and returns the stack pointer for the isolated call (below a suspended isolated frame, if any) */
+				let __isolated_stack = isolation_enter(__current_rsp);
 
-		let temp_ret = $f($($x)*);
+				asm!("mov $0, %rsp" :: "r"(__isolated_stack) :: "volatile");
+				::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
 
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence;
-		      mov $1, %rsp"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
-			: "eax", "ecx", "edx" : "volatile");
+				let temp_ret = $f($($x)*);
 
-		temp_ret
+				::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
+				asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+
+				isolation_exit(__current_rsp);
+				temp_ret
+			}
+		}
 	}};
 		
 	($p:tt.$f:ident($($x:tt)*)) => {{
-		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-                use config::DEFAULT_STACK_SIZE;
-		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
+		match ::environment::isolation_mode() {
+			::environment::IsolationMode::Off => $p.$f($($x)*),
+			::environment::IsolationMode::Weak => isolate_function_weak!($p.$f($($x)*)),
+			::environment::IsolationMode::Strong => {
+				//unsafe{ ::UNSAFE_COUNTER += 1; }
+				use x86_64::kernel::scheduler::{isolation_enter, isolation_exit};
+				let mut __current_rsp: usize = 0;
+
+				asm!("mov %rsp, $0" : "=r"(__current_rsp) ::: "volatile");
+				let __isolated_stack = isolation_enter(__current_rsp);
+
+				asm!("mov $0, %rsp" :: "r"(__isolated_stack) :: "volatile");
+				::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
+
+				let temp_ret = $p.$f($($x)*);
+
+				::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
+				asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+
+				isolation_exit(__current_rsp);
+				temp_ret
+			}
+		}
+	}};
+
+	($p:tt::$f:ident($($x:tt)*)) => {{
+		match ::environment::isolation_mode() {
+			::environment::IsolationMode::Off => $p::$f($($x)*),
+			::environment::IsolationMode::Weak => isolate_function_weak!($p::$f($($x)*)),
+			::environment::IsolationMode::Strong => {
+				//unsafe{ ::UNSAFE_COUNTER += 1; }
+				use x86_64::kernel::scheduler::{isolation_enter, isolation_exit};
+				let mut __current_rsp: usize = 0;
+
+				asm!("mov %rsp, $0" : "=r"(__current_rsp) ::: "volatile");
+				let __isolated_stack = isolation_enter(__current_rsp);
+
+				asm!("mov $0, %rsp" :: "r"(__isolated_stack) :: "volatile");
+				::arch::x86_64::mm::isolation::restrict_permissions(::mm::UNSAFE_PERMISSION_IN);
+
+				let temp_ret = $p::$f($($x)*);
+
+				::arch::x86_64::mm::isolation::relax_permissions(::mm::UNSAFE_PERMISSION_OUT);
+				asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+
+				isolation_exit(__current_rsp);
+				temp_ret
+			}
+		}
+	}};
+}
+
+/// Calls back into the kernel from a function running under isolate_function_strong!/weak!.
+/// The callback runs on the kernel stack below the frame of the suspended isolated call
+/// and may itself start further isolated calls.
+#[cfg(not(feature = "no-mpk"))]
+macro_rules! kernel_callback {
+	($f:ident($($x:tt)*)) => {{
+		use x86_64::kernel::scheduler::{kernel_callback_enter, kernel_callback_exit};
 		let mut __current_rsp: usize = 0;
 
-		asm!("mov %rsp, $0;
-		      mov $1, %rsp;
-                      push %rax; push %rcx; push %rdx;
-		      xor %ecx, %ecx;
-		      rdpkru;
-		      or $2, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence;
-                      pop %rdx; pop %rcx; pop %rax"
-			: "=r"(__current_rsp)
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
-			:: "volatile");
-
-		let temp_ret = $p.$f($($x)*);
-
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence;
-		      mov $1, %rsp"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
-			: "eax", "ecx", "edx" : "volatile");
+		/* Keep the PKRU value of the isolated caller before regaining access to the safe memory region,
+		 * where the isolation contexts are kept */
+		let __caller_pkru = ::arch::x86_64::mm::isolation::read_permissions();
+		::arch::x86_64::mm::isolation::relax_permissions(::mm::CALLBACK_PERMISSION_OUT);
 
-		temp_ret
+		if ::environment::isolation_mode() == ::environment::IsolationMode::Off {
+			$f($($x)*)
+		} else {
+			asm!("mov %rsp, $0" : "=r"(__current_rsp) ::: "volatile");
+			let __kernel_stack = kernel_callback_enter(__current_rsp, __caller_pkru);
+			asm!("mov $0, %rsp" :: "r"(__kernel_stack) :: "volatile");
+
+			let temp_ret = $f($($x)*);
+
+			asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+			/* Restores the PKRU value of the isolated caller */
+			kernel_callback_exit(__current_rsp);
+			temp_ret
+		}
+	}};
+
+	($p:tt.$f:ident($($x:tt)*)) => {{
+		use x86_64::kernel::scheduler::{kernel_callback_enter, kernel_callback_exit};
+		let mut __current_rsp: usize = 0;
+
+		let __caller_pkru = ::arch::x86_64::mm::isolation::read_permissions();
+		::arch::x86_64::mm::isolation::relax_permissions(::mm::CALLBACK_PERMISSION_OUT);
+
+		if ::environment::isolation_mode() == ::environment::IsolationMode::Off {
+			$p.$f($($x)*)
+		} else {
+			asm!("mov %rsp, $0" : "=r"(__current_rsp) ::: "volatile");
+			let __kernel_stack = kernel_callback_enter(__current_rsp, __caller_pkru);
+			asm!("mov $0, %rsp" :: "r"(__kernel_stack) :: "volatile");
+
+			let temp_ret = $p.$f($($x)*);
+
+			asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+			kernel_callback_exit(__current_rsp);
+			temp_ret
+		}
 	}};
 
 	($p:tt::$f:ident($($x:tt)*)) => {{
-		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		use x86_64::kernel::percore::core_scheduler;
-                use config::DEFAULT_STACK_SIZE;
-		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
+		use x86_64::kernel::scheduler::{kernel_callback_enter, kernel_callback_exit};
 		let mut __current_rsp: usize = 0;
 
-		asm!("mov %rsp, $0;
-		      mov $1, %rsp;
-		      xor %ecx, %ecx;
-		      rdpkru;
-		      or $2, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			: "=r"(__current_rsp)
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
-			: "eax", "ecx", "edx"
-			: "volatile");
-
-		let temp_ret = $p::$f($($x)*);
-
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence;
-		      mov $1, %rsp"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
-			: "eax", "ecx", "edx"
-			: "volatile");
+		let __caller_pkru = ::arch::x86_64::mm::isolation::read_permissions();
+		::arch::x86_64::mm::isolation::relax_permissions(::mm::CALLBACK_PERMISSION_OUT);
 
-		temp_ret
+		if ::environment::isolation_mode() == ::environment::IsolationMode::Off {
+			$p::$f($($x)*)
+		} else {
+			asm!("mov %rsp, $0" : "=r"(__current_rsp) ::: "volatile");
+			let __kernel_stack = kernel_callback_enter(__current_rsp, __caller_pkru);
+			asm!("mov $0, %rsp" :: "r"(__kernel_stack) :: "volatile");
+
+			let temp_ret = $p::$f($($x)*);
+
+			asm!("mov $0, %rsp" :: "r"(__current_rsp) :: "volatile");
+			kernel_callback_exit(__current_rsp);
+			temp_ret
+		}
+	}};
+}
+
+/* Without MPK (feature "no-mpk"), the domain switches are plain calls. */
+
+#[cfg(feature = "no-mpk")]
+macro_rules! user_start {
+	($e:expr) => {};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! user_end {
+	() => {};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! kernel_enter {
+	($e:expr) => {};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! kernel_exit {
+	($e:expr) => {};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! kernel_function {
+	($($x:tt)*) => {{
+		#[allow(unused_unsafe)]
+		unsafe {
+			$($x)*
+		}
+	}};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! isolation_start {
+	() => {};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! isolation_end {
+	() => {};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! isolation_wrapper {
+	($($x:tt)*) => {{
+		$($x)*
+	}};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! isolate_function_weak {
+	($($x:tt)*) => {{
+		$($x)*
+	}};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! isolate_function_strong {
+	($($x:tt)*) => {{
+		$($x)*
+	}};
+}
+
+#[cfg(feature = "no-mpk")]
+macro_rules! kernel_callback {
+	($($x:tt)*) => {{
+		$($x)*
 	}};
 }
+
+/// Defines the system call `sys_x` of the application.
+/// With shadow stacks, the symbol `sys_x` is a stub, which enters the function through
+/// mm::shadow_stack::shadow_stack_trampoline. So every call through the symbol, i.e. from the
+/// application, records and checks its return address. Calls from inside the kernel, including
+/// the ring-3 dispatcher, use the function directly. The stub is nested in the function, so that
+/// it shares its attributes, e.g. `#[cfg]`.
+#[cfg(feature = "shadow-stack")]
+macro_rules! syscall {
+	($(#[$attr:meta])* pub extern "C" fn $name:ident($($arg:ident: $ty:ty),* $(,)*) $(-> $ret:ty)* { $($body:tt)* }) => {
+		$(#[$attr])*
+		pub extern "C" fn $name($($arg: $ty),*) $(-> $ret)* {
+			#[naked]
+			#[no_mangle]
+			unsafe extern "C" fn $name() {
+				asm!("lea ${0:c}(%rip), %r11\n\tjmp shadow_stack_trampoline"
+					:
+					: "i"(self::$name as extern "C" fn($($ty),*) $(-> $ret)*)
+					:
+					: "volatile");
+			}
+
+			$($body)*
+		}
+	};
+}
+
+#[cfg(not(feature = "shadow-stack"))]
+macro_rules! syscall {
+	($(#[$attr:meta])* pub extern "C" fn $($x:tt)*) => {
+		$(#[$attr])*
+		#[no_mangle]
+		pub extern "C" fn $($x)*
+	};
+}
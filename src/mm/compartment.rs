@@ -0,0 +1,141 @@
+//! Micro-reboot of the unsafe compartment.
+//!
+//! After a protection fault in isolated code, the state of the unsafe compartment may be corrupt,
+//! even though only the faulting task has been aborted. `snapshot` records .unsafe_data and the
+//! memory handed out by mm::unsafe_allocate once the kernel is initialized. `reset_compartment`
+//! restores this state without restarting the rest of the kernel: it rolls back the snapshot,
+//! zeroes unsafe memory allocated since then, revokes all shared grants (see mm::share) and
+//! wipes the isolated stacks before their next use.
+//!
+//! Memory allocated after the snapshot stays allocated, because its owner may still hold it.
+//! The owner must not rely on its contents across a reset: it compares `generation` before and
+//! after using the memory and reinitializes it once the generation has changed.
+
+use alloc::vec::Vec;
+use arch::x86_64::kernel::scheduler;
+use arch::x86_64::mm::mpk;
+use arch::x86_64::mm::paging::BasePageSize;
+use core::ptr::{copy_nonoverlapping, write_bytes};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use errno::*;
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+/// Memory of the unsafe compartment outside of .unsafe_data
+struct Region {
+	address: usize,
+	size: usize,
+	/// Copy of the contents at the time of the snapshot, 0 if allocated later and thus zeroed
+	snapshot: usize,
+}
+
+safe_global_var!(static UNSAFE_REGIONS: SpinlockIrqSave<Vec<Region>> = SpinlockIrqSave::new(Vec::new()));
+/// Memory of the kernel shared with the unsafe compartment
+safe_global_var!(static SHARED_GRANTS: SpinlockIrqSave<Vec<(usize, usize)>> = SpinlockIrqSave::new(Vec::new()));
+/// Copy of .unsafe_data at the time of the snapshot, 0 until then
+safe_global_var!(static mut DATA_SNAPSHOT: usize = 0);
+/// Number of completed resets
+safe_global_var!(static GENERATION: AtomicUsize = AtomicUsize::new(0));
+
+/// Returns the number of completed resets. Unsafe memory allocated after the snapshot has been
+/// zeroed if it changes.
+pub fn generation() -> usize {
+	GENERATION.load(Ordering::SeqCst)
+}
+
+/// Called by mm::unsafe_allocate.
+pub fn register_unsafe(address: usize, size: usize) {
+	UNSAFE_REGIONS.lock().push(Region {
+		address: address,
+		size: size,
+		snapshot: 0,
+	});
+}
+
+/// Called by mm::share.
+pub fn register_grant(address: usize, size: usize) {
+	SHARED_GRANTS.lock().push((address, size));
+}
+
+/// Called by mm::deallocate, which may free unsafe memory or a shared grant.
+pub fn forget(address: usize) {
+	let region = {
+		let mut regions = UNSAFE_REGIONS.lock();
+		regions
+			.iter()
+			.position(|region| region.address == address)
+			.map(|index| regions.remove(index))
+	};
+	if let Some(region) = region {
+		if region.snapshot != 0 {
+			mm::deallocate(region.snapshot, region.size);
+		}
+	}
+
+	SHARED_GRANTS.lock().retain(|grant| grant.0 != address);
+}
+
+/// Copies `size` bytes at `address` into kernel memory.
+fn copy(address: usize, size: usize) -> usize {
+	let snapshot = mm::allocate(size);
+	unsafe {
+		copy_nonoverlapping(address as *const u8, snapshot as *mut u8, size);
+	}
+	snapshot
+}
+
+/// Records the state of the unsafe compartment, to which reset_compartment returns.
+/// Must be called once, after the kernel has been initialized.
+pub fn snapshot() {
+	unsafe {
+		assert!(DATA_SNAPSHOT == 0, "The unsafe compartment has already been recorded");
+		DATA_SNAPSHOT = copy(mm::UNSAFE_DATA_START, mm::DATA_SECTION_SIZE);
+	}
+
+	let mut regions = UNSAFE_REGIONS.lock();
+	for region in regions.iter_mut() {
+		region.snapshot = copy(region.address, region.size);
+	}
+
+	info!("Recorded the unsafe compartment ({} regions)", regions.len());
+}
+
+/// Restores the unsafe compartment to the state of the snapshot.
+/// Fails with -EBUSY while an isolated call runs, including one of a task, which has been
+/// aborted but not yet cleaned up. Kernel code outside of isolated calls must not use unsafe
+/// memory meanwhile. Shared memory has to be shared again (see mm::share), and unsafe memory
+/// allocated after the snapshot is zeroed (see generation).
+pub fn reset_compartment() -> Result<(), i32> {
+	let data_snapshot = unsafe { DATA_SNAPSHOT };
+	if data_snapshot == 0 {
+		return Err(-EINVAL);
+	}
+	if !scheduler::begin_compartment_reset() {
+		return Err(-EBUSY);
+	}
+
+	unsafe {
+		copy_nonoverlapping(data_snapshot as *const u8, mm::UNSAFE_DATA_START as *mut u8, mm::DATA_SECTION_SIZE);
+	}
+
+	for region in UNSAFE_REGIONS.lock().iter() {
+		unsafe {
+			if region.snapshot != 0 {
+				copy_nonoverlapping(region.snapshot as *const u8, region.address as *mut u8, region.size);
+			} else {
+				write_bytes(region.address as *mut u8, 0, region.size);
+			}
+		}
+	}
+
+	let grants: Vec<(usize, usize)> = SHARED_GRANTS.lock().drain(..).collect();
+	for &(address, size) in grants.iter() {
+		mpk::mpk_mem_set_key::<BasePageSize>(address, size, mm::SAFE_MEM_REGION);
+	}
+
+	GENERATION.fetch_add(1, Ordering::SeqCst);
+	scheduler::end_compartment_reset();
+	info!("Reset the unsafe compartment, revoked {} shared grants", grants.len());
+
+	Ok(())
+}
@@ -0,0 +1,67 @@
+//! Function-pointer tables and trait objects, which are write-protected after boot.
+//!
+//! Statics placed with fnptr_global_var!, e.g. syscalls::SYS, the network driver and the IDT,
+//! decide where the indirect calls of the kernel go. The linker script groups them on their own
+//! pages of the .safe_data window (from __fnptr_start to __fnptr_end). Once the kernel is
+//! initialized, `seal` gives these pages FNPTR_MEM_REGION, whose write-disable bit is set in every
+//! domain (see isolation::write_permissions). From then on, only the function passed to `unseal`
+//! may change them, so that an overwrite primitive cannot redirect the indirect calls.
+
+use arch::mm::isolation;
+#[cfg(not(test))]
+use arch::mm::paging;
+
+#[cfg(not(test))]
+extern "C" {
+	static __fnptr_start: u8;
+	static __fnptr_end: u8;
+}
+
+/// Set by `seal`
+safe_global_var!(static mut SEALED: bool = false);
+
+/// Returns the address and size of the function-pointer tables.
+#[cfg(not(test))]
+pub fn range() -> (usize, usize) {
+	let start = unsafe { &__fnptr_start as *const u8 as usize };
+	let end = unsafe { &__fnptr_end as *const u8 as usize };
+	(start, end - start)
+}
+
+/// Returns `true` once the function-pointer tables are write-protected.
+pub fn is_sealed() -> bool {
+	unsafe { SEALED }
+}
+
+/// Write-protects the function-pointer tables.
+/// Must be called once, after the kernel has been initialized.
+#[cfg(not(test))]
+pub fn seal() {
+	if cfg!(feature = "no-mpk") {
+		return;
+	}
+
+	unsafe {
+		assert!(!SEALED, "The function-pointer tables have already been sealed");
+	}
+
+	let (start, size) = range();
+	if size > 0 {
+		assert!(
+			paging::protect_fnptr_pages(start, size),
+			"The function-pointer tables at {:#X} are not mapped",
+			start
+		);
+	}
+
+	unsafe {
+		SEALED = true;
+	}
+	info!("Write-protected the function-pointer tables at {:#X} ({} bytes)", start, size);
+}
+
+/// Runs `f` with write access to the function-pointer tables, e.g. to install an interrupt handler.
+/// Keep `f` short, it runs with interrupts disabled.
+pub fn unseal<R, F: FnOnce() -> R>(f: F) -> R {
+	isolation::with_fnptr_access(f)
+}
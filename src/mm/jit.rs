@@ -0,0 +1,161 @@
+//! Regions for code generated at runtime, e.g. by a JIT compiler.
+//!
+//! JIT regions carry JIT_MEM_REGION and are writable in the page tables, but the key is
+//! write-disabled in every domain (see isolation::write_permissions). So only the function passed
+//! to `jit_write` may modify them. As all regions share the key, this function could modify any
+//! of them. So only one region is written at a time, and no region is executable meanwhile.
+//! Afterwards, all pages of every region are scanned for instructions writing PKRU, whatever
+//! -wrpkru-scan says (see mm::wrpkru_scan_enforced). Only regions passing the scan become
+//! executable again, rejected ones are overwritten with int3.
+
+use arch::mm::isolation;
+use arch::mm::paging::{self, BasePageSize, PageSize};
+use core::ptr::write_bytes;
+use core::slice;
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+/// Maximum number of JIT regions at the same time
+pub const MAX_JIT_REGIONS: usize = 32;
+
+/// int3, which fills unused and rejected code
+const FILL_BYTE: u8 = 0xCC;
+
+pub type JitId = usize;
+
+#[derive(Clone, Copy)]
+struct JitRegion {
+	address: usize,
+	size: usize,
+	/// Set from `begin_write` to `end_write`, no region is executable meanwhile
+	writing: bool,
+	/// Set once written code has passed the scan
+	executable: bool,
+}
+
+impl JitRegion {
+	/// Size of all pages of the region, which are scanned and made executable as a whole
+	fn page_size(&self) -> usize {
+		align_up!(self.size, BasePageSize::SIZE)
+	}
+}
+
+safe_global_var!(static REGIONS: SpinlockIrqSave<[Option<JitRegion>; MAX_JIT_REGIONS]> = SpinlockIrqSave::new([None; MAX_JIT_REGIONS]));
+
+/// Fills all pages of `region` with int3.
+fn fill(region: &JitRegion) {
+	isolation::with_jit_access(|| unsafe { write_bytes(region.address as *mut u8, FILL_BYTE, region.page_size()) });
+}
+
+/// Allocates a JIT region of `size` bytes, which is filled with int3.
+/// Returns None if `size` is zero or all slots are taken.
+pub fn allocate(size: usize) -> Option<JitId> {
+	if size == 0 {
+		return None;
+	}
+
+	let mut regions = REGIONS.lock();
+	let id = regions.iter().position(|region| region.is_none())?;
+
+	// The region only becomes executable once code has been written and checked.
+	let region = JitRegion {
+		address: mm::guarded_allocate(size, mm::JIT_MEM_REGION),
+		size: size,
+		writing: false,
+		executable: false,
+	};
+	fill(&region);
+	regions[id] = Some(region);
+
+	Some(id)
+}
+
+/// Frees the JIT region `id`. Returns false if there is no such region or it is being written.
+/// Nobody may execute the region anymore.
+pub fn free(id: JitId) -> bool {
+	let region = {
+		let mut regions = REGIONS.lock();
+		match regions.get_mut(id) {
+			Some(slot) if slot.map_or(false, |region| !region.writing) => slot.take().unwrap(),
+			_ => return false,
+		}
+	};
+
+	fill(&region);
+	// The translation goes first, so that the range cannot be handed out again while it is still executable.
+	mm::guarded_free(region.address, region.size);
+
+	true
+}
+
+/// Returns the address and size of the JIT region `id`.
+pub fn region(id: JitId) -> Option<(usize, usize)> {
+	let region = *REGIONS.lock().get(id)?;
+	region.map(|region| (region.address, region.size))
+}
+
+/// Takes the execute right from all JIT regions, so that code can be written to the region
+/// `id`, and returns its address and size. Returns None if there is no such region or a region
+/// is already being written.
+pub fn begin_write(id: JitId) -> Option<(usize, usize)> {
+	let mut regions = REGIONS.lock();
+	if regions.iter().flatten().any(|region| region.writing) {
+		return None;
+	}
+
+	let region = regions.get_mut(id)?.as_mut()?;
+	region.writing = true;
+	let (address, size) = (region.address, region.size);
+
+	for region in regions.iter().flatten().filter(|region| region.executable || region.writing) {
+		paging::set_jit_page_rights(region.address, region.page_size(), false);
+	}
+	Some((address, size))
+}
+
+/// Checks the code written to the JIT region `id` since `begin_write` and the other executable
+/// regions, which could have been modified meanwhile, for instructions writing PKRU. Regions
+/// passing the check become executable again. Rejected code is overwritten with int3 and its
+/// region stays non-executable. Returns false if the code of the region `id` is rejected.
+pub fn end_write(id: JitId) -> bool {
+	let mut regions = REGIONS.lock();
+	match regions.get(id) {
+		Some(Some(region)) if region.writing => {}
+		_ => return false,
+	}
+
+	for (index, slot) in regions.iter_mut().enumerate() {
+		let region = match slot {
+			Some(region) if region.executable || region.writing => region,
+			_ => continue,
+		};
+		region.writing = false;
+
+		region.executable = mm::wrpkru_scan_enforced(region.address, region.page_size());
+		if region.executable {
+			paging::set_jit_page_rights(region.address, region.page_size(), true);
+		} else {
+			if index != id {
+				warn!("JIT region {} was modified while region {} was written", index, id);
+			}
+			fill(region);
+		}
+	}
+
+	regions[id].map_or(false, |region| region.executable)
+}
+
+/// Runs `f` with write access to the JIT region `id` and returns its result.
+/// Returns None if there is no such region, it is already being written or the code written
+/// by `f` is rejected. `f` runs with interrupts disabled.
+pub fn jit_write<R, F: FnOnce(&mut [u8]) -> R>(id: JitId, f: F) -> Option<R> {
+	let (address, size) = begin_write(id)?;
+
+	let ret = isolation::with_jit_access(|| f(unsafe { slice::from_raw_parts_mut(address as *mut u8, size) }));
+
+	if end_write(id) {
+		Some(ret)
+	} else {
+		None
+	}
+}
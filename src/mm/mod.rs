@@ -6,8 +6,13 @@
 // copied, modified, or distributed except according to those terms.
 
 pub mod allocator;
+pub mod compartment;
+pub mod fnptr;
 pub mod freelist;
 mod hole;
+pub mod jit;
+pub mod secret;
+pub mod shadow_stack;
 #[cfg(test)]
 mod test;
 
@@ -17,8 +22,11 @@ use arch::mm::physicalmem::total_memory_size;
 #[cfg(feature = "newlib")]
 use arch::mm::virtualmem::kernel_heap_end;
 use core::mem;
+use core::slice;
 use core::sync::atomic::spin_loop_hint;
 use environment;
+use environment::WrpkruScan;
+use policy;
 
 #[allow(unused)]
 /// Physical and virtual address of the first 2 MiB page that maps the kernel.
@@ -42,17 +50,124 @@ safe_global_var!(static mut USER_HEAP_START_ADDRESS: usize = 0);
 safe_global_var!(static mut USER_HEAP_END_ADDRESS: usize = 0);
 safe_global_var!(static mut USER_HEAP_SIZE: usize = 0);
 
-pub const SAFE_MEM_REGION: u8 = 1;
-pub const UNSAFE_MEM_REGION: u8 = 2;
-pub const SHARED_MEM_REGION: u8 = 3;
+// The keys, sections and rights are defined by the isolation policy (see isolation.toml).
+pub const SAFE_MEM_REGION: u8 = policy::KERNEL_KEY;
+pub const UNSAFE_MEM_REGION: u8 = policy::UNSAFE_KEY;
+pub const SHARED_MEM_REGION: u8 = policy::SHARED_KEY;
+/// Key of sealed memory, which is read-only in every domain (see `seal`)
+pub const SEALED_MEM_REGION: u8 = policy::SEALED_KEY;
+/// Key of secret memory, which is only accessible inside `secret::with_secret`
+pub const SECRET_MEM_REGION: u8 = policy::SECRET_KEY;
+/// Key of JIT regions, which are only writable inside `jit::jit_write`
+pub const JIT_MEM_REGION: u8 = policy::JIT_KEY;
+/// Key of the shadow stacks, which are only writable inside the gates of `shadow_stack`
+pub const SHADOW_MEM_REGION: u8 = policy::SHADOW_KEY;
+/// Key of the function-pointer tables, which are only writable inside `fnptr::unseal`
+pub const FNPTR_MEM_REGION: u8 = policy::FNPTR_KEY;
 //pub const USER_MEM_REGION: u8 = 10;
+/// First protection key handed out to the application, the keys below are reserved by the kernel
+pub const FIRST_APPLICATION_KEY: u8 = policy::FIRST_FREE_KEY;
 
-pub const UNSAFE_PERMISSION_IN: u32 = 0xC;
+/// Identity-mapped windows of the .safe_data and .unsafe_data sections (see the linker script)
+pub const SAFE_DATA_START: usize = policy::SAFE_DATA_START;
+pub const UNSAFE_DATA_START: usize = policy::UNSAFE_DATA_START;
+pub const DATA_SECTION_SIZE: usize = policy::DATA_SECTION_SIZE;
+
+/// Size of the unmapped guard region below each guarded allocation (see `guarded_allocate`)
+pub const GUARD_PAGE_SIZE: usize = BasePageSize::SIZE;
+
+pub const UNSAFE_PERMISSION_IN: u32 = policy::ISOLATED_PERMISSION;
 pub const UNSAFE_PERMISSION_OUT: u32 = !UNSAFE_PERMISSION_IN;
+/// Restrictions kept by kernel_callback!, which also gives back key 0 to modules (see module::enter)
+pub const CALLBACK_PERMISSION_OUT: u32 = UNSAFE_PERMISSION_OUT & !0b11;
 
 //pub const USER_PERMISSION_IN: u32 = 0xfC;
 //pub const USER_PERMISSION_OUT: u32 = !USER_PERMISSION_IN;
 
+/// Write-disable bit of SEALED_MEM_REGION, which is set in every domain
+pub const SEALED_PERMISSION: u32 = 1 << (2 * SEALED_MEM_REGION as u32 + 1);
+/// Access-disable and write-disable bits of SECRET_MEM_REGION
+pub const SECRET_PERMISSION: u32 = 0b11 << (2 * SECRET_MEM_REGION as u32);
+/// Write-disable bit of JIT_MEM_REGION
+pub const JIT_PERMISSION: u32 = 1 << (2 * JIT_MEM_REGION as u32 + 1);
+/// Write-disable bit of SHADOW_MEM_REGION
+pub const SHADOW_PERMISSION: u32 = 1 << (2 * SHADOW_MEM_REGION as u32 + 1);
+/// Write-disable bit of FNPTR_MEM_REGION
+pub const FNPTR_PERMISSION: u32 = 1 << (2 * FNPTR_MEM_REGION as u32 + 1);
+/// Restrictions kept by every switch of the access rights (see isolation::write_permissions)
+pub const ENFORCED_PERMISSION: u32 =
+	SEALED_PERMISSION | SECRET_PERMISSION | JIT_PERMISSION | SHADOW_PERMISSION | FNPTR_PERMISSION;
+
+/// Access rights of the keys handed out to the application (see syscalls::sys_pkey_alloc),
+/// which are kept by every gate
+pub const APPLICATION_KEY_PERMISSION: u32 = !0 << (2 * FIRST_APPLICATION_KEY as u32);
+/// Access-disable bits of the keys of the application. Like on Linux, a new task starts with them.
+pub const APPLICATION_KEY_DISABLED: u32 = 0x5555_5555 & APPLICATION_KEY_PERMISSION;
+
+/// Access rights of the kernel and the default ones of the application (in PKRU format)
+pub const KERNEL_PERMISSION: u32 = ENFORCED_PERMISSION;
+pub const USER_PERMISSION: u32 = policy::USER_PERMISSION | ENFORCED_PERMISSION | APPLICATION_KEY_DISABLED;
+
+/// Access rights of the kernel, keeping the rights of the current task for its own keys.
+#[inline(always)]
+pub fn kernel_permission() -> u32 {
+	KERNEL_PERMISSION | (arch::mm::isolation::read_permissions() & APPLICATION_KEY_PERMISSION)
+}
+
+/// Access rights of the application, which may be changed by the -pkru-user command-line option.
+/// The rights of the current task for its own keys are kept.
+#[inline]
+pub fn user_permission() -> u32 {
+	(environment::isolation_config().user_permission & !APPLICATION_KEY_PERMISSION)
+		| (arch::mm::isolation::read_permissions() & APPLICATION_KEY_PERMISSION)
+}
+
+/// Returns `true` if `window` starts with XRSTOR or XRSTORS (0F AE /5 or 0F C7 /3 with a memory operand).
+fn is_xrstor(window: &[u8]) -> bool {
+	let modrm = window[2];
+	let reg = (modrm >> 3) & 0b111;
+
+	window[0] == 0x0f && modrm >> 6 != 0b11 && ((window[1] == 0xae && reg == 5) || (window[1] == 0xc7 && reg == 3))
+}
+
+/// Checks code, which is about to become executable, for WRPKRU instructions.
+/// These would allow the code to leave its domain without passing a gate.
+/// XRSTOR and XRSTORS count as well, because they load PKRU once it is an XSAVE state component
+/// (see processor::PkruState).
+/// Returns `false` if the code must be rejected according to the -wrpkru-scan command-line option.
+pub fn wrpkru_scan(start: usize, size: usize) -> bool {
+	scan_code(start, size, environment::isolation_config().wrpkru_scan)
+}
+
+/// Like `wrpkru_scan`, but always rejects code writing PKRU, whatever -wrpkru-scan says.
+pub fn wrpkru_scan_enforced(start: usize, size: usize) -> bool {
+	scan_code(start, size, WrpkruScan::Enforce)
+}
+
+fn scan_code(start: usize, size: usize, mode: WrpkruScan) -> bool {
+	const WRPKRU: [u8; 3] = [0x0f, 0x01, 0xef];
+
+	let code = unsafe { slice::from_raw_parts(start as *const u8, size) };
+	let count = code
+		.windows(WRPKRU.len())
+		.filter(|window| *window == WRPKRU || is_xrstor(window))
+		.count();
+	if count == 0 {
+		return true;
+	}
+
+	match mode {
+		WrpkruScan::Warn => {
+			warn!("Found {} instruction(s) writing PKRU in code at {:#X} ({} bytes)", count, start, size);
+			true
+		}
+		WrpkruScan::Enforce => {
+			error!("Rejecting code at {:#X} ({} bytes) with {} instruction(s) writing PKRU", start, size, count);
+			false
+		}
+	}
+}
+
 pub fn kernel_start_address() -> usize {
 	unsafe { KERNEL_START_ADDRESS }
 }
@@ -61,6 +176,11 @@ pub fn kernel_end_address() -> usize {
 	unsafe { KERNEL_END_ADDRESS }
 }
 
+/// Returns whether `virtual_address` lies in the heap of the application (see USER_ALLOCATOR).
+pub fn is_user_heap(virtual_address: usize) -> bool {
+	unsafe { virtual_address >= USER_HEAP_START_ADDRESS && virtual_address < USER_HEAP_END_ADDRESS }
+}
+
 #[cfg(feature = "newlib")]
 pub fn task_heap_start() -> usize {
 	unsafe { USER_HEAP_START_ADDRESS }
@@ -77,7 +197,7 @@ fn map_heap<S: PageSize>(virt_addr: usize, size: usize, is_kernel: bool) -> usiz
 
 	if is_kernel {
 		// map the kernel heap
-		flags.normal().writable().execute_disable().pkey(UNSAFE_MEM_REGION);
+		flags.normal().writable().execute_disable().pkey(SAFE_MEM_REGION);
 	} else {
 		// map the user heap
 		flags.normal().writable().execute_disable();
@@ -147,6 +267,8 @@ pub fn init() {
 	allocate_safe_data();
 	/* Init  .unsafe_data section */
 	allocate_unsafe_data();
+	/* W^X for the rest of the kernel image */
+	arch::mm::paging::protect_kernel_image();
 
 	let mut map_addr: usize;
 	let mut map_size: usize;
@@ -156,7 +278,7 @@ pub fn init() {
 		info!("An application with a C-based runtime is running on top of HermitCore!");
 
 		let size = 2 * LargePageSize::SIZE;
-		let start = allocate(size, true);
+		let start = allocate(size);
 		unsafe {
 			::ALLOCATOR.init(start, size);
 		}
@@ -180,13 +302,16 @@ pub fn init() {
                         map_size -= counter;
                         map_addr += counter;
 
-                        // remap kernel heap
+                        // remap the heap of sys_malloc, which is shared with the isolated C runtime
                         for i in 0..size/LargePageSize::SIZE {
                                 let mut flags = PageTableEntryFlags::empty();
                                 flags.normal().writable().execute_disable().pkey(UNSAFE_MEM_REGION);
                                 let physical_addr = align_down!(arch::mm::paging::virtual_to_physical(HEAP_START_ADDRESS +  i*LargePageSize::SIZE), LargePageSize::SIZE);
                                 arch::mm::paging::map::<LargePageSize>(HEAP_START_ADDRESS +  i*LargePageSize::SIZE, physical_addr, 1, flags);
                         }
+
+                        // sys_malloc must not hand out memory of the kernel heap
+                        ::USER_ALLOCATOR.init(HEAP_START_ADDRESS, size);
                 }
 	}
 
@@ -284,7 +409,8 @@ pub fn init_user_allocator() {
 		unsafe {
 			USER_HEAP_START_ADDRESS = user_heap_start_addr;
 			USER_HEAP_END_ADDRESS = user_heap_start_addr + user_heap_size;
-			::ALLOCATOR.init(user_heap_start_addr, user_heap_size);
+			// The kernel heap stays in use for kernel-internal allocations.
+			::USER_ALLOCATOR.init(user_heap_start_addr, user_heap_size);
 		}
         }
 }
@@ -315,12 +441,13 @@ fn init_pages_before_kernel()
 	let mut flags = PageTableEntryFlags::empty();
 	flags.normal().writable().execute_disable().pkey(SAFE_MEM_REGION);
 	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
+	arch::mm::paging::record_identity_mapping(physical_address, physical_address + count * BasePageSize::SIZE);
 
 	/* The first 4kb page is used by user (as a null pointer) */
 	arch::mm::paging::set_pkey_on_page_table_entry::<BasePageSize>(0x0usize, 1, 0x00u8);
 }
 
-pub fn allocate(sz: usize, execute_disable: bool) -> usize {
+pub fn allocate(sz: usize) -> usize {
 	let size = align_up!(sz, BasePageSize::SIZE);
 
 	let physical_address = arch::mm::physicalmem::allocate(size).unwrap();
@@ -328,16 +455,13 @@ pub fn allocate(sz: usize, execute_disable: bool) -> usize {
 
 	let count = size / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().pkey(SAFE_MEM_REGION);
-	if execute_disable {
-		flags.execute_disable();
-	}
+	flags.normal().writable().execute_disable().pkey(SAFE_MEM_REGION);
 	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
 
 	virtual_address
 }
 
-pub fn unsafe_allocate(sz: usize, execute_disable: bool) -> usize {
+pub fn unsafe_allocate(sz: usize) -> usize {
 	let size = align_up!(sz, BasePageSize::SIZE);
 
 	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
@@ -345,16 +469,15 @@ pub fn unsafe_allocate(sz: usize, execute_disable: bool) -> usize {
 
 	let count = size / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().pkey(UNSAFE_MEM_REGION);
-	if execute_disable {
-		flags.execute_disable();
-	}
+	flags.normal().writable().execute_disable().pkey(UNSAFE_MEM_REGION);
 	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
+	// Rolled back by compartment::reset_compartment
+	compartment::register_unsafe(virtual_address, size);
 
 	virtual_address
 }
 
-pub fn shared_allocate(sz: usize, execute_disable: bool) -> usize {
+pub fn shared_allocate(sz: usize) -> usize {
 	let size = align_up!(sz, BasePageSize::SIZE);
 
 	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
@@ -362,16 +485,25 @@ pub fn shared_allocate(sz: usize, execute_disable: bool) -> usize {
 
 	let count = size / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().pkey(SHARED_MEM_REGION);
-	if execute_disable {
-		flags.execute_disable();
-	}
+	flags.normal().writable().execute_disable().pkey(SHARED_MEM_REGION);
 	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
+	// Revoked by compartment::reset_compartment
+	compartment::register_grant(virtual_address, size);
 
 	virtual_address
 }
 
-pub fn user_allocate(sz: usize, execute_disable: bool) -> usize {
+/// Shares `size` bytes of kernel memory at the page-aligned `virtual_address` with the unsafe
+/// compartment by giving them SHARED_MEM_REGION. The grant lasts until the memory is freed or
+/// the compartment is reset (see compartment::reset_compartment).
+pub fn share(virtual_address: usize, size: usize) {
+	let size = align_up!(size, BasePageSize::SIZE);
+
+	arch::mm::mpk::mpk_mem_set_key::<BasePageSize>(virtual_address, size, SHARED_MEM_REGION);
+	compartment::register_grant(virtual_address, size);
+}
+
+pub fn user_allocate(sz: usize) -> usize {
 	let size = align_up!(sz, BasePageSize::SIZE);
 
 	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
@@ -379,20 +511,145 @@ pub fn user_allocate(sz: usize, execute_disable: bool) -> usize {
 
 	let count = size / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable();
-	if execute_disable {
-		flags.execute_disable();
+	flags.normal().writable().execute_disable();
+	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
+
+	virtual_address
+}
+
+/// Turns `size` bytes at `virtual_address`, which have been allocated before, into read-only
+/// and executable code. Memory is never executable otherwise (W^X).
+/// Returns `false` if the code is rejected by the WRPKRU scan or the range is not mapped.
+pub fn make_executable(virtual_address: usize, size: usize) -> bool {
+	make_executable_with(virtual_address, size, environment::isolation_config().wrpkru_scan)
+}
+
+/// Like `make_executable`, but always rejects code writing PKRU, whatever -wrpkru-scan says.
+/// Used for code that must not leave its compartment, e.g. modules.
+pub fn make_executable_enforced(virtual_address: usize, size: usize) -> bool {
+	make_executable_with(virtual_address, size, WrpkruScan::Enforce)
+}
+
+fn make_executable_with(virtual_address: usize, size: usize, mode: WrpkruScan) -> bool {
+	// Write-protect the code first, so that it cannot change after the scan.
+	if !arch::mm::paging::set_page_rights(virtual_address, size, false, false) {
+		return false;
+	}
+
+	if !scan_code(virtual_address, size, mode) {
+		arch::mm::paging::set_page_rights(virtual_address, size, true, false);
+		return false;
+	}
+
+	arch::mm::paging::set_page_rights(virtual_address, size, false, true)
+}
+
+/// Seals `size` bytes at the page-aligned `virtual_address`. The pages get SEALED_MEM_REGION,
+/// which every domain may read but none may write, and lose their write and execute rights.
+/// There is no way back: the rights and the key of sealed pages never change again and they are
+/// never freed. Only seal data that may be read by the unsafe domain.
+/// Returns false if the range is not page-aligned or not mapped.
+pub fn seal(virtual_address: usize, size: usize) -> bool {
+	if virtual_address % BasePageSize::SIZE != 0 || size == 0 {
+		error!("Cannot seal {:#X} bytes at {:#X}, the range must start at a page", size, virtual_address);
+		return false;
 	}
+
+	if !arch::mm::paging::seal_pages(virtual_address, size) {
+		return false;
+	}
+
+	// Other cores pick up the write-disable bit of the key with their next switch,
+	// until then the missing WRITABLE bit protects the pages.
+	arch::mm::isolation::write_permissions(arch::mm::isolation::read_permissions());
+	info!("Sealed {:#X} bytes at {:#X}", align_up!(size, BasePageSize::SIZE), virtual_address);
+
+	true
+}
+
+#[cfg(not(test))]
+extern "C" {
+	static __sealed_start: u8;
+	static __sealed_end: u8;
+}
+
+/// Seals the statics placed with sealed_global_var!, e.g. the parsed command line, the ACPI
+/// tables and the CPU features. The linker script groups them on their own pages of the
+/// .safe_data window (from __sealed_start to __sealed_end).
+/// Must be called once, after the kernel and all processors have been initialized.
+#[cfg(not(test))]
+pub fn seal_boot_data() {
+	if cfg!(feature = "no-mpk") {
+		return;
+	}
+
+	let start = unsafe { &__sealed_start as *const u8 as usize };
+	let end = unsafe { &__sealed_end as *const u8 as usize };
+	if end > start {
+		assert!(seal(start, end - start), "Unable to seal the boot data at {:#X}", start);
+	}
+}
+
+/// Allocates `sz` bytes with the protection key `pkey` and keeps an unmapped guard page
+/// right below the returned address. Mainly used for stacks, which grow downwards, so that
+/// an overflow faults instead of silently running into the neighbouring allocation.
+///
+/// A `pkey` of 0 maps the memory without a protection key (like `user_allocate`).
+pub fn guarded_allocate(sz: usize, pkey: u8) -> usize {
+	let size = align_up!(sz, BasePageSize::SIZE);
+
+	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
+	let guard_address = arch::mm::virtualmem::allocate_aligned(size + GUARD_PAGE_SIZE, BasePageSize::SIZE).unwrap();
+	let virtual_address = guard_address + GUARD_PAGE_SIZE;
+
+	// deallocate() does not unmap, so the guard page may still carry a stale translation.
+	arch::mm::paging::unmap::<BasePageSize>(guard_address, GUARD_PAGE_SIZE / BasePageSize::SIZE);
+
+	let count = size / BasePageSize::SIZE;
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable().pkey(pkey);
 	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
 
 	virtual_address
 }
 
+/// Releases memory allocated by `guarded_allocate` together with its guard page.
+pub fn guarded_deallocate(virtual_address: usize, sz: usize) {
+	let size = align_up!(sz, BasePageSize::SIZE);
+
+	if let Some(entry) = arch::mm::paging::get_page_table_entry::<BasePageSize>(virtual_address) {
+		arch::mm::virtualmem::deallocate(virtual_address - GUARD_PAGE_SIZE, size + GUARD_PAGE_SIZE);
+		arch::mm::physicalmem::deallocate(entry.address(), size);
+	} else {
+		panic!(
+			"No page table entry for virtual address {:#X}",
+			virtual_address
+		);
+	}
+}
+
+/// Like `guarded_deallocate`, but removes the translation first. Needed for memory whose
+/// contents or rights must not be reachable anymore once the range is handed out again.
+pub fn guarded_free(virtual_address: usize, sz: usize) {
+	let size = align_up!(sz, BasePageSize::SIZE);
+
+	if let Some(entry) = arch::mm::paging::get_page_table_entry::<BasePageSize>(virtual_address) {
+		arch::mm::paging::unmap::<BasePageSize>(virtual_address, size / BasePageSize::SIZE);
+		arch::mm::virtualmem::deallocate(virtual_address - GUARD_PAGE_SIZE, size + GUARD_PAGE_SIZE);
+		arch::mm::physicalmem::deallocate(entry.address(), size);
+	} else {
+		panic!(
+			"No page table entry for virtual address {:#X}",
+			virtual_address
+		);
+	}
+}
+
 fn allocate_safe_data() {
-    let safe_data_start = 0x400000usize;
-	let aligned_size = 0x200000usize;
+    let safe_data_start = SAFE_DATA_START;
+	let aligned_size = DATA_SECTION_SIZE;
 	/* We harcode the physical address here */
-	let physical_address = 0x400000usize;
+	let physical_address = SAFE_DATA_START;
 	//let physical_address = arch::mm::physicalmem::allocate_aligned(aligned_size, LargePageSize::SIZE).unwrap();
 	let count = aligned_size / LargePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
@@ -403,10 +660,10 @@ fn allocate_safe_data() {
 }
 
 fn allocate_unsafe_data() {
-    let unsafe_data_start = 0x600000usize;
-	let aligned_size = 0x200000usize;
+    let unsafe_data_start = UNSAFE_DATA_START;
+	let aligned_size = DATA_SECTION_SIZE;
 	/* We harcode the physical address here */
-	let physical_address = 0x600000usize;
+	let physical_address = UNSAFE_DATA_START;
 	let count = aligned_size / LargePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
 	flags.normal().writable().pkey(UNSAFE_MEM_REGION);
@@ -418,6 +675,16 @@ fn allocate_unsafe_data() {
 pub fn deallocate(virtual_address: usize, sz: usize) {
 	let size = align_up!(sz, BasePageSize::SIZE);
 
+	if arch::mm::paging::is_sealed(virtual_address) {
+		error!("Refusing to free the sealed memory at {:#X}", virtual_address);
+		return;
+	}
+
+	match arch::mm::paging::get_pkey(virtual_address) {
+		Some(UNSAFE_MEM_REGION) | Some(SHARED_MEM_REGION) => compartment::forget(virtual_address),
+		_ => {}
+	}
+
 	if let Some(entry) = arch::mm::paging::get_page_table_entry::<BasePageSize>(virtual_address) {
 		arch::mm::virtualmem::deallocate(virtual_address, size);
 		arch::mm::physicalmem::deallocate(entry.address(), size);
@@ -0,0 +1,151 @@
+//! Secret memory for key material and tokens.
+//!
+//! Secret regions carry SECRET_MEM_REGION, which no domain may access, not even the kernel
+//! (see isolation::write_permissions). Only the function passed to `with_secret` gets access.
+//! All regions share this key, so that function could reach every secret.
+
+use arch::mm::isolation;
+use arch::mm::paging::{self, BasePageSize, PageSize};
+use core::ptr::write_bytes;
+use core::slice;
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+/// Maximum number of secret regions at the same time
+pub const MAX_SECRETS: usize = 32;
+
+pub type SecretId = usize;
+
+#[derive(Clone, Copy)]
+struct SecretRegion {
+	address: usize,
+	size: usize,
+	/// Number of `acquire` calls without `release`
+	users: usize,
+	/// Set by `free` while the region is still in use, which then frees it on the last `release`
+	destroyed: bool,
+}
+
+safe_global_var!(static SECRETS: SpinlockIrqSave<[Option<SecretRegion>; MAX_SECRETS]> = SpinlockIrqSave::new([None; MAX_SECRETS]));
+
+/// Overwrites all pages of `region` with zeros.
+fn zeroize(region: &SecretRegion) {
+	let size = align_up!(region.size, BasePageSize::SIZE);
+	isolation::with_secret_access(|| unsafe { write_bytes(region.address as *mut u8, 0, size) });
+}
+
+/// Zeroizes `region` and returns its memory.
+/// The translation goes first, so that the range cannot be handed out again while it is still mapped.
+fn release_region(region: SecretRegion) {
+	zeroize(&region);
+	mm::guarded_free(region.address, region.size);
+}
+
+/// Allocates a zeroed secret region of `size` bytes.
+/// Returns None if `size` is zero or all slots are taken.
+pub fn allocate(size: usize) -> Option<SecretId> {
+	if size == 0 {
+		return None;
+	}
+
+	let mut secrets = SECRETS.lock();
+	let id = secrets.iter().position(|secret| secret.is_none())?;
+
+	// The guard page catches overflows into the secret as well as out of it.
+	let region = SecretRegion {
+		address: mm::guarded_allocate(size, mm::SECRET_MEM_REGION),
+		size: size,
+		users: 0,
+		destroyed: false,
+	};
+	zeroize(&region);
+	secrets[id] = Some(region);
+
+	Some(id)
+}
+
+/// Zeroizes and frees the secret `id`. Returns false if there is no such secret.
+/// A secret, which is still acquired, is freed by the last `release`.
+pub fn free(id: SecretId) -> bool {
+	let region = {
+		let mut secrets = SECRETS.lock();
+		let secret = match secrets.get_mut(id) {
+			Some(secret) => secret,
+			None => return false,
+		};
+
+		match secret {
+			Some(region) if !region.destroyed => {
+				if region.users > 0 {
+					region.destroyed = true;
+					return true;
+				}
+			}
+			_ => return false,
+		}
+
+		secret.take().unwrap()
+	};
+
+	release_region(region);
+	true
+}
+
+/// Returns the address and size of the secret `id` and keeps it alive until `release`.
+/// Returns None if there is no such secret.
+pub fn acquire(id: SecretId) -> Option<(usize, usize)> {
+	let mut secrets = SECRETS.lock();
+	match secrets.get_mut(id)? {
+		Some(region) if !region.destroyed => {
+			region.users += 1;
+			Some((region.address, region.size))
+		}
+		_ => None,
+	}
+}
+
+/// Ends an `acquire` of the secret `id`.
+pub fn release(id: SecretId) {
+	let region = {
+		let mut secrets = SECRETS.lock();
+		let secret = secrets.get_mut(id).expect("Released an invalid secret");
+		{
+			let region = secret.as_mut().expect("Released a freed secret");
+			region.users -= 1;
+			if region.users > 0 || !region.destroyed {
+				return;
+			}
+		}
+
+		secret.take().unwrap()
+	};
+
+	release_region(region);
+}
+
+/// Returns the address and size of the secret `id`.
+pub fn region(id: SecretId) -> Option<(usize, usize)> {
+	let secret = *SECRETS.lock().get(id)?;
+	secret
+		.filter(|region| !region.destroyed)
+		.map(|region| (region.address, region.size))
+}
+
+/// Runs `f` with the bytes of the secret `id` and returns its result.
+/// Returns None if there is no such secret.
+/// `f` runs with interrupts disabled, but without holding the lock of the secrets, so it may use
+/// this API as well. If it frees the secret, the memory is released once `f` returns.
+pub fn with_secret<R, F: FnOnce(&mut [u8]) -> R>(id: SecretId, f: F) -> Option<R> {
+	let (address, size) = acquire(id)?;
+	let ret = isolation::with_secret_access(|| {
+		f(unsafe { slice::from_raw_parts_mut(address as *mut u8, size) })
+	});
+	release(id);
+
+	Some(ret)
+}
+
+/// Returns whether `virtual_address` lies in secret memory.
+pub fn is_secret(virtual_address: usize) -> bool {
+	paging::get_pkey(virtual_address) == Some(mm::SECRET_MEM_REGION)
+}
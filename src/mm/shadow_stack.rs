@@ -0,0 +1,178 @@
+//! Shadow stacks for return addresses (feature shadow-stack).
+//!
+//! There is no CET hardware, so the return addresses of selected kernel functions, starting with
+//! the system calls (see syscall!), are recorded on a shadow stack of the task as
+//! well. Its pages carry SHADOW_MEM_REGION, which every domain may read, but which is only
+//! writable inside the gates of `shadow_stack_trampoline`. The gates find the shadow stack of the
+//! current task through the per-core data of the shadow stacks, so they need no rights of the
+//! kernel. A return address, which does not match the shadow stack, is fatal. This stops ROP
+//! chains through the WRPKRU gates, which the keys alone cannot stop.
+
+use arch::x86_64::kernel::percore;
+use arch::x86_64::mm::mpk;
+use arch::x86_64::mm::isolation;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use core::ptr::write_bytes;
+use mm;
+
+/// Size of a shadow stack, which is followed by a guard page like the other stacks
+pub const SHADOW_STACK_SIZE: usize = BasePageSize::SIZE;
+
+/// Number of return addresses on a shadow stack
+const SHADOW_STACK_ENTRIES: usize = SHADOW_STACK_SIZE / 8 - 1;
+
+#[repr(C)]
+struct ShadowStack {
+	depth: usize,
+	entries: [usize; SHADOW_STACK_ENTRIES],
+}
+
+/// Returns `true` if the kernel has been built with shadow stacks.
+#[inline(always)]
+pub fn is_enabled() -> bool {
+	cfg!(feature = "shadow-stack")
+}
+
+/// Sets up the per-core data of the shadow stacks, if the kernel has been built with them.
+pub fn init() {
+	if is_enabled() {
+		percore::init_shadow_stacks();
+	}
+}
+
+/// Allocates an empty shadow stack for a new task. Returns 0 if shadow stacks are disabled.
+pub fn allocate() -> usize {
+	if !is_enabled() {
+		return 0;
+	}
+
+	// Empty the stack while it is still writable.
+	let stack = mm::guarded_allocate(SHADOW_STACK_SIZE, mm::SAFE_MEM_REGION);
+	unsafe {
+		write_bytes(stack as *mut u8, 0, SHADOW_STACK_SIZE);
+	}
+	mpk::mpk_mem_set_key::<BasePageSize>(stack, SHADOW_STACK_SIZE, mm::SHADOW_MEM_REGION);
+
+	stack
+}
+
+/// Frees the shadow stack of a finished task.
+pub fn deallocate(stack: usize) {
+	if stack != 0 {
+		// Every domain could read the stack through a remaining translation.
+		mm::guarded_free(stack, SHADOW_STACK_SIZE);
+	}
+}
+
+/// Called by the scheduler before it switches to a task with the shadow stack `stack`.
+pub fn switch(stack: usize) {
+	if is_enabled() {
+		isolation::with_shadow_stack_access(|| percore::set_shadow_stack(stack));
+	}
+}
+
+/// Runs `f` on the shadow stack of the current task with write access to it.
+#[cfg(feature = "shadow-stack")]
+#[inline(always)]
+fn with_shadow_stack<R, F: FnOnce(&mut ShadowStack) -> R>(f: F) -> R {
+	isolation::with_shadow_stack_access(|| f(unsafe { &mut *(percore::shadow_stack() as *mut ShadowStack) }))
+}
+
+/// Records `return_address` before a function runs (see shadow_stack_trampoline).
+#[cfg(feature = "shadow-stack")]
+extern "C" fn shadow_stack_push(return_address: usize) {
+	let pushed = with_shadow_stack(|stack| {
+		if stack.depth == SHADOW_STACK_ENTRIES {
+			return false;
+		}
+
+		stack.entries[stack.depth] = return_address;
+		stack.depth += 1;
+		true
+	});
+
+	if !pushed {
+		panic!("Shadow stack overflow at return address {:#X}", return_address);
+	}
+}
+
+/// Checks `return_address` against the shadow stack, before a function returns to it
+/// (see shadow_stack_trampoline).
+#[cfg(feature = "shadow-stack")]
+extern "C" fn shadow_stack_check(return_address: usize) {
+	let expected = with_shadow_stack(|stack| {
+		if stack.depth == 0 {
+			return None;
+		}
+
+		stack.depth -= 1;
+		Some((stack.entries[stack.depth], &stack.entries[stack.depth] as *const usize as usize))
+	});
+
+	match expected {
+		Some((address, _)) if address == return_address => {}
+		Some((address, entry)) => {
+			// The caller may lack the rights of the kernel.
+			isolation::write_permissions(mm::kernel_permission());
+			// The isolation self-test corrupts a return address on purpose.
+			if ::selftest::record_fault(entry) {
+				::scheduler::abort();
+			}
+			panic!(
+				"Shadow stack mismatch: returning to {:#X}, but called from {:#X}",
+				return_address, address
+			)
+		}
+		None => panic!("Shadow stack underflow at return address {:#X}", return_address),
+	}
+}
+
+/// Entered by the stubs of syscall! with the function in r11 and the return address
+/// of its caller on top of the stack. Records the return address, calls the function with the
+/// original arguments and checks the return address on the stack before returning to it.
+/// Functions with arguments on the stack (more than six) or floating-point results are not supported.
+/// The gates are private and only reachable through this trampoline.
+#[cfg(feature = "shadow-stack")]
+#[inline(never)]
+#[naked]
+#[no_mangle]
+pub extern "C" fn shadow_stack_trampoline() {
+	// The pushes keep the arguments and the function across shadow_stack_push, the padding aligns
+	// the stack for the call of the function, and its slot keeps rax across shadow_stack_check.
+	// The gates are passed as symbolic operands, so that they need no symbol of their own.
+	unsafe {
+		asm!(
+			"push %rdi\n\t\
+			push %rsi\n\t\
+			push %rdx\n\t\
+			push %rcx\n\t\
+			push %r8\n\t\
+			push %r9\n\t\
+			push %r11\n\t\
+			mov 56(%rsp), %rdi\n\t\
+			call ${0:c}\n\t\
+			pop %r11\n\t\
+			pop %r9\n\t\
+			pop %r8\n\t\
+			pop %rcx\n\t\
+			pop %rdx\n\t\
+			pop %rsi\n\t\
+			pop %rdi\n\t\
+			sub $$8, %rsp\n\t\
+			call *%r11\n\t\
+			mov %rax, (%rsp)\n\t\
+			push %rdx\n\t\
+			sub $$8, %rsp\n\t\
+			mov 24(%rsp), %rdi\n\t\
+			call ${1:c}\n\t\
+			add $$8, %rsp\n\t\
+			pop %rdx\n\t\
+			pop %rax\n\t\
+			ret"
+			:
+			: "i"(shadow_stack_push as extern "C" fn(usize)), "i"(shadow_stack_check as extern "C" fn(usize))
+			:
+			: "volatile"
+		);
+	}
+}
@@ -0,0 +1,134 @@
+//! The parts of ELF64 needed to load position-independent modules.
+
+pub const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+/// 64-bit file
+pub const ELF_CLASS_64: u8 = 0x02;
+/// Little-Endian encoding
+pub const ELF_DATA_2LSB: u8 = 0x01;
+
+/// Shared object or position-independent executable
+pub const ELF_ET_DYN: u16 = 0x0003;
+/// x86_64 architecture
+pub const ELF_EM_X86_64: u16 = 0x003E;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ElfHeader {
+	pub ident: [u8; 16],
+	pub ty: u16,
+	pub machine: u16,
+	pub version: u32,
+	pub entry: usize,
+	pub ph_offset: usize,
+	pub sh_offset: usize,
+	pub flags: u32,
+	pub header_size: u16,
+	pub ph_entry_size: u16,
+	pub ph_entry_count: u16,
+	pub sh_entry_size: u16,
+	pub sh_entry_count: u16,
+	pub sh_str_table_index: u16,
+}
+
+/// Loadable program segment
+pub const ELF_PT_LOAD: u32 = 1;
+/// Dynamic linking information
+pub const ELF_PT_DYNAMIC: u32 = 2;
+/// Program interpreter
+pub const ELF_PT_INTERP: u32 = 3;
+/// TLS segment
+pub const ELF_PT_TLS: u32 = 7;
+
+/// Executable segment
+pub const ELF_PF_X: u32 = 1 << 0;
+/// Writable segment
+pub const ELF_PF_W: u32 = 1 << 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ElfProgramHeader {
+	pub ty: u32,
+	pub flags: u32,
+	pub offset: usize,
+	pub virt_addr: usize,
+	pub phys_addr: usize,
+	pub file_size: usize,
+	pub mem_size: usize,
+	pub alignment: usize,
+}
+
+pub const ELF_DT_NULL: i64 = 0;
+pub const ELF_DT_NEEDED: i64 = 1;
+pub const ELF_DT_PLTRELSZ: i64 = 2;
+pub const ELF_DT_HASH: i64 = 4;
+pub const ELF_DT_STRTAB: i64 = 5;
+pub const ELF_DT_SYMTAB: i64 = 6;
+pub const ELF_DT_RELA: i64 = 7;
+pub const ELF_DT_RELASZ: i64 = 8;
+pub const ELF_DT_STRSZ: i64 = 10;
+pub const ELF_DT_PLTREL: i64 = 20;
+pub const ELF_DT_JMPREL: i64 = 23;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ElfDynamic {
+	pub tag: i64,
+	pub value: usize,
+}
+
+/// Undefined section, the symbol is imported
+pub const ELF_SHN_UNDEF: u16 = 0;
+/// Global symbol
+pub const ELF_STB_GLOBAL: u8 = 1;
+/// Weak symbol
+pub const ELF_STB_WEAK: u8 = 2;
+/// Function
+pub const ELF_STT_FUNC: u8 = 2;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ElfSymbol {
+	pub name: u32,
+	pub info: u8,
+	pub other: u8,
+	pub section: u16,
+	pub value: usize,
+	pub size: usize,
+}
+
+impl ElfSymbol {
+	pub fn binding(&self) -> u8 {
+		self.info >> 4
+	}
+
+	pub fn ty(&self) -> u8 {
+		self.info & 0xF
+	}
+}
+
+/// S + A
+pub const ELF_R_X86_64_64: u32 = 1;
+/// S
+pub const ELF_R_X86_64_GLOB_DAT: u32 = 6;
+/// S
+pub const ELF_R_X86_64_JUMP_SLOT: u32 = 7;
+/// B + A
+pub const ELF_R_X86_64_RELATIVE: u32 = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ElfRela {
+	pub offset: usize,
+	pub info: u64,
+	pub addend: i64,
+}
+
+impl ElfRela {
+	pub fn symbol(&self) -> usize {
+		(self.info >> 32) as usize
+	}
+
+	pub fn ty(&self) -> u32 {
+		self.info as u32
+	}
+}
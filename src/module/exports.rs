@@ -0,0 +1,65 @@
+//! Kernel functions, which modules may import by name.
+//!
+//! Every export is a gate, which notes the rights of the calling module and enters the
+//! kernel through kernel_callback!. Buffers are only accepted if the module may access them.
+
+use arch;
+use arch::x86_64::kernel::irq;
+use arch::x86_64::mm::isolation;
+use console;
+use errno::*;
+use module;
+use syscalls::{self, Tid};
+
+/// Returns the gate of the export `name` or None if the kernel does not export it.
+pub fn lookup(name: &[u8]) -> Option<usize> {
+	let address = match name {
+		b"sys_write" => module_sys_write as usize,
+		b"sys_getpid" => module_sys_getpid as usize,
+		b"sys_rand" => module_sys_rand as usize,
+		b"sys_usleep" => module_sys_usleep as usize,
+		_ => return None,
+	};
+
+	Some(address)
+}
+
+fn __module_sys_write(caller: u32, fd: i32, buf: *const u8, len: usize) -> isize {
+	if fd != 1 && fd != 2 {
+		return -EINVAL as isize;
+	}
+	if !module::is_accessible(caller, buf as usize, len) {
+		return -EFAULT as isize;
+	}
+
+	let _console = console::CONSOLE.lock();
+	for i in 0..len {
+		arch::output_message_byte(unsafe { *buf.add(i) });
+	}
+	len as isize
+}
+
+/// Writes `len` bytes at `buf` to stdout (1) or stderr (2).
+extern "C" fn module_sys_write(fd: i32, buf: *const u8, len: usize) -> isize {
+	let caller = isolation::read_permissions();
+	unsafe { kernel_callback!(__module_sys_write(caller, fd, buf, len)) }
+}
+
+extern "C" fn module_sys_getpid() -> Tid {
+	unsafe { kernel_callback!(syscalls::__sys_getpid()) }
+}
+
+extern "C" fn module_sys_rand() -> u32 {
+	unsafe { kernel_callback!(syscalls::__sys_rand()) }
+}
+
+fn __module_sys_usleep(usecs: u64) {
+	// Modules run with interrupts disabled (see module::call), but the kernel may block here.
+	irq::enable();
+	syscalls::__sys_usleep(usecs);
+	irq::disable();
+}
+
+extern "C" fn module_sys_usleep(usecs: u64) {
+	unsafe { kernel_callback!(__module_sys_usleep(usecs)) }
+}
@@ -0,0 +1,604 @@
+//! Runtime loading of position-independent ELF modules (shared objects or PIEs).
+//!
+//! Every module gets a compartment with its own protection key, which is taken from the keys
+//! of the application (see syscalls::allocate_module_key). Its imports are resolved against the
+//! export table of the kernel (see exports) and its functions run as strong isolated calls:
+//! on the isolated stack without access to the kernel domain, the keys of the application and
+//! the other modules. Unlike isolated code, modules cannot access key 0 either, which holds the
+//! statics of the kernel image and the kernel heap. As interrupt handlers rely on key 0, module
+//! functions run with interrupts disabled, apart from kernel callbacks that may block.
+//! Module code is always rejected if it writes PKRU, whatever -wrpkru-scan says.
+//!
+//! Modules may not depend on other shared objects. There is no initrd yet, so images are either
+//! passed in memory or read from a file of uhyve.
+
+mod elf;
+mod exports;
+
+use self::elf::*;
+use alloc::vec::Vec;
+use arch::x86_64::kernel::irq;
+use arch::x86_64::mm::isolation;
+use arch::x86_64::mm::mpk;
+use arch::x86_64::mm::paging::{self, BasePageSize, PageSize};
+use core::{mem, ptr, slice, str};
+use environment::{self, IsolationMode};
+use errno::*;
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+use syscalls;
+
+/// Modules are identified by the key of their compartment.
+pub type ModuleId = i32;
+
+/// Maximum size of the memory image of a module
+const MAX_MODULE_SIZE: usize = 0x400_0000;
+/// Maximum length of the name of a symbol
+pub const MAX_SYMBOL_LENGTH: usize = 256;
+
+struct Module {
+	key: u8,
+	address: usize,
+	size: usize,
+	/// Functions of the module, which may be called (see `call`)
+	functions: Vec<(Vec<u8>, usize)>,
+	/// Number of calls in progress
+	calls: usize,
+}
+
+safe_global_var!(static MODULES: SpinlockIrqSave<Vec<Module>> = SpinlockIrqSave::new(Vec::new()));
+
+/// Tables of the dynamic section as offsets into the memory image
+#[derive(Default)]
+struct Dynamic {
+	rela: usize,
+	rela_size: usize,
+	jmprel: usize,
+	jmprel_size: usize,
+	symtab: usize,
+	strtab: usize,
+	strtab_size: usize,
+	symbol_count: usize,
+}
+
+/// Returns the offset of entry `index` of a table at `table`, whose entries are `T`.
+fn entry_offset<T>(table: usize, index: usize) -> Result<usize, i32> {
+	index
+		.checked_mul(mem::size_of::<T>())
+		.and_then(|offset| table.checked_add(offset))
+		.ok_or(-ENOEXEC)
+}
+
+/// Reads a `T` at `offset` of `data`.
+fn read<T: Copy>(data: &[u8], offset: usize) -> Result<T, i32> {
+	match offset.checked_add(mem::size_of::<T>()) {
+		Some(end) if end <= data.len() => Ok(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) }),
+		_ => Err(-ENOEXEC),
+	}
+}
+
+/// Returns the name at `index` of the string table.
+fn symbol_name<'a>(memory: &'a [u8], dynamic: &Dynamic, index: u32) -> Result<&'a [u8], i32> {
+	let start = dynamic.strtab.checked_add(index as usize).ok_or(-ENOEXEC)?;
+	let end = dynamic.strtab.saturating_add(dynamic.strtab_size).min(memory.len());
+	if start >= end {
+		return Err(-ENOEXEC);
+	}
+
+	let names = &memory[start..end];
+	match names.iter().take(MAX_SYMBOL_LENGTH).position(|&byte| byte == 0) {
+		Some(len) => Ok(&names[..len]),
+		None => Err(-ENOEXEC),
+	}
+}
+
+/// Runs `f` with access to the compartment `key`, which the caller may have disabled for itself.
+fn with_access<R, F: FnOnce() -> R>(key: u8, f: F) -> R {
+	let permissions = isolation::read_permissions();
+	isolation::write_permissions(permissions & !(0b11 << (2 * key)));
+	let ret = f();
+	isolation::write_permissions(permissions);
+	ret
+}
+
+/// Checks the ELF header and returns the loadable segments and the dynamic segment.
+fn parse(image: &[u8]) -> Result<(Vec<ElfProgramHeader>, Option<ElfProgramHeader>), i32> {
+	let header: ElfHeader = read(image, 0)?;
+	if header.ident[0..4] != ELF_MAGIC
+		|| header.ident[4] != ELF_CLASS_64
+		|| header.ident[5] != ELF_DATA_2LSB
+		|| header.ty != ELF_ET_DYN
+		|| header.machine != ELF_EM_X86_64
+		|| usize::from(header.ph_entry_size) != mem::size_of::<ElfProgramHeader>()
+	{
+		return Err(-ENOEXEC);
+	}
+
+	let mut segments = Vec::new();
+	let mut dynamic = None;
+	for i in 0..usize::from(header.ph_entry_count) {
+		let offset = entry_offset::<ElfProgramHeader>(header.ph_offset, i)?;
+		let segment: ElfProgramHeader = read(image, offset)?;
+		match segment.ty {
+			ELF_PT_LOAD => segments.push(segment),
+			ELF_PT_DYNAMIC => dynamic = Some(segment),
+			// Modules are linked by the kernel and have no thread-local storage.
+			ELF_PT_INTERP | ELF_PT_TLS => return Err(-ENOEXEC),
+			_ => {}
+		}
+	}
+
+	// Segments are sorted by address and may not share pages, because they get different rights.
+	let mut end = 0;
+	for segment in segments.iter() {
+		let file_end = segment.offset.checked_add(segment.file_size).ok_or(-ENOEXEC)?;
+		let memory_end = segment.virt_addr.checked_add(segment.mem_size).ok_or(-ENOEXEC)?;
+		if segment.file_size > segment.mem_size
+			|| file_end > image.len()
+			|| memory_end > usize::max_value() - BasePageSize::SIZE
+			|| segment.virt_addr % BasePageSize::SIZE != segment.offset % BasePageSize::SIZE
+			|| align_down!(segment.virt_addr, BasePageSize::SIZE) < end
+			|| (segment.flags & ELF_PF_W != 0 && segment.flags & ELF_PF_X != 0)
+		{
+			return Err(-ENOEXEC);
+		}
+		end = align_up!(memory_end, BasePageSize::SIZE);
+	}
+
+	if segments.is_empty() {
+		return Err(-ENOEXEC);
+	}
+	Ok((segments, dynamic))
+}
+
+/// Reads the dynamic section at `offset` of the memory image, which starts at `start`.
+fn read_dynamic(memory: &[u8], offset: usize, start: usize) -> Result<Dynamic, i32> {
+	let to_offset = |address: usize| address.checked_sub(start).ok_or(-ENOEXEC);
+	let mut dynamic = Dynamic::default();
+	let mut hash = None;
+
+	for i in 0.. {
+		let entry: ElfDynamic = read(memory, entry_offset::<ElfDynamic>(offset, i)?)?;
+		match entry.tag {
+			ELF_DT_NULL => break,
+			ELF_DT_NEEDED => {
+				warn!("Modules may not depend on shared objects");
+				return Err(-ENOEXEC);
+			}
+			ELF_DT_RELA => dynamic.rela = to_offset(entry.value)?,
+			ELF_DT_RELASZ => dynamic.rela_size = entry.value,
+			ELF_DT_JMPREL => dynamic.jmprel = to_offset(entry.value)?,
+			ELF_DT_PLTRELSZ => dynamic.jmprel_size = entry.value,
+			ELF_DT_PLTREL if entry.value != ELF_DT_RELA as usize => return Err(-ENOEXEC),
+			ELF_DT_SYMTAB => dynamic.symtab = to_offset(entry.value)?,
+			ELF_DT_STRTAB => dynamic.strtab = to_offset(entry.value)?,
+			ELF_DT_STRSZ => dynamic.strtab_size = entry.value,
+			ELF_DT_HASH => hash = Some(to_offset(entry.value)?),
+			_ => {}
+		}
+	}
+
+	// nchain of the hash table is the number of symbols. Without it, we rely on the usual
+	// layout, in which the string table follows the symbol table.
+	dynamic.symbol_count = match hash {
+		Some(hash) => read::<u32>(memory, hash.checked_add(4).ok_or(-ENOEXEC)?)? as usize,
+		None if dynamic.strtab > dynamic.symtab => (dynamic.strtab - dynamic.symtab) / mem::size_of::<ElfSymbol>(),
+		None => 0,
+	};
+
+	Ok(dynamic)
+}
+
+/// Returns the address of the symbol `index`, resolving imports against the export table.
+fn resolve(memory: &[u8], dynamic: &Dynamic, base: usize, index: usize) -> Result<usize, i32> {
+	if index >= dynamic.symbol_count {
+		return Err(-ENOEXEC);
+	}
+
+	let symbol: ElfSymbol = read(memory, entry_offset::<ElfSymbol>(dynamic.symtab, index)?)?;
+	if symbol.section != ELF_SHN_UNDEF {
+		return Ok(base.wrapping_add(symbol.value));
+	}
+
+	let name = symbol_name(memory, dynamic, symbol.name)?;
+	match exports::lookup(name) {
+		Some(address) => Ok(address),
+		None if symbol.binding() == ELF_STB_WEAK => Ok(0),
+		None => {
+			warn!("Module imports {}, which the kernel does not export", str::from_utf8(name).unwrap_or("?"));
+			Err(-ENOENT)
+		}
+	}
+}
+
+/// Applies the relocations of the table at `table` with `size` bytes.
+fn relocate(memory: &mut [u8], dynamic: &Dynamic, base: usize, start: usize, table: usize, size: usize) -> Result<(), i32> {
+	for i in 0..size / mem::size_of::<ElfRela>() {
+		let rela: ElfRela = read(memory, entry_offset::<ElfRela>(table, i)?)?;
+		let value = match rela.ty() {
+			ELF_R_X86_64_RELATIVE => base.wrapping_add(rela.addend as usize),
+			ELF_R_X86_64_64 => resolve(memory, dynamic, base, rela.symbol())?.wrapping_add(rela.addend as usize),
+			ELF_R_X86_64_GLOB_DAT | ELF_R_X86_64_JUMP_SLOT => resolve(memory, dynamic, base, rela.symbol())?,
+			ty => {
+				warn!("Unsupported relocation {} in module", ty);
+				return Err(-ENOEXEC);
+			}
+		};
+
+		let offset = rela.offset.checked_sub(start).ok_or(-ENOEXEC)?;
+		match offset.checked_add(mem::size_of::<usize>()) {
+			Some(end) if end <= memory.len() => unsafe {
+				ptr::write_unaligned(memory[offset..].as_mut_ptr() as *mut usize, value);
+			},
+			_ => return Err(-ENOEXEC),
+		}
+	}
+
+	Ok(())
+}
+
+/// Copies and relocates the module, collects its functions and sets the rights of its segments.
+fn link(module: &mut Module, image: &[u8], segments: &[ElfProgramHeader], dynamic: Option<ElfProgramHeader>) -> Result<(), i32> {
+	let start = align_down!(segments[0].virt_addr, BasePageSize::SIZE);
+	let base = module.address.wrapping_sub(start);
+	let memory = unsafe { slice::from_raw_parts_mut(module.address as *mut u8, module.size) };
+
+	unsafe {
+		ptr::write_bytes(memory.as_mut_ptr(), 0, memory.len());
+	}
+	for segment in segments.iter() {
+		let offset = segment.virt_addr - start;
+		memory[offset..offset + segment.file_size].copy_from_slice(&image[segment.offset..segment.offset + segment.file_size]);
+	}
+
+	if let Some(segment) = dynamic {
+		let offset = segment.virt_addr.checked_sub(start).ok_or(-ENOEXEC)?;
+		let dynamic = read_dynamic(memory, offset, start)?;
+		relocate(memory, &dynamic, base, start, dynamic.rela, dynamic.rela_size)?;
+		relocate(memory, &dynamic, base, start, dynamic.jmprel, dynamic.jmprel_size)?;
+
+		let is_code = |value: usize| {
+			segments
+				.iter()
+				.any(|segment| segment.flags & ELF_PF_X != 0 && value >= segment.virt_addr && value - segment.virt_addr < segment.mem_size)
+		};
+		for index in 1..dynamic.symbol_count {
+			let symbol: ElfSymbol = read(memory, entry_offset::<ElfSymbol>(dynamic.symtab, index)?)?;
+			if symbol.section != ELF_SHN_UNDEF
+				&& symbol.ty() == ELF_STT_FUNC
+				&& (symbol.binding() == ELF_STB_GLOBAL || symbol.binding() == ELF_STB_WEAK)
+				&& is_code(symbol.value)
+			{
+				let name = symbol_name(memory, &dynamic, symbol.name)?;
+				module.functions.push((name.to_vec(), base.wrapping_add(symbol.value)));
+			}
+		}
+	}
+
+	// The code is scanned for WRPKRU and XRSTOR, which would let the module leave its compartment.
+	for segment in segments.iter() {
+		let address = align_down!(base.wrapping_add(segment.virt_addr), BasePageSize::SIZE);
+		let size = align_up!(base.wrapping_add(segment.virt_addr + segment.mem_size), BasePageSize::SIZE) - address;
+		if segment.flags & ELF_PF_X != 0 {
+			if !mm::make_executable_enforced(address, size) {
+				return Err(-EACCES);
+			}
+		} else if segment.flags & ELF_PF_W == 0 {
+			paging::set_page_rights(address, size, false, false);
+		}
+	}
+
+	Ok(())
+}
+
+/// Wipes and frees the memory of `module` and its key.
+fn release(module: Module) {
+	with_access(module.key, || {
+		paging::set_page_rights(module.address, module.size, true, false);
+		unsafe {
+			ptr::write_bytes(module.address as *mut u8, 0, module.size);
+		}
+	});
+	// Stale translations must not carry a key, which may be handed out again.
+	mpk::mpk_mem_set_key::<BasePageSize>(module.address, module.size, mm::SAFE_MEM_REGION);
+	mm::guarded_deallocate(module.address, module.size);
+	syscalls::free_module_key(module.key);
+}
+
+/// Loads the module `image` into a new compartment.
+/// Returns the ID of the module or a negative error number.
+pub fn load(image: &[u8]) -> Result<ModuleId, i32> {
+	// Modules rely on the isolated stack and on switching the rights of their key and of key 0.
+	if cfg!(feature = "no-mpk") || environment::isolation_mode() != IsolationMode::Strong || !isolation::uses_pku() {
+		return Err(-EOPNOTSUPP);
+	}
+
+	let (segments, dynamic) = parse(image)?;
+	let start = align_down!(segments[0].virt_addr, BasePageSize::SIZE);
+	let last = &segments[segments.len() - 1];
+	let size = align_up!(last.virt_addr + last.mem_size, BasePageSize::SIZE) - start;
+	if size > MAX_MODULE_SIZE {
+		return Err(-ENOMEM);
+	}
+
+	let key = syscalls::allocate_module_key().ok_or(-ENOSPC)?;
+	let mut module = Module {
+		key: key,
+		address: mm::guarded_allocate(size, key),
+		size: size,
+		functions: Vec::new(),
+		calls: 0,
+	};
+
+	match with_access(key, || link(&mut module, image, &segments, dynamic)) {
+		Ok(()) => {
+			info!(
+				"Loaded module with key {} at {:#X} ({} functions)",
+				key,
+				module.address,
+				module.functions.len()
+			);
+			MODULES.lock().push(module);
+			Ok(ModuleId::from(key))
+		}
+		Err(errno) => {
+			release(module);
+			Err(errno)
+		}
+	}
+}
+
+/// Loads the module from the file `name` (see syscalls::read_file).
+pub fn load_file(name: *const u8) -> Result<ModuleId, i32> {
+	let image = syscalls::read_file(name)?;
+	load(&image)
+}
+
+/// Runs the function of a module at `entry` with the rights of its compartment.
+/// Called on the isolated stack, so it may not touch the kernel domain.
+/// Key 0 is denied as well, so nothing between the switches may touch the kernel image.
+#[inline(never)]
+fn enter(entry: usize, arg: usize, key: u8) -> usize {
+	let permissions = (isolation::read_permissions() | mm::APPLICATION_KEY_PERMISSION | 0b11) & !(0b11 << (2 * key));
+	isolation::write_permissions(permissions);
+
+	let function: extern "C" fn(usize) -> usize = unsafe { mem::transmute(entry) };
+	let ret = function(arg);
+
+	// The isolated domain, which isolate_function_strong! leaves, has access to key 0.
+	isolation::write_permissions(isolation::read_permissions() & !0b11);
+	ret
+}
+
+/// Calls the function `name` of the module `id`, which takes and returns a usize, as an isolated call.
+pub fn call(id: ModuleId, name: &[u8], arg: usize) -> Result<usize, i32> {
+	let (key, entry) = {
+		let mut modules = MODULES.lock();
+		let module = modules
+			.iter_mut()
+			.find(|module| ModuleId::from(module.key) == id)
+			.ok_or(-EINVAL)?;
+		let entry = module
+			.functions
+			.iter()
+			.find(|function| function.0[..] == name[..])
+			.map(|function| function.1)
+			.ok_or(-ENOENT)?;
+		module.calls += 1;
+		(module.key, entry)
+	};
+
+	// isolation_exit restores the rights of the kernel before interrupts are enabled again.
+	let irq = irq::nested_disable();
+	let ret = unsafe { isolate_function_strong!(enter(entry, arg, key)) };
+	irq::nested_enable(irq);
+
+	if let Some(module) = MODULES.lock().iter_mut().find(|module| module.key == key) {
+		module.calls -= 1;
+	}
+	Ok(ret)
+}
+
+/// Unloads the module `id`. Fails with -EBUSY while one of its functions runs.
+pub fn unload(id: ModuleId) -> Result<(), i32> {
+	let module = {
+		let mut modules = MODULES.lock();
+		let index = modules
+			.iter()
+			.position(|module| ModuleId::from(module.key) == id)
+			.ok_or(-EINVAL)?;
+		if modules[index].calls > 0 {
+			return Err(-EBUSY);
+		}
+		modules.remove(index)
+	};
+
+	release(module);
+	Ok(())
+}
+
+/// Returns whether `len` bytes at `address` are readable with the rights `permissions`.
+pub fn is_accessible(permissions: u32, address: usize, len: usize) -> bool {
+	let end = match address.checked_add(len) {
+		Some(end) => end,
+		None => return false,
+	};
+
+	(align_down!(address, BasePageSize::SIZE)..end)
+		.step_by(BasePageSize::SIZE)
+		.all(|page| match paging::get_pkey(page) {
+			Some(key) => permissions & (1 << (2 * key)) == 0,
+			None => false,
+		})
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::prelude::v1::*;
+
+	fn bytes<T: Copy>(value: &T) -> Vec<u8> {
+		unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }.to_vec()
+	}
+
+	fn header(ph_offset: usize, ph_entry_count: u16) -> ElfHeader {
+		let mut ident = [0; 16];
+		ident[0..4].copy_from_slice(&ELF_MAGIC);
+		ident[4] = ELF_CLASS_64;
+		ident[5] = ELF_DATA_2LSB;
+
+		ElfHeader {
+			ident: ident,
+			ty: ELF_ET_DYN,
+			machine: ELF_EM_X86_64,
+			version: 1,
+			entry: 0,
+			ph_offset: ph_offset,
+			sh_offset: 0,
+			flags: 0,
+			header_size: mem::size_of::<ElfHeader>() as u16,
+			ph_entry_size: mem::size_of::<ElfProgramHeader>() as u16,
+			ph_entry_count: ph_entry_count,
+			sh_entry_size: 0,
+			sh_entry_count: 0,
+			sh_str_table_index: 0,
+		}
+	}
+
+	/// Loadable segment without contents in the file
+	fn segment(flags: u32, virt_addr: usize, mem_size: usize) -> ElfProgramHeader {
+		ElfProgramHeader {
+			ty: ELF_PT_LOAD,
+			flags: flags,
+			offset: 0,
+			virt_addr: virt_addr,
+			phys_addr: virt_addr,
+			file_size: 0,
+			mem_size: mem_size,
+			alignment: BasePageSize::SIZE,
+		}
+	}
+
+	/// Header followed by the program headers `segments`
+	fn image(segments: &[ElfProgramHeader]) -> Vec<u8> {
+		let mut image = bytes(&header(mem::size_of::<ElfHeader>(), segments.len() as u16));
+		for segment in segments.iter() {
+			image.extend(bytes(segment));
+		}
+		image
+	}
+
+	fn rela(offset: usize, ty: u32, addend: i64) -> ElfRela {
+		ElfRela {
+			offset: offset,
+			info: u64::from(ty),
+			addend: addend,
+		}
+	}
+
+	#[test]
+	fn parse_module() {
+		let image = image(&[segment(ELF_PF_X, 0, 0x1800), segment(ELF_PF_W, 0x2000, 0x1000)]);
+		let (segments, dynamic) = parse(&image).expect("Module rejected");
+		assert_eq!(segments.len(), 2);
+		assert!(dynamic.is_none());
+	}
+
+	#[test]
+	fn parse_truncated_header() {
+		let image = image(&[segment(ELF_PF_X, 0, 0x1000)]);
+		assert_eq!(parse(&image[..mem::size_of::<ElfHeader>() - 1]).err(), Some(-ENOEXEC));
+		assert_eq!(parse(&image[..image.len() - 1]).err(), Some(-ENOEXEC));
+	}
+
+	#[test]
+	fn parse_program_headers_out_of_range() {
+		let mut image = bytes(&header(0x1000, 1));
+		image.extend(bytes(&segment(ELF_PF_X, 0, 0x1000)));
+		assert_eq!(parse(&image).err(), Some(-ENOEXEC));
+
+		let mut image = bytes(&header(usize::max_value() - 8, 2));
+		image.extend(bytes(&segment(ELF_PF_X, 0, 0x1000)));
+		assert_eq!(parse(&image).err(), Some(-ENOEXEC));
+	}
+
+	#[test]
+	fn parse_overlapping_segments() {
+		let image = image(&[segment(ELF_PF_X, 0, 0x1800), segment(ELF_PF_W, 0x1000, 0x1000)]);
+		assert_eq!(parse(&image).err(), Some(-ENOEXEC));
+	}
+
+	#[test]
+	fn parse_writable_code() {
+		let image = image(&[segment(ELF_PF_W | ELF_PF_X, 0, 0x1000)]);
+		assert_eq!(parse(&image).err(), Some(-ENOEXEC));
+	}
+
+	#[test]
+	fn symbol_name_out_of_range() {
+		let memory = b"\0function\0unterminated";
+		let mut dynamic = Dynamic {
+			strtab: 0,
+			strtab_size: memory.len(),
+			..Default::default()
+		};
+		assert_eq!(symbol_name(memory, &dynamic, 1), Ok(&b"function"[..]));
+		assert_eq!(symbol_name(memory, &dynamic, 10), Err(-ENOEXEC));
+		assert_eq!(symbol_name(memory, &dynamic, memory.len() as u32), Err(-ENOEXEC));
+
+		dynamic.strtab = 0x1000;
+		assert_eq!(symbol_name(memory, &dynamic, 1), Err(-ENOEXEC));
+		dynamic.strtab = usize::max_value();
+		assert_eq!(symbol_name(memory, &dynamic, 1), Err(-ENOEXEC));
+	}
+
+	#[test]
+	fn resolve_symtab_out_of_range() {
+		let memory = [0; 64];
+		let mut dynamic = Dynamic {
+			symtab: 0x1000,
+			symbol_count: 2,
+			..Default::default()
+		};
+		assert_eq!(resolve(&memory, &dynamic, 0, 1), Err(-ENOEXEC));
+		assert_eq!(resolve(&memory, &dynamic, 0, 2), Err(-ENOEXEC));
+
+		dynamic.symtab = usize::max_value() - 4;
+		assert_eq!(resolve(&memory, &dynamic, 0, 1), Err(-ENOEXEC));
+	}
+
+	#[test]
+	fn read_dynamic_hash_out_of_range() {
+		let mut memory = bytes(&ElfDynamic {
+			tag: ELF_DT_HASH,
+			value: usize::max_value() - 2,
+		});
+		memory.extend(bytes(&ElfDynamic { tag: ELF_DT_NULL, value: 0 }));
+		assert!(read_dynamic(&memory, 0, 0).is_err());
+
+		// The entries must be terminated by DT_NULL.
+		assert!(read_dynamic(&memory[..mem::size_of::<ElfDynamic>()], 0, 0).is_err());
+		assert!(read_dynamic(&memory, usize::max_value(), 0).is_err());
+	}
+
+	#[test]
+	fn relocate_relative() {
+		let mut memory = bytes(&rela(0x20, ELF_R_X86_64_RELATIVE, 0x10));
+		memory.resize(0x28, 0);
+		let size = mem::size_of::<ElfRela>();
+		assert_eq!(relocate(&mut memory, &Dynamic::default(), 0x1000, 0, 0, size), Ok(()));
+		assert_eq!(read::<usize>(&memory, 0x20), Ok(0x1010));
+
+		// The target must lie inside the image.
+		let mut memory = bytes(&rela(0x20, ELF_R_X86_64_RELATIVE, 0x10));
+		assert_eq!(relocate(&mut memory, &Dynamic::default(), 0x1000, 0, 0, size), Err(-ENOEXEC));
+	}
+
+	#[test]
+	fn relocate_unsupported_type() {
+		// R_X86_64_IRELATIVE would run code of the module while it is linked.
+		let mut memory = bytes(&rela(0, 37, 0));
+		let size = mem::size_of::<ElfRela>();
+		assert_eq!(relocate(&mut memory, &Dynamic::default(), 0x1000, 0, 0, size), Err(-ENOEXEC));
+	}
+}
@@ -0,0 +1,45 @@
+//! Isolation policy of the kernel, generated by build.rs from isolation.toml.
+//!
+//! Compartments, their keys and data sections, the rights of the application and of
+//! isolated code and the allowed calls between domains are all defined there.
+
+/// A compartment of the kernel with its own protection key
+pub struct Compartment {
+	pub name: &'static str,
+	pub key: u8,
+	/// Section of its statics, if any (see safe_global_var!)
+	pub section: Option<&'static str>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Domain {
+	/// The application
+	User,
+	/// The kernel itself
+	Kernel,
+	/// Code isolated in the unsafe compartment
+	Isolated,
+}
+
+/// A call from one domain into another, which the policy allows.
+/// Calls from the kernel into isolated code and back are always part of the policy (see build.rs),
+/// and system calls of the application are always allowed.
+pub struct Call {
+	pub from: Domain,
+	pub to: Domain,
+}
+
+include!(concat!(env!("OUT_DIR"), "/policy.rs"));
+
+pub fn print_information() {
+	infoheader!(" ISOLATION POLICY ");
+	for compartment in COMPARTMENTS.iter() {
+		info!("Compartment {:13}key {} {}", compartment.name, compartment.key, compartment.section.unwrap_or(""));
+	}
+	infoentry!("Application rights", "{:#X}", USER_PERMISSION);
+	infoentry!("Isolated rights", "{:#X}", ISOLATED_PERMISSION);
+	for call in CALLS.iter() {
+		infoentry!("Allowed call", "{:?} -> {:?}", call.from, call.to);
+	}
+	infofooter!();
+}
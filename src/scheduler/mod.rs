@@ -11,6 +11,7 @@ pub mod task;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
 use alloc::rc::Rc;
+use alloc::vec::Vec;
 use arch;
 use arch::irq;
 use arch::percore::*;
@@ -58,6 +59,8 @@ pub struct PerCoreScheduler {
 	pub blocked_tasks: SpinlockIrqSave<BlockedTaskQueue>,
 	/// Processor Timer Tick when we last switched the current task.
 	last_task_switch_tick: u64,
+	/// Isolated stacks of finished tasks, which can be handed to the next task on this core
+	pub isolated_stack_pool: SpinlockIrqSave<Vec<usize>>,
 }
 
 impl PerCoreScheduler {
@@ -227,11 +230,12 @@ impl PerCoreScheduler {
 		self.cleanup_tasks();
 
 		// Get information about the current task.
-		let (id, last_stack_pointer, kernel_stack_pointer, user_stack_pointer, prio, status) = {
+		let (id, last_stack_pointer, last_pkru_state, kernel_stack_pointer, user_stack_pointer, prio, status) = {
 			let mut borrowed = self.current_task.borrow_mut();
 			(
 				borrowed.id,
 				&mut borrowed.last_stack_pointer as *mut usize,
+				&mut borrowed.last_pkru_state as *mut arch::processor::PkruState,
 				&mut borrowed.kernel_stack_pointer as *mut usize,
 				&mut borrowed.user_stack_pointer as *mut usize,
 				borrowed.prio,
@@ -296,7 +300,7 @@ impl PerCoreScheduler {
 			}
 
 			// Handle the new task and get information about it.
-			let (new_id, new_stack_pointer, new_kernel_stack_pointer, new_user_stack_pointer) = 
+			let (new_id, new_stack_pointer, new_pkru_state, new_kernel_stack_pointer, new_user_stack_pointer, new_shadow_stack) = 
 			{
 				let mut borrowed = task.borrow_mut();
 				if borrowed.status != TaskStatus::TaskIdle {
@@ -304,7 +308,14 @@ impl PerCoreScheduler {
 					borrowed.status = TaskStatus::TaskRunning;
 				}
 
-				(borrowed.id, borrowed.last_stack_pointer, borrowed.kernel_stack_pointer, borrowed.user_stack_pointer)
+				(
+					borrowed.id,
+					borrowed.last_stack_pointer,
+					&borrowed.last_pkru_state as *const arch::processor::PkruState,
+					borrowed.kernel_stack_pointer,
+					borrowed.user_stack_pointer,
+					borrowed.stacks.shadow_stack,
+				)
 			};
 
 			if id != new_id {
@@ -322,12 +333,14 @@ impl PerCoreScheduler {
 				);
 				self.current_task = task;
 				self.last_task_switch_tick = arch::processor::get_timer_ticks();
+				// The gates of the shadow stacks find the one of the new task through the core.
+				::mm::shadow_stack::switch(new_shadow_stack);
 
 				// Unlock the state and reenable interrupts.
 				drop(state_locked);
 
 				// Finally save our current context and restore the context of the new task.
-				switch(last_stack_pointer, new_stack_pointer);
+				switch(last_stack_pointer, new_stack_pointer, last_pkru_state, new_pkru_state);
 			}
 		} else {
 			// There is no new task to switch to.
@@ -368,6 +381,7 @@ pub fn add_current_core() {
 	let core_id = core_id();
 	let tid = get_tid();
 	let idle_task = Rc::new(RefCell::new(Task::new_idle(tid, core_id)));
+	::mm::shadow_stack::switch(idle_task.borrow().stacks.shadow_stack);
 
 	// Add the ID -> Task mapping.
 	unsafe {
@@ -395,6 +409,7 @@ pub fn add_current_core() {
 		finished_tasks: VecDeque::new(),
 		blocked_tasks: SpinlockIrqSave::new(BlockedTaskQueue::new()),
 		last_task_switch_tick: 0,
+		isolated_stack_pool: SpinlockIrqSave::new(Vec::new()),
 	});
 
 	let scheduler = Box::into_raw(boxed_scheduler);
@@ -313,7 +313,7 @@ impl TaskTLS {
 		// additional alignment for TLS variables.
 		let memory_size = align_up!(size, BasePageSize::SIZE);
 		Self {
-			address: mm::user_allocate(memory_size, true),
+			address: mm::user_allocate(memory_size),
 			size: memory_size,
 		}
 	}
@@ -350,6 +350,8 @@ pub struct Task {
 	pub user_stack_pointer: usize,
 	/// Last FPU state before a context switch to another task using the FPU
 	pub last_fpu_state: arch::processor::FPUState,
+	/// Access rights before a context switch to another task
+	pub last_pkru_state: arch::processor::PkruState,
 	/// ID of the core this task is running on
 	pub core_id: usize,
 	/// Stack of the task
@@ -386,6 +388,7 @@ impl Task {
 			kernel_stack_pointer: 0,
 			user_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
+			last_pkru_state: arch::processor::PkruState::new(),
 			core_id: core_id,
 			stacks: TaskStacks::new(),
 			next: None,
@@ -409,6 +412,7 @@ impl Task {
 			kernel_stack_pointer: 0,
 			user_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
+			last_pkru_state: arch::processor::PkruState::new(),
 			core_id: core_id,
 			stacks: TaskStacks::from_boot_stacks(),
 			next: None,
@@ -432,6 +436,7 @@ impl Task {
 			kernel_stack_pointer: 0,
 			user_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
+			last_pkru_state: arch::processor::PkruState::new(),
 			core_id: core_id,
 			stacks: TaskStacks::new(),
 			next: None,
@@ -0,0 +1,574 @@
+//! Boot-time self-test of the isolation (-isolation-selftest).
+//!
+//! Every test performs one access from one domain in its own task. If the access
+//! must be denied, the page fault handler (or the shadow-stack check) records the
+//! fault and terminates only that task. At the end, a summary is printed and the
+//! kernel shuts down with the number of failed tests as exit code.
+
+use alloc::boxed::Box;
+use arch::irq;
+use arch::percore::*;
+use arch::x86_64::kernel::{gdt, idt};
+use arch::x86_64::mm::isolation;
+use arch::x86_64::mm::paging;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use core::mem;
+use core::ptr::{read_volatile, write_volatile};
+use environment;
+use mm;
+use mm::fnptr;
+use mm::jit::{self, JitId};
+use mm::secret::{self, SecretId};
+use scheduler;
+use scheduler::task::{TaskId, NORMAL_PRIO};
+use syscalls;
+
+struct SelfTest {
+	name: &'static str,
+	func: extern "C" fn(usize),
+	/// Protection key of the expected fault, None if the access has to succeed
+	expected_pkey: Option<u8>,
+}
+
+#[derive(Clone, Copy)]
+struct Fault {
+	address: usize,
+	pkey: Option<u8>,
+}
+
+/// Sealed with the boot data (see mm::seal_boot_data) before the tests run
+sealed_global_var!(static mut SEALED_BOOT_DATA: usize = 0);
+
+/// Task of the running test, None if no test is running
+safe_global_var!(static mut RUNNING_TASK: Option<TaskId> = None);
+/// Fault caused by the running test
+safe_global_var!(static mut RECORDED_FAULT: Option<Fault> = None);
+
+safe_global_var!(static mut SAFE_DATA: usize = 0);
+unsafe_global_var!(static mut UNSAFE_DATA: usize = 0);
+/// Address accessed by the isolated functions. It lives in the unsafe domain,
+/// so that they can read it without touching the stack of their caller.
+unsafe_global_var!(static mut TARGET: usize = 0);
+/// Page sealed by the first test that needs one
+safe_global_var!(static mut SEALED_PAGE: usize = 0);
+/// Secret created by the first test that needs one
+safe_global_var!(static mut SECRET: Option<SecretId> = None);
+/// JIT region holding a single `ret`, created by the first test that needs one
+safe_global_var!(static mut JIT_REGION: Option<JitId> = None);
+
+static TESTS: &[SelfTest] = &[
+	SelfTest {
+		name: "kernel domain reads safe data",
+		func: kernel_reads_safe_data,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "unsafe domain reads unsafe data",
+		func: unsafe_domain_reads_unsafe_data,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "unsafe domain reads safe data",
+		func: unsafe_domain_reads_safe_data,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain writes safe data",
+		func: unsafe_domain_writes_safe_data,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads the kernel stack",
+		func: unsafe_domain_reads_kernel_stack,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads the kernel heap",
+		func: unsafe_domain_reads_kernel_heap,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads the page tables",
+		func: unsafe_domain_reads_page_tables,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads the IDT",
+		func: unsafe_domain_reads_idt,
+		expected_pkey: Some(mm::FNPTR_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads the GDT",
+		func: unsafe_domain_reads_gdt,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads sealed data",
+		func: unsafe_domain_reads_sealed_data,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "kernel domain writes sealed data",
+		func: kernel_writes_sealed_data,
+		expected_pkey: Some(mm::SEALED_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain writes sealed data",
+		func: unsafe_domain_writes_sealed_data,
+		expected_pkey: Some(mm::SEALED_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads sealed boot data",
+		func: unsafe_domain_reads_sealed_boot_data,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "kernel domain writes sealed boot data",
+		func: kernel_writes_sealed_boot_data,
+		expected_pkey: Some(mm::SEALED_MEM_REGION),
+	},
+	SelfTest {
+		name: "kernel domain reads a secret inside with_secret",
+		func: kernel_reads_secret_inside_accessor,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "kernel domain looks up a secret inside with_secret",
+		func: kernel_uses_secret_api_inside_accessor,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "kernel domain reads a secret",
+		func: kernel_reads_secret,
+		expected_pkey: Some(mm::SECRET_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads a secret",
+		func: unsafe_domain_reads_secret,
+		expected_pkey: Some(mm::SECRET_MEM_REGION),
+	},
+	SelfTest {
+		name: "kernel domain writes a JIT region inside jit_write",
+		func: kernel_writes_jit_region_inside_writer,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "kernel domain writes a JIT region",
+		func: kernel_writes_jit_region,
+		expected_pkey: Some(mm::JIT_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain writes a JIT region",
+		func: unsafe_domain_writes_jit_region,
+		expected_pkey: Some(mm::JIT_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain executes a JIT region",
+		func: unsafe_domain_executes_jit_region,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "kernel domain writes the IDT inside unseal",
+		func: kernel_writes_idt_inside_unseal,
+		expected_pkey: None,
+	},
+	SelfTest {
+		name: "kernel domain writes the IDT",
+		func: kernel_writes_idt,
+		expected_pkey: Some(mm::FNPTR_MEM_REGION),
+	},
+	#[cfg(feature = "shadow-stack")]
+	SelfTest {
+		name: "kernel domain writes the shadow stack",
+		func: kernel_writes_shadow_stack,
+		expected_pkey: Some(mm::SHADOW_MEM_REGION),
+	},
+	#[cfg(feature = "shadow-stack")]
+	SelfTest {
+		name: "kernel domain returns to a corrupted return address",
+		func: kernel_returns_to_corrupted_address,
+		expected_pkey: Some(mm::SHADOW_MEM_REGION),
+	},
+	SelfTest {
+		name: "unsafe domain reads safe data after a task switch",
+		func: unsafe_domain_reads_safe_data_after_switch,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "application writes safe data",
+		func: application_writes_safe_data,
+		expected_pkey: Some(mm::SAFE_MEM_REGION),
+	},
+	SelfTest {
+		name: "application reads unsafe data",
+		func: application_reads_unsafe_data,
+		expected_pkey: Some(mm::UNSAFE_MEM_REGION),
+	},
+];
+
+#[inline(never)]
+fn read_target() -> usize {
+	unsafe { read_volatile(TARGET as *const usize) }
+}
+
+#[inline(never)]
+fn write_target() {
+	unsafe { write_volatile(TARGET as *mut usize, 0) }
+}
+
+#[inline(never)]
+fn call_target() {
+	unsafe {
+		let code: extern "C" fn() = mem::transmute(TARGET);
+		code()
+	}
+}
+
+fn reschedule() {
+	core_scheduler().reschedule();
+}
+
+/// Lets other tasks run in the kernel domain, before reading TARGET in the unsafe domain again.
+#[inline(never)]
+fn yield_and_read_target() -> usize {
+	unsafe { kernel_callback!(reschedule()) };
+	read_target()
+}
+
+fn safe_data_address() -> usize {
+	unsafe { &SAFE_DATA as *const usize as usize }
+}
+
+fn unsafe_data_address() -> usize {
+	unsafe { &UNSAFE_DATA as *const usize as usize }
+}
+
+fn sealed_page_address() -> usize {
+	unsafe {
+		if SEALED_PAGE == 0 {
+			let page = mm::allocate(BasePageSize::SIZE);
+			assert!(mm::seal(page, BasePageSize::SIZE), "Could not seal {:#X}", page);
+			SEALED_PAGE = page;
+		}
+		SEALED_PAGE
+	}
+}
+
+fn secret_id() -> SecretId {
+	unsafe {
+		if SECRET.is_none() {
+			SECRET = Some(secret::allocate(mem::size_of::<usize>()).expect("Could not allocate a secret"));
+		}
+		SECRET.unwrap()
+	}
+}
+
+fn secret_address() -> usize {
+	secret::region(secret_id()).unwrap().0
+}
+
+fn jit_id() -> JitId {
+	unsafe {
+		if JIT_REGION.is_none() {
+			let id = jit::allocate(BasePageSize::SIZE).expect("Could not allocate a JIT region");
+			jit::jit_write(id, |code| code[0] = 0xC3).expect("Could not write the JIT region");
+			JIT_REGION = Some(id);
+		}
+		JIT_REGION.unwrap()
+	}
+}
+
+fn jit_address() -> usize {
+	jit::region(jit_id()).unwrap().0
+}
+
+fn read_from_unsafe_domain(address: usize) {
+	unsafe {
+		TARGET = address;
+		isolate_function_strong!(read_target());
+	}
+}
+
+/// Switches to the access rights of the application and reads or writes `address`.
+#[inline(never)]
+fn access_from_application(address: usize, write: bool) {
+	let permission = mm::user_permission();
+
+	isolation::write_permissions(permission);
+	unsafe {
+		if write {
+			write_volatile(address as *mut usize, 0);
+		} else {
+			read_volatile(address as *const usize);
+		}
+	}
+	isolation::write_permissions(mm::KERNEL_PERMISSION);
+}
+
+extern "C" fn kernel_reads_safe_data(_arg: usize) {
+	unsafe {
+		read_volatile(safe_data_address() as *const usize);
+	}
+}
+
+extern "C" fn unsafe_domain_reads_unsafe_data(_arg: usize) {
+	read_from_unsafe_domain(unsafe_data_address());
+}
+
+extern "C" fn unsafe_domain_reads_safe_data(_arg: usize) {
+	read_from_unsafe_domain(safe_data_address());
+}
+
+extern "C" fn unsafe_domain_writes_safe_data(_arg: usize) {
+	unsafe {
+		TARGET = safe_data_address();
+		isolate_function_strong!(write_target());
+	}
+}
+
+extern "C" fn unsafe_domain_reads_kernel_stack(_arg: usize) {
+	let local: usize = 0;
+	read_from_unsafe_domain(&local as *const usize as usize);
+}
+
+extern "C" fn unsafe_domain_reads_kernel_heap(_arg: usize) {
+	let data = Box::new(0usize);
+	read_from_unsafe_domain(&*data as *const usize as usize);
+}
+
+extern "C" fn unsafe_domain_reads_page_tables(_arg: usize) {
+	read_from_unsafe_domain(paging::ROOT_PAGE_TABLE_ADDRESS);
+}
+
+extern "C" fn unsafe_domain_reads_idt(_arg: usize) {
+	read_from_unsafe_domain(idt::table_range().0);
+}
+
+/// Writes the first word of the IDT back unchanged.
+#[inline(never)]
+fn rewrite_idt() {
+	let address = idt::table_range().0 as *mut usize;
+	unsafe {
+		write_volatile(address, read_volatile(address));
+	}
+}
+
+extern "C" fn kernel_writes_idt_inside_unseal(_arg: usize) {
+	fnptr::unseal(rewrite_idt);
+}
+
+extern "C" fn kernel_writes_idt(_arg: usize) {
+	rewrite_idt();
+}
+
+extern "C" fn unsafe_domain_reads_gdt(_arg: usize) {
+	read_from_unsafe_domain(gdt::table_range().0);
+}
+
+extern "C" fn unsafe_domain_reads_sealed_data(_arg: usize) {
+	read_from_unsafe_domain(sealed_page_address());
+}
+
+extern "C" fn kernel_writes_sealed_data(_arg: usize) {
+	unsafe {
+		write_volatile(sealed_page_address() as *mut usize, 0);
+	}
+}
+
+extern "C" fn unsafe_domain_writes_sealed_data(_arg: usize) {
+	unsafe {
+		TARGET = sealed_page_address();
+		isolate_function_strong!(write_target());
+	}
+}
+
+extern "C" fn unsafe_domain_reads_sealed_boot_data(_arg: usize) {
+	read_from_unsafe_domain(unsafe { &SEALED_BOOT_DATA as *const usize as usize });
+}
+
+extern "C" fn kernel_writes_sealed_boot_data(_arg: usize) {
+	unsafe {
+		write_volatile(&mut SEALED_BOOT_DATA as *mut usize, 1);
+	}
+}
+
+extern "C" fn kernel_reads_secret_inside_accessor(_arg: usize) {
+	secret::with_secret(secret_id(), |bytes| unsafe { read_volatile(&bytes[0]) });
+}
+
+extern "C" fn kernel_uses_secret_api_inside_accessor(_arg: usize) {
+	secret::with_secret(secret_id(), |_| secret::region(secret_id()).expect("Secret vanished"));
+}
+
+extern "C" fn kernel_reads_secret(_arg: usize) {
+	unsafe {
+		read_volatile(secret_address() as *const usize);
+	}
+}
+
+extern "C" fn unsafe_domain_reads_secret(_arg: usize) {
+	read_from_unsafe_domain(secret_address());
+}
+
+extern "C" fn kernel_writes_jit_region_inside_writer(_arg: usize) {
+	jit::jit_write(jit_id(), |code| unsafe { write_volatile(&mut code[0], 0xC3) });
+}
+
+extern "C" fn kernel_writes_jit_region(_arg: usize) {
+	unsafe {
+		write_volatile(jit_address() as *mut u8, 0xC3);
+	}
+}
+
+extern "C" fn unsafe_domain_writes_jit_region(_arg: usize) {
+	unsafe {
+		TARGET = jit_address();
+		isolate_function_strong!(write_target());
+	}
+}
+
+extern "C" fn unsafe_domain_executes_jit_region(_arg: usize) {
+	unsafe {
+		TARGET = jit_address();
+		isolate_function_strong!(call_target());
+	}
+}
+
+#[cfg(feature = "shadow-stack")]
+extern "C" fn kernel_writes_shadow_stack(_arg: usize) {
+	unsafe {
+		write_volatile(shadow_stack() as *mut usize, 0);
+	}
+}
+
+/// Overwrites the return address of the caller of the trampoline, which then fails its check.
+/// On entry, the return address into the trampoline and its padding precede it.
+#[cfg(feature = "shadow-stack")]
+#[naked]
+extern "C" fn overwrite_return_address() {
+	unsafe {
+		asm!("movq $$0, 16(%rsp)\n\tret" :::: "volatile");
+	}
+}
+
+/// Enters overwrite_return_address through the shadow-stack trampoline, like a stub of syscall!.
+#[cfg(feature = "shadow-stack")]
+#[naked]
+unsafe extern "C" fn enter_overwrite_return_address() {
+	asm!("lea ${0:c}(%rip), %r11\n\tjmp shadow_stack_trampoline"
+		:
+		: "i"(overwrite_return_address as extern "C" fn())
+		:
+		: "volatile");
+}
+
+#[cfg(feature = "shadow-stack")]
+extern "C" fn kernel_returns_to_corrupted_address(_arg: usize) {
+	unsafe {
+		enter_overwrite_return_address();
+	}
+}
+
+extern "C" fn unsafe_domain_reads_safe_data_after_switch(_arg: usize) {
+	unsafe {
+		TARGET = safe_data_address();
+		isolate_function_strong!(yield_and_read_target());
+	}
+}
+
+extern "C" fn application_writes_safe_data(_arg: usize) {
+	access_from_application(safe_data_address(), true);
+}
+
+extern "C" fn application_reads_unsafe_data(_arg: usize) {
+	access_from_application(unsafe_data_address(), false);
+}
+
+/// Called by the page fault handler with the access rights of the kernel.
+/// Returns true if the fault was caused by a running test, which must be terminated.
+pub fn record_fault(virtual_address: usize) -> bool {
+	let running_task = unsafe { RUNNING_TASK };
+	let current_task = match core_scheduler().current_task.try_borrow() {
+		Ok(task) => task.id,
+		Err(_) => return false,
+	};
+
+	if running_task != Some(current_task) {
+		return false;
+	}
+
+	debug!(
+		"Isolation self-test: task {} faulted at {:#X}",
+		current_task, virtual_address
+	);
+	unsafe {
+		// Only the first fault tells which access was denied.
+		if RECORDED_FAULT.is_none() {
+			RECORDED_FAULT = Some(Fault {
+				address: virtual_address,
+				pkey: paging::get_pkey(virtual_address),
+			});
+		}
+	}
+
+	true
+}
+
+fn run_test(test: &SelfTest) -> bool {
+	unsafe {
+		RECORDED_FAULT = None;
+	}
+
+	// The test must not fault before the handler knows its task.
+	let irq = irq::nested_disable();
+	let id = core_scheduler().spawn(test.func, 0, NORMAL_PRIO);
+	unsafe {
+		RUNNING_TASK = Some(id);
+	}
+	irq::nested_enable(irq);
+
+	// An error only means that the test has already finished.
+	let _ = scheduler::join(id);
+
+	let fault = unsafe {
+		RUNNING_TASK = None;
+		RECORDED_FAULT
+	};
+	let passed = match (test.expected_pkey, fault) {
+		(None, None) => true,
+		(Some(pkey), Some(fault)) => fault.pkey == Some(pkey),
+		_ => false,
+	};
+	let verdict = if passed { "PASS" } else { "FAIL" };
+
+	match fault {
+		Some(fault) => info!(
+			"[{}] {}: fault at {:#X} with pkey {:?} (expected pkey {:?})",
+			verdict, test.name, fault.address, fault.pkey, test.expected_pkey
+		),
+		None => info!(
+			"[{}] {}: no fault (expected pkey {:?})",
+			verdict, test.name, test.expected_pkey
+		),
+	}
+
+	passed
+}
+
+/// Runs all tests, prints a summary and shuts down with the number of failed tests as exit code.
+pub fn run() -> ! {
+	info!(
+		"Running the isolation self-test ({} backend, isolation {:?})",
+		isolation::backend().name(),
+		environment::isolation_mode()
+	);
+
+	let failed = TESTS.iter().filter(|test| !run_test(test)).count();
+
+	info!(
+		"Isolation self-test: {} passed, {} failed",
+		TESTS.len() - failed,
+		failed
+	);
+	syscalls::shutdown(failed as i32)
+}
@@ -0,0 +1,145 @@
+//! System call numbers of the ring-3 mode (see arch::x86_64::kernel::syscall).
+//!
+//! Pointers passed by the application must refer to user pages outside of the kernel image,
+//! which the application may access with its own rights. Otherwise, the call fails with -EFAULT.
+//! Secrets and JIT regions are not reachable from ring 3, because their accessors and writers
+//! would run in ring 0.
+
+use arch::x86_64::mm::paging::{self, BasePageSize, PageSize};
+use core::mem;
+use core::slice;
+use errno::*;
+use mm;
+use syscalls::*;
+
+pub const SYS_EXIT: usize = 0;
+pub const SYS_THREAD_EXIT: usize = 1;
+pub const SYS_ABORT: usize = 2;
+pub const SYS_GETPID: usize = 3;
+pub const SYS_YIELD: usize = 4;
+pub const SYS_USLEEP: usize = 5;
+pub const SYS_SBRK: usize = 6;
+pub const SYS_READ: usize = 7;
+pub const SYS_WRITE: usize = 8;
+pub const SYS_OPEN: usize = 9;
+pub const SYS_CLOSE: usize = 10;
+pub const SYS_LSEEK: usize = 11;
+pub const SYS_UNLINK: usize = 12;
+pub const SYS_CLOCK_GETTIME: usize = 13;
+pub const SYS_GETPAGESIZE: usize = 14;
+pub const SYS_RAND: usize = 15;
+pub const SYS_GET_PROCESSOR_COUNT: usize = 16;
+pub const SYS_SPAWN: usize = 17;
+pub const SYS_JOIN: usize = 18;
+pub const SYS_PKEY_ALLOC: usize = 19;
+pub const SYS_PKEY_FREE: usize = 20;
+pub const SYS_PKEY_MPROTECT: usize = 21;
+pub const SYS_MODULE_LOAD: usize = 22;
+pub const SYS_MODULE_OPEN: usize = 23;
+pub const SYS_MODULE_CALL: usize = 24;
+pub const SYS_MODULE_UNLOAD: usize = 25;
+pub const SYS_RESET_COMPARTMENT: usize = 26;
+pub const SYS_BENCH_PKRU_SWITCH: usize = 27;
+
+/// Returns whether the application may pass `size` bytes at `address` to the kernel,
+/// which may also write them if `write` is set.
+fn is_user_buffer(address: usize, size: usize, write: bool) -> bool {
+	if size == 0 {
+		return true;
+	}
+
+	let end = match address.checked_add(size) {
+		Some(end) => end,
+		None => return false,
+	};
+	// The application is linked into the kernel image, whose pages stay user pages.
+	if address < mm::kernel_end_address() && end > mm::kernel_start_address() {
+		return false;
+	}
+
+	paging::is_user_range(address, size, write, mm::user_permission())
+}
+
+/// Returns whether the application may pass the zero-terminated string at `address` to the kernel.
+fn is_user_string(address: usize) -> bool {
+	let mut start = address;
+	loop {
+		let end = align_down!(start, BasePageSize::SIZE) + BasePageSize::SIZE;
+		if !is_user_buffer(start, end - start, false) {
+			return false;
+		}
+
+		let bytes = unsafe { slice::from_raw_parts(start as *const u8, end - start) };
+		if bytes.contains(&0) {
+			return true;
+		}
+		start = end;
+	}
+}
+
+/// Returns whether the pointers among the arguments of the system call `number` may be passed to the kernel.
+fn has_valid_pointers(number: usize, args: &[usize; 5]) -> bool {
+	match number {
+		SYS_READ => is_user_buffer(args[1], args[2], true),
+		SYS_WRITE => is_user_buffer(args[1], args[2], false),
+		SYS_OPEN | SYS_UNLINK | SYS_MODULE_OPEN => is_user_string(args[0]),
+		SYS_CLOCK_GETTIME => is_user_buffer(args[1], mem::size_of::<timespec>(), true),
+		SYS_SPAWN => args[0] == 0 || is_user_buffer(args[0], mem::size_of::<Tid>(), true),
+		SYS_MODULE_LOAD => is_user_buffer(args[0], args[1], false),
+		SYS_MODULE_CALL => is_user_string(args[1]) && is_user_buffer(args[3], mem::size_of::<usize>(), true),
+		SYS_BENCH_PKRU_SWITCH => {
+			is_user_buffer(args[1], mem::size_of::<u64>(), true) && is_user_buffer(args[2], mem::size_of::<u64>(), true)
+		}
+		_ => true,
+	}
+}
+
+/// Calls the system call `number` with `args` and returns its result.
+/// Returns -ENOSYS for unknown numbers and -EFAULT for invalid pointers.
+pub fn dispatch(number: usize, args: [usize; 5]) -> usize {
+	if !has_valid_pointers(number, &args) {
+		return (-EFAULT) as usize;
+	}
+
+	match number {
+		SYS_EXIT => sys_exit(args[0] as i32),
+		SYS_THREAD_EXIT => sys_thread_exit(args[0] as i32),
+		SYS_ABORT => sys_abort(),
+		SYS_GETPID => sys_getpid() as usize,
+		SYS_YIELD => {
+			sys_yield();
+			0
+		}
+		SYS_USLEEP => {
+			sys_usleep(args[0] as u64);
+			0
+		}
+		#[cfg(feature = "newlib")]
+		SYS_SBRK => sys_sbrk(args[0] as isize),
+		SYS_READ => sys_read(args[0] as i32, args[1] as *mut u8, args[2]) as usize,
+		SYS_WRITE => sys_write(args[0] as i32, args[1] as *const u8, args[2]) as usize,
+		SYS_OPEN => sys_open(args[0] as *const u8, args[1] as i32, args[2] as i32) as usize,
+		SYS_CLOSE => sys_close(args[0] as i32) as usize,
+		SYS_LSEEK => sys_lseek(args[0] as i32, args[1] as isize, args[2] as i32) as usize,
+		SYS_UNLINK => sys_unlink(args[0] as *const u8) as usize,
+		SYS_CLOCK_GETTIME => sys_clock_gettime(args[0] as u64, args[1] as *mut timespec) as usize,
+		SYS_GETPAGESIZE => sys_getpagesize() as usize,
+		SYS_RAND => sys_rand() as usize,
+		SYS_GET_PROCESSOR_COUNT => sys_get_processor_count(),
+		SYS_SPAWN => {
+			let func: extern "C" fn(usize) = unsafe { mem::transmute(args[1]) };
+			sys_spawn(args[0] as *mut Tid, func, args[2], args[3] as u8, args[4] as isize) as usize
+		}
+		SYS_JOIN => sys_join(args[0] as Tid) as usize,
+		SYS_PKEY_ALLOC => sys_pkey_alloc(args[0] as u32, args[1] as u32) as usize,
+		SYS_PKEY_FREE => sys_pkey_free(args[0] as i32) as usize,
+		SYS_PKEY_MPROTECT => sys_pkey_mprotect(args[0], args[1], args[2] as i32, args[3] as i32) as usize,
+		SYS_MODULE_LOAD => sys_module_load(args[0] as *const u8, args[1]) as usize,
+		SYS_MODULE_OPEN => sys_module_open(args[0] as *const u8) as usize,
+		SYS_MODULE_CALL => sys_module_call(args[0] as i32, args[1] as *const u8, args[2], args[3] as *mut usize) as usize,
+		SYS_MODULE_UNLOAD => sys_module_unload(args[0] as i32) as usize,
+		SYS_RESET_COMPARTMENT => sys_reset_compartment() as usize,
+		SYS_BENCH_PKRU_SWITCH => sys_bench_pkru_switch(args[0] as u64, args[1] as *mut u64, args[2] as *mut u64) as usize,
+		_ => (-ENOSYS) as usize,
+	}
+}
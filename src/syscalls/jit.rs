@@ -0,0 +1,101 @@
+//! JIT regions for the application (see mm::jit).
+
+use arch::x86_64::mm::isolation;
+use errno::*;
+use mm::jit::{self, JitId, MAX_JIT_REGIONS};
+
+/// Function of the application, which emits code into a JIT region: (address, size, argument)
+pub type JitWriter = extern "C" fn(*mut u8, usize, usize) -> i32;
+
+fn to_id(id: i32) -> Option<JitId> {
+	if id < 0 || id as usize >= MAX_JIT_REGIONS {
+		None
+	} else {
+		Some(id as JitId)
+	}
+}
+
+#[no_mangle]
+fn __sys_jit_create(size: usize) -> i32 {
+	match jit::allocate(size) {
+		Some(id) => id as i32,
+		None => -ENOMEM,
+	}
+}
+
+syscall! {
+	/// Creates a JIT region of `size` bytes, which every domain may execute.
+	/// Returns the ID of the region or a negative error number.
+	pub extern "C" fn sys_jit_create(size: usize) -> i32 {
+		let ret = kernel_function!(__sys_jit_create(size));
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_jit_address(id: i32) -> usize {
+	to_id(id).and_then(jit::region).map_or(0, |(address, _)| address)
+}
+
+syscall! {
+	/// Returns the address of the JIT region `id` or 0 if there is no such region.
+	pub extern "C" fn sys_jit_address(id: i32) -> usize {
+		let ret = kernel_function!(__sys_jit_address(id));
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_jit_begin_write(id: i32) -> Option<(usize, usize)> {
+	to_id(id).and_then(jit::begin_write)
+}
+
+#[no_mangle]
+fn __sys_jit_end_write(id: i32) -> i32 {
+	match to_id(id) {
+		Some(id) if jit::end_write(id) => 0,
+		_ => -EACCES,
+	}
+}
+
+syscall! {
+	/// Calls `writer` with write access to the JIT region `id` and `arg` and returns its result.
+	/// The writer runs in the domain of the caller with interrupts disabled. Meanwhile, no region
+	/// is executable. Returns -EINVAL while another region is being written and -EACCES if the
+	/// emitted code writes PKRU and is rejected (see mm::jit).
+	pub extern "C" fn sys_jit_write(id: i32, writer: Option<JitWriter>, arg: usize) -> i32 {
+		let writer = match writer {
+			Some(writer) => writer,
+			None => return -EINVAL,
+		};
+
+		let lookup = kernel_function!(__sys_jit_begin_write(id));
+		let ret = match lookup {
+			Some((address, size)) => isolation::with_jit_access(|| writer(address as *mut u8, size, arg)),
+			None => return -EINVAL,
+		};
+
+		let check = kernel_function!(__sys_jit_end_write(id));
+		if check < 0 {
+			check
+		} else {
+			ret
+		}
+	}
+}
+
+#[no_mangle]
+fn __sys_jit_destroy(id: i32) -> i32 {
+	match to_id(id) {
+		Some(id) if jit::free(id) => 0,
+		_ => -EINVAL,
+	}
+}
+
+syscall! {
+	/// Frees the JIT region `id`, which nobody may execute anymore.
+	pub extern "C" fn sys_jit_destroy(id: i32) -> i32 {
+		let ret = kernel_function!(__sys_jit_destroy(id));
+		return ret;
+	}
+}
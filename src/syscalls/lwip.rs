@@ -32,9 +32,10 @@ fn __sys_lwip_register_tcpip_task(id: Tid) {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_lwip_register_tcpip_task(id: Tid) {
-	kernel_function!(__sys_lwip_register_tcpip_task(id));
+syscall! {
+	pub extern "C" fn sys_lwip_register_tcpip_task(id: Tid) {
+		kernel_function!(__sys_lwip_register_tcpip_task(id));
+	}
 }
 
 #[no_mangle]
@@ -42,10 +43,11 @@ fn __sys_lwip_get_errno() -> i32 {
 	core_scheduler().current_task.borrow().lwip_errno
 }
 
-#[no_mangle]
-pub extern "C" fn sys_lwip_get_errno() -> i32 {
-	let lwip_errno = kernel_function!(__sys_lwip_get_errno());
-	return lwip_errno;
+syscall! {
+	pub extern "C" fn sys_lwip_get_errno() -> i32 {
+		let lwip_errno = kernel_function!(__sys_lwip_get_errno());
+		return lwip_errno;
+	}
 }
 
 #[no_mangle]
@@ -53,9 +55,10 @@ fn __sys_lwip_set_errno(errno: i32) {
 	core_scheduler().current_task.borrow_mut().lwip_errno = errno;
 }
 
-#[no_mangle]
-pub extern "C" fn sys_lwip_set_errno(errno: i32) {
-	kernel_function!(__sys_lwip_set_errno(errno));
+syscall! {
+	pub extern "C" fn sys_lwip_set_errno(errno: i32) {
+		kernel_function!(__sys_lwip_set_errno(errno));
+	}
 }
 
 #[no_mangle]
@@ -66,9 +69,10 @@ fn __sys_acquire_putchar_lock() {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_acquire_putchar_lock() {
-	kernel_function!(__sys_acquire_putchar_lock());
+syscall! {
+	pub extern "C" fn sys_acquire_putchar_lock() {
+		kernel_function!(__sys_acquire_putchar_lock());
+	}
 }
 
 #[no_mangle]
@@ -76,9 +80,10 @@ fn __sys_putchar(character: u8) {
 	arch::output_message_byte(character);
 }
 
-#[no_mangle]
-pub extern "C" fn sys_putchar(character: u8) {
-	kernel_function!(__sys_putchar(character));
+syscall! {
+	pub extern "C" fn sys_putchar(character: u8) {
+		kernel_function!(__sys_putchar(character));
+	}
 }
 
 #[no_mangle]
@@ -89,7 +94,8 @@ fn __sys_release_putchar_lock() {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_release_putchar_lock() {
-	kernel_function!(__sys_release_putchar_lock());
+syscall! {
+	pub extern "C" fn sys_release_putchar_lock() {
+		kernel_function!(__sys_release_putchar_lock());
+	}
 }
@@ -7,12 +7,17 @@
 // copied, modified, or distributed except according to those terms.
 
 mod condvar;
+mod dispatch;
 mod interfaces;
+mod jit;
 #[cfg(feature = "newlib")]
 mod lwip;
+mod module;
+mod pkey;
 mod processor;
 mod random;
 mod recmutex;
+mod secret;
 mod semaphore;
 mod spinlock;
 mod system;
@@ -20,15 +25,22 @@ mod tasks;
 mod timer;
 
 pub use self::condvar::*;
+pub use self::dispatch::*;
+pub use self::jit::*;
+pub use self::module::*;
+pub use self::pkey::*;
 pub use self::processor::*;
 pub use self::random::*;
 pub use self::recmutex::*;
+pub use self::secret::*;
 pub use self::semaphore::*;
 pub use self::spinlock::*;
 pub use self::system::*;
 pub use self::tasks::*;
 pub use self::timer::*;
+use alloc::vec::Vec;
 use environment;
+use mm::fnptr;
 #[cfg(feature = "newlib")]
 use synch::spinlock::SpinlockIrqSave;
 use syscalls::interfaces::SyscallInterface;
@@ -39,7 +51,8 @@ const LWIP_FD_BIT: i32 = (1 << 30);
 #[cfg(feature = "newlib")]
 safe_global_var!(pub static LWIP_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(()));
 
-safe_global_var!(static mut SYS: &'static dyn SyscallInterface = &interfaces::Generic);
+/// Chosen by `init`, afterwards the trait object is write-protected (see mm::fnptr).
+fnptr_global_var!(static mut SYS: &'static dyn SyscallInterface = &interfaces::Generic);
 
 pub fn init() {
 	
@@ -48,7 +61,7 @@ pub fn init() {
 	if environment::is_proxy() {
 		panic!("Currently, we don't support the proxy mode!");
 	} else if environment::is_uhyve() {
-		unsafe {SYS = &interfaces::Uhyve};
+		fnptr::unseal(|| unsafe { SYS = &interfaces::Uhyve });
 	}
 
 		// Perform interface-specific initialization steps.
@@ -63,42 +76,84 @@ pub fn get_application_parameters() -> (i32, *const *const u8, *const *const u8)
 	unsafe { SYS.get_application_parameters() }
 }
 
-#[no_mangle]
-pub extern "C" fn sys_shutdown(arg: i32) -> ! {
-	unsafe { kernel_function!(SYS.shutdown(arg)) }
+/// Shuts down from kernel code, which already runs on the kernel stack.
+pub fn shutdown(arg: i32) -> ! {
+	unsafe { SYS.shutdown(arg) }
 }
 
-#[no_mangle]
-pub extern "C" fn sys_unlink(name: *const u8) -> i32 {
-	unsafe { kernel_function!(SYS.unlink(name)) }
+syscall! {
+	pub extern "C" fn sys_shutdown(arg: i32) -> ! {
+		unsafe { kernel_function!(SYS.shutdown(arg)) }
+	}
+}
+
+/// Reads the whole file `name` from kernel code, e.g. a module from the host of uhyve.
+/// Returns the contents or a negative error number.
+pub fn read_file(name: *const u8) -> Result<Vec<u8>, i32> {
+	const CHUNK_SIZE: usize = 0x1000;
+
+	let fd = unsafe { SYS.open(name, 0, 0) };
+	if fd < 0 {
+		return Err(fd);
+	}
+
+	let mut data = Vec::new();
+	let ret = loop {
+		let len = data.len();
+		data.resize(len + CHUNK_SIZE, 0);
+		let ret = unsafe { SYS.read(fd, data[len..].as_mut_ptr(), CHUNK_SIZE) };
+		data.truncate(len + if ret > 0 { ret as usize } else { 0 });
+		if ret <= 0 {
+			break ret as i32;
+		}
+	};
+	unsafe { SYS.close(fd) };
+
+	if ret < 0 {
+		Err(ret)
+	} else {
+		Ok(data)
+	}
+}
+
+syscall! {
+	pub extern "C" fn sys_unlink(name: *const u8) -> i32 {
+		unsafe { kernel_function!(SYS.unlink(name)) }
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_open(name: *const u8, flags: i32, mode: i32) -> i32 {
-	unsafe { kernel_function!(SYS.open(name, flags, mode)) }
+syscall! {
+	pub extern "C" fn sys_open(name: *const u8, flags: i32, mode: i32) -> i32 {
+		unsafe { kernel_function!(SYS.open(name, flags, mode)) }
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_close(fd: i32) -> i32 {
-	unsafe { kernel_function!(SYS.close(fd)) }
+syscall! {
+	pub extern "C" fn sys_close(fd: i32) -> i32 {
+		unsafe { kernel_function!(SYS.close(fd)) }
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_read(fd: i32, buf: *mut u8, len: usize) -> isize {
-	unsafe { kernel_function!(SYS.read(fd, buf, len)) }
+syscall! {
+	pub extern "C" fn sys_read(fd: i32, buf: *mut u8, len: usize) -> isize {
+		unsafe { kernel_function!(SYS.read(fd, buf, len)) }
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_write(fd: i32, buf: *const u8, len: usize) -> isize {
-	unsafe { kernel_function!(SYS.write(fd, buf, len)) }
+syscall! {
+	pub extern "C" fn sys_write(fd: i32, buf: *const u8, len: usize) -> isize {
+		unsafe { kernel_function!(SYS.write(fd, buf, len)) }
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_lseek(fd: i32, offset: isize, whence: i32) -> isize {
-	unsafe { kernel_function!(SYS.lseek(fd, offset, whence)) }
+syscall! {
+	pub extern "C" fn sys_lseek(fd: i32, offset: isize, whence: i32) -> isize {
+		unsafe { kernel_function!(SYS.lseek(fd, offset, whence)) }
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_stan(file: *const u8, st: usize) -> i32 {
-	unsafe { kernel_function!(SYS.stat(file, st)) }
+syscall! {
+	pub extern "C" fn sys_stan(file: *const u8, st: usize) -> i32 {
+		unsafe { kernel_function!(SYS.stat(file, st)) }
+	}
 }
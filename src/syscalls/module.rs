@@ -0,0 +1,94 @@
+//! Loading and calling modules from the application (see module).
+
+use core::slice;
+use errno::*;
+use module::{self, ModuleId, MAX_SYMBOL_LENGTH};
+
+fn result(ret: Result<ModuleId, i32>) -> i32 {
+	match ret {
+		Ok(id) => id,
+		Err(errno) => errno,
+	}
+}
+
+#[no_mangle]
+fn __sys_module_load(image: *const u8, len: usize) -> i32 {
+	if image.is_null() {
+		return -EINVAL;
+	}
+
+	result(module::load(unsafe { slice::from_raw_parts(image, len) }))
+}
+
+syscall! {
+	/// Loads the module from the ELF image of `len` bytes at `image`.
+	/// Returns the ID of the module or a negative error number.
+	pub extern "C" fn sys_module_load(image: *const u8, len: usize) -> i32 {
+		let ret = kernel_function!(__sys_module_load(image, len));
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_module_open(name: *const u8) -> i32 {
+	if name.is_null() {
+		return -EINVAL;
+	}
+
+	result(module::load_file(name))
+}
+
+syscall! {
+	/// Loads the module from the file `name`, e.g. from the host of uhyve.
+	/// Returns the ID of the module or a negative error number.
+	pub extern "C" fn sys_module_open(name: *const u8) -> i32 {
+		let ret = kernel_function!(__sys_module_open(name));
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_module_call(id: ModuleId, name: *const u8, arg: usize, ret: *mut usize) -> i32 {
+	if name.is_null() || ret.is_null() {
+		return -EINVAL;
+	}
+
+	let name = unsafe {
+		let len = (0..MAX_SYMBOL_LENGTH).take_while(|&i| *name.add(i) != 0).count();
+		slice::from_raw_parts(name, len)
+	};
+	match module::call(id, name, arg) {
+		Ok(value) => {
+			unsafe {
+				*ret = value;
+			}
+			0
+		}
+		Err(errno) => errno,
+	}
+}
+
+syscall! {
+	/// Calls the function `name` of the module `id` with `arg` and stores its result in `ret`.
+	/// The function runs in the compartment of the module.
+	pub extern "C" fn sys_module_call(id: ModuleId, name: *const u8, arg: usize, ret: *mut usize) -> i32 {
+		let ret = kernel_function!(__sys_module_call(id, name, arg, ret));
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_module_unload(id: ModuleId) -> i32 {
+	match module::unload(id) {
+		Ok(()) => 0,
+		Err(errno) => errno,
+	}
+}
+
+syscall! {
+	/// Unloads the module `id` and frees its key.
+	pub extern "C" fn sys_module_unload(id: ModuleId) -> i32 {
+		let ret = kernel_function!(__sys_module_unload(id));
+		return ret;
+	}
+}
@@ -0,0 +1,190 @@
+//! Protection keys for the application, following pkey_alloc(2), pkey_free(2) and pkey_mprotect(2) of Linux.
+//!
+//! The application gets the keys from mm::FIRST_APPLICATION_KEY upwards and switches their
+//! rights itself with RDPKRU/WRPKRU. The gates keep these rights (see mm::kernel_permission).
+
+use arch::x86_64::kernel::processor;
+use arch::x86_64::mm::isolation;
+use arch::x86_64::mm::mpk;
+use arch::x86_64::mm::paging::{self, BasePageSize, PageSize};
+use errno::*;
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+pub const PKEY_DISABLE_ACCESS: u32 = 0x1;
+pub const PKEY_DISABLE_WRITE: u32 = 0x2;
+
+pub const PROT_NONE: i32 = 0x0;
+pub const PROT_READ: i32 = 0x1;
+pub const PROT_WRITE: i32 = 0x2;
+pub const PROT_EXEC: i32 = 0x4;
+
+/// Keys handed out to the application and to modules (bit k stands for key k)
+struct Pkeys {
+	allocated: u16,
+	/// Keys of the compartments of modules (see module::load), which the application may not use
+	modules: u16,
+}
+
+safe_global_var!(static PKEYS: SpinlockIrqSave<Pkeys> = SpinlockIrqSave::new(Pkeys { allocated: 0, modules: 0 }));
+
+fn is_allocated(pkey: i32) -> bool {
+	if pkey < i32::from(mm::FIRST_APPLICATION_KEY) || pkey >= 16 {
+		return false;
+	}
+
+	let pkeys = PKEYS.lock();
+	pkeys.allocated & !pkeys.modules & (1 << pkey) != 0
+}
+
+fn allocate(module: bool) -> Option<u8> {
+	let mut pkeys = PKEYS.lock();
+	let key = (mm::FIRST_APPLICATION_KEY..16).find(|key| pkeys.allocated & (1 << key) == 0)?;
+	pkeys.allocated |= 1 << key;
+	if module {
+		pkeys.modules |= 1 << key;
+	}
+	Some(key)
+}
+
+/// Allocates the key of the compartment of a module.
+pub fn allocate_module_key() -> Option<u8> {
+	allocate(true)
+}
+
+/// Frees the key of an unloaded module, whose pages no longer carry it.
+pub fn free_module_key(key: u8) {
+	let mut pkeys = PKEYS.lock();
+	pkeys.allocated &= !(1 << key);
+	pkeys.modules &= !(1 << key);
+}
+
+/// Returns whether `key` belongs to a module.
+pub fn is_module_key(key: u8) -> bool {
+	key < 16 && PKEYS.lock().modules & (1 << key) != 0
+}
+
+#[no_mangle]
+fn __sys_pkey_alloc(flags: u32, access_rights: u32) -> i32 {
+	if flags != 0 || access_rights & !(PKEY_DISABLE_ACCESS | PKEY_DISABLE_WRITE) != 0 {
+		return -EINVAL;
+	}
+
+	// Without PKU, the application could not switch the rights of its keys.
+	if cfg!(feature = "no-mpk") || !processor::supports_ospke() {
+		return -ENOSPC;
+	}
+
+	match allocate(false) {
+		Some(key) => i32::from(key),
+		None => -ENOSPC,
+	}
+}
+
+syscall! {
+	/// Allocates a protection key with the initial rights `access_rights` for the calling task.
+	/// Returns the key or a negative error number.
+	pub extern "C" fn sys_pkey_alloc(flags: u32, access_rights: u32) -> i32 {
+		let ret = kernel_function!(__sys_pkey_alloc(flags, access_rights));
+
+		// The rights belong to the caller, so they are set in its domain.
+		if ret >= 0 {
+			let shift = 2 * ret as u32;
+			let permissions = isolation::read_permissions() & !(0b11 << shift);
+			isolation::write_permissions(permissions | access_rights << shift);
+		}
+
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_pkey_free(pkey: i32) -> i32 {
+	if !is_allocated(pkey) {
+		return -EINVAL;
+	}
+
+	// Like on Linux, pages keep the key.
+	PKEYS.lock().allocated &= !(1 << pkey);
+	0
+}
+
+syscall! {
+	/// Frees the protection key `pkey`.
+	pub extern "C" fn sys_pkey_free(pkey: i32) -> i32 {
+		let ret = kernel_function!(__sys_pkey_free(pkey));
+		return ret;
+	}
+}
+
+/// Returns whether the application may change the page at `virtual_address`.
+/// Only pages of its heap without a kernel key or the key of a module qualify,
+/// so stacks and other memory of the kernel keep their rights.
+fn is_application_page(virtual_address: usize) -> Result<(), i32> {
+	if !mm::is_user_heap(virtual_address) {
+		return Err(-EACCES);
+	}
+
+	match paging::get_pkey(virtual_address) {
+		None => Err(-ENOMEM),
+		Some(key) if key == 0 || (key >= mm::FIRST_APPLICATION_KEY && !is_module_key(key)) => Ok(()),
+		Some(_) => Err(-EACCES),
+	}
+}
+
+#[no_mangle]
+fn __sys_pkey_mprotect(addr: usize, len: usize, prot: i32, pkey: i32) -> i32 {
+	if addr % BasePageSize::SIZE != 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+		return -EINVAL;
+	}
+	// W^X
+	if prot & PROT_WRITE != 0 && prot & PROT_EXEC != 0 {
+		return -EACCES;
+	}
+	// Like on Linux, the default key 0 is always allocated.
+	if pkey != -1 && pkey != 0 && !is_allocated(pkey) {
+		return -EINVAL;
+	}
+
+	let end = match len.checked_add(BasePageSize::SIZE - 1).and_then(|len| addr.checked_add(len)) {
+		Some(end) => align_down!(end, BasePageSize::SIZE),
+		None => return -ENOMEM,
+	};
+	let size = end - addr;
+	if size == 0 {
+		return 0;
+	}
+
+	for page in (addr..end).step_by(BasePageSize::SIZE) {
+		if let Err(errno) = is_application_page(page) {
+			return errno;
+		}
+	}
+
+	let changed = if prot == PROT_NONE {
+		paging::set_page_inaccessible(addr, size)
+	} else if prot & PROT_EXEC != 0 {
+		mm::make_executable(addr, size)
+	} else {
+		paging::set_page_rights(addr, size, prot & PROT_WRITE != 0, false)
+	};
+	if !changed {
+		return -EACCES;
+	}
+
+	// A key of -1 keeps the current keys.
+	if pkey != -1 {
+		mpk::mpk_mem_set_key::<BasePageSize>(addr, size, pkey as u8);
+	}
+
+	0
+}
+
+syscall! {
+	/// Sets the rights `prot` of `len` bytes at `addr` and tags them with the key `pkey`.
+	/// Only pages of the application may be changed and writable pages never become executable.
+	pub extern "C" fn sys_pkey_mprotect(addr: usize, len: usize, prot: i32, pkey: i32) -> i32 {
+		let ret = kernel_function!(__sys_pkey_mprotect(addr, len, prot, pkey));
+		return ret;
+	}
+}
@@ -6,6 +6,7 @@
 // copied, modified, or distributed except according to those terms.
 
 use arch;
+use errno::*;
 //use mm;
 
 /** Returns the number of processors currently online. */
@@ -14,10 +15,11 @@ fn __sys_get_processor_count() -> usize {
         arch::get_processor_count()
 }
 
-#[no_mangle]
-pub extern "C" fn sys_get_processor_count() -> usize {
-        let ret = kernel_function!(__sys_get_processor_count());
-        return ret;
+syscall! {
+	pub extern "C" fn sys_get_processor_count() -> usize {
+	        let ret = kernel_function!(__sys_get_processor_count());
+	        return ret;
+	}
 }
 
 /** Returns the processor frequency in MHz. */
@@ -26,8 +28,33 @@ fn __sys_get_processor_frequency() -> u16 {
         arch::processor::get_frequency()
 }
 
+syscall! {
+	pub extern "C" fn sys_get_processor_frequency() -> u16 {
+	        let ret = kernel_function!(__sys_get_processor_frequency());
+	        return ret;
+	}
+}
+
+/** Measures how the task switch saves and restores PKRU, see isolation::bench_pkru_switch. */
 #[no_mangle]
-pub extern "C" fn sys_get_processor_frequency() -> u16 {
-        let ret = kernel_function!(__sys_get_processor_frequency());
-        return ret;
+fn __sys_bench_pkru_switch(iterations: u64, xsave_ticks: *mut u64, register_ticks: *mut u64) -> i32 {
+        match arch::mm::isolation::bench_pkru_switch(iterations) {
+                Some((xsave, register)) => {
+                        unsafe {
+                                *xsave_ticks = xsave;
+                                *register_ticks = register;
+                        }
+                        0
+                }
+                None => -EOPNOTSUPP,
+        }
+}
+
+syscall! {
+	/// Writes the average ticks of a PKRU switch as XSAVE state component and with RDPKRU/WRPKRU.
+	/// Returns -EOPNOTSUPP without PKU or if PKRU is no XSAVE state component.
+	pub extern "C" fn sys_bench_pkru_switch(iterations: u64, xsave_ticks: *mut u64, register_ticks: *mut u64) -> i32 {
+	        let ret = kernel_function!(__sys_bench_pkru_switch(iterations, xsave_ticks, register_ticks));
+	        return ret;
+	}
 }
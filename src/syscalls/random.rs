@@ -20,7 +20,7 @@ fn generate_park_miller_lehmer_random_number() -> u32 {
 }
 
 #[no_mangle]
-fn __sys_rand() -> u32 {
+pub fn __sys_rand() -> u32 {
 	if let Some(value) = arch::processor::generate_random_number() {
 		value
 	} else {
@@ -28,10 +28,11 @@ fn __sys_rand() -> u32 {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_rand() -> u32 {
-	let ret = kernel_function!(__sys_rand());
-	return ret;
+syscall! {
+	pub extern "C" fn sys_rand() -> u32 {
+		let ret = kernel_function!(__sys_rand());
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -27,10 +27,11 @@ fn __sys_recmutex_init(recmutex: *mut *mut RecursiveMutex) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_recmutex_init(recmutex: *mut *mut RecursiveMutex) -> i32 {
-	let ret = kernel_function!(__sys_recmutex_init(recmutex));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_recmutex_init(recmutex: *mut *mut RecursiveMutex) -> i32 {
+		let ret = kernel_function!(__sys_recmutex_init(recmutex));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -47,10 +48,11 @@ fn __sys_recmutex_destroy(recmutex: *mut RecursiveMutex) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_recmutex_destroy(recmutex: *mut RecursiveMutex) -> i32 {
-	let ret = kernel_function!(__sys_recmutex_destroy(recmutex));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_recmutex_destroy(recmutex: *mut RecursiveMutex) -> i32 {
+		let ret = kernel_function!(__sys_recmutex_destroy(recmutex));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -69,10 +71,11 @@ fn __sys_recmutex_lock(recmutex: *mut RecursiveMutex) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_recmutex_lock(recmutex: *mut RecursiveMutex) -> i32 {
-	let ret =  kernel_function!(__sys_recmutex_lock(recmutex));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_recmutex_lock(recmutex: *mut RecursiveMutex) -> i32 {
+		let ret =  kernel_function!(__sys_recmutex_lock(recmutex));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -91,8 +94,9 @@ fn __sys_recmutex_unlock(recmutex: *mut RecursiveMutex) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_recmutex_unlock(recmutex: *mut RecursiveMutex) -> i32 {
-	let ret = kernel_function!(__sys_recmutex_unlock(recmutex));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_recmutex_unlock(recmutex: *mut RecursiveMutex) -> i32 {
+		let ret = kernel_function!(__sys_recmutex_unlock(recmutex));
+		return ret;
+	}
 }
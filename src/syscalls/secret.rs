@@ -0,0 +1,93 @@
+use arch::x86_64::mm::isolation;
+use errno::*;
+use mm;
+use mm::secret::{self, SecretId, MAX_SECRETS};
+use synch::spinlock::SpinlockIrqSave;
+
+/// Function of the application, which gets access to a secret: (address, size, argument)
+pub type SecretAccessor = extern "C" fn(*mut u8, usize, usize) -> i32;
+
+/// Accessor registered for every secret created through sys_secret_create
+safe_global_var!(static ACCESSORS: SpinlockIrqSave<[Option<SecretAccessor>; MAX_SECRETS]> = SpinlockIrqSave::new([None; MAX_SECRETS]));
+
+#[no_mangle]
+fn __sys_secret_create(size: usize, accessor: Option<SecretAccessor>) -> i32 {
+	let accessor = match accessor {
+		Some(accessor) => accessor,
+		None => return -EINVAL,
+	};
+
+	match secret::allocate(size) {
+		Some(id) => {
+			ACCESSORS.lock()[id] = Some(accessor);
+			id as i32
+		}
+		None => -ENOMEM,
+	}
+}
+
+syscall! {
+	/// Creates a zeroed secret of `size` bytes, which is only accessible inside `accessor`.
+	/// Returns the ID of the secret or a negative error number.
+	pub extern "C" fn sys_secret_create(size: usize, accessor: Option<SecretAccessor>) -> i32 {
+		let ret = kernel_function!(__sys_secret_create(size, accessor));
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_secret_lookup(id: i32) -> Option<(usize, usize, SecretAccessor)> {
+	if id < 0 {
+		return None;
+	}
+
+	let id = id as SecretId;
+	let accessor = (*ACCESSORS.lock().get(id)?)?;
+	// Keeps the secret alive until __sys_secret_release, even if it is destroyed meanwhile.
+	let (address, size) = secret::acquire(id)?;
+	Some((address, size, accessor))
+}
+
+#[no_mangle]
+fn __sys_secret_release(id: i32) {
+	secret::release(id as SecretId);
+}
+
+syscall! {
+	/// Calls the accessor of the secret `id` with its bytes and `arg` and returns its result.
+	/// The accessor runs in the domain of the caller with interrupts disabled.
+	pub extern "C" fn sys_secret_call(id: i32, arg: usize) -> i32 {
+		let lookup = kernel_function!(__sys_secret_lookup(id));
+
+		match lookup {
+			Some((address, size, accessor)) => {
+				let ret = isolation::with_secret_access(|| accessor(address as *mut u8, size, arg));
+				kernel_function!(__sys_secret_release(id));
+				ret
+			}
+			None => -EINVAL,
+		}
+	}
+}
+
+#[no_mangle]
+fn __sys_secret_destroy(id: i32) -> i32 {
+	if id < 0 || id as usize >= MAX_SECRETS {
+		return -EINVAL;
+	}
+
+	ACCESSORS.lock()[id as usize] = None;
+	if secret::free(id as SecretId) {
+		0
+	} else {
+		-EINVAL
+	}
+}
+
+syscall! {
+	/// Zeroizes and frees the secret `id`.
+	pub extern "C" fn sys_secret_destroy(id: i32) -> i32 {
+		let ret = kernel_function!(__sys_secret_destroy(id));
+		return ret;
+	}
+}
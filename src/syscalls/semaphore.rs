@@ -29,10 +29,11 @@ fn __sys_sem_init(sem: *mut *mut Semaphore, value: u32) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_sem_init(sem: *mut *mut Semaphore, value: u32) -> i32 {
-	let ret = kernel_function!(__sys_sem_init(sem, value));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_sem_init(sem: *mut *mut Semaphore, value: u32) -> i32 {
+		let ret = kernel_function!(__sys_sem_init(sem, value));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -49,10 +50,11 @@ fn __sys_sem_destroy(sem: *mut Semaphore) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_sem_destroy(sem: *mut Semaphore) -> i32 {
-	let ret = kernel_function!(__sys_sem_destroy(sem: *mut Semaphore));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_sem_destroy(sem: *mut Semaphore) -> i32 {
+		let ret = kernel_function!(__sys_sem_destroy(sem: *mut Semaphore));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -72,10 +74,11 @@ fn __sys_sem_post(sem: *const Semaphore) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_sem_post(sem: *const Semaphore) -> i32 {
-	let ret = kernel_function!(__sys_sem_post(sem: *const Semaphore));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_sem_post(sem: *const Semaphore) -> i32 {
+		let ret = kernel_function!(__sys_sem_post(sem: *const Semaphore));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -98,10 +101,11 @@ fn __sys_sem_trywait(sem: *const Semaphore) -> i32 {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_sem_trywait(sem: *const Semaphore) -> i32 {
-	let ret = kernel_function!(__sys_sem_trywait(sem));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_sem_trywait(sem: *const Semaphore) -> i32 {
+		let ret = kernel_function!(__sys_sem_trywait(sem));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -132,12 +136,14 @@ fn __sys_sem_timedwait(sem: *const Semaphore, ms: u32) -> i32 {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_sem_timedwait(sem: *const Semaphore, ms: u32) -> i32 {
-	return kernel_function!(__sys_sem_timedwait(sem, ms));
+syscall! {
+	pub extern "C" fn sys_sem_timedwait(sem: *const Semaphore, ms: u32) -> i32 {
+		return kernel_function!(__sys_sem_timedwait(sem, ms));
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_sem_cancelablewait(sem: *const Semaphore, ms: u32) -> i32 {
-	sys_sem_timedwait(sem, ms)
+syscall! {
+	pub extern "C" fn sys_sem_cancelablewait(sem: *const Semaphore, ms: u32) -> i32 {
+		sys_sem_timedwait(sem, ms)
+	}
 }
@@ -39,10 +39,11 @@ fn __sys_spinlock_init(lock: *mut *mut SpinlockContainer) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_init(lock: *mut *mut SpinlockContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_init(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_init(lock: *mut *mut SpinlockContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_init(lock));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -58,10 +59,11 @@ fn __sys_spinlock_destroy(lock: *mut SpinlockContainer) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_destroy(lock: *mut SpinlockContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_destroy(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_destroy(lock: *mut SpinlockContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_destroy(lock));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -84,10 +86,11 @@ fn __sys_spinlock_lock(lock: *mut SpinlockContainer) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_lock(lock: *mut SpinlockContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_lock(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_lock(lock: *mut SpinlockContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_lock(lock));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -110,10 +113,11 @@ fn __sys_spinlock_unlock(lock: *mut SpinlockContainer) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_unlock(lock: *mut SpinlockContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_unlock(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_unlock(lock: *mut SpinlockContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_unlock(lock));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -135,10 +139,11 @@ fn __sys_spinlock_irqsave_init(lock: *mut *mut SpinlockIrqSaveContainer) -> i32
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_irqsave_init(lock: *mut *mut SpinlockIrqSaveContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_irqsave_init(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_irqsave_init(lock: *mut *mut SpinlockIrqSaveContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_irqsave_init(lock));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -154,10 +159,11 @@ fn __sys_spinlock_irqsave_destroy(lock: *mut SpinlockIrqSaveContainer) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_irqsave_destroy(lock: *mut SpinlockIrqSaveContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_irqsave_destroy(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_irqsave_destroy(lock: *mut SpinlockIrqSaveContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_irqsave_destroy(lock));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -180,10 +186,11 @@ fn __sys_spinlock_irqsave_lock(lock: *mut SpinlockIrqSaveContainer) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_irqsave_lock(lock: *mut SpinlockIrqSaveContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_irqsave_lock(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_irqsave_lock(lock: *mut SpinlockIrqSaveContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_irqsave_lock(lock));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -206,8 +213,9 @@ fn __sys_spinlock_irqsave_unlock(lock: *mut SpinlockIrqSaveContainer) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spinlock_irqsave_unlock(lock: *mut SpinlockIrqSaveContainer) -> i32 {
-	let ret = kernel_function!(__sys_spinlock_irqsave_unlock(lock));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spinlock_irqsave_unlock(lock: *mut SpinlockIrqSaveContainer) -> i32 {
+		let ret = kernel_function!(__sys_spinlock_irqsave_unlock(lock));
+		return ret;
+	}
 }
@@ -22,15 +22,46 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch;
-//use mm;
+use mm;
 
 #[no_mangle]
 fn __sys_getpagesize() -> i32 {
 	arch::mm::paging::get_application_page_size() as i32
 }
 
+syscall! {
+	pub extern "C" fn sys_getpagesize() -> i32 {
+		let ret = kernel_function!(__sys_getpagesize());
+		return ret;
+	}
+}
+
 #[no_mangle]
-pub extern "C" fn sys_getpagesize() -> i32 {
-	let ret = kernel_function!(__sys_getpagesize());
-	return ret;
+fn __sys_audit_address_space() -> i32 {
+	arch::mm::paging::audit_address_space() as i32
+}
+
+syscall! {
+	/// Prints the address space and returns the number of violated isolation invariants.
+	pub extern "C" fn sys_audit_address_space() -> i32 {
+		let ret = kernel_function!(__sys_audit_address_space());
+		return ret;
+	}
+}
+
+#[no_mangle]
+fn __sys_reset_compartment() -> i32 {
+	match mm::compartment::reset_compartment() {
+		Ok(()) => 0,
+		Err(errno) => errno,
+	}
+}
+
+syscall! {
+	/// Restores the unsafe compartment to its state after the initialization, e.g. after a
+	/// protection fault in isolated code. Returns -EBUSY while an isolated call runs.
+	pub extern "C" fn sys_reset_compartment() -> i32 {
+		let ret = kernel_function!(__sys_reset_compartment());
+		return ret;
+	}
 }
@@ -24,13 +24,14 @@ pub type SignalHandler = extern "C" fn(i32);
 pub type Tid = u32;
 
 #[no_mangle]
-fn __sys_getpid() -> Tid {
-	safe_core_scheduler().current_task.borrow().id.into() as Tid
+pub fn __sys_getpid() -> Tid {
+	core_scheduler().current_task.borrow().id.into() as Tid
 }
 
-#[no_mangle]
-pub extern "C" fn sys_getpid() -> Tid {
-	kernel_function!(__sys_getpid())
+syscall! {
+	pub extern "C" fn sys_getpid() -> Tid {
+		kernel_function!(__sys_getpid())
+	}
 }
 
 #[no_mangle]
@@ -47,35 +48,40 @@ fn __sys_getprio(id: *const Tid) -> i32 {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_getprio(id: *const Tid) -> i32 {
-	let ret = kernel_function!(__sys_getprio(id));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_getprio(id: *const Tid) -> i32 {
+		let ret = kernel_function!(__sys_getprio(id));
+		return ret;
+	}
 }
 
 
-#[no_mangle]
-pub extern "C" fn sys_setprio(_id: *const Tid, _prio: i32) -> i32 {
-	-ENOSYS
+syscall! {
+	pub extern "C" fn sys_setprio(_id: *const Tid, _prio: i32) -> i32 {
+		-ENOSYS
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_exit(arg: i32) -> ! {
-	kernel_enter!("sys_thread_exit");
-	//debug!("Exit program with error code {}!", arg);
-	syscalls::sys_shutdown(arg);
+syscall! {
+	pub extern "C" fn sys_exit(arg: i32) -> ! {
+		kernel_enter!("sys_thread_exit");
+		//debug!("Exit program with error code {}!", arg);
+		syscalls::sys_shutdown(arg);
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_thread_exit(arg: i32) -> ! {
-	kernel_enter!("sys_thread_exit");
-	//debug!("Exit thread with error code {}!", arg);
-	core_scheduler().exit(arg);
+syscall! {
+	pub extern "C" fn sys_thread_exit(arg: i32) -> ! {
+		kernel_enter!("sys_thread_exit");
+		//debug!("Exit thread with error code {}!", arg);
+		core_scheduler().exit(arg);
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_abort() -> ! {
-	sys_exit(-1);
+syscall! {
+	pub extern "C" fn sys_abort() -> ! {
+		sys_exit(-1);
+	}
 }
 
 #[cfg(feature = "newlib")]
@@ -104,15 +110,16 @@ fn __sys_sbrk(incr: isize) -> usize {
 	old_end
 }
 
-#[cfg(feature = "newlib")]
-#[no_mangle]
-pub extern "C" fn sys_sbrk(incr: isize) -> usize {
-	kernel_function!(__sys_sbrk(incr))
-    //__sys_sbrk(incr)
+syscall! {
+	#[cfg(feature = "newlib")]
+	pub extern "C" fn sys_sbrk(incr: isize) -> usize {
+		kernel_function!(__sys_sbrk(incr))
+	    //__sys_sbrk(incr)
+	}
 }
 
 #[no_mangle]
-fn __sys_usleep(usecs: u64) {
+pub fn __sys_usleep(usecs: u64) {
 	if usecs > (scheduler::TASK_TIME_SLICE as u64) {
 		// Enough time to set a wakeup timer and block the current task.
 		debug!("sys_usleep blocking the task for {} microseconds", usecs);
@@ -132,41 +139,44 @@ fn __sys_usleep(usecs: u64) {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_usleep(usecs: u64) {
-	kernel_function!(__sys_usleep(usecs));
+syscall! {
+	pub extern "C" fn sys_usleep(usecs: u64) {
+		kernel_function!(__sys_usleep(usecs));
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_msleep(ms: u32) {
-	sys_usleep(u64::from(ms) * 1000);
+syscall! {
+	pub extern "C" fn sys_msleep(ms: u32) {
+		sys_usleep(u64::from(ms) * 1000);
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_nanosleep(rqtp: *const timespec, _rmtp: *mut timespec) -> i32 {
-	assert!(
-		!rqtp.is_null(),
-		"sys_nanosleep called with a zero rqtp parameter"
-	);
-	let requested_time = unsafe {
-									isolation_start!();
-									let temp = &*rqtp;
-									isolation_end!();
-									temp
-								};
-	if requested_time.tv_sec < 0
-		|| requested_time.tv_nsec < 0
-		|| requested_time.tv_nsec > 999_999_999
-	{
-		debug!("sys_nanosleep called with an invalid requested time, returning -EINVAL");
-		return -EINVAL;
-	}
+syscall! {
+	pub extern "C" fn sys_nanosleep(rqtp: *const timespec, _rmtp: *mut timespec) -> i32 {
+		assert!(
+			!rqtp.is_null(),
+			"sys_nanosleep called with a zero rqtp parameter"
+		);
+		let requested_time = unsafe {
+										isolation_start!();
+										let temp = &*rqtp;
+										isolation_end!();
+										temp
+									};
+		if requested_time.tv_sec < 0
+			|| requested_time.tv_nsec < 0
+			|| requested_time.tv_nsec > 999_999_999
+		{
+			debug!("sys_nanosleep called with an invalid requested time, returning -EINVAL");
+			return -EINVAL;
+		}
 
-	let microseconds =
-		(requested_time.tv_sec as u64) * 1_000_000 + (requested_time.tv_nsec as u64) / 1_000;
-	sys_usleep(microseconds);
+		let microseconds =
+			(requested_time.tv_sec as u64) * 1_000_000 + (requested_time.tv_nsec as u64) / 1_000;
+		sys_usleep(microseconds);
 
-	0
+		0
+	}
 }
 
 #[cfg(feature = "newlib")]
@@ -186,35 +196,39 @@ fn __sys_clone(id: *mut Tid, func: extern "C" fn(usize), arg: usize) -> i32 {
 	0
 }
 
-#[cfg(feature = "newlib")]
-#[no_mangle]
-pub extern "C" fn sys_clone(id: *mut Tid, func: extern "C" fn(usize), arg: usize) -> i32 {
-	let ret = kernel_function!(__sys_clone(id, func, arg));
-	return ret;
+syscall! {
+	#[cfg(feature = "newlib")]
+	pub extern "C" fn sys_clone(id: *mut Tid, func: extern "C" fn(usize), arg: usize) -> i32 {
+		let ret = kernel_function!(__sys_clone(id, func, arg));
+		return ret;
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_yield() {
-	kernel_enter!("sys_yield");
-	core_scheduler().reschedule();
-	kernel_exit!("sys_yield");
+syscall! {
+	pub extern "C" fn sys_yield() {
+		kernel_enter!("sys_yield");
+		core_scheduler().reschedule();
+		kernel_exit!("sys_yield");
+	}
 }
 
-#[cfg(feature = "newlib")]
-#[no_mangle]
-pub extern "C" fn sys_kill(dest: Tid, signum: i32) -> i32 {
-	debug!(
-		"sys_kill is unimplemented, returning -ENOSYS for killing {} with signal {}",
-		dest, signum
-	);
-	-ENOSYS
+syscall! {
+	#[cfg(feature = "newlib")]
+	pub extern "C" fn sys_kill(dest: Tid, signum: i32) -> i32 {
+		debug!(
+			"sys_kill is unimplemented, returning -ENOSYS for killing {} with signal {}",
+			dest, signum
+		);
+		-ENOSYS
+	}
 }
 
-#[cfg(feature = "newlib")]
-#[no_mangle]
-pub extern "C" fn sys_signal(_handler: SignalHandler) -> i32 {
-	debug!("sys_signal is unimplemented");
-	0
+syscall! {
+	#[cfg(feature = "newlib")]
+	pub extern "C" fn sys_signal(_handler: SignalHandler) -> i32 {
+		debug!("sys_signal is unimplemented");
+		0
+	}
 }
 
 #[no_mangle]
@@ -249,16 +263,17 @@ fn __sys_spawn(
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_spawn(
-	id: *mut Tid,
-	func: extern "C" fn(usize),
-	arg: usize,
-	prio: u8,
-	selector: isize,
-) -> i32 {
-	let ret = kernel_function!(__sys_spawn(id, func, arg, prio, selector));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_spawn(
+		id: *mut Tid,
+		func: extern "C" fn(usize),
+		arg: usize,
+		prio: u8,
+		selector: isize,
+	) -> i32 {
+		let ret = kernel_function!(__sys_spawn(id, func, arg, prio, selector));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -269,10 +284,11 @@ fn __sys_join(id: Tid) -> i32 {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_join(id: Tid) -> i32 {
-	let ret = kernel_function!(__sys_join(id));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_join(id: Tid) -> i32 {
+		let ret = kernel_function!(__sys_join(id));
+		return ret;
+	}
 }
 /*
 #[no_mangle]
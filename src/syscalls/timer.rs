@@ -73,10 +73,11 @@ fn __sys_clock_getres(clock_id: u64, res: *mut timespec) -> i32 {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_clock_getres(clock_id: u64, res: *mut timespec) -> i32 {
-	let ret = kernel_function!(__sys_clock_getres(clock_id, res));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_clock_getres(clock_id: u64, res: *mut timespec) -> i32 {
+		let ret = kernel_function!(__sys_clock_getres(clock_id, res));
+		return ret;
+	}
 }
 
 #[no_mangle]
@@ -113,9 +114,10 @@ fn __sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
-	kernel_function!(__sys_clock_gettime(clock_id, tp))
+syscall! {
+	pub extern "C" fn sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
+		kernel_function!(__sys_clock_gettime(clock_id, tp))
+	}
 }
 
 #[no_mangle]
@@ -166,22 +168,24 @@ fn __sys_clock_nanosleep(
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_clock_nanosleep(
-	clock_id: u64,
-	flags: i32,
-	rqtp: *const timespec,
-	rmtp: *mut timespec,
-) -> i32 {
-	let ret = kernel_function!(__sys_clock_nanosleep(clock_id, flags, rqtp, rmtp));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_clock_nanosleep(
+		clock_id: u64,
+		flags: i32,
+		rqtp: *const timespec,
+		rmtp: *mut timespec,
+	) -> i32 {
+		let ret = kernel_function!(__sys_clock_nanosleep(clock_id, flags, rqtp, rmtp));
+		return ret;
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_clock_settime(_clock_id: u64, _tp: *const timespec) -> i32 {
-	// We don't support setting any clocks yet.
-	debug!("sys_clock_settime is unimplemented, returning -EINVAL");
-	-EINVAL
+syscall! {
+	pub extern "C" fn sys_clock_settime(_clock_id: u64, _tp: *const timespec) -> i32 {
+		// We don't support setting any clocks yet.
+		debug!("sys_clock_settime is unimplemented, returning -EINVAL");
+		-EINVAL
+	}
 }
 
 #[no_mangle]
@@ -201,18 +205,20 @@ fn __sys_gettimeofday(tp: *mut timeval, tz: usize) -> i32 {
 	0
 }
 
-#[no_mangle]
-pub extern "C" fn sys_gettimeofday(tp: *mut timeval, tz: usize) -> i32 {
-	let ret =  kernel_function!(__sys_gettimeofday(tp, tz));
-	return ret;
+syscall! {
+	pub extern "C" fn sys_gettimeofday(tp: *mut timeval, tz: usize) -> i32 {
+		let ret =  kernel_function!(__sys_gettimeofday(tp, tz));
+		return ret;
+	}
 }
 
-#[no_mangle]
-pub extern "C" fn sys_setitimer(
-	_which: i32,
-	_value: *const itimerval,
-	_ovalue: *mut itimerval,
-) -> i32 {
-	debug!("Called sys_setitimer, which is unimplemented and always returns 0");
-	0
+syscall! {
+	pub extern "C" fn sys_setitimer(
+		_which: i32,
+		_value: *const itimerval,
+		_ovalue: *mut itimerval,
+	) -> i32 {
+		debug!("Called sys_setitimer, which is unimplemented and always returns 0");
+		0
+	}
 }
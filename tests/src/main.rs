@@ -113,7 +113,23 @@ fn test_threading() -> Result<(), ()> {
 */
 
 fn main() {
-        println!("Test {} ... {}", stringify!(hello), test_result(hello()));
+	println!("Test {} ... {}", stringify!(hello), test_result(hello()));
+	println!("Test {} ... {}", stringify!(domains), test_result(domains()));
+	println!(
+		"Test {} ... {}",
+		stringify!(reset_compartment),
+		test_result(reset_compartment())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(load_module),
+		test_result(load_module())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(bench_pkru_switch),
+		test_result(bench_pkru_switch())
+	);
 
 /*	
         test_syscall_cost();
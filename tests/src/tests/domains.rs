@@ -0,0 +1,54 @@
+use rusty_domains::{Access, Domain, PkruGuard, Secret};
+
+/// Exercises the safe compartments of rusty_domains.
+pub fn domains() -> Result<(), ()> {
+	let domain = match Domain::new() {
+		Ok(domain) => domain,
+		Err(error) => {
+			// Without PKU, there are no keys for the application.
+			println!("Unable to create a domain ({}), skipping", error);
+			return Ok(());
+		}
+	};
+	println!("Created domain with key {}", domain.pkey());
+
+	if domain.is_open() || !domain.call(|| domain.is_open()) || domain.is_open() {
+		println!("Domain is not closed outside of Domain::call");
+		return Err(());
+	}
+
+	let mut counter = domain.boxed(41u64).map_err(|_| ())?;
+	counter.with_mut(|value| *value += 1);
+	if counter.with(|value| *value) != 42 {
+		println!("Domain box lost its value");
+		return Err(());
+	}
+
+	let address = counter.as_ptr();
+	if domain.call(|| unsafe { *address }) != 42 {
+		println!("Domain::call cannot read the box");
+		return Err(());
+	}
+
+	{
+		let _guard = PkruGuard::new(&domain, Access::ReadOnly);
+		if unsafe { *address } != 42 {
+			return Err(());
+		}
+	}
+	if domain.is_open() {
+		println!("PKRU guard did not restore the rights");
+		return Err(());
+	}
+	drop(counter);
+
+	let secret = Secret::new(32).map_err(|_| ())?;
+	secret.with(|bytes| bytes.copy_from_slice(&[0x5a; 32])).map_err(|_| ())?;
+	let sum = secret.with(|bytes| bytes.iter().map(|byte| u32::from(*byte)).sum::<u32>()).map_err(|_| ())?;
+	if sum != 32 * 0x5a {
+		println!("Secret lost its bytes");
+		return Err(());
+	}
+
+	Ok(())
+}
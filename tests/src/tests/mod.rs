@@ -1,18 +1,22 @@
 use core::arch::x86_64 as arch;
 use http::{Request, Response};
+use rusty_domains::{rdpkru, wrpkru, UNSAFE_PKEY};
 use std::env;
 use std::f64::consts::PI;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
 use std::net::TcpStream;
+use std::ptr;
 use std::thread;
 use std::time::Instant;
 use std::vec;
 
+mod domains;
 mod laplace;
 mod matmul;
 
+pub use domains::domains;
 pub use matmul::test_matmul_strassen;
 
 #[inline]
@@ -163,6 +167,138 @@ pub fn pi_parallel(nthreads: u64, num_steps: u64) -> Result<(), ()> {
 	}
 }
 
+/// Part of the unsafe compartment, restored by sys_reset_compartment
+#[link_section = ".unsafe_data"]
+static mut UNSAFE_MARKER: u64 = 0x5afe;
+
+/// Runs `f` with write access to the unsafe compartment, which the application lacks otherwise.
+fn with_unsafe_compartment<R, F: FnOnce() -> R>(f: F) -> R {
+	let pkru = rdpkru();
+	unsafe {
+		wrpkru(pkru & !(0b11 << (2 * UNSAFE_PKEY)));
+	}
+	let result = f();
+	unsafe {
+		wrpkru(pkru);
+	}
+	result
+}
+
+/// Changes a static of .unsafe_data and resets the unsafe compartment twice, which must
+/// work while no isolated call runs and restore the static each time.
+pub fn reset_compartment() -> Result<(), ()> {
+	extern "C" {
+		fn sys_reset_compartment() -> i32;
+	}
+
+	// Without PKU (CPUID.07H:ECX.OSPKE), RDPKRU and WRPKRU are undefined.
+	if unsafe { arch::__cpuid_count(7, 0).ecx } & (1 << 4) == 0 {
+		println!("PKU is not enabled, skipping");
+		return Ok(());
+	}
+
+	for _ in 0..2 {
+		with_unsafe_compartment(|| unsafe { ptr::write_volatile(&mut UNSAFE_MARKER, 0xdead) });
+
+		if unsafe { sys_reset_compartment() } != 0 {
+			return Err(());
+		}
+
+		let marker = with_unsafe_compartment(|| unsafe { ptr::read_volatile(&UNSAFE_MARKER) });
+		if marker != 0x5afe {
+			println!("Reset left {:#x} in .unsafe_data", marker);
+			return Err(());
+		}
+	}
+
+	Ok(())
+}
+
+/// Builds a module, which exports `increment` (lea 1(%rdi), %rax; ret).
+/// It consists of one executable segment, which holds the headers, the dynamic section, the hash
+/// table, the symbol and string tables and the code, so file offsets equal addresses.
+fn increment_module() -> Vec<u8> {
+	const PROGRAM_HEADERS: u64 = 64;
+	const DYNAMIC: u64 = 176;
+	const HASH: u64 = 256;
+	const SYMTAB: u64 = 280;
+	const STRTAB: u64 = 328;
+	const CODE: u64 = 352;
+	const STRINGS: &[u8] = b"\0increment\0";
+	const INSTRUCTIONS: &[u8] = &[0x48, 0x8d, 0x47, 0x01, 0xc3];
+	let size = CODE + INSTRUCTIONS.len() as u64;
+
+	fn put(image: &mut Vec<u8>, offset: u64, data: &[u8]) {
+		let offset = offset as usize;
+		if image.len() < offset + data.len() {
+			image.resize(offset + data.len(), 0);
+		}
+		image[offset..offset + data.len()].copy_from_slice(data);
+	}
+	fn words(values: &[u64]) -> Vec<u8> {
+		values.iter().flat_map(|value| value.to_le_bytes().to_vec()).collect()
+	}
+
+	let mut image = Vec::new();
+	// ELF header: 64-bit, little-endian, ET_DYN for x86_64, two program headers
+	put(&mut image, 0, &[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+	put(&mut image, 16, &[3, 0, 0x3e, 0, 1, 0, 0, 0]);
+	put(&mut image, 32, &PROGRAM_HEADERS.to_le_bytes());
+	put(&mut image, 52, &[64, 0, 56, 0, 2, 0]);
+	// PT_LOAD (readable and executable) and PT_DYNAMIC
+	put(&mut image, PROGRAM_HEADERS, &[1, 0, 0, 0, 5, 0, 0, 0]);
+	put(&mut image, PROGRAM_HEADERS + 8, &words(&[0, 0, 0, size, size, 0x1000]));
+	put(&mut image, PROGRAM_HEADERS + 56, &[2, 0, 0, 0, 4, 0, 0, 0]);
+	put(&mut image, PROGRAM_HEADERS + 64, &words(&[DYNAMIC, DYNAMIC, DYNAMIC, 80, 80, 8]));
+	// DT_HASH, DT_SYMTAB, DT_STRTAB, DT_STRSZ, DT_NULL
+	put(
+		&mut image,
+		DYNAMIC,
+		&words(&[4, HASH, 6, SYMTAB, 5, STRTAB, 10, STRINGS.len() as u64, 0, 0]),
+	);
+	// One bucket and two symbols, the first one being the null symbol
+	put(&mut image, HASH, &[1, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+	// increment: global function in section 1
+	put(&mut image, SYMTAB + 24, &[1, 0, 0, 0, 0x12, 0, 1, 0]);
+	put(&mut image, SYMTAB + 32, &words(&[CODE, INSTRUCTIONS.len() as u64]));
+	put(&mut image, STRTAB, STRINGS);
+	put(&mut image, CODE, INSTRUCTIONS);
+
+	image
+}
+
+/// Loads a module into its own compartment, calls its function and unloads it.
+pub fn load_module() -> Result<(), ()> {
+	extern "C" {
+		fn sys_module_load(image: *const u8, len: usize) -> i32;
+		fn sys_module_call(id: i32, name: *const u8, arg: usize, ret: *mut usize) -> i32;
+		fn sys_module_unload(id: i32) -> i32;
+	}
+
+	let image = increment_module();
+	let id = match unsafe { sys_module_load(image.as_ptr(), image.len()) } {
+		// EOPNOTSUPP, e.g. no PKU or no strong isolation
+		-95 => {
+			println!("Modules are not supported, skipping");
+			return Ok(());
+		}
+		id if id < 0 => {
+			println!("Unable to load the module ({})", id);
+			return Err(());
+		}
+		id => id,
+	};
+
+	let mut ret = 0;
+	let status = unsafe { sys_module_call(id, b"increment\0".as_ptr(), 41, &mut ret) };
+	if unsafe { sys_module_unload(id) } != 0 || status != 0 || ret != 42 {
+		println!("Module call returned {} with {}", status, ret);
+		return Err(());
+	}
+
+	Ok(())
+}
+
 pub fn read_file() -> Result<(), std::io::Error> {
 	let mut file = File::open("/etc/hostname")?;
 	let mut contents = String::new();
@@ -366,3 +502,28 @@ pub fn test_pkru_context_switch() -> Result<(), ()> {
 	Ok(())
 }
 
+/// Compares the PKRU switch of the task switch as XSAVE state component with RDPKRU/WRPKRU.
+pub fn bench_pkru_switch() -> Result<(), ()> {
+	extern "C" {
+		fn sys_bench_pkru_switch(iterations: u64, xsave_ticks: *mut u64, register_ticks: *mut u64) -> i32;
+	}
+
+	let mut xsave_ticks = 0;
+	let mut register_ticks = 0;
+	match unsafe { sys_bench_pkru_switch(1000000, &mut xsave_ticks, &mut register_ticks) } {
+		0 => {
+			println!(
+				"PKRU switch {} ticks (XSAVE), {} ticks (WRPKRU)",
+				xsave_ticks, register_ticks
+			);
+			Ok(())
+		}
+		// EOPNOTSUPP, e.g. no PKU or no XSAVEC
+		-95 => {
+			println!("PKRU switch as XSAVE state component is not supported");
+			Ok(())
+		}
+		_ => Err(()),
+	}
+}
+